@@ -1,6 +1,8 @@
 // Export our platform-specific modules.
 #[cfg(target_arch="x86_64")]
-pub use self::x86_64::{vga, cpuio, serial, pic, interrupts};
+pub use self::x86_64::{vga, cpuio, serial, pic, interrupts, pci, bga, pit, cpuid, registers, msr,
+                       fpu, acpi, apic, ioapic, aml, power, gdt, smp, percpu, ipi, irqstat,
+                       softirq, thread, workqueue};
 
 // Implementations for x86_64.
 #[cfg(target_arch="x86_64")]
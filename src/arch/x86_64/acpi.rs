@@ -0,0 +1,646 @@
+//! ACPI tables: discovery, validation and parsing of the tables describing
+//! the interrupt controllers (MADT), power management (FADT), the HPET and
+//! the PCI Express configuration space (MCFG).
+// http://wiki.osdev.org/RSDP
+// http://wiki.osdev.org/MADT
+// http://www.uefi.org/sites/default/files/resources/ACPI_6_1.pdf, Chapter 5
+//
+// The tables are read in place through the physical memory window, so
+// tables above `MAX_PHYSICAL_ADDRESS` are ignored.
+
+use core::cmp;
+use core::fmt;
+use core::mem::size_of;
+use core::slice;
+use core::str;
+use spin::Mutex;
+use memory::{self, MAX_PHYSICAL_ADDRESS};
+use multiboot2::BootInformation;
+
+/// Where the real mode segment of the Extended BIOS Data Area is stored.
+const EBDA_POINTER: usize = 0x40E;
+
+/// The main BIOS area, searched for the RSDP after the EBDA.
+const BIOS_AREA_START: usize = 0xE_0000;
+const BIOS_AREA_END: usize = 0x10_0000;
+
+const RSDP_SIGNATURE: &'static [u8] = b"RSD PTR ";
+
+/// Size of the ACPI 1.0 RSDP, covered by its checksum.
+const RSDP_V1_LENGTH: usize = 20;
+
+/// Size of the ACPI 2.0 RSDP.
+const RSDP_V2_LENGTH: usize = 36;
+
+/// The header common to all the tables.
+#[repr(C, packed)]
+pub struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+impl SdtHeader {
+    pub fn signature(&self) -> &str {
+        str::from_utf8(&self.signature).unwrap_or("????")
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    pub fn oem_id(&self) -> &str {
+        str::from_utf8(&self.oem_id).unwrap_or("")
+    }
+
+    pub fn oem_table_id(&self) -> &str {
+        str::from_utf8(&self.oem_table_id).unwrap_or("")
+    }
+
+    /// The whole table, header included.
+    pub fn bytes(&self) -> &'static [u8] {
+        unsafe { slice::from_raw_parts(self as *const SdtHeader as *const u8, self.length as usize) }
+    }
+
+    /// The table after the header.
+    pub fn data(&self) -> &'static [u8] {
+        &self.bytes()[size_of::<SdtHeader>()..]
+    }
+
+    /// The physical address of the table.
+    pub fn address(&self) -> usize {
+        memory::virt_to_phys(self as *const SdtHeader as usize)
+    }
+}
+
+impl fmt::Display for SdtHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:#010x} {:6} (v{:02} {:6} {:8})", self.signature(), self.address(),
+               self.length, self.revision, self.oem_id(), self.oem_table_id())
+    }
+}
+
+/// The parts of the Root System Description Pointer we need.
+#[derive(Clone, Copy)]
+struct Rsdp {
+    revision: u8,
+    rsdt_address: u32,
+    /// ACPI 2.0 and later.
+    xsdt_address: u64,
+}
+
+/// The RSDT or the XSDT, listing 32 or 64 bit table addresses.
+#[derive(Clone, Copy)]
+struct Root {
+    table: &'static SdtHeader,
+    entry_size: usize,
+}
+
+impl Root {
+    /// The physical addresses of the tables.
+    fn entries(&self) -> RootEntries {
+        RootEntries { data: self.table.data(), entry_size: self.entry_size }
+    }
+}
+
+struct RootEntries {
+    data: &'static [u8],
+    entry_size: usize,
+}
+
+impl Iterator for RootEntries {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.data.len() < self.entry_size {
+            return None;
+        }
+        let address = if self.entry_size == 8 {
+            read_u64(self.data, 0) as usize
+        } else {
+            read_u32(self.data, 0) as usize
+        };
+        self.data = &self.data[self.entry_size..];
+        Some(address)
+    }
+}
+
+static ROOT: Mutex<Option<Root>> = Mutex::new(None);
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_u16(bytes, offset) as u32 | (read_u16(bytes, offset + 2) as u32) << 16
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    read_u32(bytes, offset) as u64 | (read_u32(bytes, offset + 4) as u64) << 32
+}
+
+/// Whether the bytes add up to 0, as required of all the ACPI structures.
+fn checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// `length` bytes of physical memory at `address`, if mapped.
+fn physical_bytes(address: usize, length: usize) -> Option<&'static [u8]> {
+    match address.checked_add(length) {
+        Some(end) if address != 0 && end <= MAX_PHYSICAL_ADDRESS => {}
+        _ => return None,
+    }
+    Some(unsafe { slice::from_raw_parts(memory::phys_to_virt(address) as *const u8, length) })
+}
+
+/// The table at the physical `address`, if it's mapped and valid.
+pub fn table_at(address: usize) -> Option<&'static SdtHeader> {
+    let header = match physical_bytes(address, size_of::<SdtHeader>()) {
+        Some(bytes) => unsafe { &*(bytes.as_ptr() as *const SdtHeader) },
+        None => return None,
+    };
+    let length = header.length as usize;
+    if length < size_of::<SdtHeader>() || physical_bytes(address, length).is_none() {
+        warn!("ACPI table at {:#x} has an invalid length", address);
+        return None;
+    }
+    if !checksum_valid(header.bytes()) {
+        warn!("ACPI table {} at {:#x} has a bad checksum", header.signature(), address);
+        return None;
+    }
+    Some(header)
+}
+
+/// Parse and validate the RSDP in `bytes`.
+fn parse_rsdp(bytes: &[u8]) -> Option<Rsdp> {
+    if bytes.len() < RSDP_V1_LENGTH || &bytes[..8] != RSDP_SIGNATURE ||
+       !checksum_valid(&bytes[..RSDP_V1_LENGTH]) {
+        return None;
+    }
+
+    let mut rsdp = Rsdp { revision: bytes[15], rsdt_address: read_u32(bytes, 16), xsdt_address: 0 };
+    if rsdp.revision >= 2 && bytes.len() >= RSDP_V2_LENGTH {
+        let length = read_u32(bytes, 20) as usize;
+        if length >= RSDP_V2_LENGTH && length <= bytes.len() && checksum_valid(&bytes[..length]) {
+            rsdp.xsdt_address = read_u64(bytes, 24);
+        }
+    }
+    Some(rsdp)
+}
+
+/// Look for the RSDP, on a 16 byte boundary, in `[start, end)`.
+fn scan_rsdp(start: usize, end: usize) -> Option<Rsdp> {
+    let mut address = start;
+    while address + RSDP_V2_LENGTH <= end {
+        if let Some(rsdp) = physical_bytes(address, RSDP_V2_LENGTH).and_then(parse_rsdp) {
+            return Some(rsdp);
+        }
+        address += 16;
+    }
+    None
+}
+
+/// Take the copy of the RSDP made by the bootloader, otherwise search the
+/// first KiB of the EBDA, then the BIOS area.
+fn find_rsdp(boot_info: &BootInformation) -> Option<Rsdp> {
+    if let Some(rsdp) = boot_info.acpi_rsdp().and_then(parse_rsdp) {
+        return Some(rsdp);
+    }
+
+    let segment = unsafe { *(memory::phys_to_virt(EBDA_POINTER) as *const u16) };
+    let ebda = (segment as usize) << 4;
+    if ebda != 0 {
+        if let Some(rsdp) = scan_rsdp(ebda, ebda + 1024) {
+            return Some(rsdp);
+        }
+    }
+    scan_rsdp(BIOS_AREA_START, BIOS_AREA_END)
+}
+
+/// Find and check the root table, and print a summary of the platform.
+/// Return `false` if there is no ACPI.
+pub fn init(boot_info: &BootInformation) -> bool {
+    let rsdp = match find_rsdp(boot_info) {
+        Some(rsdp) => rsdp,
+        None => {
+            warn!("No ACPI tables found");
+            return false;
+        }
+    };
+
+    let root = if rsdp.xsdt_address != 0 {
+        table_at(rsdp.xsdt_address as usize).map(|table| Root { table: table, entry_size: 8 })
+    } else {
+        None
+    };
+    let root = match root.or_else(|| {
+        table_at(rsdp.rsdt_address as usize).map(|table| Root { table: table, entry_size: 4 })
+    }) {
+        Some(root) => root,
+        None => {
+            warn!("No valid ACPI root table");
+            return false;
+        }
+    };
+
+    info!("ACPI revision {}, {} {}", rsdp.revision, root.table.signature(), root.table.oem_id());
+    *ROOT.lock() = Some(root);
+    for table in tables() {
+        debug!("  {}", table);
+    }
+    print_summary();
+    true
+}
+
+/// Whether ACPI tables were found.
+pub fn is_present() -> bool {
+    ROOT.lock().is_some()
+}
+
+/// The valid tables listed in the root table.
+pub fn tables() -> Tables {
+    Tables { entries: ROOT.lock().map(|root| root.entries()) }
+}
+
+pub struct Tables {
+    entries: Option<RootEntries>,
+}
+
+impl Iterator for Tables {
+    type Item = &'static SdtHeader;
+
+    fn next(&mut self) -> Option<&'static SdtHeader> {
+        let entries = match self.entries {
+            Some(ref mut entries) => entries,
+            None => return None,
+        };
+        loop {
+            match entries.next() {
+                Some(address) => if let Some(table) = table_at(address) {
+                    return Some(table);
+                },
+                None => return None,
+            }
+        }
+    }
+}
+
+/// The first table with `signature`, if any.
+pub fn find_table(signature: &str) -> Option<&'static SdtHeader> {
+    tables().find(|table| table.signature() == signature)
+}
+
+/// Print the processors, I/O APICs and interrupt overrides, and the
+/// timers and PCI Express segments.
+fn print_summary() {
+    if let Some(madt) = Madt::get() {
+        let (mut cpus, mut disabled) = (0, 0);
+        for entry in madt.entries() {
+            match entry {
+                MadtEntry::LocalApic { flags, .. } | MadtEntry::LocalX2Apic { flags, .. } => {
+                    if flags & LOCAL_APIC_ENABLED != 0 { cpus += 1 } else { disabled += 1 }
+                }
+                MadtEntry::IoApic { id, address, gsi_base } => {
+                    info!("  I/O APIC {} at {:#x}, GSI base {}", id, address, gsi_base);
+                }
+                MadtEntry::InterruptOverride { source, gsi, flags, .. } => {
+                    info!("  IRQ {} -> GSI {} (flags {:#x})", source, gsi, flags);
+                }
+                _ => {}
+            }
+        }
+        info!("  {} CPUs ({} disabled), local APICs at {:#x}{}", cpus, disabled,
+              madt.local_apic_address(),
+              if madt.flags() & MADT_PCAT_COMPAT != 0 { ", 8259 PICs" } else { "" });
+    }
+
+    if let Some(hpet) = Hpet::get() {
+        info!("  HPET {} at {:#x}, {} comparators, minimum tick {}", hpet.number,
+              hpet.address.address, hpet.comparators(), hpet.minimum_tick);
+    }
+
+    if let Some(mcfg) = Mcfg::get() {
+        for segment in mcfg.segments() {
+            info!("  PCI Express segment {} buses {}-{} at {:#x}", segment.segment,
+                  segment.start_bus, segment.end_bus, segment.address);
+        }
+    }
+}
+
+/// Address spaces of a `GenericAddress`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressSpace {
+    Memory,
+    Io,
+    PciConfig,
+    Other(u8),
+}
+
+/// A register location, in memory or I/O space.
+#[derive(Clone, Copy, Debug)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// Parse the 12 byte structure at `offset`, `None` if it's all zero.
+    fn parse(bytes: &[u8], offset: usize) -> Option<GenericAddress> {
+        if bytes.len() < offset + 12 {
+            return None;
+        }
+        let address = read_u64(bytes, offset + 4);
+        if address == 0 {
+            return None;
+        }
+        Some(GenericAddress {
+            space: match bytes[offset] {
+                0 => AddressSpace::Memory,
+                1 => AddressSpace::Io,
+                2 => AddressSpace::PciConfig,
+                space => AddressSpace::Other(space),
+            },
+            bit_width: bytes[offset + 1],
+            bit_offset: bytes[offset + 2],
+            access_size: bytes[offset + 3],
+            address: address,
+        })
+    }
+}
+
+/// Fixed ACPI Description Table, the power management hardware.
+#[derive(Clone, Copy, Debug)]
+pub struct Fadt {
+    /// Physical address of the DSDT.
+    pub dsdt: usize,
+    /// The ISA IRQ of the System Control Interrupt.
+    pub sci_interrupt: u16,
+    /// Port taking `acpi_enable` and `acpi_disable`, 0 if ACPI is always on.
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    /// RTC CMOS index of the century, 0 if none.
+    pub century: u8,
+    /// IA-PC boot architecture flags, see `BOOT_ARCH_*`.
+    pub boot_architecture: u16,
+    /// See `FADT_*`.
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+/// FADT IA-PC boot architecture flags.
+pub const BOOT_ARCH_LEGACY_DEVICES: u16 = 1 << 0;
+pub const BOOT_ARCH_8042: u16 = 1 << 1;
+pub const BOOT_ARCH_NO_VGA: u16 = 1 << 2;
+
+/// FADT flags.
+pub const FADT_PM_TIMER_32BIT: u32 = 1 << 8;
+pub const FADT_RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+pub const FADT_HARDWARE_REDUCED: u32 = 1 << 20;
+
+impl Fadt {
+    /// The FADT, if there is one.
+    pub fn get() -> Option<Fadt> {
+        let bytes = match find_table("FACP") {
+            Some(table) => table.bytes(),
+            None => return None,
+        };
+        if bytes.len() < 116 {
+            warn!("FADT too short");
+            return None;
+        }
+        let x_dsdt = if bytes.len() >= 148 { read_u64(bytes, 140) as usize } else { 0 };
+        Some(Fadt {
+            dsdt: if x_dsdt != 0 { x_dsdt } else { read_u32(bytes, 40) as usize },
+            sci_interrupt: read_u16(bytes, 46),
+            smi_command: read_u32(bytes, 48),
+            acpi_enable: bytes[52],
+            acpi_disable: bytes[53],
+            pm1a_event_block: read_u32(bytes, 56),
+            pm1b_event_block: read_u32(bytes, 60),
+            pm1a_control_block: read_u32(bytes, 64),
+            pm1b_control_block: read_u32(bytes, 68),
+            pm_timer_block: read_u32(bytes, 76),
+            pm1_event_length: bytes[88],
+            pm1_control_length: bytes[89],
+            century: bytes[108],
+            boot_architecture: read_u16(bytes, 109),
+            flags: read_u32(bytes, 112),
+            reset_register: GenericAddress::parse(bytes, 116),
+            // ACPI 1.0 tables stop after the flags.
+            reset_value: if bytes.len() > 128 { bytes[128] } else { 0 },
+        })
+    }
+}
+
+/// High Precision Event Timer description.
+#[derive(Clone, Copy, Debug)]
+pub struct Hpet {
+    /// Hardware ID of the event timer block.
+    pub event_timer_block_id: u32,
+    pub address: GenericAddress,
+    pub number: u8,
+    /// Minimum main counter ticks between periodic interrupts.
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+impl Hpet {
+    /// The HPET table, if there is one.
+    pub fn get() -> Option<Hpet> {
+        let bytes = match find_table("HPET") {
+            Some(table) => table.bytes(),
+            None => return None,
+        };
+        if bytes.len() < 56 {
+            return None;
+        }
+        GenericAddress::parse(bytes, 40).map(|address| Hpet {
+            event_timer_block_id: read_u32(bytes, 36),
+            address: address,
+            number: bytes[52],
+            minimum_tick: read_u16(bytes, 53),
+            page_protection: bytes[55],
+        })
+    }
+
+    /// Number of comparators of the timer block.
+    pub fn comparators(&self) -> u32 {
+        (self.event_timer_block_id >> 8 & 0x1F) + 1
+    }
+}
+
+/// PCI Express memory mapped configuration space of a segment.
+#[derive(Clone, Copy, Debug)]
+pub struct McfgSegment {
+    pub address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// PCI Express memory mapped configuration table.
+pub struct Mcfg {
+    table: &'static SdtHeader,
+}
+
+impl Mcfg {
+    /// The MCFG, if there is one.
+    pub fn get() -> Option<Mcfg> {
+        find_table("MCFG").map(|table| Mcfg { table: table })
+    }
+
+    pub fn segments(&self) -> McfgSegments {
+        // 8 reserved bytes after the header.
+        let data = self.table.data();
+        McfgSegments { data: &data[cmp::min(8, data.len())..] }
+    }
+}
+
+pub struct McfgSegments {
+    data: &'static [u8],
+}
+
+impl Iterator for McfgSegments {
+    type Item = McfgSegment;
+
+    fn next(&mut self) -> Option<McfgSegment> {
+        if self.data.len() < 16 {
+            return None;
+        }
+        let d = self.data;
+        self.data = &self.data[16..];
+        Some(McfgSegment {
+            address: read_u64(d, 0),
+            segment: read_u16(d, 8),
+            start_bus: d[10],
+            end_bus: d[11],
+        })
+    }
+}
+
+/// Bytes of the MADT before its entries: the local APIC address and the
+/// flags.
+const MADT_HEADER_SIZE: usize = 8;
+
+/// Multiple APIC Description Table.
+pub struct Madt {
+    table: &'static SdtHeader,
+}
+
+/// MADT flag: the system also has the 8259 PICs.
+pub const MADT_PCAT_COMPAT: u32 = 1 << 0;
+
+/// The polarity and trigger mode bits of an interrupt source.
+pub const MPS_POLARITY_MASK: u16 = 0x3;
+pub const MPS_POLARITY_HIGH: u16 = 0x1;
+pub const MPS_POLARITY_LOW: u16 = 0x3;
+pub const MPS_TRIGGER_MASK: u16 = 0xC;
+pub const MPS_TRIGGER_EDGE: u16 = 0x4;
+pub const MPS_TRIGGER_LEVEL: u16 = 0xC;
+
+/// Local APIC flag: the processor can be used.
+pub const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+/// An entry of the MADT.
+#[derive(Clone, Copy, Debug)]
+pub enum MadtEntry {
+    LocalApic { processor_id: u8, apic_id: u8, flags: u32 },
+    IoApic { id: u8, address: u32, gsi_base: u32 },
+    /// ISA IRQ `source` is connected to `gsi`.
+    InterruptOverride { bus: u8, source: u8, gsi: u32, flags: u16 },
+    /// Local APIC pin `lint` is connected to NMI, on all processors if
+    /// `processor_id` is 0xFF.
+    LocalApicNmi { processor_id: u8, flags: u16, lint: u8 },
+    LocalApicAddressOverride { address: u64 },
+    LocalX2Apic { apic_id: u32, flags: u32, processor_uid: u32 },
+    Unknown { typ: u8 },
+}
+
+impl Madt {
+    /// The MADT, if there is one and it's long enough for its header.
+    pub fn get() -> Option<Madt> {
+        let table = match find_table("APIC") {
+            Some(table) => table,
+            None => return None,
+        };
+        if table.data().len() < MADT_HEADER_SIZE {
+            warn!("MADT too short");
+            return None;
+        }
+        Some(Madt { table: table })
+    }
+
+    /// Physical address of the local APICs.
+    pub fn local_apic_address(&self) -> usize {
+        read_u32(self.table.data(), 0) as usize
+    }
+
+    pub fn flags(&self) -> u32 {
+        read_u32(self.table.data(), 4)
+    }
+
+    pub fn entries(&self) -> MadtEntries {
+        MadtEntries { data: &self.table.data()[MADT_HEADER_SIZE..] }
+    }
+}
+
+pub struct MadtEntries {
+    data: &'static [u8],
+}
+
+impl Iterator for MadtEntries {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        if self.data.len() < 2 {
+            return None;
+        }
+        let length = self.data[1] as usize;
+        if length < 2 || length > self.data.len() {
+            return None;
+        }
+        let d = &self.data[..length];
+        self.data = &self.data[length..];
+
+        Some(match (d[0], length) {
+            (0, 8) => MadtEntry::LocalApic {
+                processor_id: d[2],
+                apic_id: d[3],
+                flags: read_u32(d, 4),
+            },
+            (1, 12) => MadtEntry::IoApic { id: d[2], address: read_u32(d, 4), gsi_base: read_u32(d, 8) },
+            (2, 10) => MadtEntry::InterruptOverride {
+                bus: d[2],
+                source: d[3],
+                gsi: read_u32(d, 4),
+                flags: read_u16(d, 8),
+            },
+            (4, 6) => MadtEntry::LocalApicNmi { processor_id: d[2], flags: read_u16(d, 3), lint: d[5] },
+            (5, 12) => MadtEntry::LocalApicAddressOverride { address: read_u64(d, 4) },
+            (9, 16) => MadtEntry::LocalX2Apic {
+                apic_id: read_u32(d, 4),
+                flags: read_u32(d, 8),
+                processor_uid: read_u32(d, 12),
+            },
+            (typ, _) => MadtEntry::Unknown { typ: typ },
+        })
+    }
+}
@@ -0,0 +1,1798 @@
+//! Execution of AML: loading definition blocks into the namespace and
+//! evaluating control methods.
+// http://www.uefi.org/sites/default/files/resources/ACPI_6_1.pdf, Chapters 19 and 20
+//
+// Terms are executed straight from the bytecode, there is no parse tree.
+// Strings, buffers and packages created while loading the tables live in
+// the persistent arena, those created by methods in the temporary arena,
+// which the caller frees once it's done with the results.
+
+use core::cmp::{self, Ordering};
+use core::str;
+use arch::acpi;
+use arch::cpuio;
+use arch::pit;
+use super::Error;
+use super::namespace::{Field, FieldKind, NamePath, Namespace, Object, Region, ROOT};
+use super::region::{self, SPACE_SYSTEM_MEMORY};
+use super::value::*;
+
+/// How deep method calls can nest.
+const MAX_CALL_DEPTH: usize = 32;
+
+/// How deep term lists and term arguments can nest, method calls included.
+/// The interpreter recurses on the caller's stack, the boot stack while
+/// loading the tables, this bounds how much of it firmware can use.
+const MAX_NESTING: usize = 64;
+
+/// How many loop iterations an evaluation can run, so firmware polling
+/// hardware we don't emulate doesn't hang the kernel.
+const MAX_LOOP_ITERATIONS: usize = 0x10_0000;
+
+const MAX_ARGS: usize = 7;
+const MAX_LOCALS: usize = 8;
+
+/// Prefix of the two byte opcodes.
+const EXT_OP_PREFIX: u8 = 0x5B;
+
+/// Returned by the `Revision` opcode.
+const INTERPRETER_REVISION: u64 = 1;
+
+/// The interfaces `_OSI` answers yes to. Firmware tends to only enable
+/// features for the Windows versions it knows.
+const OSI_INTERFACES: &'static [&'static str] = &[
+    "Windows 2000",
+    "Windows 2001",
+    "Windows 2001 SP1",
+    "Windows 2001 SP2",
+    "Windows 2001.1",
+    "Windows 2006",
+    "Windows 2009",
+    "Windows 2012",
+    "Windows 2013",
+    "Windows 2015",
+    "Module Device",
+    "Processor Device",
+    "3.0 Thermal Model",
+    "Extended Address Space Descriptor",
+    "Processor Aggregator Device",
+];
+
+const HEX_DIGITS: &'static [u8] = b"0123456789ABCDEF";
+
+/// A position in AML code.
+#[derive(Clone, Copy)]
+pub struct Cursor {
+    code: &'static [u8],
+    position: usize,
+}
+
+impl Cursor {
+    pub fn new(code: &'static [u8]) -> Cursor {
+        Cursor { code: code, position: 0 }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.position >= self.code.len()
+    }
+
+    fn peek(&self) -> Result<u8, Error> {
+        self.code.get(self.position).cloned().ok_or(Error::UnexpectedEnd)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<u8> {
+        self.code.get(self.position + offset).cloned()
+    }
+
+    fn skip(&mut self, count: usize) {
+        self.position += count;
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        let byte = try!(self.peek());
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn word(&mut self) -> Result<u16, Error> {
+        Ok(try!(self.byte()) as u16 | (try!(self.byte()) as u16) << 8)
+    }
+
+    fn dword(&mut self) -> Result<u32, Error> {
+        Ok(try!(self.word()) as u32 | (try!(self.word()) as u32) << 16)
+    }
+
+    fn qword(&mut self) -> Result<u64, Error> {
+        Ok(try!(self.dword()) as u64 | (try!(self.dword()) as u64) << 32)
+    }
+
+    /// The bytes left.
+    fn rest(&self) -> &'static [u8] {
+        &self.code[cmp::min(self.position, self.code.len())..]
+    }
+
+    /// A PkgLength, as a plain number.
+    fn length(&mut self) -> Result<usize, Error> {
+        let lead = try!(self.byte());
+        let count = (lead >> 6) as usize;
+        if count == 0 {
+            return Ok((lead & 0x3F) as usize);
+        }
+        let mut length = (lead & 0x0F) as usize;
+        for i in 0..count {
+            length |= (try!(self.byte()) as usize) << (4 + 8 * i);
+        }
+        Ok(length)
+    }
+
+    /// A PkgLength and what it covers: the returned cursor ends with the
+    /// package, this one moves past it.
+    fn package(&mut self) -> Result<Cursor, Error> {
+        let start = self.position;
+        let end = start + try!(self.length());
+        if end > self.code.len() || end < self.position {
+            return Err(Error::UnexpectedEnd);
+        }
+        let body = Cursor { code: &self.code[..end], position: self.position };
+        self.position = end;
+        Ok(body)
+    }
+
+    fn name_segment(&mut self) -> Result<[u8; 4], Error> {
+        if self.position + 4 > self.code.len() {
+            return Err(Error::UnexpectedEnd);
+        }
+        let mut segment = [0; 4];
+        for i in 0..4 {
+            segment[i] = self.code[self.position + i];
+        }
+        if !is_lead_name_char(segment[0]) {
+            return Err(Error::InvalidName);
+        }
+        self.position += 4;
+        Ok(segment)
+    }
+
+    /// A NameString.
+    pub fn name_path(&mut self) -> Result<NamePath, Error> {
+        let mut path = NamePath::new();
+        if try!(self.peek()) == b'\\' {
+            path.root = true;
+            self.skip(1);
+        } else {
+            while try!(self.peek()) == b'^' {
+                path.parents += 1;
+                self.skip(1);
+            }
+        }
+        let count = match try!(self.peek()) {
+            0x00 => { self.skip(1); 0 }
+            0x2E => { self.skip(1); 2 }
+            0x2F => { self.skip(1); try!(self.byte()) as usize }
+            _ => 1,
+        };
+        for _ in 0..count {
+            let segment = try!(self.name_segment());
+            try!(path.push(segment));
+        }
+        Ok(path)
+    }
+}
+
+fn is_lead_name_char(b: u8) -> bool {
+    b == b'_' || (b >= b'A' && b <= b'Z')
+}
+
+/// Whether `b` starts a NameString.
+fn is_name_start(b: u8) -> bool {
+    is_lead_name_char(b) || b == b'\\' || b == b'^' || b == 0x2E || b == 0x2F
+}
+
+/// The arguments and locals of a method.
+pub struct Frame {
+    args: [Value; MAX_ARGS],
+    locals: [Value; MAX_LOCALS],
+}
+
+impl Frame {
+    fn new(args: &[Value]) -> Frame {
+        let mut frame = Frame {
+            args: [Value::Uninitialized; MAX_ARGS],
+            locals: [Value::Uninitialized; MAX_LOCALS],
+        };
+        for (slot, &arg) in frame.args.iter_mut().zip(args) {
+            *slot = arg;
+        }
+        frame
+    }
+}
+
+/// What to do after a term.
+enum Flow {
+    Next,
+    Return(Value),
+    Break,
+    Continue,
+}
+
+/// Writes formatted bytes to an arena allocation.
+struct Writer {
+    buffer: &'static mut [u8],
+    len: usize,
+}
+
+impl Writer {
+    fn push(&mut self, b: u8) {
+        if self.len < self.buffer.len() {
+            self.buffer[self.len] = b;
+            self.len += 1;
+        }
+    }
+
+    fn hex(&mut self, value: u64, digits: usize) {
+        for i in (0..digits).rev() {
+            self.push(HEX_DIGITS[((value >> (4 * i)) & 0xF) as usize]);
+        }
+    }
+
+    fn decimal(&mut self, value: u64) {
+        let mut digits = [0u8; 20];
+        let mut count = 0;
+        let mut value = value;
+        loop {
+            digits[count] = b'0' + (value % 10) as u8;
+            count += 1;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        for i in (0..count).rev() {
+            self.push(digits[i]);
+        }
+    }
+
+    fn bytes(&self) -> Bytes {
+        Bytes { address: self.buffer.as_ptr() as usize, len: self.len }
+    }
+}
+
+pub struct Interpreter {
+    pub namespace: Namespace,
+    persistent: Arena,
+    temporary: Arena,
+    /// All ones in the width of integers: 32 bits for revision 1 DSDTs.
+    ones: u64,
+    /// Nesting of the running methods, 0 while loading tables.
+    depth: usize,
+    /// Nesting of `exec_list` and `eval`, see `MAX_NESTING`.
+    nesting: usize,
+    /// Number of nodes when the outermost method started, the ones which
+    /// outlive it.
+    method_nodes: usize,
+    iterations: usize,
+}
+
+impl Interpreter {
+    pub const fn empty() -> Interpreter {
+        Interpreter {
+            namespace: Namespace::empty(),
+            persistent: Arena::empty(),
+            temporary: Arena::empty(),
+            ones: !0,
+            depth: 0,
+            nesting: 0,
+            method_nodes: 0,
+            iterations: 0,
+        }
+    }
+
+    pub fn new(namespace: Namespace, persistent: Arena, temporary: Arena) -> Interpreter {
+        Interpreter {
+            namespace: namespace,
+            persistent: persistent,
+            temporary: temporary,
+            ones: !0,
+            depth: 0,
+            nesting: 0,
+            method_nodes: 0,
+            iterations: 0,
+        }
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.namespace.count() > 0
+    }
+
+    /// Integers are 32-bit for revision 1 DSDTs.
+    pub fn set_32bit_integers(&mut self) {
+        self.ones = 0xFFFF_FFFF;
+    }
+
+    /// Size of an integer in bytes.
+    pub fn integer_bytes(&self) -> usize {
+        if self.ones == !0 { 8 } else { 4 }
+    }
+
+    /// Bytes of persistent memory used by the loaded tables.
+    pub fn memory_used(&self) -> usize {
+        self.persistent.used()
+    }
+
+    /// Where the temporary arena is at, to free what the next evaluations
+    /// allocate with `free_temporary`.
+    pub fn temporary_mark(&self) -> usize {
+        self.temporary.mark()
+    }
+
+    pub fn free_temporary(&mut self, mark: usize) {
+        self.temporary.reset(mark);
+    }
+
+    /// The arena for new values: the temporary one while running methods.
+    fn arena(&mut self) -> &mut Arena {
+        if self.depth == 0 { &mut self.persistent } else { &mut self.temporary }
+    }
+
+    /// `len` zeroed bytes, for values built outside the interpreter.
+    pub fn allocate_bytes(&mut self, len: usize) -> Result<Bytes, Error> {
+        self.arena().bytes(len)
+    }
+
+    /// Define the ASL name `name` from the root, for the predefined objects.
+    pub fn predefine(&mut self, name: &str, object: Object) -> Result<NodeId, Error> {
+        let path = try!(NamePath::parse(name));
+        self.namespace.define(ROOT, &path, object)
+    }
+
+    /// The object the ASL name `name` leads to from the root.
+    pub fn lookup(&self, name: &str) -> Option<NodeId> {
+        NamePath::parse(name).ok().and_then(|path| self.namespace.lookup(ROOT, &path))
+    }
+
+    /// Execute the definition block `aml`, from the DSDT or an SSDT.
+    pub fn load(&mut self, aml: &'static [u8]) -> Result<(), Error> {
+        let mut frame = Frame::new(&[]);
+        let mut cursor = Cursor::new(aml);
+        self.iterations = 0;
+        try!(self.exec_list(&mut cursor, ROOT, &mut frame));
+        Ok(())
+    }
+
+    /// Call `node` with `args` if it's a method, otherwise read its value.
+    pub fn evaluate(&mut self, node: NodeId, args: &[Value]) -> Result<Value, Error> {
+        let value = try!(self.call(node, args));
+        self.deref(value)
+    }
+
+    /// Evaluate the child `name` of `node`, `None` if there's no such
+    /// object.
+    pub fn evaluate_child(&mut self, node: NodeId, name: &str, args: &[Value])
+                          -> Result<Option<Value>, Error> {
+        let child = match try!(NamePath::parse(name)).last() {
+            Some(segment) => self.namespace.child(node, segment),
+            None => return Err(Error::InvalidName),
+        };
+        match child {
+            Some(child) => self.evaluate(child, args).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The object a name in a package, a reference or a string designates.
+    pub fn resolve(&self, value: Value) -> Option<NodeId> {
+        match value {
+            Value::Path { path, scope } => {
+                let mut cursor = Cursor::new(path.as_slice());
+                cursor.name_path().ok().and_then(|path| self.namespace.lookup(scope, &path))
+            }
+            Value::Node(node) | Value::Reference(Target::Node(node)) => Some(node),
+            Value::String(bytes) => {
+                str::from_utf8(bytes.as_slice()).ok()
+                    .and_then(|name| NamePath::parse(name).ok())
+                    .and_then(|path| self.namespace.lookup(ROOT, &path))
+            }
+            _ => None,
+        }
+    }
+
+    fn resolve_alias(&self, node: NodeId) -> NodeId {
+        let mut node = node;
+        for _ in 0..MAX_CALL_DEPTH {
+            match self.namespace.get(node).object {
+                Object::Alias(target) => node = target,
+                _ => break,
+            }
+        }
+        node
+    }
+
+    fn call(&mut self, node: NodeId, args: &[Value]) -> Result<Value, Error> {
+        let node = self.resolve_alias(node);
+        let code = match self.namespace.get(node).object {
+            Object::Method { code, .. } => code,
+            Object::Osi => return self.osi(args.get(0).cloned().unwrap_or(Value::Uninitialized)),
+            _ => return self.read_node(node),
+        };
+        if self.depth == MAX_CALL_DEPTH {
+            return Err(Error::TooDeep);
+        }
+        if self.depth == 0 {
+            self.method_nodes = self.namespace.count();
+            self.iterations = 0;
+        }
+
+        let nodes = self.namespace.count();
+        let mut frame = Frame::new(args);
+        let mut cursor = Cursor::new(code.as_slice());
+        self.depth += 1;
+        let result = self.exec_list(&mut cursor, node, &mut frame);
+        self.depth -= 1;
+        // The objects a method creates go away when it returns.
+        self.namespace.truncate(nodes);
+
+        match try!(result) {
+            Flow::Return(value) => Ok(value),
+            _ => Ok(Value::Integer(0)),
+        }
+    }
+
+    fn define(&mut self, scope: NodeId, path: &NamePath, object: Object) -> Result<NodeId, Error> {
+        match self.namespace.define(scope, path, object) {
+            Err(Error::AlreadyExists) if self.depth == 0 => {
+                warn!("AML: {} already defined in {}, keeping the first definition", path,
+                      self.namespace.path(scope));
+                Ok(self.namespace.lookup(scope, path).unwrap_or(scope))
+            }
+            result => result,
+        }
+    }
+
+    fn exec_list(&mut self, c: &mut Cursor, scope: NodeId, frame: &mut Frame)
+                 -> Result<Flow, Error> {
+        try!(self.nest());
+        let result = self.exec_terms(c, scope, frame);
+        self.nesting -= 1;
+        result
+    }
+
+    fn exec_terms(&mut self, c: &mut Cursor, scope: NodeId, frame: &mut Frame)
+                  -> Result<Flow, Error> {
+        while !c.is_done() {
+            match try!(self.exec_term(c, scope, frame)) {
+                Flow::Next => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Enter one more level of `exec_list` or `eval`, the caller leaves it.
+    fn nest(&mut self) -> Result<(), Error> {
+        if self.nesting == MAX_NESTING {
+            return Err(Error::TooDeep);
+        }
+        self.nesting += 1;
+        Ok(())
+    }
+
+    /// Execute the body of a scope-like definition. While loading, errors
+    /// only skip the rest of the body so the other definitions are kept.
+    fn exec_body(&mut self, body: Cursor, node: NodeId, frame: &mut Frame) -> Result<Flow, Error> {
+        let mut body = body;
+        match self.exec_list(&mut body, node, frame) {
+            Err(error) if self.depth == 0 => {
+                warn!("AML: {:?} in {}, skipping the rest of its definition", error,
+                      self.namespace.path(node));
+                Ok(Flow::Next)
+            }
+            result => result,
+        }
+    }
+
+    fn exec_term(&mut self, c: &mut Cursor, scope: NodeId, frame: &mut Frame)
+                 -> Result<Flow, Error> {
+        match try!(c.peek()) {
+            0x06 => {
+                // Alias
+                c.skip(1);
+                let source = try!(c.name_path());
+                let alias = try!(c.name_path());
+                let target = try!(self.namespace.lookup(scope, &source).ok_or(Error::NotFound));
+                try!(self.define(scope, &alias, Object::Alias(target)));
+            }
+            0x08 => {
+                // Name
+                c.skip(1);
+                let name = try!(c.name_path());
+                let value = try!(self.eval(c, scope, frame));
+                let value = if self.depth == 0 { try!(self.persist(value)) } else { value };
+                try!(self.define(scope, &name, Object::Name(value)));
+            }
+            0x10 => {
+                // Scope
+                c.skip(1);
+                let mut body = try!(c.package());
+                let name = try!(body.name_path());
+                let node = match self.namespace.lookup(scope, &name) {
+                    Some(node) => node,
+                    None => {
+                        warn!("AML: scope {} not found", name);
+                        return Err(Error::NotFound);
+                    }
+                };
+                return self.exec_body(body, node, frame);
+            }
+            0x14 => {
+                // Method
+                c.skip(1);
+                let mut body = try!(c.package());
+                let name = try!(body.name_path());
+                let flags = try!(body.byte());
+                let code = Bytes::from_slice(body.rest());
+                try!(self.define(scope, &name, Object::Method { code: code, args: flags & 0x7 }));
+            }
+            0x15 => {
+                // External
+                c.skip(1);
+                try!(c.name_path());
+                try!(c.byte());
+                try!(c.byte());
+            }
+            EXT_OP_PREFIX => return self.exec_ext_term(c, scope, frame),
+            0x86 => {
+                // Notify, nobody is listening.
+                c.skip(1);
+                try!(self.super_name(c, scope, frame));
+                try!(self.integer(c, scope, frame));
+            }
+            op @ 0x8A...0x8D | op @ 0x8F => {
+                // CreateDWordField, CreateWordField, CreateByteField,
+                // CreateBitField, CreateQWordField
+                c.skip(1);
+                let source = try!(self.eval(c, scope, frame));
+                let index = try!(self.integer(c, scope, frame)) as usize;
+                let name = try!(c.name_path());
+                let (bit_offset, bit_length) = match op {
+                    0x8A => (index * 8, 32),
+                    0x8B => (index * 8, 16),
+                    0x8C => (index * 8, 8),
+                    0x8D => (index, 1),
+                    _ => (index * 8, 64),
+                };
+                try!(self.create_field(scope, &name, source, bit_offset, bit_length));
+            }
+            0xA0 => {
+                // If, and Else
+                c.skip(1);
+                let mut body = try!(c.package());
+                let predicate = try!(self.integer(&mut body, scope, frame));
+                let mut otherwise = None;
+                if c.peek_at(0) == Some(0xA1) {
+                    c.skip(1);
+                    otherwise = Some(try!(c.package()));
+                }
+                if predicate != 0 {
+                    return self.exec_list(&mut body, scope, frame);
+                }
+                if let Some(mut otherwise) = otherwise {
+                    return self.exec_list(&mut otherwise, scope, frame);
+                }
+            }
+            0xA1 => {
+                // Else without an If
+                c.skip(1);
+                try!(c.package());
+            }
+            0xA2 => {
+                // While
+                c.skip(1);
+                let body = try!(c.package());
+                loop {
+                    let mut iteration = body;
+                    if try!(self.integer(&mut iteration, scope, frame)) == 0 {
+                        break;
+                    }
+                    self.iterations += 1;
+                    if self.iterations > MAX_LOOP_ITERATIONS {
+                        warn!("AML: loop in {} runs forever", self.namespace.path(scope));
+                        return Err(Error::Timeout);
+                    }
+                    match try!(self.exec_list(&mut iteration, scope, frame)) {
+                        Flow::Break => break,
+                        Flow::Return(value) => return Ok(Flow::Return(value)),
+                        Flow::Next | Flow::Continue => {}
+                    }
+                }
+            }
+            0xA3 | 0xCC => {
+                // Noop, BreakPoint
+                c.skip(1);
+            }
+            0xA4 => {
+                // Return
+                c.skip(1);
+                let value = try!(self.eval(c, scope, frame));
+                return Ok(Flow::Return(value));
+            }
+            0xA5 => {
+                c.skip(1);
+                return Ok(Flow::Break);
+            }
+            0x9F => {
+                c.skip(1);
+                return Ok(Flow::Continue);
+            }
+            _ => {
+                try!(self.eval(c, scope, frame));
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn exec_ext_term(&mut self, c: &mut Cursor, scope: NodeId, frame: &mut Frame)
+                     -> Result<Flow, Error> {
+        match c.peek_at(1) {
+            Some(0x01) => {
+                // Mutex, the kernel only runs AML under its own lock.
+                c.skip(2);
+                let name = try!(c.name_path());
+                try!(c.byte());
+                try!(self.define(scope, &name, Object::Mutex));
+            }
+            Some(0x02) => {
+                // Event
+                c.skip(2);
+                let name = try!(c.name_path());
+                try!(self.define(scope, &name, Object::Event));
+            }
+            Some(0x13) => {
+                // CreateField
+                c.skip(2);
+                let source = try!(self.eval(c, scope, frame));
+                let bit_offset = try!(self.integer(c, scope, frame)) as usize;
+                let bit_length = try!(self.integer(c, scope, frame)) as usize;
+                let name = try!(c.name_path());
+                try!(self.create_field(scope, &name, source, bit_offset, bit_length));
+            }
+            Some(0x21) => {
+                // Stall
+                c.skip(2);
+                let microseconds = try!(self.integer(c, scope, frame));
+                delay(microseconds);
+            }
+            Some(0x22) => {
+                // Sleep
+                c.skip(2);
+                let milliseconds = try!(self.integer(c, scope, frame));
+                delay(milliseconds * 1000);
+            }
+            Some(0x24) | Some(0x26) | Some(0x27) => {
+                // Signal, Reset, Release
+                c.skip(2);
+                try!(self.super_name(c, scope, frame));
+            }
+            Some(0x32) => {
+                // Fatal
+                c.skip(2);
+                let typ = try!(c.byte());
+                let code = try!(c.dword());
+                let argument = try!(self.integer(c, scope, frame));
+                error!("AML: fatal error type {:#x} code {:#x} argument {:#x}", typ, code, argument);
+                return Err(Error::Fatal);
+            }
+            Some(0x80) => {
+                // OpRegion
+                c.skip(2);
+                let name = try!(c.name_path());
+                let space = try!(c.byte());
+                let offset = try!(self.integer(c, scope, frame));
+                let length = try!(self.integer(c, scope, frame));
+                let region = Region { space: space, offset: offset, length: length, scope: scope };
+                try!(self.define(scope, &name, Object::Region(region)));
+            }
+            Some(0x81) => {
+                // Field
+                c.skip(2);
+                let mut body = try!(c.package());
+                let region = try!(self.lookup_path(&mut body, scope));
+                let flags = try!(body.byte());
+                try!(self.field_list(&mut body, scope, FieldKind::Region(region), flags));
+            }
+            Some(0x82) => {
+                // Device
+                c.skip(2);
+                let mut body = try!(c.package());
+                let name = try!(body.name_path());
+                let node = try!(self.define(scope, &name, Object::Device));
+                return self.exec_body(body, node, frame);
+            }
+            Some(0x83) => {
+                // Processor
+                c.skip(2);
+                let mut body = try!(c.package());
+                let name = try!(body.name_path());
+                try!(body.byte());
+                try!(body.dword());
+                try!(body.byte());
+                let node = try!(self.define(scope, &name, Object::Processor));
+                return self.exec_body(body, node, frame);
+            }
+            Some(0x84) => {
+                // PowerResource
+                c.skip(2);
+                let mut body = try!(c.package());
+                let name = try!(body.name_path());
+                try!(body.byte());
+                try!(body.word());
+                let node = try!(self.define(scope, &name, Object::PowerResource));
+                return self.exec_body(body, node, frame);
+            }
+            Some(0x85) => {
+                // ThermalZone
+                c.skip(2);
+                let mut body = try!(c.package());
+                let name = try!(body.name_path());
+                let node = try!(self.define(scope, &name, Object::ThermalZone));
+                return self.exec_body(body, node, frame);
+            }
+            Some(0x86) => {
+                // IndexField
+                c.skip(2);
+                let mut body = try!(c.package());
+                let index = try!(self.lookup_path(&mut body, scope));
+                let data = try!(self.lookup_path(&mut body, scope));
+                let flags = try!(body.byte());
+                let kind = FieldKind::Index { index: index, data: data };
+                try!(self.field_list(&mut body, scope, kind, flags));
+            }
+            Some(0x87) => {
+                // BankField
+                c.skip(2);
+                let mut body = try!(c.package());
+                let region = try!(self.lookup_path(&mut body, scope));
+                let bank = try!(self.lookup_path(&mut body, scope));
+                let value = try!(self.integer(&mut body, scope, frame));
+                let flags = try!(body.byte());
+                let kind = FieldKind::Bank { region: region, bank: bank, value: value };
+                try!(self.field_list(&mut body, scope, kind, flags));
+            }
+            Some(0x88) => {
+                // DataRegion, over an ACPI table.
+                c.skip(2);
+                let name = try!(c.name_path());
+                let signature = try!(self.eval(c, scope, frame));
+                try!(self.eval(c, scope, frame));
+                try!(self.eval(c, scope, frame));
+                let signature = match try!(self.deref(signature)) {
+                    Value::String(bytes) => str::from_utf8(bytes.as_slice()).unwrap_or(""),
+                    _ => return Err(Error::TypeMismatch),
+                };
+                let table = try!(acpi::find_table(signature).ok_or(Error::NotFound));
+                let region = Region {
+                    space: SPACE_SYSTEM_MEMORY,
+                    offset: table.address() as u64,
+                    length: table.bytes().len() as u64,
+                    scope: scope,
+                };
+                try!(self.define(scope, &name, Object::Region(region)));
+            }
+            Some(0x1F) | Some(0x20) | Some(0x2A) => {
+                return Err(Error::Unsupported("loading tables from AML"));
+            }
+            _ => {
+                try!(self.eval(c, scope, frame));
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Look up the NameString at `c`, which must exist.
+    fn lookup_path(&mut self, c: &mut Cursor, scope: NodeId) -> Result<NodeId, Error> {
+        let path = try!(c.name_path());
+        match self.namespace.lookup(scope, &path) {
+            Some(node) => Ok(self.resolve_alias(node)),
+            None => {
+                debug!("AML: {} not found from {}", path, self.namespace.path(scope));
+                Err(Error::NotFound)
+            }
+        }
+    }
+
+    fn create_field(&mut self, scope: NodeId, name: &NamePath, source: Value, bit_offset: usize,
+                    bit_length: usize) -> Result<(), Error> {
+        let buffer = match try!(self.deref(source)) {
+            Value::Buffer(bytes) => bytes,
+            _ => return Err(Error::TypeMismatch),
+        };
+        if bit_offset + bit_length > buffer.len * 8 {
+            return Err(Error::InvalidIndex);
+        }
+        let field = Object::BufferField {
+            buffer: buffer,
+            bit_offset: bit_offset,
+            bit_length: bit_length,
+        };
+        try!(self.define(scope, name, field));
+        Ok(())
+    }
+
+    /// Define the fields of a Field, IndexField or BankField.
+    fn field_list(&mut self, body: &mut Cursor, scope: NodeId, kind: FieldKind, flags: u8)
+                  -> Result<(), Error> {
+        let mut flags = flags;
+        let mut bit_offset = 0;
+        while !body.is_done() {
+            match try!(body.peek()) {
+                0x00 => {
+                    // ReservedField
+                    body.skip(1);
+                    bit_offset += try!(body.length());
+                }
+                0x01 => {
+                    // AccessField
+                    body.skip(1);
+                    let access = try!(body.byte());
+                    try!(body.byte());
+                    flags = (flags & 0xF0) | (access & 0x0F);
+                }
+                0x02 => return Err(Error::Unsupported("ConnectField")),
+                0x03 => {
+                    // ExtendedAccessField
+                    body.skip(1);
+                    let access = try!(body.byte());
+                    try!(body.byte());
+                    try!(body.byte());
+                    flags = (flags & 0xF0) | (access & 0x0F);
+                }
+                _ => {
+                    let segment = try!(body.name_segment());
+                    let bit_length = try!(body.length());
+                    let mut name = NamePath::new();
+                    try!(name.push(segment));
+                    let field = Field {
+                        kind: kind,
+                        bit_offset: bit_offset,
+                        bit_length: bit_length,
+                        flags: flags,
+                    };
+                    try!(self.define(scope, &name, Object::Field(field)));
+                    bit_offset += bit_length;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn integer(&mut self, c: &mut Cursor, scope: NodeId, frame: &mut Frame) -> Result<u64, Error> {
+        let value = try!(self.eval(c, scope, frame));
+        self.to_integer(value)
+    }
+
+    fn boolean(&self, value: bool) -> Value {
+        Value::Integer(if value { self.ones } else { 0 })
+    }
+
+    fn store_result(&mut self, value: Value, target: Target, frame: &mut Frame)
+                    -> Result<Value, Error> {
+        try!(self.store(target, value, frame));
+        Ok(value)
+    }
+
+    /// Evaluate a TermArg.
+    fn eval(&mut self, c: &mut Cursor, scope: NodeId, frame: &mut Frame) -> Result<Value, Error> {
+        try!(self.nest());
+        let result = self.eval_term(c, scope, frame);
+        self.nesting -= 1;
+        result
+    }
+
+    fn eval_term(&mut self, c: &mut Cursor, scope: NodeId, frame: &mut Frame)
+                 -> Result<Value, Error> {
+        let op = try!(c.byte());
+        Ok(match op {
+            0x00 => Value::Integer(0),
+            0x01 => Value::Integer(1),
+            0xFF => Value::Integer(self.ones),
+            0x0A => Value::Integer(try!(c.byte()) as u64),
+            0x0B => Value::Integer(try!(c.word()) as u64),
+            0x0C => Value::Integer(try!(c.dword()) as u64),
+            0x0E => Value::Integer(try!(c.qword()) & self.ones),
+            0x0D => {
+                // String
+                let rest = c.rest();
+                let len = try!(rest.iter().position(|&b| b == 0).ok_or(Error::UnexpectedEnd));
+                c.skip(len + 1);
+                // Copied, as `Index` makes it writable.
+                Value::String(try!(self.arena().copy_bytes(&rest[..len], len)))
+            }
+            0x11 => {
+                // Buffer
+                let mut body = try!(c.package());
+                let size = try!(self.integer(&mut body, scope, frame)) as usize;
+                let initializer = body.rest();
+                let len = cmp::max(size, initializer.len());
+                Value::Buffer(try!(self.arena().copy_bytes(initializer, len)))
+            }
+            0x12 => {
+                // Package
+                let mut body = try!(c.package());
+                let count = try!(body.byte()) as usize;
+                try!(self.package(&mut body, count, scope, frame))
+            }
+            0x13 => {
+                // VarPackage
+                let mut body = try!(c.package());
+                let count = try!(self.integer(&mut body, scope, frame)) as usize;
+                try!(self.package(&mut body, count, scope, frame))
+            }
+            0x60...0x67 => frame.locals[(op - 0x60) as usize],
+            0x68...0x6E => frame.args[(op - 0x68) as usize],
+            EXT_OP_PREFIX => return self.eval_ext(c, scope, frame),
+            op if is_name_start(op) => {
+                c.position -= 1;
+                let node = try!(self.lookup_path(c, scope));
+                return self.invoke(node, c, scope, frame);
+            }
+            0x70 => {
+                // Store
+                let value = try!(self.eval(c, scope, frame));
+                let target = try!(self.target(c, scope, frame));
+                try!(self.store_result(value, target, frame))
+            }
+            0x71 => {
+                // RefOf
+                match try!(self.super_name(c, scope, frame)) {
+                    Some(Target::Local(_)) | Some(Target::Arg(_)) => {
+                        return Err(Error::Unsupported("references to locals"));
+                    }
+                    Some(target) => Value::Reference(target),
+                    None => return Err(Error::NotFound),
+                }
+            }
+            0x72 | 0x74 | 0x77 | 0x79 | 0x7A | 0x7B | 0x7C | 0x7D | 0x7E | 0x7F | 0x85 => {
+                // Add, Subtract, Multiply, ShiftLeft, ShiftRight, And,
+                // NAnd, Or, NOr, XOr, Mod
+                let a = try!(self.integer(c, scope, frame));
+                let b = try!(self.integer(c, scope, frame));
+                let target = try!(self.target(c, scope, frame));
+                let result = match op {
+                    0x72 => a.wrapping_add(b),
+                    0x74 => a.wrapping_sub(b),
+                    0x77 => a.wrapping_mul(b),
+                    0x79 => if b >= 64 { 0 } else { a << b },
+                    0x7A => if b >= 64 { 0 } else { a >> b },
+                    0x7B => a & b,
+                    0x7C => !(a & b),
+                    0x7D => a | b,
+                    0x7E => !(a | b),
+                    0x7F => a ^ b,
+                    _ => {
+                        if b == 0 {
+                            return Err(Error::DivideByZero);
+                        }
+                        a % b
+                    }
+                };
+                let result = Value::Integer(result & self.ones);
+                try!(self.store_result(result, target, frame))
+            }
+            0x73 | 0x84 => {
+                // Concat, ConcatRes
+                let a = try!(self.eval(c, scope, frame));
+                let b = try!(self.eval(c, scope, frame));
+                let target = try!(self.target(c, scope, frame));
+                let result = if op == 0x73 {
+                    try!(self.concat(a, b))
+                } else {
+                    try!(self.concat_resources(a, b))
+                };
+                try!(self.store_result(result, target, frame))
+            }
+            0x75 | 0x76 => {
+                // Increment, Decrement
+                let target = try!(self.target(c, scope, frame));
+                let value = try!(self.read_target(target, frame));
+                let value = try!(self.to_integer(value));
+                let value = if op == 0x75 { value.wrapping_add(1) } else { value.wrapping_sub(1) };
+                let result = Value::Integer(value & self.ones);
+                try!(self.store_result(result, target, frame))
+            }
+            0x78 => {
+                // Divide
+                let dividend = try!(self.integer(c, scope, frame));
+                let divisor = try!(self.integer(c, scope, frame));
+                let remainder = try!(self.target(c, scope, frame));
+                let quotient = try!(self.target(c, scope, frame));
+                if divisor == 0 {
+                    return Err(Error::DivideByZero);
+                }
+                try!(self.store(remainder, Value::Integer(dividend % divisor), frame));
+                try!(self.store_result(Value::Integer(dividend / divisor), quotient, frame))
+            }
+            0x80 | 0x81 | 0x82 => {
+                // Not, FindSetLeftBit, FindSetRightBit
+                let value = try!(self.integer(c, scope, frame));
+                let target = try!(self.target(c, scope, frame));
+                let result = match op {
+                    0x80 => !value & self.ones,
+                    _ if value == 0 => 0,
+                    0x81 => 64 - value.leading_zeros() as u64,
+                    _ => value.trailing_zeros() as u64 + 1,
+                };
+                try!(self.store_result(Value::Integer(result), target, frame))
+            }
+            0x83 => {
+                // DerefOf
+                let value = try!(self.eval(c, scope, frame));
+                match value {
+                    Value::String(_) => {
+                        let node = try!(self.resolve(value).ok_or(Error::NotFound));
+                        try!(self.read_node(node))
+                    }
+                    _ => try!(self.deref(value)),
+                }
+            }
+            0x87 => {
+                // SizeOf
+                let target = try!(self.target(c, scope, frame));
+                let value = try!(self.read_target(target, frame));
+                Value::Integer(match try!(self.deref(value)) {
+                    Value::String(bytes) | Value::Buffer(bytes) => bytes.len as u64,
+                    Value::Package(elements) => elements.len as u64,
+                    _ => return Err(Error::TypeMismatch),
+                })
+            }
+            0x88 => {
+                // Index
+                let source = try!(self.eval(c, scope, frame));
+                let index = try!(self.integer(c, scope, frame)) as usize;
+                let target = try!(self.target(c, scope, frame));
+                let reference = match try!(self.deref(source)) {
+                    Value::Package(elements) if index < elements.len => {
+                        Target::Element { package: elements, index: index }
+                    }
+                    Value::Buffer(bytes) | Value::String(bytes) if index < bytes.len => {
+                        Target::Byte { buffer: bytes, index: index }
+                    }
+                    Value::Package(_) | Value::Buffer(_) | Value::String(_) => {
+                        return Err(Error::InvalidIndex);
+                    }
+                    _ => return Err(Error::TypeMismatch),
+                };
+                try!(self.store_result(Value::Reference(reference), target, frame))
+            }
+            0x89 => {
+                // Match
+                let package = try!(self.eval(c, scope, frame));
+                let first_op = try!(c.byte());
+                let first = try!(self.eval(c, scope, frame));
+                let second_op = try!(c.byte());
+                let second = try!(self.eval(c, scope, frame));
+                let start = try!(self.integer(c, scope, frame)) as usize;
+                let elements = match try!(self.deref(package)) {
+                    Value::Package(elements) => elements,
+                    _ => return Err(Error::TypeMismatch),
+                };
+                let mut found = self.ones;
+                for index in start..elements.len {
+                    let element = try!(self.deref(elements.get(index)));
+                    if try!(self.matches(element, first_op, first)) &&
+                       try!(self.matches(element, second_op, second)) {
+                        found = index as u64;
+                        break;
+                    }
+                }
+                Value::Integer(found)
+            }
+            0x8E => {
+                // ObjectType
+                let typ = match try!(self.super_name(c, scope, frame)) {
+                    Some(Target::Node(node)) => self.node_type(node),
+                    Some(Target::Debug) => TYPE_DEBUG,
+                    Some(target) => {
+                        let value = try!(self.read_target(target, frame));
+                        let value = try!(self.deref(value));
+                        value_type(&value)
+                    }
+                    None => return Err(Error::NotFound),
+                };
+                Value::Integer(typ)
+            }
+            0x90 | 0x91 => {
+                // LAnd, LOr
+                let a = try!(self.integer(c, scope, frame));
+                let b = try!(self.integer(c, scope, frame));
+                self.boolean(if op == 0x90 { a != 0 && b != 0 } else { a != 0 || b != 0 })
+            }
+            0x92 => {
+                // LNot, LNotEqual, LLessEqual and LGreaterEqual are LNot
+                // of the opposite comparison.
+                let value = try!(self.integer(c, scope, frame));
+                self.boolean(value == 0)
+            }
+            0x93 | 0x94 | 0x95 => {
+                // LEqual, LGreater, LLess
+                let a = try!(self.eval(c, scope, frame));
+                let b = try!(self.eval(c, scope, frame));
+                let ordering = try!(self.compare(a, b));
+                self.boolean(ordering == match op {
+                    0x93 => Ordering::Equal,
+                    0x94 => Ordering::Greater,
+                    _ => Ordering::Less,
+                })
+            }
+            0x96 => {
+                // ToBuffer
+                let value = try!(self.eval(c, scope, frame));
+                let target = try!(self.target(c, scope, frame));
+                let result = Value::Buffer(try!(self.to_buffer(value)));
+                try!(self.store_result(result, target, frame))
+            }
+            0x97 | 0x98 => {
+                // ToDecimalString, ToHexString
+                let value = try!(self.eval(c, scope, frame));
+                let target = try!(self.target(c, scope, frame));
+                let result = try!(self.to_formatted_string(value, op == 0x98));
+                try!(self.store_result(Value::String(result), target, frame))
+            }
+            0x99 => {
+                // ToInteger, where strings can be decimal.
+                let value = try!(self.eval(c, scope, frame));
+                let target = try!(self.target(c, scope, frame));
+                let result = match try!(self.deref(value)) {
+                    Value::String(bytes) => parse_integer(bytes.as_slice()) & self.ones,
+                    value => try!(self.to_integer(value)),
+                };
+                try!(self.store_result(Value::Integer(result), target, frame))
+            }
+            0x9C => {
+                // ToString
+                let value = try!(self.eval(c, scope, frame));
+                let length = try!(self.integer(c, scope, frame));
+                let target = try!(self.target(c, scope, frame));
+                let source = try!(self.to_buffer(value));
+                let source = source.as_slice();
+                let end = source.iter().position(|&b| b == 0).unwrap_or(source.len());
+                let end = if length < end as u64 { length as usize } else { end };
+                let result = try!(self.arena().copy_bytes(&source[..end], end));
+                try!(self.store_result(Value::String(result), target, frame))
+            }
+            0x9D => {
+                // CopyObject
+                let value = try!(self.eval(c, scope, frame));
+                let value = try!(self.deref(value));
+                match try!(self.super_name(c, scope, frame)) {
+                    Some(Target::Node(node)) => {
+                        let persistent = self.depth == 0 || node < self.method_nodes;
+                        let value = if persistent { try!(self.persist(value)) } else { value };
+                        self.namespace.set_object(node, Object::Name(value));
+                    }
+                    Some(Target::Local(index)) => frame.locals[index] = value,
+                    Some(Target::Arg(index)) => frame.args[index] = value,
+                    Some(target) => try!(self.store(target, value, frame)),
+                    None => return Err(Error::NotFound),
+                }
+                value
+            }
+            0x9E => {
+                // Mid
+                let value = try!(self.eval(c, scope, frame));
+                let index = try!(self.integer(c, scope, frame));
+                let length = try!(self.integer(c, scope, frame));
+                let target = try!(self.target(c, scope, frame));
+                let (source, string) = match try!(self.deref(value)) {
+                    Value::String(bytes) => (bytes, true),
+                    Value::Buffer(bytes) => (bytes, false),
+                    _ => return Err(Error::TypeMismatch),
+                };
+                let start = cmp::min(index, source.len as u64) as usize;
+                let end = cmp::min(start as u64 + cmp::min(length, source.len as u64),
+                                   source.len as u64) as usize;
+                let bytes = try!(self.arena().copy_bytes(&source.as_slice()[start..end], end - start));
+                let result = if string { Value::String(bytes) } else { Value::Buffer(bytes) };
+                try!(self.store_result(result, target, frame))
+            }
+            _ => {
+                debug!("AML: invalid opcode {:#x} in {}", op, self.namespace.path(scope));
+                return Err(Error::InvalidOpcode(op as u16));
+            }
+        })
+    }
+
+    fn eval_ext(&mut self, c: &mut Cursor, scope: NodeId, frame: &mut Frame)
+                -> Result<Value, Error> {
+        let op = try!(c.byte());
+        Ok(match op {
+            0x12 => {
+                // CondRefOf
+                let source = try!(self.super_name(c, scope, frame));
+                let target = try!(self.target(c, scope, frame));
+                match source {
+                    Some(source) => {
+                        try!(self.store(target, Value::Reference(source), frame));
+                        self.boolean(true)
+                    }
+                    None => self.boolean(false),
+                }
+            }
+            0x23 => {
+                // Acquire, which never times out.
+                try!(self.super_name(c, scope, frame));
+                try!(c.word());
+                Value::Integer(0)
+            }
+            0x25 => {
+                // Wait, events are always signaled.
+                try!(self.super_name(c, scope, frame));
+                try!(self.integer(c, scope, frame));
+                Value::Integer(0)
+            }
+            0x28 | 0x29 => {
+                // FromBCD, ToBCD
+                let value = try!(self.integer(c, scope, frame));
+                let target = try!(self.target(c, scope, frame));
+                let result = if op == 0x28 { from_bcd(value) } else { to_bcd(value) };
+                try!(self.store_result(Value::Integer(result & self.ones), target, frame))
+            }
+            0x30 => Value::Integer(INTERPRETER_REVISION),
+            0x31 => Value::Reference(Target::Debug),
+            0x33 => {
+                // Timer, in 100 ns units.
+                Value::Integer(pit::uptime_ms().wrapping_mul(10_000))
+            }
+            _ => {
+                debug!("AML: invalid opcode {:#x} {:#x} in {}", EXT_OP_PREFIX, op,
+                       self.namespace.path(scope));
+                return Err(Error::InvalidOpcode((EXT_OP_PREFIX as u16) << 8 | op as u16));
+            }
+        })
+    }
+
+    /// The elements of a package, names being stored as such to be looked
+    /// up when used.
+    fn package(&mut self, body: &mut Cursor, count: usize, scope: NodeId, frame: &mut Frame)
+               -> Result<Value, Error> {
+        let elements = try!(self.arena().elements(count));
+        let mut index = 0;
+        while !body.is_done() {
+            let value = if is_name_start(try!(body.peek())) {
+                let start = body.position;
+                try!(body.name_path());
+                Value::Path { path: Bytes::from_slice(&body.code[start..body.position]), scope: scope }
+            } else {
+                try!(self.eval(body, scope, frame))
+            };
+            if index < count {
+                elements.set(index, value);
+            }
+            index += 1;
+        }
+        Ok(Value::Package(elements))
+    }
+
+    /// Evaluate the object `node` found in the code: a method is called
+    /// with the arguments following its name.
+    fn invoke(&mut self, node: NodeId, c: &mut Cursor, scope: NodeId, frame: &mut Frame)
+              -> Result<Value, Error> {
+        let count = match self.namespace.get(node).object {
+            Object::Method { args, .. } => args as usize,
+            Object::Osi => 1,
+            _ => return self.read_node(node),
+        };
+        let mut args = [Value::Uninitialized; MAX_ARGS];
+        for i in 0..count {
+            args[i] = try!(self.eval(c, scope, frame));
+        }
+        self.call(node, &args[..count])
+    }
+
+    /// Parse a SuperName. Names aren't evaluated, and unknown ones give
+    /// `None`.
+    fn super_name(&mut self, c: &mut Cursor, scope: NodeId, frame: &mut Frame)
+                  -> Result<Option<Target>, Error> {
+        match try!(c.peek()) {
+            0x00 => {
+                c.skip(1);
+                Ok(Some(Target::Null))
+            }
+            op @ 0x60...0x67 => {
+                c.skip(1);
+                Ok(Some(Target::Local((op - 0x60) as usize)))
+            }
+            op @ 0x68...0x6E => {
+                c.skip(1);
+                Ok(Some(Target::Arg((op - 0x68) as usize)))
+            }
+            EXT_OP_PREFIX if c.peek_at(1) == Some(0x31) => {
+                c.skip(2);
+                Ok(Some(Target::Debug))
+            }
+            op if is_name_start(op) => {
+                let path = try!(c.name_path());
+                Ok(self.namespace.lookup(scope, &path)
+                    .map(|node| Target::Node(self.resolve_alias(node))))
+            }
+            _ => {
+                let value = try!(self.eval(c, scope, frame));
+                match value {
+                    Value::Reference(target) => Ok(Some(target)),
+                    _ => Err(Error::TypeMismatch),
+                }
+            }
+        }
+    }
+
+    /// Parse a Target. A method name is called, for the reference it
+    /// returns.
+    fn target(&mut self, c: &mut Cursor, scope: NodeId, frame: &mut Frame)
+              -> Result<Target, Error> {
+        match try!(self.super_name(c, scope, frame)) {
+            Some(Target::Node(node)) => {
+                if let Object::Method { .. } = self.namespace.get(node).object {
+                    return match try!(self.invoke(node, c, scope, frame)) {
+                        Value::Reference(target) => Ok(target),
+                        _ => Err(Error::TypeMismatch),
+                    };
+                }
+                Ok(Target::Node(node))
+            }
+            Some(target) => Ok(target),
+            None => Err(Error::NotFound),
+        }
+    }
+
+    fn read_node(&mut self, node: NodeId) -> Result<Value, Error> {
+        let node = self.resolve_alias(node);
+        match self.namespace.get(node).object {
+            Object::Name(value) => Ok(value),
+            Object::Field(field) => region::read_field(self, &field),
+            Object::BufferField { buffer, bit_offset, bit_length } => {
+                region::read_buffer_field(self, buffer, bit_offset, bit_length)
+            }
+            _ => Ok(Value::Node(node)),
+        }
+    }
+
+    /// Read a target outside method frames.
+    fn read_reference(&mut self, target: Target) -> Result<Value, Error> {
+        match target {
+            Target::Node(node) => self.read_node(node),
+            Target::Element { package, index } => self.deref(package.get(index)),
+            Target::Byte { buffer, index } => Ok(Value::Integer(buffer.as_slice()[index] as u64)),
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+
+    fn read_target(&mut self, target: Target, frame: &Frame) -> Result<Value, Error> {
+        match target {
+            Target::Local(index) => Ok(frame.locals[index]),
+            Target::Arg(index) => Ok(frame.args[index]),
+            target => self.read_reference(target),
+        }
+    }
+
+    /// The value behind references and names.
+    fn deref(&mut self, value: Value) -> Result<Value, Error> {
+        match value {
+            Value::Reference(target) => self.read_reference(target),
+            Value::Path { .. } => match self.resolve(value) {
+                Some(node) => self.read_node(node),
+                None => Err(Error::NotFound),
+            },
+            value => Ok(value),
+        }
+    }
+
+    fn store(&mut self, target: Target, value: Value, frame: &mut Frame) -> Result<(), Error> {
+        match target {
+            Target::Null => Ok(()),
+            Target::Debug => {
+                let value = try!(self.deref(value));
+                debug!("AML debug: {}", value);
+                Ok(())
+            }
+            Target::Local(index) => {
+                frame.locals[index] = try!(self.copy_buffer(value));
+                Ok(())
+            }
+            Target::Arg(index) => {
+                let arg = frame.args[index];
+                match arg {
+                    Value::Reference(target) => self.store(target, value, frame),
+                    _ => {
+                        frame.args[index] = try!(self.copy_buffer(value));
+                        Ok(())
+                    }
+                }
+            }
+            target => self.store_reference(target, value),
+        }
+    }
+
+    /// Store `value` in a target outside method frames.
+    fn store_reference(&mut self, target: Target, value: Value) -> Result<(), Error> {
+        let value = try!(self.deref(value));
+        match target {
+            Target::Node(node) => self.store_node(node, value),
+            Target::Element { package, index } => {
+                let value = if self.persistent.contains(package.address) {
+                    try!(self.persist(value))
+                } else {
+                    value
+                };
+                package.set(index, value);
+                Ok(())
+            }
+            Target::Byte { buffer, index } => {
+                // Constant strings are read only.
+                if !self.persistent.contains(buffer.address) &&
+                   !self.temporary.contains(buffer.address) {
+                    return Err(Error::TypeMismatch);
+                }
+                let byte = try!(self.to_integer(value));
+                buffer.as_mut_slice()[index] = byte as u8;
+                Ok(())
+            }
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+
+    /// Store `value` in a named object, converted to its type.
+    fn store_node(&mut self, node: NodeId, value: Value) -> Result<(), Error> {
+        let node = self.resolve_alias(node);
+        match self.namespace.get(node).object {
+            Object::Name(old) => {
+                let new = match old {
+                    Value::Integer(_) => Value::Integer(try!(self.to_integer(value))),
+                    Value::String(_) => Value::String(try!(self.to_string(value))),
+                    Value::Buffer(bytes) => {
+                        // Buffers keep their size, the value is truncated or
+                        // zero padded.
+                        let source = try!(self.to_buffer(value));
+                        let source = source.as_slice();
+                        for (i, byte) in bytes.as_mut_slice().iter_mut().enumerate() {
+                            *byte = source.get(i).cloned().unwrap_or(0);
+                        }
+                        return Ok(());
+                    }
+                    _ => value,
+                };
+                let new = if self.depth == 0 || node < self.method_nodes {
+                    try!(self.persist(new))
+                } else {
+                    new
+                };
+                self.namespace.set_object(node, Object::Name(new));
+                Ok(())
+            }
+            Object::Field(field) => region::write_field(self, &field, value),
+            Object::BufferField { buffer, bit_offset, bit_length } => {
+                region::write_buffer_field(self, buffer, bit_offset, bit_length, value)
+            }
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+
+    /// Buffers have copy semantics, unlike their bytes.
+    fn copy_buffer(&mut self, value: Value) -> Result<Value, Error> {
+        match value {
+            Value::Buffer(bytes) => {
+                Ok(Value::Buffer(try!(self.arena().copy_bytes(bytes.as_slice(), bytes.len))))
+            }
+            value => Ok(value),
+        }
+    }
+
+    /// A copy of `value` in the persistent arena, for a value stored in an
+    /// object which outlives the running method.
+    fn persist(&mut self, value: Value) -> Result<Value, Error> {
+        Ok(match value {
+            Value::String(bytes) if self.temporary.contains(bytes.address) => {
+                Value::String(try!(self.persistent.copy_bytes(bytes.as_slice(), bytes.len)))
+            }
+            Value::Buffer(bytes) if self.temporary.contains(bytes.address) => {
+                Value::Buffer(try!(self.persistent.copy_bytes(bytes.as_slice(), bytes.len)))
+            }
+            Value::Package(elements) if self.temporary.contains(elements.address) => {
+                let copy = try!(self.persistent.elements(elements.len));
+                for i in 0..elements.len {
+                    let element = try!(self.persist(elements.get(i)));
+                    copy.set(i, element);
+                }
+                Value::Package(copy)
+            }
+            value => value,
+        })
+    }
+
+    pub fn to_integer(&mut self, value: Value) -> Result<u64, Error> {
+        match try!(self.deref(value)) {
+            Value::Integer(value) => Ok(value & self.ones),
+            Value::Buffer(bytes) => {
+                let mut value = 0;
+                for (i, &b) in bytes.as_slice().iter().take(self.integer_bytes()).enumerate() {
+                    value |= (b as u64) << (8 * i);
+                }
+                Ok(value)
+            }
+            Value::String(bytes) => Ok(parse_hex(bytes.as_slice()) & self.ones),
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+
+    /// Strings become buffers with their terminating NUL.
+    pub fn to_buffer(&mut self, value: Value) -> Result<Bytes, Error> {
+        match try!(self.deref(value)) {
+            Value::Buffer(bytes) => Ok(bytes),
+            Value::Integer(value) => {
+                let len = self.integer_bytes();
+                let bytes = try!(self.arena().bytes(len));
+                for (i, b) in bytes.as_mut_slice().iter_mut().enumerate() {
+                    *b = (value >> (8 * i)) as u8;
+                }
+                Ok(bytes)
+            }
+            Value::String(bytes) => self.arena().copy_bytes(bytes.as_slice(), bytes.len + 1),
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+
+    /// The implicit conversion to a string: integers in hexadecimal,
+    /// buffers as space separated hexadecimal bytes.
+    fn to_string(&mut self, value: Value) -> Result<Bytes, Error> {
+        match try!(self.deref(value)) {
+            Value::String(bytes) => Ok(bytes),
+            Value::Integer(value) => {
+                let digits = self.integer_bytes() * 2;
+                let mut writer = try!(self.writer(digits));
+                writer.hex(value, digits);
+                Ok(writer.bytes())
+            }
+            Value::Buffer(bytes) => {
+                let mut writer = try!(self.writer(bytes.len * 3));
+                for (i, &b) in bytes.as_slice().iter().enumerate() {
+                    if i > 0 {
+                        writer.push(b' ');
+                    }
+                    writer.hex(b as u64, 2);
+                }
+                Ok(writer.bytes())
+            }
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+
+    /// `ToDecimalString` and `ToHexString`: buffers as comma separated
+    /// numbers.
+    fn to_formatted_string(&mut self, value: Value, hex: bool) -> Result<Bytes, Error> {
+        match try!(self.deref(value)) {
+            Value::String(bytes) => Ok(bytes),
+            Value::Integer(value) => {
+                let mut writer = try!(self.writer(20));
+                if hex {
+                    writer.push(b'0');
+                    writer.push(b'x');
+                    writer.hex(value, cmp::max(1, (67 - value.leading_zeros() as usize) / 4));
+                } else {
+                    writer.decimal(value);
+                }
+                Ok(writer.bytes())
+            }
+            Value::Buffer(bytes) => {
+                let mut writer = try!(self.writer(bytes.len * 5));
+                for (i, &b) in bytes.as_slice().iter().enumerate() {
+                    if i > 0 {
+                        writer.push(b',');
+                    }
+                    if hex {
+                        writer.push(b'0');
+                        writer.push(b'x');
+                        writer.hex(b as u64, 2);
+                    } else {
+                        writer.decimal(b as u64);
+                    }
+                }
+                Ok(writer.bytes())
+            }
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+
+    fn writer(&mut self, capacity: usize) -> Result<Writer, Error> {
+        let bytes = try!(self.arena().bytes(capacity));
+        Ok(Writer { buffer: bytes.as_mut_slice(), len: 0 })
+    }
+
+    fn join(&mut self, a: &[u8], b: &[u8]) -> Result<Bytes, Error> {
+        let bytes = try!(self.arena().copy_bytes(a, a.len() + b.len()));
+        for (i, &byte) in b.iter().enumerate() {
+            bytes.as_mut_slice()[a.len() + i] = byte;
+        }
+        Ok(bytes)
+    }
+
+    /// `Concat`: the second value takes the type of the first, integers
+    /// making a buffer.
+    fn concat(&mut self, a: Value, b: Value) -> Result<Value, Error> {
+        match try!(self.deref(a)) {
+            Value::Integer(value) => {
+                let a = try!(self.to_buffer(Value::Integer(value)));
+                let b = try!(self.to_integer(b));
+                let b = try!(self.to_buffer(Value::Integer(b)));
+                Ok(Value::Buffer(try!(self.join(a.as_slice(), b.as_slice()))))
+            }
+            Value::String(a) => {
+                let b = try!(self.to_string(b));
+                Ok(Value::String(try!(self.join(a.as_slice(), b.as_slice()))))
+            }
+            Value::Buffer(a) => {
+                let b = try!(self.to_buffer(b));
+                Ok(Value::Buffer(try!(self.join(a.as_slice(), b.as_slice()))))
+            }
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+
+    /// `ConcatRes`: two resource templates, the end tag of the first one
+    /// dropped.
+    fn concat_resources(&mut self, a: Value, b: Value) -> Result<Value, Error> {
+        let a = try!(self.to_buffer(a));
+        let b = try!(self.to_buffer(b));
+        let a = a.as_slice();
+        let a = if a.len() >= 2 { &a[..a.len() - 2] } else { a };
+        Ok(Value::Buffer(try!(self.join(a, b.as_slice()))))
+    }
+
+    /// Compare `a` to `b` converted to its type.
+    fn compare(&mut self, a: Value, b: Value) -> Result<Ordering, Error> {
+        match try!(self.deref(a)) {
+            Value::Integer(a) => {
+                let b = try!(self.to_integer(b));
+                Ok(a.cmp(&b))
+            }
+            Value::String(a) => {
+                let b = try!(self.to_string(b));
+                Ok(a.as_slice().cmp(b.as_slice()))
+            }
+            Value::Buffer(a) => {
+                let b = try!(self.to_buffer(b));
+                Ok(a.as_slice().cmp(b.as_slice()))
+            }
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+
+    /// Whether a package element satisfies a `Match` condition.
+    fn matches(&mut self, element: Value, op: u8, operand: Value) -> Result<bool, Error> {
+        if op == 0 {
+            // MTR, always true.
+            return Ok(true);
+        }
+        match element {
+            Value::Integer(_) | Value::String(_) | Value::Buffer(_) => {}
+            _ => return Ok(false),
+        }
+        let ordering = match self.compare(element, operand) {
+            Ok(ordering) => ordering,
+            Err(_) => return Ok(false),
+        };
+        Ok(match op {
+            1 => ordering == Ordering::Equal,
+            2 => ordering != Ordering::Greater,
+            3 => ordering == Ordering::Less,
+            4 => ordering != Ordering::Less,
+            5 => ordering == Ordering::Greater,
+            _ => false,
+        })
+    }
+
+    fn node_type(&self, node: NodeId) -> u64 {
+        match self.namespace.get(node).object {
+            Object::Scope => TYPE_UNINITIALIZED,
+            Object::Device => TYPE_DEVICE,
+            Object::Processor => TYPE_PROCESSOR,
+            Object::PowerResource => TYPE_POWER_RESOURCE,
+            Object::ThermalZone => TYPE_THERMAL_ZONE,
+            Object::Name(value) => value_type(&value),
+            Object::Method { .. } | Object::Osi => TYPE_METHOD,
+            Object::Region(_) => TYPE_REGION,
+            Object::Field(_) => TYPE_FIELD_UNIT,
+            Object::BufferField { .. } => TYPE_BUFFER_FIELD,
+            Object::Alias(target) => self.node_type(target),
+            Object::Mutex => TYPE_MUTEX,
+            Object::Event => TYPE_EVENT,
+        }
+    }
+
+    /// `_OSI`: whether the OS supports an interface.
+    fn osi(&mut self, interface: Value) -> Result<Value, Error> {
+        let name = match try!(self.deref(interface)) {
+            Value::String(bytes) => bytes.as_slice(),
+            _ => return Err(Error::TypeMismatch),
+        };
+        let supported = OSI_INTERFACES.iter().any(|interface| interface.as_bytes() == name);
+        debug!("AML: _OSI(\"{}\") = {}", str::from_utf8(name).unwrap_or("?"), supported);
+        Ok(self.boolean(supported))
+    }
+}
+
+fn value_type(value: &Value) -> u64 {
+    match *value {
+        Value::Integer(_) => TYPE_INTEGER,
+        Value::String(_) => TYPE_STRING,
+        Value::Buffer(_) => TYPE_BUFFER,
+        Value::Package(_) => TYPE_PACKAGE,
+        _ => TYPE_UNINITIALIZED,
+    }
+}
+
+fn hex_value(b: u8) -> Option<u64> {
+    match b {
+        b'0'...b'9' => Some((b - b'0') as u64),
+        b'a'...b'f' => Some((b - b'a' + 10) as u64),
+        b'A'...b'F' => Some((b - b'A' + 10) as u64),
+        _ => None,
+    }
+}
+
+/// The implicit string to integer conversion: hexadecimal, up to the first
+/// other character.
+fn parse_hex(bytes: &[u8]) -> u64 {
+    let mut digits = bytes;
+    if digits.len() >= 2 && digits[0] == b'0' && (digits[1] == b'x' || digits[1] == b'X') {
+        digits = &digits[2..];
+    }
+    let mut value: u64 = 0;
+    for &b in digits.iter() {
+        match hex_value(b) {
+            Some(digit) => value = value.wrapping_shl(4) | digit,
+            None => break,
+        }
+    }
+    value
+}
+
+/// `ToInteger`: hexadecimal with a `0x` prefix, otherwise decimal.
+fn parse_integer(bytes: &[u8]) -> u64 {
+    if bytes.len() >= 2 && bytes[0] == b'0' && (bytes[1] == b'x' || bytes[1] == b'X') {
+        return parse_hex(bytes);
+    }
+    let mut value: u64 = 0;
+    for &b in bytes.iter() {
+        match b {
+            b'0'...b'9' => value = value.wrapping_mul(10).wrapping_add((b - b'0') as u64),
+            _ => break,
+        }
+    }
+    value
+}
+
+fn from_bcd(value: u64) -> u64 {
+    let mut result = 0;
+    let mut scale = 1;
+    for i in 0..16 {
+        result += ((value >> (4 * i)) & 0xF) * scale;
+        scale = scale.wrapping_mul(10);
+    }
+    result
+}
+
+fn to_bcd(value: u64) -> u64 {
+    let mut result = 0;
+    let mut value = value;
+    for i in 0..16 {
+        result |= (value % 10) << (4 * i);
+        value /= 10;
+    }
+    result
+}
+
+/// Busy wait about `microseconds`: a write to the POST port takes about
+/// one, and doesn't depend on the timer interrupts, not running yet when
+/// the tables are loaded.
+fn delay(microseconds: u64) {
+    for _ in 0..microseconds {
+        unsafe { cpuio::outb(0x80, 0); }
+    }
+}
@@ -0,0 +1,356 @@
+//! ACPI Machine Language, the bytecode of the DSDT and the SSDTs which
+//! describes what the static tables don't: the devices of the platform,
+//! their resources and interrupt routing, and the methods to control them.
+//! The tables are loaded into a namespace at boot, and methods evaluated
+//! on demand.
+// http://www.uefi.org/sites/default/files/resources/ACPI_6_1.pdf, Chapters 5.3, 19 and 20
+// http://wiki.osdev.org/AML
+
+use core::fmt;
+use core::str;
+use spin::Mutex;
+use arch::acpi::{self, Fadt};
+use arch::apic;
+use arch::ioapic::Trigger;
+use memory::paging::{WRITABLE, NO_EXECUTE};
+use memory::vm;
+use self::interpreter::Interpreter;
+use self::namespace::{Namespace, Object};
+use self::resource::{Resource, Resources};
+use self::value::{Arena, Bytes, NodeId, Value};
+
+mod interpreter;
+mod namespace;
+mod region;
+mod resource;
+mod value;
+
+/// Why AML could not be loaded or evaluated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The code ends in the middle of a term.
+    UnexpectedEnd,
+    InvalidOpcode(u16),
+    InvalidName,
+    NotFound,
+    AlreadyExists,
+    TypeMismatch,
+    InvalidIndex,
+    DivideByZero,
+    OutOfMemory,
+    NamespaceFull,
+    /// Too many nested method calls, or terms.
+    TooDeep,
+    /// A loop ran for too long.
+    Timeout,
+    Unsupported(&'static str),
+    /// The firmware executed `Fatal`.
+    Fatal,
+    /// No tables were loaded.
+    NotLoaded,
+}
+
+/// Sizes of the namespace, of the values of the loaded tables, and of the
+/// values created by methods.
+const NAMESPACE_SIZE: usize = 256 * 1024;
+const PERSISTENT_SIZE: usize = 256 * 1024;
+const TEMPORARY_SIZE: usize = 256 * 1024;
+
+/// Compressed EISA and string IDs of the PCI and PCI Express root bridges.
+const PCI_ROOT_BRIDGE: (u32, &'static str) = (0x030A_D041, "PNP0A03");
+const PCIE_ROOT_BRIDGE: (u32, &'static str) = (0x080A_D041, "PNP0A08");
+
+/// Level triggered and active low, the PCI interrupt default.
+const PCI_TRIGGER: Trigger = Trigger { active_low: true, level: true };
+
+/// `_STA` bits, and the status of devices without `_STA`.
+const STATUS_PRESENT: u64 = 1 << 0;
+const STATUS_DEFAULT: u64 = 0xF;
+
+/// Names of the sleep state packages.
+const SLEEP_STATES: [&'static str; 6] = ["\\_S0_", "\\_S1_", "\\_S2_", "\\_S3_", "\\_S4_", "\\_S5_"];
+
+static AML: Mutex<Interpreter> = Mutex::new(Interpreter::empty());
+
+/// Displays a compressed EISA ID such as `PNP0A03`.
+struct EisaId(u32);
+
+impl fmt::Display for EisaId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Three 5-bit letters then four hexadecimal digits, big endian.
+        let id = self.0.swap_bytes();
+        let letter = |shift: u32| (((id >> shift) & 0x1F) as u8 + 0x40) as char;
+        write!(f, "{}{}{}{:04X}", letter(26), letter(21), letter(16), id & 0xFFFF)
+    }
+}
+
+/// Whether a `_HID` or `_CID` value is the ID `id`.
+fn is_id(value: &Value, id: (u32, &'static str)) -> bool {
+    match *value {
+        Value::Integer(eisa) => eisa == id.0 as u64,
+        Value::String(bytes) => bytes.as_slice() == id.1.as_bytes(),
+        _ => false,
+    }
+}
+
+/// The objects the OS provides.
+fn predefine(aml: &mut Interpreter) {
+    let objects = [
+        ("\\_GPE", Object::Scope),
+        ("\\_PR_", Object::Scope),
+        ("\\_SB_", Object::Scope),
+        ("\\_SI_", Object::Scope),
+        ("\\_TZ_", Object::Scope),
+        ("\\_GL_", Object::Mutex),
+        ("\\_OSI", Object::Osi),
+        ("\\_OS_", Object::Name(Value::String(Bytes::from_slice(b"Microsoft Windows NT")))),
+        ("\\_REV", Object::Name(Value::Integer(2))),
+    ];
+    for &(name, object) in objects.iter() {
+        if let Err(error) = aml.predefine(name, object) {
+            warn!("AML: failed to define {}: {:?}", name, error);
+        }
+    }
+}
+
+/// Load the DSDT and the SSDTs into the namespace, tell the firmware
+/// which interrupt controllers are used, and list the devices present.
+pub fn init() {
+    let fadt = match Fadt::get() {
+        Some(fadt) => fadt,
+        None => {
+            warn!("No FADT, AML not loaded");
+            return;
+        }
+    };
+    let dsdt = match acpi::table_at(fadt.dsdt) {
+        Some(dsdt) => dsdt,
+        None => {
+            warn!("No valid DSDT, AML not loaded");
+            return;
+        }
+    };
+    let base = match vm::allocate(NAMESPACE_SIZE + PERSISTENT_SIZE + TEMPORARY_SIZE, "AML",
+                                  WRITABLE | NO_EXECUTE) {
+        Ok(base) => base,
+        Err(error) => {
+            warn!("Failed to allocate memory for AML: {:?}", error);
+            return;
+        }
+    };
+
+    {
+        let mut aml = AML.lock();
+        *aml = Interpreter::new(
+            Namespace::new(base, NAMESPACE_SIZE),
+            Arena::new(base + NAMESPACE_SIZE, PERSISTENT_SIZE),
+            Arena::new(base + NAMESPACE_SIZE + PERSISTENT_SIZE, TEMPORARY_SIZE));
+        if dsdt.revision() < 2 {
+            aml.set_32bit_integers();
+        }
+        predefine(&mut aml);
+
+        let mut count = 0;
+        let ssdts = acpi::tables().filter(|table| table.signature() == "SSDT");
+        for table in Some(dsdt).into_iter().chain(ssdts) {
+            let mark = aml.temporary_mark();
+            match aml.load(table.data()) {
+                Ok(()) => count += 1,
+                Err(error) => warn!("AML: failed to load {}: {:?}", table, error),
+            }
+            aml.free_temporary(mark);
+        }
+        info!("AML: {} objects from {} tables, {} KiB", aml.namespace.count(), count,
+              aml.memory_used() / 1024);
+    }
+
+    set_interrupt_model(apic::is_enabled());
+    print_devices();
+}
+
+/// Run `f` on the loaded interpreter, freeing the temporary values after.
+fn with_interpreter<F, T>(f: F) -> Result<T, Error>
+    where F: FnOnce(&mut Interpreter) -> Result<T, Error>
+{
+    let mut aml = AML.lock();
+    if !aml.is_loaded() {
+        return Err(Error::NotLoaded);
+    }
+    let mark = aml.temporary_mark();
+    let result = f(&mut *aml);
+    aml.free_temporary(mark);
+    result
+}
+
+/// Evaluate the object `name`, such as `\_SB.PCI0._STA`, as an integer.
+pub fn evaluate_integer(name: &str) -> Result<u64, Error> {
+    with_interpreter(|aml| {
+        let node = try!(aml.lookup(name).ok_or(Error::NotFound));
+        let value = try!(aml.evaluate(node, &[]));
+        aml.to_integer(value)
+    })
+}
+
+/// Tell the firmware whether interrupts go through the PICs or the APICs
+/// with `\_PIC`, which changes the routing `_PRT` returns.
+pub fn set_interrupt_model(apic: bool) {
+    let result = with_interpreter(|aml| {
+        match aml.lookup("\\_PIC") {
+            Some(node) => aml.evaluate(node, &[Value::Integer(apic as u64)]).map(|_| ()),
+            None => Ok(()),
+        }
+    });
+    if let Err(error) = result {
+        warn!("AML: \\_PIC failed: {:?}", error);
+    }
+}
+
+/// The SLP_TYP values for the PM1a and PM1b control registers to enter
+/// the sleep `state`, from `\_Sx_`.
+pub fn sleep_type(state: u8) -> Result<(u8, u8), Error> {
+    let name = try!(SLEEP_STATES.get(state as usize).ok_or(Error::InvalidIndex));
+    with_interpreter(|aml| {
+        let node = try!(aml.lookup(name).ok_or(Error::NotFound));
+        let package = try!(try!(aml.evaluate(node, &[])).as_package().ok_or(Error::TypeMismatch));
+        if package.len == 0 {
+            return Err(Error::InvalidIndex);
+        }
+        let a = try!(aml.to_integer(package.get(0)));
+        // Some firmware packs both values in the first element.
+        let b = if package.len > 1 { try!(aml.to_integer(package.get(1))) } else { a >> 8 };
+        Ok((a as u8, b as u8))
+    })
+}
+
+/// Let the firmware prepare for the sleep `state` with `\_PTS`, if it has
+/// one.
+pub fn prepare_to_sleep(state: u8) {
+    let result = with_interpreter(|aml| {
+        match aml.lookup("\\_PTS") {
+            Some(node) => aml.evaluate(node, &[Value::Integer(state as u64)]).map(|_| ()),
+            None => Ok(()),
+        }
+    });
+    if let Err(error) = result {
+        warn!("AML: \\_PTS failed: {:?}", error);
+    }
+}
+
+/// The first PCI root bridge, by hardware or compatible ID.
+fn find_root_bridge(aml: &mut Interpreter) -> Option<NodeId> {
+    for node in 0..aml.namespace.count() {
+        match aml.namespace.get(node).object {
+            Object::Device => {}
+            _ => continue,
+        }
+        for name in ["_HID", "_CID"].iter() {
+            if let Ok(Some(id)) = aml.evaluate_child(node, name, &[]) {
+                if is_id(&id, PCI_ROOT_BRIDGE) || is_id(&id, PCIE_ROOT_BRIDGE) {
+                    return Some(node);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// The `index`th interrupt of the current resources of a PCI interrupt
+/// link device.
+fn link_interrupt(aml: &mut Interpreter, link: NodeId, index: u32)
+                  -> Result<(u32, Trigger), Error> {
+    let resources = match try!(aml.evaluate_child(link, "_CRS", &[])) {
+        Some(value) => try!(value.as_buffer().ok_or(Error::TypeMismatch)),
+        None => return Err(Error::NotFound),
+    };
+    let mut count = 0;
+    for resource in Resources::new(resources) {
+        let interrupt = match resource {
+            Resource::Irq { mask, trigger } if mask != 0 => (mask.trailing_zeros(), trigger),
+            Resource::Interrupt { gsi, trigger, .. } => (gsi, trigger),
+            _ => continue,
+        };
+        if count == index {
+            return Ok(interrupt);
+        }
+        count += 1;
+    }
+    Err(Error::NotFound)
+}
+
+/// The interrupt INTx `pin` (1 for INTA# to 4 for INTD#, as in the
+/// configuration space) of the device in `slot` of the root bus is routed
+/// to, from the `_PRT` of the root bridge: a GSI in APIC mode, an ISA IRQ
+/// in PIC mode.
+pub fn pci_interrupt(slot: u8, pin: u8) -> Result<(u32, Trigger), Error> {
+    if pin == 0 || pin > 4 {
+        return Err(Error::InvalidIndex);
+    }
+    with_interpreter(|aml| {
+        let bridge = try!(find_root_bridge(aml).ok_or(Error::NotFound));
+        let routing = match try!(aml.evaluate_child(bridge, "_PRT", &[])) {
+            Some(value) => try!(value.as_package().ok_or(Error::TypeMismatch)),
+            None => return Err(Error::NotFound),
+        };
+        for i in 0..routing.len {
+            // Address (slot in the high word), pin, source and source index.
+            let entry = match routing.get(i).as_package() {
+                Some(entry) if entry.len >= 4 => entry,
+                _ => continue,
+            };
+            let address = try!(aml.to_integer(entry.get(0)));
+            let entry_pin = try!(aml.to_integer(entry.get(1)));
+            if (address >> 16) as u8 != slot || entry_pin != (pin - 1) as u64 {
+                continue;
+            }
+            let index = try!(aml.to_integer(entry.get(3))) as u32;
+            // Without a link device, the index is the GSI.
+            if let Value::Integer(0) = entry.get(2) {
+                return Ok((index, PCI_TRIGGER));
+            }
+            let link = try!(aml.resolve(entry.get(2)).ok_or(Error::NotFound));
+            return link_interrupt(aml, link, index);
+        }
+        Err(Error::NotFound)
+    })
+}
+
+/// Log the devices present, with their hardware ID.
+fn print_devices() {
+    let result = with_interpreter(|aml| {
+        let (mut present, mut total) = (0, 0);
+        for node in 0..aml.namespace.count() {
+            match aml.namespace.get(node).object {
+                Object::Device => total += 1,
+                _ => continue,
+            }
+            let mark = aml.temporary_mark();
+            let status = match aml.evaluate_child(node, "_STA", &[]) {
+                Ok(Some(value)) => aml.to_integer(value).unwrap_or(0),
+                Ok(None) => STATUS_DEFAULT,
+                Err(error) => {
+                    debug!("AML: {}._STA failed: {:?}", aml.namespace.path(node), error);
+                    0
+                }
+            };
+            if status & STATUS_PRESENT != 0 {
+                present += 1;
+                match aml.evaluate_child(node, "_HID", &[]) {
+                    Ok(Some(Value::Integer(id))) => {
+                        debug!("  {} {}", aml.namespace.path(node), EisaId(id as u32));
+                    }
+                    Ok(Some(Value::String(id))) => {
+                        debug!("  {} {}", aml.namespace.path(node),
+                               str::from_utf8(id.as_slice()).unwrap_or("?"));
+                    }
+                    _ => debug!("  {}", aml.namespace.path(node)),
+                }
+            }
+            aml.free_temporary(mark);
+        }
+        info!("AML: {} of {} devices present", present, total);
+        Ok(())
+    });
+    if let Err(error) = result {
+        warn!("AML: failed to list the devices: {:?}", error);
+    }
+}
@@ -0,0 +1,357 @@
+//! The ACPI namespace: a tree of named objects, stored as a flat array of
+//! nodes pointing to their parent.
+
+use core::fmt;
+use core::mem::size_of;
+use core::ptr;
+use super::Error;
+use super::value::{Bytes, NodeId, Value};
+
+/// The root scope, `\`.
+pub const ROOT: NodeId = 0;
+
+/// How many segments a name can have.
+const MAX_SEGMENTS: usize = 8;
+
+/// Field access types, from the field flags.
+pub const ACCESS_ANY: u8 = 0;
+pub const ACCESS_BYTE: u8 = 1;
+pub const ACCESS_WORD: u8 = 2;
+pub const ACCESS_DWORD: u8 = 3;
+pub const ACCESS_QWORD: u8 = 4;
+pub const ACCESS_BUFFER: u8 = 5;
+
+/// Field update rules, from the field flags.
+pub const UPDATE_PRESERVE: u8 = 0;
+pub const UPDATE_WRITE_AS_ONES: u8 = 1;
+pub const UPDATE_WRITE_AS_ZEROS: u8 = 2;
+
+/// An operation region.
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    /// See `region::SPACE_*`.
+    pub space: u8,
+    pub offset: u64,
+    pub length: u64,
+    /// The scope the region was defined in, the device for PCI regions.
+    pub scope: NodeId,
+}
+
+/// Where the bits of a field live.
+#[derive(Clone, Copy, Debug)]
+pub enum FieldKind {
+    Region(NodeId),
+    /// Selected by writing the offset to `index`, accessed through `data`.
+    Index { index: NodeId, data: NodeId },
+    /// In `region`, once `value` is written to the `bank` field.
+    Bank { region: NodeId, bank: NodeId, value: u64 },
+}
+
+/// A field unit.
+#[derive(Clone, Copy, Debug)]
+pub struct Field {
+    pub kind: FieldKind,
+    pub bit_offset: usize,
+    pub bit_length: usize,
+    /// Access type, lock and update rule bits.
+    pub flags: u8,
+}
+
+impl Field {
+    pub fn access_type(&self) -> u8 {
+        self.flags & 0xF
+    }
+
+    pub fn update_rule(&self) -> u8 {
+        (self.flags >> 5) & 0x3
+    }
+}
+
+/// What a node holds.
+#[derive(Clone, Copy, Debug)]
+pub enum Object {
+    Scope,
+    Device,
+    Processor,
+    PowerResource,
+    ThermalZone,
+    /// A data object.
+    Name(Value),
+    Method { code: Bytes, args: u8 },
+    Region(Region),
+    Field(Field),
+    /// Bits of a buffer.
+    BufferField { buffer: Bytes, bit_offset: usize, bit_length: usize },
+    Alias(NodeId),
+    Mutex,
+    Event,
+    /// The `_OSI` method, implemented by the interpreter.
+    Osi,
+}
+
+impl Object {
+    /// Whether the object can hold other objects, so a definition with the
+    /// same name extends it rather than clash.
+    fn is_scope(&self) -> bool {
+        match *self {
+            Object::Scope | Object::Device | Object::Processor | Object::PowerResource |
+            Object::ThermalZone => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Node {
+    pub name: [u8; 4],
+    pub parent: NodeId,
+    pub object: Object,
+}
+
+/// A name, as found in AML or given by the kernel.
+#[derive(Clone, Copy)]
+pub struct NamePath {
+    /// Starts at the root.
+    pub root: bool,
+    /// Number of `^` prefixes, scopes to go up first.
+    pub parents: usize,
+    pub count: usize,
+    pub segments: [[u8; 4]; MAX_SEGMENTS],
+}
+
+impl NamePath {
+    pub fn new() -> NamePath {
+        NamePath { root: false, parents: 0, count: 0, segments: [[0; 4]; MAX_SEGMENTS] }
+    }
+
+    pub fn push(&mut self, segment: [u8; 4]) -> Result<(), Error> {
+        if self.count == MAX_SEGMENTS {
+            return Err(Error::InvalidName);
+        }
+        self.segments[self.count] = segment;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Parse a dotted ASL name such as `\_SB.PCI0._PRT`. Short segments
+    /// are padded with `_`.
+    pub fn parse(name: &str) -> Result<NamePath, Error> {
+        let mut path = NamePath::new();
+        let mut rest = name;
+        if rest.starts_with('\\') {
+            path.root = true;
+            rest = &rest[1..];
+        }
+        while rest.starts_with('^') {
+            path.parents += 1;
+            rest = &rest[1..];
+        }
+        if rest.is_empty() {
+            return Ok(path);
+        }
+        for part in rest.split('.') {
+            if part.is_empty() || part.len() > 4 {
+                return Err(Error::InvalidName);
+            }
+            let mut segment = [b'_'; 4];
+            for (i, b) in part.bytes().enumerate() {
+                segment[i] = b;
+            }
+            try!(path.push(segment));
+        }
+        Ok(path)
+    }
+
+    /// The last segment, the name of the object a definition creates.
+    pub fn last(&self) -> Option<[u8; 4]> {
+        if self.count == 0 { None } else { Some(self.segments[self.count - 1]) }
+    }
+
+    /// Whether the name is looked up in the parent scopes too: single
+    /// segment relative names only.
+    fn searches(&self) -> bool {
+        !self.root && self.parents == 0 && self.count == 1
+    }
+}
+
+impl fmt::Display for NamePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.root {
+            try!(write!(f, "\\"));
+        }
+        for _ in 0..self.parents {
+            try!(write!(f, "^"));
+        }
+        for (i, segment) in self.segments[..self.count].iter().enumerate() {
+            if i > 0 {
+                try!(write!(f, "."));
+            }
+            for &b in segment.iter() {
+                try!(write!(f, "{}", b as char));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Namespace {
+    /// Address of the node array.
+    nodes: usize,
+    capacity: usize,
+    count: usize,
+}
+
+impl Namespace {
+    pub const fn empty() -> Namespace {
+        Namespace { nodes: 0, capacity: 0, count: 0 }
+    }
+
+    /// A namespace with only the root, in `size` bytes at `address`.
+    pub fn new(address: usize, size: usize) -> Namespace {
+        let mut namespace = Namespace {
+            nodes: address,
+            capacity: size / size_of::<Node>(),
+            count: 0,
+        };
+        namespace.push(Node { name: *b"\\___", parent: ROOT, object: Object::Scope })
+            .expect("No room for the AML namespace root");
+        namespace
+    }
+
+    fn push(&mut self, node: Node) -> Result<NodeId, Error> {
+        if self.count == self.capacity {
+            return Err(Error::NamespaceFull);
+        }
+        unsafe { ptr::write((self.nodes as *mut Node).offset(self.count as isize), node); }
+        self.count += 1;
+        Ok(self.count - 1)
+    }
+
+    pub fn get(&self, id: NodeId) -> Node {
+        assert!(id < self.count);
+        unsafe { *(self.nodes as *const Node).offset(id as isize) }
+    }
+
+    pub fn set_object(&mut self, id: NodeId, object: Object) {
+        assert!(id < self.count);
+        unsafe { (*(self.nodes as *mut Node).offset(id as isize)).object = object; }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Drop the nodes from `count` on, the objects created by a method.
+    pub fn truncate(&mut self, count: usize) {
+        if count < self.count {
+            self.count = count;
+        }
+    }
+
+    pub fn child(&self, parent: NodeId, name: [u8; 4]) -> Option<NodeId> {
+        // The latest definition wins, and the root is its own parent.
+        (1..self.count).rev().find(|&id| {
+            let node = self.get(id);
+            node.parent == parent && node.name == name
+        })
+    }
+
+    /// The node `path` leads to from `scope`, except for the last segment:
+    /// the scope a definition goes in.
+    fn prefix(&self, scope: NodeId, path: &NamePath) -> Option<NodeId> {
+        let mut node = if path.root { ROOT } else { scope };
+        for _ in 0..path.parents {
+            if node == ROOT {
+                return None;
+            }
+            node = self.get(node).parent;
+        }
+        for segment in path.segments[..path.count.saturating_sub(1)].iter() {
+            node = match self.child(node, *segment) {
+                Some(child) => child,
+                None => return None,
+            };
+        }
+        Some(node)
+    }
+
+    /// Look `path` up from `scope`, following the AML search rules.
+    pub fn lookup(&self, scope: NodeId, path: &NamePath) -> Option<NodeId> {
+        let last = match path.last() {
+            Some(last) => last,
+            None => return if path.root { Some(ROOT) } else { self.prefix(scope, path) },
+        };
+
+        if path.searches() {
+            let mut node = scope;
+            loop {
+                if let Some(found) = self.child(node, last) {
+                    return Some(found);
+                }
+                if node == ROOT {
+                    return None;
+                }
+                node = self.get(node).parent;
+            }
+        }
+
+        self.prefix(scope, path).and_then(|parent| self.child(parent, last))
+    }
+
+    /// Define `path` from `scope` as `object`. Redefining a scope, a device
+    /// or such with a compatible object returns the existing node.
+    pub fn define(&mut self, scope: NodeId, path: &NamePath, object: Object)
+                  -> Result<NodeId, Error> {
+        let (parent, name) = match (self.prefix(scope, path), path.last()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Err(Error::NotFound),
+        };
+        if let Some(existing) = self.child(parent, name) {
+            if self.get(existing).object.is_scope() && object.is_scope() {
+                return Ok(existing);
+            }
+            return Err(Error::AlreadyExists);
+        }
+        self.push(Node { name: name, parent: parent, object: object })
+    }
+
+    /// The absolute name of `id`, for messages.
+    pub fn path(&self, id: NodeId) -> NodeName {
+        NodeName { namespace: self, id: id }
+    }
+}
+
+/// Displays the absolute name of a node.
+pub struct NodeName<'a> {
+    namespace: &'a Namespace,
+    id: NodeId,
+}
+
+impl<'a> fmt::Display for NodeName<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.id == ROOT {
+            return write!(f, "\\");
+        }
+        let mut ancestors = [ROOT; MAX_DEPTH];
+        let mut depth = 0;
+        let mut node = self.id;
+        while node != ROOT && depth < MAX_DEPTH {
+            ancestors[depth] = node;
+            depth += 1;
+            node = self.namespace.get(node).parent;
+        }
+        try!(write!(f, "\\"));
+        for (i, &id) in ancestors[..depth].iter().rev().enumerate() {
+            if i > 0 {
+                try!(write!(f, "."));
+            }
+            for &b in self.namespace.get(id).name.iter() {
+                try!(write!(f, "{}", b as char));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Deepest node `NodeName` prints.
+const MAX_DEPTH: usize = 16;
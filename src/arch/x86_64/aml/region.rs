@@ -0,0 +1,362 @@
+//! Operation regions: reading and writing fields in memory, I/O port and
+//! PCI configuration space.
+//
+// Fields are accessed in units of their access width, aligned on it. The
+// bits of a unit outside the field are kept, set or cleared on writes
+// according to the update rule.
+
+use core::cmp;
+use core::ptr;
+use spin::Mutex;
+use arch::cpuio;
+use arch::pci;
+use memory::{self, vm, MAX_PHYSICAL_ADDRESS, PAGE_SIZE};
+use super::Error;
+use super::interpreter::Interpreter;
+use super::namespace::{Field, FieldKind, Object, Region, ROOT, ACCESS_WORD, ACCESS_DWORD,
+                       ACCESS_QWORD, UPDATE_PRESERVE, UPDATE_WRITE_AS_ONES};
+use super::value::{Bytes, NodeId, Value};
+
+/// Operation region spaces.
+pub const SPACE_SYSTEM_MEMORY: u8 = 0;
+pub const SPACE_SYSTEM_IO: u8 = 1;
+pub const SPACE_PCI_CONFIG: u8 = 2;
+
+/// Size of the legacy PCI configuration space of a function.
+const PCI_CONFIG_SIZE: u64 = 256;
+
+/// Most device memory ranges mapped for the memory regions.
+const MAX_DEVICE_MAPPINGS: usize = 32;
+
+/// The device memory mapped for the memory regions: the physical pages and
+/// the virtual address of the first. The mappings are never removed.
+static DEVICE_MAPPINGS: Mutex<[Option<(usize, usize, usize)>; MAX_DEVICE_MAPPINGS]> =
+    Mutex::new([None; MAX_DEVICE_MAPPINGS]);
+
+/// Copy `count` bits from `source` at bit `from` to `destination` at bit
+/// `to`. Bits past the end of `source` read as zeros.
+fn copy_bits(source: &[u8], from: usize, destination: &mut [u8], to: usize, count: usize) {
+    for i in 0..count {
+        let (s, d) = (from + i, to + i);
+        if s / 8 < source.len() && source[s / 8] & (1 << (s % 8)) != 0 {
+            destination[d / 8] |= 1 << (d % 8);
+        } else {
+            destination[d / 8] &= !(1 << (d % 8));
+        }
+    }
+}
+
+fn to_bytes(value: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = (value >> (8 * i)) as u8;
+    }
+    bytes
+}
+
+fn from_bytes(bytes: &[u8]) -> u64 {
+    bytes.iter().take(8).enumerate().fold(0, |value, (i, &b)| value | (b as u64) << (8 * i))
+}
+
+/// The access width of `field` in bytes.
+fn access_width(field: &Field) -> usize {
+    match field.access_type() {
+        ACCESS_WORD => 2,
+        ACCESS_DWORD => 4,
+        ACCESS_QWORD => 8,
+        _ => 1,
+    }
+}
+
+/// Read `bits` from a field, an integer if it fits, otherwise a buffer.
+/// `read` fills the buffer it's given.
+fn read_bits<F>(interpreter: &mut Interpreter, bit_length: usize, read: F) -> Result<Value, Error>
+    where F: FnOnce(&mut Interpreter, &mut [u8]) -> Result<(), Error>
+{
+    if bit_length <= interpreter.integer_bytes() * 8 {
+        let mut bits = [0u8; 8];
+        try!(read(interpreter, &mut bits));
+        Ok(Value::Integer(from_bytes(&bits)))
+    } else {
+        let bytes = try!(interpreter.allocate_bytes((bit_length + 7) / 8));
+        try!(read(interpreter, bytes.as_mut_slice()));
+        Ok(Value::Buffer(bytes))
+    }
+}
+
+pub fn read_field(interpreter: &mut Interpreter, field: &Field) -> Result<Value, Error> {
+    let width = access_width(field);
+    let unit_bits = width * 8;
+    let end = field.bit_offset + field.bit_length;
+    read_bits(interpreter, field.bit_length, |interpreter, bits| {
+        let mut unit = field.bit_offset / unit_bits;
+        while unit * unit_bits < end {
+            let start = unit * unit_bits;
+            let low = cmp::max(field.bit_offset, start);
+            let high = cmp::min(end, start + unit_bits);
+            let value = try!(read_unit(interpreter, field, unit * width, width));
+            copy_bits(&to_bytes(value), low - start, bits, low - field.bit_offset, high - low);
+            unit += 1;
+        }
+        Ok(())
+    })
+}
+
+pub fn write_field(interpreter: &mut Interpreter, field: &Field, value: Value)
+                   -> Result<(), Error> {
+    let integer;
+    let bits: &[u8] = match value {
+        Value::Integer(value) => {
+            integer = to_bytes(value);
+            &integer
+        }
+        value => try!(interpreter.to_buffer(value)).as_slice(),
+    };
+    let width = access_width(field);
+    let unit_bits = width * 8;
+    let end = field.bit_offset + field.bit_length;
+
+    let mut unit = field.bit_offset / unit_bits;
+    while unit * unit_bits < end {
+        let start = unit * unit_bits;
+        let low = cmp::max(field.bit_offset, start);
+        let high = cmp::min(end, start + unit_bits);
+        let partial = high - low < unit_bits;
+        let current = match field.update_rule() {
+            UPDATE_PRESERVE if partial => try!(read_unit(interpreter, field, unit * width, width)),
+            UPDATE_WRITE_AS_ONES => !0,
+            _ => 0,
+        };
+        let mut bytes = to_bytes(current);
+        copy_bits(bits, low - field.bit_offset, &mut bytes, low - start, high - low);
+        try!(write_unit(interpreter, field, unit * width, width, from_bytes(&bytes)));
+        unit += 1;
+    }
+    Ok(())
+}
+
+pub fn read_buffer_field(interpreter: &mut Interpreter, buffer: Bytes, bit_offset: usize,
+                         bit_length: usize) -> Result<Value, Error> {
+    read_bits(interpreter, bit_length, |_, bits| {
+        copy_bits(buffer.as_slice(), bit_offset, bits, 0, bit_length);
+        Ok(())
+    })
+}
+
+pub fn write_buffer_field(interpreter: &mut Interpreter, buffer: Bytes, bit_offset: usize,
+                          bit_length: usize, value: Value) -> Result<(), Error> {
+    let integer;
+    let bits: &[u8] = match value {
+        Value::Integer(value) => {
+            integer = to_bytes(value);
+            &integer
+        }
+        value => try!(interpreter.to_buffer(value)).as_slice(),
+    };
+    copy_bits(bits, 0, buffer.as_mut_slice(), bit_offset, bit_length);
+    Ok(())
+}
+
+/// Read the field `node`, used as an index or data register.
+fn read_node(interpreter: &mut Interpreter, node: NodeId) -> Result<u64, Error> {
+    match interpreter.namespace.get(node).object {
+        Object::Field(field) => {
+            let value = try!(read_field(interpreter, &field));
+            interpreter.to_integer(value)
+        }
+        _ => Err(Error::TypeMismatch),
+    }
+}
+
+fn write_node(interpreter: &mut Interpreter, node: NodeId, value: u64) -> Result<(), Error> {
+    match interpreter.namespace.get(node).object {
+        Object::Field(field) => write_field(interpreter, &field, Value::Integer(value)),
+        _ => Err(Error::TypeMismatch),
+    }
+}
+
+/// Read `width` bytes at byte `offset` of a field's storage.
+fn read_unit(interpreter: &mut Interpreter, field: &Field, offset: usize, width: usize)
+             -> Result<u64, Error> {
+    match field.kind {
+        FieldKind::Region(region) => read_region(interpreter, region, offset, width),
+        FieldKind::Bank { region, bank, value } => {
+            try!(write_node(interpreter, bank, value));
+            read_region(interpreter, region, offset, width)
+        }
+        FieldKind::Index { index, data } => {
+            try!(write_node(interpreter, index, offset as u64));
+            read_node(interpreter, data)
+        }
+    }
+}
+
+fn write_unit(interpreter: &mut Interpreter, field: &Field, offset: usize, width: usize,
+              value: u64) -> Result<(), Error> {
+    match field.kind {
+        FieldKind::Region(region) => write_region(interpreter, region, offset, width, value),
+        FieldKind::Bank { region, bank, value: bank_value } => {
+            try!(write_node(interpreter, bank, bank_value));
+            write_region(interpreter, region, offset, width, value)
+        }
+        FieldKind::Index { index, data } => {
+            try!(write_node(interpreter, index, offset as u64));
+            write_node(interpreter, data, value)
+        }
+    }
+}
+
+/// The region `node` and the address of `width` bytes at `offset` in it.
+fn region(interpreter: &Interpreter, node: NodeId, offset: usize, width: usize)
+          -> Result<(Region, u64), Error> {
+    let region = match interpreter.namespace.get(node).object {
+        Object::Region(region) => region,
+        _ => return Err(Error::TypeMismatch),
+    };
+    if offset as u64 + width as u64 > region.length {
+        return Err(Error::InvalidIndex);
+    }
+    Ok((region, region.offset + offset as u64))
+}
+
+/// Where the physical `address` is mapped: RAM in the physical memory
+/// window, devices uncached in a mapping kept for the next accesses.
+fn memory_address(address: u64, width: usize) -> Result<usize, Error> {
+    if address + width as u64 > MAX_PHYSICAL_ADDRESS as u64 {
+        return Err(Error::Unsupported("memory regions above 4 GiB"));
+    }
+    let (start, end) = (address as usize, address as usize + width);
+    if memory::ram_bytes(start, end) >= width {
+        return Ok(memory::phys_to_virt(start));
+    }
+
+    let mut mappings = DEVICE_MAPPINGS.lock();
+    for &(first, last, virt) in mappings.iter().filter_map(|mapping| mapping.as_ref()) {
+        if first <= start && end <= last {
+            return Ok(virt + start - first);
+        }
+    }
+    let slot = match mappings.iter().position(|mapping| mapping.is_none()) {
+        Some(slot) => slot,
+        None => return Err(Error::OutOfMemory),
+    };
+    let first = start / PAGE_SIZE * PAGE_SIZE;
+    let last = (end + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    let virt = try!(vm::map_device(first, last - first, "AML region")
+        .map_err(|_| Error::OutOfMemory));
+    mappings[slot] = Some((first, last, virt));
+    Ok(virt + start - first)
+}
+
+/// The bus, slot and function of the device a PCI_Config region is
+/// defined in: `_ADR` gives the slot and function, the `_BBN` of the root
+/// bridge above it the bus.
+fn pci_address(interpreter: &mut Interpreter, device: NodeId) -> Result<(u8, u8, u8), Error> {
+    let address = match try!(interpreter.evaluate_child(device, "_ADR", &[])) {
+        Some(value) => try!(interpreter.to_integer(value)),
+        None => 0,
+    };
+    let mut bus = 0;
+    let mut node = device;
+    while node != ROOT {
+        if let Some(value) = try!(interpreter.evaluate_child(node, "_BBN", &[])) {
+            bus = try!(interpreter.to_integer(value));
+            break;
+        }
+        node = interpreter.namespace.get(node).parent;
+    }
+    Ok((bus as u8, (address >> 16) as u8, address as u8))
+}
+
+fn read_region(interpreter: &mut Interpreter, node: NodeId, offset: usize, width: usize)
+               -> Result<u64, Error> {
+    let (region, address) = try!(self::region(interpreter, node, offset, width));
+    match region.space {
+        SPACE_SYSTEM_MEMORY => {
+            let address = try!(memory_address(address, width));
+            Ok(unsafe {
+                match width {
+                    1 => ptr::read_volatile(address as *const u8) as u64,
+                    2 => ptr::read_volatile(address as *const u16) as u64,
+                    4 => ptr::read_volatile(address as *const u32) as u64,
+                    _ => ptr::read_volatile(address as *const u64),
+                }
+            })
+        }
+        SPACE_SYSTEM_IO => {
+            let port = address as u16;
+            Ok(unsafe {
+                match width {
+                    1 => cpuio::inb(port) as u64,
+                    2 => cpuio::inw(port) as u64,
+                    4 => cpuio::inl(port) as u64,
+                    _ => cpuio::inl(port) as u64 | (cpuio::inl(port + 4) as u64) << 32,
+                }
+            })
+        }
+        SPACE_PCI_CONFIG => {
+            if address + width as u64 > PCI_CONFIG_SIZE {
+                return Err(Error::InvalidIndex);
+            }
+            let (bus, slot, function) = try!(pci_address(interpreter, region.scope));
+            let mut value = 0;
+            for i in 0..width {
+                let offset = address as u8 + i as u8;
+                let dword = pci::read_config(bus, slot, function, offset & !3);
+                value |= (((dword >> (8 * (offset & 3))) & 0xFF) as u64) << (8 * i);
+            }
+            Ok(value)
+        }
+        _ => Err(Error::Unsupported("operation region space")),
+    }
+}
+
+fn write_region(interpreter: &mut Interpreter, node: NodeId, offset: usize, width: usize,
+                value: u64) -> Result<(), Error> {
+    let (region, address) = try!(self::region(interpreter, node, offset, width));
+    match region.space {
+        SPACE_SYSTEM_MEMORY => {
+            let address = try!(memory_address(address, width));
+            unsafe {
+                match width {
+                    1 => ptr::write_volatile(address as *mut u8, value as u8),
+                    2 => ptr::write_volatile(address as *mut u16, value as u16),
+                    4 => ptr::write_volatile(address as *mut u32, value as u32),
+                    _ => ptr::write_volatile(address as *mut u64, value),
+                }
+            }
+        }
+        SPACE_SYSTEM_IO => {
+            let port = address as u16;
+            unsafe {
+                match width {
+                    1 => cpuio::outb(port, value as u8),
+                    2 => cpuio::outw(port, value as u16),
+                    4 => cpuio::outl(port, value as u32),
+                    _ => {
+                        cpuio::outl(port, value as u32);
+                        cpuio::outl(port + 4, (value >> 32) as u32);
+                    }
+                }
+            }
+        }
+        SPACE_PCI_CONFIG => {
+            if address + width as u64 > PCI_CONFIG_SIZE {
+                return Err(Error::InvalidIndex);
+            }
+            let (bus, slot, function) = try!(pci_address(interpreter, region.scope));
+            // Only whole registers can be written, so the bytes around are
+            // written back. Firmware doesn't put fields next to write one to
+            // clear bits.
+            for i in 0..width {
+                let offset = address as u8 + i as u8;
+                let shift = 8 * (offset & 3) as u32;
+                let dword = pci::read_config(bus, slot, function, offset & !3);
+                let dword = dword & !(0xFF << shift) | ((value >> (8 * i)) as u32 & 0xFF) << shift;
+                unsafe { pci::write_config(bus, slot, function, offset & !3, dword); }
+            }
+        }
+        _ => return Err(Error::Unsupported("operation region space")),
+    }
+    Ok(())
+}
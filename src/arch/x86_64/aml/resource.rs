@@ -0,0 +1,145 @@
+//! Resource templates, the buffers returned by `_CRS` describing the I/O
+//! ports, memory and interrupts of a device.
+// http://www.uefi.org/sites/default/files/resources/ACPI_6_1.pdf, Section 6.4
+
+use arch::ioapic::{Trigger, ISA_TRIGGER};
+
+/// Small resource descriptor tags.
+const SMALL_IRQ: u8 = 0x04;
+const SMALL_DMA: u8 = 0x05;
+const SMALL_IO: u8 = 0x08;
+const SMALL_FIXED_IO: u8 = 0x09;
+const SMALL_END: u8 = 0x0F;
+
+/// Large resource descriptor tags.
+const LARGE_MEMORY32: u8 = 0x05;
+const LARGE_FIXED_MEMORY32: u8 = 0x06;
+const LARGE_DWORD_ADDRESS: u8 = 0x07;
+const LARGE_WORD_ADDRESS: u8 = 0x08;
+const LARGE_EXTENDED_INTERRUPT: u8 = 0x09;
+const LARGE_QWORD_ADDRESS: u8 = 0x0A;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Resource {
+    /// ISA IRQs, a bit per IRQ.
+    Irq { mask: u16, trigger: Trigger },
+    Dma { mask: u8 },
+    /// A range of ports starting between `minimum` and `maximum`.
+    Io { minimum: u16, maximum: u16, length: u8 },
+    FixedIo { base: u16, length: u8 },
+    Memory32 { base: u32, length: u32 },
+    /// A range of memory (`kind` 0), ports (1) or bus numbers (2).
+    Address { kind: u8, minimum: u64, length: u64 },
+    /// The first of the GSIs of an extended interrupt descriptor.
+    Interrupt { gsi: u32, count: u8, trigger: Trigger },
+    /// The tag is the first byte of the descriptor.
+    Unknown { tag: u8 },
+}
+
+/// The descriptors of a resource template.
+pub struct Resources {
+    data: &'static [u8],
+}
+
+impl Resources {
+    pub fn new(data: &'static [u8]) -> Resources {
+        Resources { data: data }
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_u16(bytes, offset) as u32 | (read_u16(bytes, offset + 2) as u32) << 16
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    read_u32(bytes, offset) as u64 | (read_u32(bytes, offset + 4) as u64) << 32
+}
+
+/// An address space descriptor, with `size` byte fields.
+fn address(d: &[u8], tag: u8, size: usize) -> Resource {
+    // Resource type, general flags and type specific flags, then the
+    // granularity, minimum, maximum, translation offset and length.
+    if d.len() < 3 + 5 * size {
+        return Resource::Unknown { tag: tag };
+    }
+    let field = |index: usize| {
+        let offset = 3 + index * size;
+        match size {
+            2 => read_u16(d, offset) as u64,
+            4 => read_u32(d, offset) as u64,
+            _ => read_u64(d, offset),
+        }
+    };
+    Resource::Address { kind: d[0], minimum: field(1), length: field(4) }
+}
+
+impl Iterator for Resources {
+    type Item = Resource;
+
+    fn next(&mut self) -> Option<Resource> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let lead = self.data[0];
+        let (tag, header, length) = if lead & 0x80 == 0 {
+            ((lead >> 3) & 0xF, 1, (lead & 0x7) as usize)
+        } else if self.data.len() >= 3 {
+            (lead & 0x7F, 3, read_u16(self.data, 1) as usize)
+        } else {
+            return None;
+        };
+        if self.data.len() < header + length {
+            return None;
+        }
+        let d = &self.data[header..header + length];
+        self.data = &self.data[header + length..];
+
+        if lead & 0x80 == 0 {
+            match (tag, length) {
+                (SMALL_END, _) => {
+                    self.data = &[];
+                    None
+                }
+                (SMALL_IRQ, 2) => Some(Resource::Irq { mask: read_u16(d, 0), trigger: ISA_TRIGGER }),
+                (SMALL_IRQ, 3) => Some(Resource::Irq {
+                    mask: read_u16(d, 0),
+                    trigger: Trigger { active_low: d[2] & 0x08 != 0, level: d[2] & 0x01 == 0 },
+                }),
+                (SMALL_DMA, 2) => Some(Resource::Dma { mask: d[0] }),
+                (SMALL_IO, 7) => Some(Resource::Io {
+                    minimum: read_u16(d, 1),
+                    maximum: read_u16(d, 3),
+                    length: d[6],
+                }),
+                (SMALL_FIXED_IO, 3) => Some(Resource::FixedIo { base: read_u16(d, 0), length: d[2] }),
+                _ => Some(Resource::Unknown { tag: lead }),
+            }
+        } else {
+            match (tag, length) {
+                (LARGE_MEMORY32, 17) => Some(Resource::Memory32 {
+                    base: read_u32(d, 1),
+                    length: read_u32(d, 13),
+                }),
+                (LARGE_FIXED_MEMORY32, 9) => Some(Resource::Memory32 {
+                    base: read_u32(d, 1),
+                    length: read_u32(d, 5),
+                }),
+                (LARGE_WORD_ADDRESS, _) => Some(address(d, lead, 2)),
+                (LARGE_DWORD_ADDRESS, _) => Some(address(d, lead, 4)),
+                (LARGE_QWORD_ADDRESS, _) => Some(address(d, lead, 8)),
+                (LARGE_EXTENDED_INTERRUPT, _) if length >= 6 && d[1] > 0 => {
+                    Some(Resource::Interrupt {
+                        gsi: read_u32(d, 2),
+                        count: d[1],
+                        trigger: Trigger { active_low: d[0] & 0x04 != 0, level: d[0] & 0x02 == 0 },
+                    })
+                }
+                _ => Some(Resource::Unknown { tag: lead }),
+            }
+        }
+    }
+}
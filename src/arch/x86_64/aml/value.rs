@@ -0,0 +1,210 @@
+//! AML data objects, and the arenas holding the contents of strings,
+//! buffers and packages.
+
+use core::fmt;
+use core::mem::size_of;
+use core::ptr;
+use core::slice;
+use core::str;
+use super::Error;
+
+/// Index of a node in the namespace.
+pub type NodeId = usize;
+
+/// A byte string: the contents of a string or a buffer, either in an AML
+/// table or in an arena.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bytes {
+    pub address: usize,
+    pub len: usize,
+}
+
+impl Bytes {
+    pub fn from_slice(bytes: &'static [u8]) -> Bytes {
+        Bytes { address: bytes.as_ptr() as usize, len: bytes.len() }
+    }
+
+    pub fn as_slice(&self) -> &'static [u8] {
+        unsafe { slice::from_raw_parts(self.address as *const u8, self.len) }
+    }
+
+    /// Only for bytes allocated in an arena, AML tables are read only.
+    pub fn as_mut_slice(&self) -> &'static mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.address as *mut u8, self.len) }
+    }
+}
+
+/// The elements of a package, in an arena.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elements {
+    pub address: usize,
+    pub len: usize,
+}
+
+impl Elements {
+    pub fn get(&self, index: usize) -> Value {
+        assert!(index < self.len);
+        unsafe { *(self.address as *const Value).offset(index as isize) }
+    }
+
+    pub fn set(&self, index: usize, value: Value) {
+        assert!(index < self.len);
+        unsafe { *(self.address as *mut Value).offset(index as isize) = value; }
+    }
+}
+
+/// Where a value can be stored.
+#[derive(Clone, Copy, Debug)]
+pub enum Target {
+    /// Results are discarded.
+    Null,
+    /// Results are logged.
+    Debug,
+    Local(usize),
+    Arg(usize),
+    Node(NodeId),
+    Element { package: Elements, index: usize },
+    Byte { buffer: Bytes, index: usize },
+}
+
+/// An AML value.
+#[derive(Clone, Copy, Debug)]
+pub enum Value {
+    Uninitialized,
+    Integer(u64),
+    String(Bytes),
+    Buffer(Bytes),
+    Package(Elements),
+    /// A name in a package, in its AML encoding, looked up from `scope`
+    /// when used as objects may be defined after the package.
+    Path { path: Bytes, scope: NodeId },
+    /// A namespace object which isn't data: a device, a method...
+    Node(NodeId),
+    Reference(Target),
+}
+
+/// Object type codes returned by `ObjectType`.
+pub const TYPE_UNINITIALIZED: u64 = 0;
+pub const TYPE_INTEGER: u64 = 1;
+pub const TYPE_STRING: u64 = 2;
+pub const TYPE_BUFFER: u64 = 3;
+pub const TYPE_PACKAGE: u64 = 4;
+pub const TYPE_FIELD_UNIT: u64 = 5;
+pub const TYPE_DEVICE: u64 = 6;
+pub const TYPE_EVENT: u64 = 7;
+pub const TYPE_METHOD: u64 = 8;
+pub const TYPE_MUTEX: u64 = 9;
+pub const TYPE_REGION: u64 = 10;
+pub const TYPE_POWER_RESOURCE: u64 = 11;
+pub const TYPE_PROCESSOR: u64 = 12;
+pub const TYPE_THERMAL_ZONE: u64 = 13;
+pub const TYPE_BUFFER_FIELD: u64 = 14;
+pub const TYPE_DEBUG: u64 = 16;
+
+impl Value {
+    pub fn as_package(&self) -> Option<Elements> {
+        match *self {
+            Value::Package(elements) => Some(elements),
+            _ => None,
+        }
+    }
+
+    pub fn as_buffer(&self) -> Option<&'static [u8]> {
+        match *self {
+            Value::Buffer(bytes) => Some(bytes.as_slice()),
+            _ => None,
+        }
+    }
+}
+
+/// A bump allocator over a range of virtual memory, freed all at once.
+pub struct Arena {
+    start: usize,
+    end: usize,
+    next: usize,
+}
+
+impl Arena {
+    pub const fn empty() -> Arena {
+        Arena { start: 0, end: 0, next: 0 }
+    }
+
+    pub fn new(start: usize, size: usize) -> Arena {
+        Arena { start: start, end: start + size, next: start }
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        self.start <= address && address < self.end
+    }
+
+    fn allocate(&mut self, size: usize, align: usize) -> Result<usize, Error> {
+        let address = (self.next + align - 1) & !(align - 1);
+        let end = try!(address.checked_add(size).ok_or(Error::OutOfMemory));
+        if end > self.end {
+            return Err(Error::OutOfMemory);
+        }
+        self.next = end;
+        Ok(address)
+    }
+
+    /// `len` zeroed bytes.
+    pub fn bytes(&mut self, len: usize) -> Result<Bytes, Error> {
+        let address = try!(self.allocate(len, 1));
+        unsafe { ptr::write_bytes(address as *mut u8, 0, len); }
+        Ok(Bytes { address: address, len: len })
+    }
+
+    /// A copy of `source`, zero padded to `len` bytes.
+    pub fn copy_bytes(&mut self, source: &[u8], len: usize) -> Result<Bytes, Error> {
+        let bytes = try!(self.bytes(len));
+        let count = if source.len() < len { source.len() } else { len };
+        unsafe { ptr::copy_nonoverlapping(source.as_ptr(), bytes.address as *mut u8, count); }
+        Ok(bytes)
+    }
+
+    /// `len` uninitialized package elements.
+    pub fn elements(&mut self, len: usize) -> Result<Elements, Error> {
+        let size = try!(len.checked_mul(size_of::<Value>()).ok_or(Error::OutOfMemory));
+        let address = try!(self.allocate(size, size_of::<u64>()));
+        for i in 0..len {
+            unsafe { ptr::write((address as *mut Value).offset(i as isize), Value::Uninitialized); }
+        }
+        Ok(Elements { address: address, len: len })
+    }
+
+    /// The current allocation point, to free everything allocated after.
+    pub fn mark(&self) -> usize {
+        self.next
+    }
+
+    pub fn reset(&mut self, mark: usize) {
+        self.next = mark;
+    }
+
+    pub fn used(&self) -> usize {
+        self.next - self.start
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Uninitialized => write!(f, "uninitialized"),
+            Value::Integer(value) => write!(f, "{:#x}", value),
+            Value::String(bytes) => {
+                write!(f, "\"{}\"", str::from_utf8(bytes.as_slice()).unwrap_or("<invalid>"))
+            }
+            Value::Buffer(bytes) => {
+                try!(write!(f, "buffer"));
+                for b in bytes.as_slice().iter() {
+                    try!(write!(f, " {:02x}", b));
+                }
+                Ok(())
+            }
+            Value::Package(elements) => write!(f, "package of {}", elements.len),
+            Value::Path { .. } => write!(f, "name"),
+            Value::Node(node) => write!(f, "object {}", node),
+            Value::Reference(target) => write!(f, "reference to {:?}", target),
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! Local APIC driver, in xAPIC (memory mapped) or x2APIC (MSR) mode, and
+//! setup of the APIC interrupt routing replacing the 8259 PICs.
+// http://wiki.osdev.org/APIC
+// http://www.intel.com/Assets/en_US/PDF/manual/253668.pdf, Chapter 10
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use arch::acpi::{Madt, MadtEntry};
+use arch::cpuid::{self, Feature};
+use arch::ioapic::{self, Trigger, ISA_IRQ_COUNT, ISA_TRIGGER};
+use arch::msr::{self, APIC_BASE_ENABLE, APIC_BASE_X2APIC};
+use arch::percpu;
+use arch::softirq;
+use cmdline::{self, Param, Kind};
+use memory::{vm, PAGE_SIZE};
+
+param! {
+    /// Disable the APICs and use the 8259 PICs.
+    pub static NOAPIC: Param = Param {
+        name: "noapic",
+        kind: Kind::Flag,
+        default: "",
+        help: "Use the legacy 8259 PICs instead of the APICs",
+    };
+}
+
+/// Vector of the ISA IRQ 0, the others follow, as with the PICs.
+pub const ISA_VECTOR_BASE: u8 = 0x20;
+
+/// Raised for internal APIC errors.
+pub const ERROR_VECTOR: u8 = 0xFE;
+
+/// Raised when an interrupt goes away before being delivered. Its low
+/// nibble must be all ones on old APICs.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Register offsets, in xAPIC mode. x2APIC MSRs are at
+/// `X2APIC_MSR_BASE + offset / 16`.
+const ID: u32 = 0x020;
+const VERSION: u32 = 0x030;
+const TASK_PRIORITY: u32 = 0x080;
+const END_OF_INTERRUPT: u32 = 0x0B0;
+const SPURIOUS: u32 = 0x0F0;
+const ERROR_STATUS: u32 = 0x280;
+const INTERRUPT_COMMAND: u32 = 0x300;
+const INTERRUPT_COMMAND_HIGH: u32 = 0x310;
+const LVT_TIMER: u32 = 0x320;
+const LVT_LINT0: u32 = 0x350;
+const LVT_LINT1: u32 = 0x360;
+const LVT_ERROR: u32 = 0x370;
+
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Spurious interrupt register: software enable.
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+
+/// Local vector table entry fields.
+const LVT_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL: u32 = 1 << 15;
+const LVT_MASKED: u32 = 1 << 16;
+
+/// Interrupt command register fields.
+const ICR_NMI: u32 = 0b100 << 8;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_SELF: u32 = 0b01 << 18;
+const ICR_ALL_INCLUDING_SELF: u32 = 0b10 << 18;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+/// Who receives an inter-processor interrupt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Destination {
+    /// The processor of a local APIC ID.
+    Apic(u32),
+    SelfOnly,
+    AllIncludingSelf,
+    AllExcludingSelf,
+}
+
+/// What an inter-processor interrupt does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// Raise a vector.
+    Fixed(u8),
+    Nmi,
+    /// Reset the processor, which then waits for a startup IPI.
+    Init,
+    /// Start a processor waiting after INIT, in real mode at the page.
+    Startup(u8),
+}
+
+/// Whether the APICs handle interrupts instead of the PICs.
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Whether the local APIC is in x2APIC mode.
+static X2APIC: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Virtual address of the local APIC registers in xAPIC mode.
+static BASE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+unsafe fn read(register: u32) -> u32 {
+    if X2APIC.load(Ordering::Relaxed) {
+        msr::rdmsr(X2APIC_MSR_BASE + register / 16) as u32
+    } else {
+        ptr::read_volatile((BASE.load(Ordering::Relaxed) + register as usize) as *const u32)
+    }
+}
+
+unsafe fn write(register: u32, value: u32) {
+    if X2APIC.load(Ordering::Relaxed) {
+        msr::wrmsr(X2APIC_MSR_BASE + register / 16, value as u64);
+    } else {
+        ptr::write_volatile((BASE.load(Ordering::Relaxed) + register as usize) as *mut u32, value);
+    }
+}
+
+/// Whether interrupts go through the APICs.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The ID of the local APIC of the running processor.
+pub fn id() -> u32 {
+    let id = unsafe { read(ID) };
+    if X2APIC.load(Ordering::Relaxed) { id } else { id >> 24 }
+}
+
+/// Signal the end of the interrupt being handled.
+pub fn end_of_interrupt() {
+    unsafe { write(END_OF_INTERRUPT, 0); }
+}
+
+/// Handle `ERROR_VECTOR`: latch and clear the error status, reported later
+/// from a tasklet.
+pub fn handle_error() {
+    let status = unsafe {
+        write(ERROR_STATUS, 0);
+        read(ERROR_STATUS)
+    };
+    softirq::schedule_tasklet(report_error, status as usize);
+}
+
+fn report_error(status: usize) {
+    error!("Local APIC error on CPU {}, status {:#x}", percpu::cpu_id(), status);
+}
+
+/// Send an inter-processor interrupt, and wait for the local APIC to
+/// accept it.
+pub fn send_ipi(destination: Destination, delivery: Delivery) {
+    let mut command = match delivery {
+        Delivery::Fixed(vector) => vector as u32,
+        Delivery::Nmi => ICR_NMI,
+        Delivery::Init => ICR_INIT,
+        Delivery::Startup(page) => ICR_STARTUP | page as u32,
+    } | ICR_ASSERT;
+    let apic_id = match destination {
+        Destination::Apic(apic_id) => apic_id,
+        Destination::SelfOnly => {
+            command |= ICR_SELF;
+            0
+        }
+        Destination::AllIncludingSelf => {
+            command |= ICR_ALL_INCLUDING_SELF;
+            0
+        }
+        Destination::AllExcludingSelf => {
+            command |= ICR_ALL_EXCLUDING_SELF;
+            0
+        }
+    };
+
+    unsafe {
+        if X2APIC.load(Ordering::Relaxed) {
+            msr::wrmsr(X2APIC_MSR_BASE + INTERRUPT_COMMAND / 16,
+                       (apic_id as u64) << 32 | command as u64);
+        } else {
+            write(INTERRUPT_COMMAND_HIGH, apic_id << 24);
+            write(INTERRUPT_COMMAND, command);
+            while read(INTERRUPT_COMMAND) & ICR_DELIVERY_PENDING != 0 {}
+        }
+    }
+}
+
+/// The ACPI processor ID of the local APIC `apic_id`, 0 if unknown.
+fn processor_id(madt: &Madt, apic_id: u32) -> u8 {
+    madt.entries()
+        .filter_map(|entry| match entry {
+            MadtEntry::LocalApic { processor_id, apic_id: id, .. } if id as u32 == apic_id => {
+                Some(processor_id)
+            }
+            _ => None,
+        })
+        .next()
+        .unwrap_or(0)
+}
+
+/// Set up the local APIC of the running processor, with the NMI pins
+/// listed in the MADT.
+fn init_local(madt: &Madt, processor_id: u8) {
+    unsafe {
+        write(TASK_PRIORITY, 0);
+        write(SPURIOUS, SPURIOUS_ENABLE | SPURIOUS_VECTOR as u32);
+        write(LVT_TIMER, LVT_MASKED);
+        write(LVT_LINT0, LVT_MASKED);
+        write(LVT_LINT1, LVT_MASKED);
+        write(LVT_ERROR, ERROR_VECTOR as u32);
+
+        for entry in madt.entries() {
+            if let MadtEntry::LocalApicNmi { processor_id: id, flags, lint } = entry {
+                if id != 0xFF && id != processor_id {
+                    continue;
+                }
+                let trigger = Trigger::from_mps(flags, ISA_TRIGGER);
+                let mut lvt = LVT_NMI;
+                if trigger.active_low {
+                    lvt |= LVT_ACTIVE_LOW;
+                }
+                if trigger.level {
+                    lvt |= LVT_LEVEL;
+                }
+                write(if lint == 0 { LVT_LINT0 } else { LVT_LINT1 }, lvt);
+            }
+        }
+
+        // Clear the errors, the register is latched by a write.
+        write(ERROR_STATUS, 0);
+        write(ERROR_STATUS, 0);
+        write(END_OF_INTERRUPT, 0);
+    }
+}
+
+/// Enable the local APIC of the bootstrap processor and route the ISA
+/// IRQs through the I/O APICs. Return `false`, leaving the PICs in charge,
+/// if there is no APIC or if disabled on the command line.
+pub fn init() -> bool {
+    if cmdline::flag(&NOAPIC) {
+        info!("APIC disabled on the command line");
+        return false;
+    }
+    if !cpuid::has(Feature::Apic) {
+        warn!("No local APIC, using the PICs");
+        return false;
+    }
+    let madt = match Madt::get() {
+        Some(madt) => madt,
+        None => {
+            warn!("No MADT, using the PICs");
+            return false;
+        }
+    };
+
+    for entry in madt.entries() {
+        match entry {
+            MadtEntry::IoApic { id, address, gsi_base } => {
+                ioapic::add(id, address as usize, gsi_base);
+            }
+            MadtEntry::InterruptOverride { bus: 0, source, gsi, flags } => {
+                ioapic::set_isa_override(source, gsi, Trigger::from_mps(flags, ISA_TRIGGER));
+            }
+            _ => {}
+        }
+    }
+    if !ioapic::is_present() {
+        warn!("No I/O APIC, using the PICs");
+        return false;
+    }
+
+    let (address, flags) = msr::apic_base();
+    let x2apic = cpuid::has(Feature::X2apic);
+    unsafe {
+        if x2apic {
+            // xAPIC must be enabled before switching to x2APIC.
+            msr::write_apic_base(address, flags | APIC_BASE_ENABLE);
+            msr::write_apic_base(address, flags | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+            X2APIC.store(true, Ordering::Relaxed);
+        } else {
+            match vm::map_device(address, PAGE_SIZE, "local APIC") {
+                Ok(base) => BASE.store(base, Ordering::Relaxed),
+                Err(error) => {
+                    warn!("Failed to map the local APIC: {:?}", error);
+                    return false;
+                }
+            }
+            msr::write_apic_base(address, flags | APIC_BASE_ENABLE);
+        }
+    }
+
+    let id = self::id();
+    init_local(&madt, processor_id(&madt, id));
+
+    for irq in 0..ISA_IRQ_COUNT as u8 {
+        // IRQ 2 is the cascade of the PICs, never raised.
+        if irq != 2 {
+            ioapic::route_isa(irq, ISA_VECTOR_BASE + irq, id as u8);
+        }
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+
+    info!("Local APIC {} at {:#x} in {} mode, version {:#x}", id, address,
+          if x2apic { "x2APIC" } else { "xAPIC" }, unsafe { read(VERSION) } & 0xFF);
+    true
+}
+
+/// Enable the local APIC of an application processor, in the mode chosen
+/// by the bootstrap processor. Its interrupts all go to the latter.
+pub fn init_ap() {
+    let madt = match Madt::get() {
+        Some(madt) => madt,
+        None => return,
+    };
+    let (address, flags) = msr::apic_base();
+    unsafe {
+        msr::write_apic_base(address, flags | APIC_BASE_ENABLE);
+        if X2APIC.load(Ordering::Relaxed) {
+            msr::write_apic_base(address, flags | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+        }
+    }
+    init_local(&madt, processor_id(&madt, id()));
+}
+
+/// Let ISA `irq` through.
+pub fn unmask_isa(irq: u8) {
+    ioapic::unmask(ioapic::isa_gsi(irq));
+}
+
+/// Stop ISA `irq`.
+pub fn mask_isa(irq: u8) {
+    ioapic::mask(ioapic::isa_gsi(irq));
+}
@@ -0,0 +1,133 @@
+//! Bochs Graphics Adapter (BGA) driver, the VBE extensions exposed by
+//! Bochs and by QEMU with `-vga std`.
+// http://wiki.osdev.org/Bochs_VBE_Extensions
+
+use spin::Mutex;
+use arch::cpuio::Port;
+use arch::pci::{self, Bar};
+use framebuffer::{Framebuffer, PixelLayout};
+use memory;
+
+/// PCI vendor and device ID of the Bochs/QEMU standard VGA.
+const PCI_VENDOR_ID: u16 = 0x1234;
+const PCI_DEVICE_ID: u16 = 0x1111;
+
+/// Oldest and newest interface versions, as read from the `Id` register.
+const ID_MIN: u16 = 0xB0C0;
+const ID_MAX: u16 = 0xB0C5;
+
+/// Bits of the `Enable` register.
+const DISABLED: u16 = 0x00;
+const ENABLED: u16 = 0x01;
+const LINEAR_FRAMEBUFFER: u16 = 0x40;
+
+/// The BGA registers, selected by writing their index to the index port.
+#[repr(u16)]
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+enum Register {
+    Id = 0,
+    XRes = 1,
+    YRes = 2,
+    Bpp = 3,
+    Enable = 4,
+    Bank = 5,
+    VirtWidth = 6,
+    VirtHeight = 7,
+    XOffset = 8,
+    YOffset = 9,
+}
+
+/// Reasons for failing to set a graphics mode.
+#[derive(Debug)]
+pub enum Error {
+    /// No BGA found on the I/O ports.
+    NotPresent,
+    /// The adapter was not found on the PCI bus, so the framebuffer
+    /// address is unknown.
+    NoFramebuffer,
+    /// The adapter refused the requested resolution or depth.
+    UnsupportedMode,
+}
+
+/// The Bochs Graphics Adapter.
+pub struct Bga {
+    index: Port<u16>,
+    data: Port<u16>,
+}
+
+impl Bga {
+    fn read(&mut self, register: Register) -> u16 {
+        self.index.write(register as u16);
+        self.data.read()
+    }
+
+    fn write(&mut self, register: Register, value: u16) {
+        self.index.write(register as u16);
+        self.data.write(value);
+    }
+
+    /// The interface version, if an adapter is present.
+    pub fn version(&mut self) -> Option<u16> {
+        match self.read(Register::Id) {
+            id @ ID_MIN...ID_MAX => Some(id),
+            _ => None,
+        }
+    }
+
+    /// Switch to a `width` x `height` graphics mode with `bpp` bits per
+    /// pixel, 15, 16, 24 or 32, and return the linear framebuffer.
+    pub fn set_mode(&mut self, width: u16, height: u16, bpp: u16) -> Result<Framebuffer, Error> {
+        // 15 bits per pixel modes take 16 bits.
+        let (layout, stored_bpp) = match bpp {
+            15 => (PixelLayout::rgb555(), 16),
+            16 => (PixelLayout::rgb565(), 16),
+            24 | 32 => (PixelLayout::rgb888(), bpp),
+            _ => return Err(Error::UnsupportedMode),
+        };
+        if self.version().is_none() {
+            return Err(Error::NotPresent);
+        }
+
+        let address = match pci::find_device(PCI_VENDOR_ID, PCI_DEVICE_ID).map(|d| d.bar(0)) {
+            Some(Bar::Memory { address, .. }) => address,
+            _ => return Err(Error::NoFramebuffer),
+        };
+
+        // The mode can only be changed while the display is disabled.
+        self.write(Register::Enable, DISABLED);
+        self.write(Register::XRes, width);
+        self.write(Register::YRes, height);
+        self.write(Register::Bpp, bpp);
+        self.write(Register::Enable, ENABLED | LINEAR_FRAMEBUFFER);
+
+        // Unsupported values are silently clamped, read them back.
+        if self.read(Register::XRes) != width || self.read(Register::YRes) != height ||
+           self.read(Register::Bpp) != bpp {
+            self.disable();
+            return Err(Error::UnsupportedMode);
+        }
+
+        let bytes_per_pixel = stored_bpp as usize / 8;
+        let pitch = self.read(Register::VirtWidth) as usize * bytes_per_pixel;
+
+        // The PCI memory hole is below 4 GiB, in the physical memory window
+        // of the boot page tables. `console::remap_framebuffer` maps it as
+        // a device once paging is set up.
+        let framebuffer = unsafe {
+            Framebuffer::new(memory::phys_to_virt(address as usize), width as usize, height as usize,
+                             pitch, stored_bpp as usize, layout)
+        };
+        framebuffer.ok_or(Error::UnsupportedMode)
+    }
+
+    /// Go back to VGA text mode.
+    pub fn disable(&mut self) {
+        self.write(Register::Enable, DISABLED);
+    }
+}
+
+pub static BGA: Mutex<Bga> = Mutex::new(Bga {
+    index: Port::new(0x01CE),
+    data: Port::new(0x01CF),
+});
@@ -1,12 +1,23 @@
 global start
 global gdt64_code_offset
+global gdt64_pointer
+global p4_table
+global kernel_stack_guard
+global kernel_stack_top
 
 extern long_mode_start
 
-section .text
+;;; The kernel is linked in the higher half but loaded at 1 MiB, and this code
+;;; runs before paging is enabled. Until then, every symbol outside of the
+;;; .boot.text section must be accessed through its physical address, that is
+;;; its linked address minus KERNEL_OFFSET.
+KERNEL_OFFSET equ 0xFFFFFFFF80000000
+
+section .boot.text
 bits 32
 start:
-    mov esp, kernel_stack_top           ; point esp to the start of the stack (end of memory, stack grows downwards)
+    mov esp, kernel_stack_top - KERNEL_OFFSET   ; point esp to the start of the stack (end of memory, stack grows downwards)
+    mov edi, ebx                        ; Multiboot information pointer, first argument of rust_main
 
     call test_multiboot
     call test_cpuid
@@ -16,7 +27,7 @@ start:
     call enable_paging
 
     ; load the 64bit GDT
-    lgdt [gdt64.pointer]
+    lgdt [gdt64.pointer - KERNEL_OFFSET]
 
     ; update selectors
     mov ax, gdt64.data
@@ -25,7 +36,7 @@ start:
     mov es, ax  ; extra selector
 
     ;; No way of setting the cs (code selector) manually.
-    ;; A far jump to 64 bit code is needed
+    ;; A far jump to 64 bit code is needed, still at a physical address.
     jmp gdt64.code:long_mode_start
 
 test_multiboot:
@@ -80,18 +91,45 @@ test_long_mode:
 ;   NM) Bits 48-63 rest unused, actual copies of bit 47.
 ; Each entry in the tables contains the page aligned 52bit physical address of
 ; the next table, ORed in with some bit flags (present 0, writable 1, etc.).
-; Finally, to identity map the first gigabytes of memory, use the following mapping:
-; 1 PML4 -> 1 PDP -> 512 2MiB PD tables
+; The first 4 gigabytes of physical memory are mapped with 1 P3 -> 4 * 512 2MiB P2
+; tables. Mapping 4 GiB instead of 1 also covers the memory mapped devices
+; (PCI hole), i.e. linear framebuffers. This P3 is used by three P4 entries:
+;   1) P4[0], the identity map. Needed to keep running after enabling paging,
+;      dropped once in the higher half (see long_mode.asm)
+;   2) P4[256], all physical memory at 0xFFFF800000000000, to reach it from Rust
+;   3) P4[511] -> P3 high, whose entry 510 maps the first GiB only, that is the
+;      kernel at 0xFFFFFFFF80000000
 setup_page_tables:
     ; map first P4 entry to P3
-    mov eax, p3_table
+    mov eax, p3_table - KERNEL_OFFSET
     or eax, 0b11            ; Set present and writable flags
-    mov [p4_table], eax     ; p4_table is a address. [addr] deferences the memory at the addr
+    mov [p4_table - KERNEL_OFFSET], eax     ; [addr] deferences the memory at the addr
 
-    ; map first P3 entry to P2
-    mov eax, p2_table
-    or eax, 0b11            ; Set present and writable flags
-    mov [p3_table], eax
+    ; map the physical memory window to the same P3
+    mov [p4_table - KERNEL_OFFSET + 256 * 8], eax
+
+    ; map last P4 entry to P3 high
+    mov eax, p3_high_table - KERNEL_OFFSET
+    or eax, 0b11
+    mov [p4_table - KERNEL_OFFSET + 511 * 8], eax
+
+    ; map P3 high entry 510 to the P2 table of the first GiB
+    mov eax, p2_table - KERNEL_OFFSET
+    or eax, 0b11
+    mov [p3_high_table - KERNEL_OFFSET + 510 * 8], eax
+
+    ; map the first 4 P3 entries to the 4 consecutive P2 tables
+    mov ecx, 0              ; counter variable
+.map_p3_table:
+    mov eax, 4096
+    mul ecx                         ; Offset of the ecx-th P2 table
+    add eax, p2_table - KERNEL_OFFSET
+    or eax, 0b11                    ; Set present and writable flags
+    mov [p3_table - KERNEL_OFFSET + ecx * 8], eax
+
+    inc ecx
+    cmp ecx, 4                      ; if ecx == 4, we're done
+    jne .map_p3_table
 
     ; map each P2 entry to a 2MiB page
     mov ecx, 0              ; counter variable
@@ -100,17 +138,17 @@ setup_page_tables:
     mov eax, 0x200000               ; 2 MiB
     mul ecx                         ; Multiply what's in eax with ecx and store in eax (start address of ecx-th page)
     or eax, 0b10000011              ; Set present, writable and huge page (in P2 means 2 MiB pages) flags
-    mov [p2_table + ecx * 8], eax   ; Set each ecx-th entry of P2 to the ecx-th page
+    mov [p2_table - KERNEL_OFFSET + ecx * 8], eax   ; Set each ecx-th entry of P2 to the ecx-th page
 
     inc ecx                         ; Increment counter
-    cmp ecx, 512                    ; if ecx == 512, we're done
+    cmp ecx, 2048                   ; if ecx == 4 * 512, we're done
     jne .map_p2_table               ; else loop again
 
     ret
 
 enable_paging:
     ; load P4 adress into cr3 register
-    mov eax, p4_table
+    mov eax, p4_table - KERNEL_OFFSET
     mov cr3, eax
 
     ; enable PAE flag in cr4 (Physical Adrees Extension)
@@ -140,30 +178,44 @@ error:
     mov byte  [0xb800a], al
     hlt
 
-section .bss:
+section .bss
 align 4096
 p4_table:                   ; Page-Map Level-4 Table (PML4) or P4
     resb 4096
 p3_table:                   ; Page-Directory Pointer Table (PDP) or P3
     resb 4096
-p2_table:                   ; Page-Directory Table (PD) or P2
+p3_high_table:              ; P3 for the last 512 GiB, where the kernel lives
     resb 4096
+p2_table:                   ; Page-Directory Tables (PD) or P2, one per GiB
+    resb 4096 * 4
 
-;;; Reserve space for the kernel stack.
+;;; Reserve space for the kernel stack, above a guard page which
+;;; memory::paging::init leaves unmapped, so overflowing the stack faults
+;;; instead of running over the page tables.
+align 4096
+kernel_stack_guard:
+    resb 4096
 kernel_stack_bottom:
-    resb 8192               ; Reserve 8192 bytes for the kernel stack
+    resb 32768              ; Reserve 32 KiB for the kernel stack
 kernel_stack_top:
 
 section .rodata
 ;;; Global Description Table. Used to describe available segments.
+;;; The accessed bit (40) is preset: the table is read only once the kernel is
+;;; remapped, and the CPU would fault setting it when loading a selector.
 gdt64:
-    dq 0                                                    ; zero entry
+    dq 0                                                            ; zero entry
 .code: equ $ - gdt64
-    dq (1<<44) | (1<<47) | (1<<41) | (1<<43) | (1<<53)      ; code segment
+    dq (1<<40) | (1<<44) | (1<<47) | (1<<41) | (1<<43) | (1<<53)    ; code segment
 .data: equ $ - gdt64
-    dq (1<<44) | (1<<47) | (1<<41)                          ; data segment
-.pointer:
+    dq (1<<40) | (1<<44) | (1<<47) | (1<<41)                        ; data segment
+.pointer:                   ; physical address, for lgdt in 32 bit mode
     dw $ - gdt64 - 1
+    dq gdt64 - KERNEL_OFFSET
+
+;;; The same GDT at its higher half address, reloaded in 64 bit mode.
+gdt64_pointer:
+    dw gdt64.pointer - gdt64 - 1
     dq gdt64
 
 ;;; Export code selector so Rust can read it.
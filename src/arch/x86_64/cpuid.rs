@@ -0,0 +1,464 @@
+//! CPU identification and feature detection.
+// http://wiki.osdev.org/CPUID
+// http://www.intel.com/Assets/en_US/PDF/manual/253666.pdf, CPUID instruction
+
+use core::fmt;
+use core::str;
+use spin::Mutex;
+
+/// Leaves queried for feature bits, in the order of `CpuFeatures::words`.
+const WORD_LEAF1_ECX: usize = 0;
+const WORD_LEAF1_EDX: usize = 1;
+const WORD_LEAF7_EBX: usize = 2;
+const WORD_LEAF7_ECX: usize = 3;
+const WORD_LEAF7_EDX: usize = 4;
+const WORD_EXT1_ECX: usize = 5;
+const WORD_EXT1_EDX: usize = 6;
+const WORD_EXT7_EDX: usize = 7;
+const WORD_COUNT: usize = 8;
+
+/// How many cache descriptions are kept.
+const MAX_CACHES: usize = 8;
+
+/// The registers returned by the `cpuid` instruction.
+#[derive(Clone, Copy, Debug)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Execute `cpuid` for `leaf` and `subleaf`.
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+             : "{eax}"(leaf), "{ecx}"(subleaf));
+    }
+    CpuidResult { eax: eax, ebx: ebx, ecx: ecx, edx: edx }
+}
+
+/// CPU features the kernel may care about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    // Leaf 1, edx
+    Fpu,
+    Vme,
+    Pse,
+    Tsc,
+    Msr,
+    Pae,
+    Mce,
+    Cx8,
+    Apic,
+    Sep,
+    Mtrr,
+    Pge,
+    Mca,
+    Cmov,
+    Pat,
+    Pse36,
+    Clflush,
+    Mmx,
+    Fxsr,
+    Sse,
+    Sse2,
+    Htt,
+    // Leaf 1, ecx
+    Sse3,
+    Pclmulqdq,
+    Monitor,
+    Vmx,
+    Ssse3,
+    Fma,
+    Cx16,
+    Pcid,
+    Sse41,
+    Sse42,
+    X2apic,
+    Movbe,
+    Popcnt,
+    TscDeadline,
+    Aes,
+    Xsave,
+    Osxsave,
+    Avx,
+    F16c,
+    Rdrand,
+    Hypervisor,
+    // Leaf 7
+    Fsgsbase,
+    Bmi1,
+    Avx2,
+    Smep,
+    Bmi2,
+    Erms,
+    Invpcid,
+    Avx512f,
+    Rdseed,
+    Smap,
+    Umip,
+    Pku,
+    MdClear,
+    // Leaf 0x80000001
+    LahfLm,
+    Svm,
+    Lzcnt,
+    TopologyExtensions,
+    Syscall,
+    Nx,
+    Page1Gb,
+    Rdtscp,
+    LongMode,
+    // Leaf 0x80000007
+    InvariantTsc,
+}
+
+/// Each feature, with its /proc/cpuinfo style name and where CPUID reports it.
+static FEATURES: [(Feature, &'static str, usize, u32); 66] = [
+    (Feature::Fpu, "fpu", WORD_LEAF1_EDX, 0),
+    (Feature::Vme, "vme", WORD_LEAF1_EDX, 1),
+    (Feature::Pse, "pse", WORD_LEAF1_EDX, 3),
+    (Feature::Tsc, "tsc", WORD_LEAF1_EDX, 4),
+    (Feature::Msr, "msr", WORD_LEAF1_EDX, 5),
+    (Feature::Pae, "pae", WORD_LEAF1_EDX, 6),
+    (Feature::Mce, "mce", WORD_LEAF1_EDX, 7),
+    (Feature::Cx8, "cx8", WORD_LEAF1_EDX, 8),
+    (Feature::Apic, "apic", WORD_LEAF1_EDX, 9),
+    (Feature::Sep, "sep", WORD_LEAF1_EDX, 11),
+    (Feature::Mtrr, "mtrr", WORD_LEAF1_EDX, 12),
+    (Feature::Pge, "pge", WORD_LEAF1_EDX, 13),
+    (Feature::Mca, "mca", WORD_LEAF1_EDX, 14),
+    (Feature::Cmov, "cmov", WORD_LEAF1_EDX, 15),
+    (Feature::Pat, "pat", WORD_LEAF1_EDX, 16),
+    (Feature::Pse36, "pse36", WORD_LEAF1_EDX, 17),
+    (Feature::Clflush, "clflush", WORD_LEAF1_EDX, 19),
+    (Feature::Mmx, "mmx", WORD_LEAF1_EDX, 23),
+    (Feature::Fxsr, "fxsr", WORD_LEAF1_EDX, 24),
+    (Feature::Sse, "sse", WORD_LEAF1_EDX, 25),
+    (Feature::Sse2, "sse2", WORD_LEAF1_EDX, 26),
+    (Feature::Htt, "ht", WORD_LEAF1_EDX, 28),
+    (Feature::Sse3, "sse3", WORD_LEAF1_ECX, 0),
+    (Feature::Pclmulqdq, "pclmulqdq", WORD_LEAF1_ECX, 1),
+    (Feature::Monitor, "monitor", WORD_LEAF1_ECX, 3),
+    (Feature::Vmx, "vmx", WORD_LEAF1_ECX, 5),
+    (Feature::Ssse3, "ssse3", WORD_LEAF1_ECX, 9),
+    (Feature::Fma, "fma", WORD_LEAF1_ECX, 12),
+    (Feature::Cx16, "cx16", WORD_LEAF1_ECX, 13),
+    (Feature::Pcid, "pcid", WORD_LEAF1_ECX, 17),
+    (Feature::Sse41, "sse4_1", WORD_LEAF1_ECX, 19),
+    (Feature::Sse42, "sse4_2", WORD_LEAF1_ECX, 20),
+    (Feature::X2apic, "x2apic", WORD_LEAF1_ECX, 21),
+    (Feature::Movbe, "movbe", WORD_LEAF1_ECX, 22),
+    (Feature::Popcnt, "popcnt", WORD_LEAF1_ECX, 23),
+    (Feature::TscDeadline, "tsc_deadline_timer", WORD_LEAF1_ECX, 24),
+    (Feature::Aes, "aes", WORD_LEAF1_ECX, 25),
+    (Feature::Xsave, "xsave", WORD_LEAF1_ECX, 26),
+    (Feature::Osxsave, "osxsave", WORD_LEAF1_ECX, 27),
+    (Feature::Avx, "avx", WORD_LEAF1_ECX, 28),
+    (Feature::F16c, "f16c", WORD_LEAF1_ECX, 29),
+    (Feature::Rdrand, "rdrand", WORD_LEAF1_ECX, 30),
+    (Feature::Hypervisor, "hypervisor", WORD_LEAF1_ECX, 31),
+    (Feature::Fsgsbase, "fsgsbase", WORD_LEAF7_EBX, 0),
+    (Feature::Bmi1, "bmi1", WORD_LEAF7_EBX, 3),
+    (Feature::Avx2, "avx2", WORD_LEAF7_EBX, 5),
+    (Feature::Smep, "smep", WORD_LEAF7_EBX, 7),
+    (Feature::Bmi2, "bmi2", WORD_LEAF7_EBX, 8),
+    (Feature::Erms, "erms", WORD_LEAF7_EBX, 9),
+    (Feature::Invpcid, "invpcid", WORD_LEAF7_EBX, 10),
+    (Feature::Avx512f, "avx512f", WORD_LEAF7_EBX, 16),
+    (Feature::Rdseed, "rdseed", WORD_LEAF7_EBX, 18),
+    (Feature::Smap, "smap", WORD_LEAF7_EBX, 20),
+    (Feature::Umip, "umip", WORD_LEAF7_ECX, 2),
+    (Feature::Pku, "pku", WORD_LEAF7_ECX, 3),
+    (Feature::MdClear, "md_clear", WORD_LEAF7_EDX, 10),
+    (Feature::LahfLm, "lahf_lm", WORD_EXT1_ECX, 0),
+    (Feature::Svm, "svm", WORD_EXT1_ECX, 2),
+    (Feature::Lzcnt, "abm", WORD_EXT1_ECX, 5),
+    (Feature::TopologyExtensions, "topoext", WORD_EXT1_ECX, 22),
+    (Feature::Syscall, "syscall", WORD_EXT1_EDX, 11),
+    (Feature::Nx, "nx", WORD_EXT1_EDX, 20),
+    (Feature::Page1Gb, "pdpe1gb", WORD_EXT1_EDX, 26),
+    (Feature::Rdtscp, "rdtscp", WORD_EXT1_EDX, 27),
+    (Feature::LongMode, "lm", WORD_EXT1_EDX, 29),
+    (Feature::InvariantTsc, "invariant_tsc", WORD_EXT7_EDX, 8),
+];
+
+impl Feature {
+    /// Short lower case name, as in Linux /proc/cpuinfo.
+    pub fn name(&self) -> &'static str {
+        FEATURES.iter().find(|f| f.0 == *self).map(|f| f.1).unwrap_or("?")
+    }
+
+    /// Index in `CpuFeatures::words` and bit.
+    fn location(&self) -> (usize, u32) {
+        FEATURES.iter().find(|f| f.0 == *self).map(|f| (f.2, f.3)).unwrap_or((0, 0))
+    }
+}
+
+/// The kind of data a cache holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheKind {
+    Data,
+    Instruction,
+    Unified,
+}
+
+/// A level of the cache hierarchy, from the deterministic cache parameters
+/// leaf (4 on Intel, 0x8000001D on AMD).
+#[derive(Clone, Copy, Debug)]
+pub struct Cache {
+    pub level: u8,
+    pub kind: CacheKind,
+    /// Size in bytes.
+    pub size: usize,
+    pub line_size: usize,
+    pub ways: usize,
+    pub sets: usize,
+    /// Logical processors sharing the cache.
+    pub shared_by: usize,
+}
+
+impl Cache {
+    /// Decode the cache parameters in `result`, `None` past the last cache.
+    fn parse(result: CpuidResult) -> Option<Cache> {
+        let kind = match result.eax & 0x1F {
+            1 => CacheKind::Data,
+            2 => CacheKind::Instruction,
+            3 => CacheKind::Unified,
+            _ => return None,
+        };
+        let line_size = (result.ebx & 0xFFF) as usize + 1;
+        let partitions = ((result.ebx >> 12) & 0x3FF) as usize + 1;
+        let ways = (result.ebx >> 22) as usize + 1;
+        let sets = result.ecx as usize + 1;
+        Some(Cache {
+            level: ((result.eax >> 5) & 0x7) as u8,
+            kind: kind,
+            size: ways * partitions * line_size * sets,
+            line_size: line_size,
+            ways: ways,
+            sets: sets,
+            shared_by: ((result.eax >> 14) & 0xFFF) as usize + 1,
+        })
+    }
+}
+
+impl fmt::Display for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            CacheKind::Data => "d",
+            CacheKind::Instruction => "i",
+            CacheKind::Unified => "",
+        };
+        write!(f, "L{}{} {} KiB, {}-way, {} byte lines, shared by {}",
+               self.level, kind, self.size / 1024, self.ways, self.line_size, self.shared_by)
+    }
+}
+
+/// What CPUID reports about the processor.
+#[derive(Clone, Copy)]
+pub struct CpuFeatures {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    /// Highest standard and extended leaves.
+    pub max_leaf: u32,
+    pub max_extended_leaf: u32,
+    words: [u32; WORD_COUNT],
+    caches: [Option<Cache>; MAX_CACHES],
+}
+
+impl CpuFeatures {
+    /// Query the CPU this runs on.
+    pub fn detect() -> CpuFeatures {
+        let leaf0 = cpuid(0, 0);
+        let mut features = CpuFeatures {
+            vendor: [0; 12],
+            brand: [0; 48],
+            family: 0,
+            model: 0,
+            stepping: 0,
+            max_leaf: leaf0.eax,
+            max_extended_leaf: cpuid(0x8000_0000, 0).eax,
+            words: [0; WORD_COUNT],
+            caches: [None; MAX_CACHES],
+        };
+
+        // The vendor string is in ebx, edx, ecx in that order.
+        for (i, &register) in [leaf0.ebx, leaf0.edx, leaf0.ecx].iter().enumerate() {
+            write_u32(&mut features.vendor[i * 4..], register);
+        }
+
+        if features.max_leaf >= 1 {
+            let leaf1 = cpuid(1, 0);
+            let base_family = (leaf1.eax >> 8) & 0xF;
+            let base_model = (leaf1.eax >> 4) & 0xF;
+            features.family = if base_family == 0xF {
+                base_family + ((leaf1.eax >> 20) & 0xFF)
+            } else {
+                base_family
+            };
+            features.model = if base_family == 0x6 || base_family == 0xF {
+                base_model + (((leaf1.eax >> 16) & 0xF) << 4)
+            } else {
+                base_model
+            };
+            features.stepping = leaf1.eax & 0xF;
+            features.words[WORD_LEAF1_ECX] = leaf1.ecx;
+            features.words[WORD_LEAF1_EDX] = leaf1.edx;
+        }
+        if features.max_leaf >= 7 {
+            let leaf7 = cpuid(7, 0);
+            features.words[WORD_LEAF7_EBX] = leaf7.ebx;
+            features.words[WORD_LEAF7_ECX] = leaf7.ecx;
+            features.words[WORD_LEAF7_EDX] = leaf7.edx;
+        }
+        if features.max_extended_leaf >= 0x8000_0001 {
+            let ext1 = cpuid(0x8000_0001, 0);
+            features.words[WORD_EXT1_ECX] = ext1.ecx;
+            features.words[WORD_EXT1_EDX] = ext1.edx;
+        }
+        if features.max_extended_leaf >= 0x8000_0004 {
+            for i in 0..3 {
+                let result = cpuid(0x8000_0002 + i, 0);
+                let registers = [result.eax, result.ebx, result.ecx, result.edx];
+                for (j, &register) in registers.iter().enumerate() {
+                    write_u32(&mut features.brand[i as usize * 16 + j * 4..], register);
+                }
+            }
+        }
+        if features.max_extended_leaf >= 0x8000_0007 {
+            features.words[WORD_EXT7_EDX] = cpuid(0x8000_0007, 0).edx;
+        }
+
+        let cache_leaf = if features.has(Feature::TopologyExtensions) {
+            Some(0x8000_001D)
+        } else if features.max_leaf >= 4 {
+            Some(4)
+        } else {
+            None
+        };
+        if let Some(leaf) = cache_leaf {
+            for i in 0..MAX_CACHES {
+                match Cache::parse(cpuid(leaf, i as u32)) {
+                    Some(cache) => features.caches[i] = Some(cache),
+                    None => break,
+                }
+            }
+        }
+
+        features
+    }
+
+    /// Whether the CPU supports `feature`.
+    pub fn has(&self, feature: Feature) -> bool {
+        let (word, bit) = feature.location();
+        self.words[word] & (1 << bit) != 0
+    }
+
+    /// The vendor identification, i.e. "GenuineIntel" or "AuthenticAMD".
+    pub fn vendor(&self) -> &str {
+        str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+
+    /// The processor name, if reported.
+    pub fn brand(&self) -> &str {
+        let length = self.brand.iter().position(|&b| b == 0).unwrap_or(self.brand.len());
+        str::from_utf8(&self.brand[..length]).unwrap_or("").trim()
+    }
+
+    /// The supported features among those known.
+    pub fn features<'a>(&'a self) -> FeatureIter<'a> {
+        FeatureIter { features: self, index: 0 }
+    }
+
+    /// The cache hierarchy.
+    pub fn caches<'a>(&'a self) -> CacheIter<'a> {
+        CacheIter { features: self, index: 0 }
+    }
+}
+
+impl fmt::Display for CpuFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{} {} (family {:#x}, model {:#x}, stepping {})\n",
+                    self.vendor(), self.brand(), self.family, self.model, self.stepping));
+        try!(write!(f, "Features:"));
+        for feature in self.features() {
+            try!(write!(f, " {}", feature.name()));
+        }
+        for cache in self.caches() {
+            try!(write!(f, "\n{}", cache));
+        }
+        Ok(())
+    }
+}
+
+/// An iterator over the supported features.
+pub struct FeatureIter<'a> {
+    features: &'a CpuFeatures,
+    index: usize,
+}
+
+impl<'a> Iterator for FeatureIter<'a> {
+    type Item = Feature;
+
+    fn next(&mut self) -> Option<Feature> {
+        while self.index < FEATURES.len() {
+            let feature = FEATURES[self.index].0;
+            self.index += 1;
+            if self.features.has(feature) {
+                return Some(feature);
+            }
+        }
+        None
+    }
+}
+
+/// An iterator over the caches.
+pub struct CacheIter<'a> {
+    features: &'a CpuFeatures,
+    index: usize,
+}
+
+impl<'a> Iterator for CacheIter<'a> {
+    type Item = Cache;
+
+    fn next(&mut self) -> Option<Cache> {
+        let cache = self.features.caches.get(self.index).and_then(|c| *c);
+        self.index += 1;
+        cache
+    }
+}
+
+/// Store `value` little endian at the start of `bytes`.
+fn write_u32(bytes: &mut [u8], value: u32) {
+    for i in 0..4 {
+        bytes[i] = (value >> (i * 8)) as u8;
+    }
+}
+
+static CPU_FEATURES: Mutex<Option<CpuFeatures>> = Mutex::new(None);
+
+/// The features of the boot processor, detected on first use.
+pub fn features() -> CpuFeatures {
+    let mut features = CPU_FEATURES.lock();
+    if features.is_none() {
+        *features = Some(CpuFeatures::detect());
+    }
+    features.unwrap()
+}
+
+/// Whether the boot processor supports `feature`.
+pub fn has(feature: Feature) -> bool {
+    features().has(feature)
+}
+
+/// Detect the CPU features and print them.
+pub fn init() {
+    info!("CPU: {}", features());
+}
@@ -0,0 +1,312 @@
+//! FPU, SSE and AVX state management.
+// http://wiki.osdev.org/SSE
+// http://www.intel.com/Assets/en_US/PDF/manual/253665.pdf, Chapter 13
+//
+// The kernel is built with soft-float, so it never touches the FPU
+// registers behind our back and interrupt handlers don't need to save them.
+// Code running on behalf of an `FpuContext` (the boot code, later threads)
+// can use them: switching contexts only sets CR0.TS, and the first FPU or
+// SSE instruction of the new context traps with #NM. The handler then saves
+// the registers in the context owning them and loads the new one.
+//
+// The kernel itself uses them in sections between `kernel_fpu_begin` and
+// `kernel_fpu_end`, with assembly: the state of their owner is saved first,
+// and loaded again on its next use.
+//
+// Each CPU has its own registers, so the owner and current contexts are
+// per-CPU. Threads can go on on another CPU, so a context switched out
+// while owning the registers is saved then, and the next one loaded
+// lazily.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use arch::cpuid::{self, Feature};
+use arch::interrupts;
+use arch::smp::MAX_CPUS;
+use arch::registers::{self, CR0_MONITOR_COPROCESSOR, CR0_EMULATE_COPROCESSOR,
+                      CR0_NUMERIC_ERROR, CR0_TASK_SWITCHED, CR4_OSFXSR, CR4_OSXMMEXCPT,
+                      CR4_OSXSAVE, XCR0_X87, XCR0_SSE, XCR0_AVX, RFLAGS_INTERRUPT};
+use memory::PAGE_SIZE;
+use memory::slab::Cache;
+
+/// Room for the x87, SSE and AVX state with XSAVE (832 bytes).
+const AREA_SIZE: usize = 1024;
+
+/// XSAVE needs 64 byte alignment, FXSAVE 16.
+const AREA_ALIGN: usize = 64;
+
+/// `size_of::<FpuContext>()`, which is not a const fn.
+const CONTEXT_SIZE: usize = AREA_SIZE + AREA_ALIGN + 1;
+
+/// MXCSR at reset: all SIMD exceptions masked.
+const DEFAULT_MXCSR: u32 = 0x1F80;
+
+/// Whether XSAVE is used instead of FXSAVE.
+static XSAVE: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Whether the FPU and SSE are enabled.
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+per_cpu! {
+    /// The context whose state is in the registers, 0 if none.
+    static OWNER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// The context of the code running now, 0 if none.
+    static CURRENT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// Whether the kernel is in a `kernel_fpu_begin` section.
+    static IN_KERNEL: AtomicBool = ATOMIC_BOOL_INIT;
+
+    /// Whether interrupts were enabled when the section began.
+    static INTERRUPTS_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+}
+
+/// The FPU state of the code running until the first thread switch.
+static mut BOOT_CONTEXT: FpuContext = FpuContext::new();
+
+/// The contexts of the threads. Free ones are in the default state.
+static CONTEXTS: Cache = Cache::new("fpu contexts", CONTEXT_SIZE, 16, Some(construct));
+
+/// Saved FPU, SSE and AVX registers.
+#[repr(C)]
+pub struct FpuContext {
+    area: [u8; AREA_SIZE + AREA_ALIGN],
+    /// Whether `area` holds a state, otherwise the default one is loaded.
+    saved: bool,
+}
+
+impl FpuContext {
+    /// A context starting with the default state.
+    pub const fn new() -> FpuContext {
+        FpuContext { area: [0; AREA_SIZE + AREA_ALIGN], saved: false }
+    }
+
+    /// The save area, aligned.
+    fn area(&mut self) -> *mut u8 {
+        let address = self.area.as_mut_ptr() as usize;
+        ((address + AREA_ALIGN - 1) & !(AREA_ALIGN - 1)) as *mut u8
+    }
+
+    unsafe fn save(&mut self) {
+        let area = self.area();
+        if XSAVE.load(Ordering::Relaxed) {
+            asm!("xsave64 ($0)" :: "r"(area), "{eax}"(!0u32), "{edx}"(!0u32) : "memory");
+        } else {
+            asm!("fxsave64 ($0)" :: "r"(area) : "memory");
+        }
+        self.saved = true;
+    }
+
+    unsafe fn restore(&mut self) {
+        if !self.saved {
+            reset();
+            return;
+        }
+
+        let area = self.area();
+        if XSAVE.load(Ordering::Relaxed) {
+            asm!("xrstor64 ($0)" :: "r"(area), "{eax}"(!0u32), "{edx}"(!0u32) : "memory");
+        } else {
+            asm!("fxrstor64 ($0)" :: "r"(area) : "memory");
+        }
+    }
+}
+
+/// Load the default state.
+unsafe fn reset() {
+    let mxcsr = DEFAULT_MXCSR;
+    asm!("fninit");
+    asm!("ldmxcsr ($0)" :: "r"(&mxcsr));
+}
+
+/// Enable the FPU and SSE, and AVX with XSAVE if available, on the running
+/// CPU. Return the size of the saved state.
+unsafe fn enable(features: cpuid::CpuFeatures) -> usize {
+    registers::write_cr0((registers::cr0() | CR0_MONITOR_COPROCESSOR | CR0_NUMERIC_ERROR) -
+                         CR0_EMULATE_COPROCESSOR);
+    registers::write_cr4(registers::cr4() | CR4_OSFXSR | CR4_OSXMMEXCPT);
+
+    if features.has(Feature::Xsave) {
+        registers::write_cr4(registers::cr4() | CR4_OSXSAVE);
+        let mut components = XCR0_X87 | XCR0_SSE;
+        if features.has(Feature::Avx) {
+            components.insert(XCR0_AVX);
+        }
+        registers::write_xcr0(components);
+
+        // Size of the area for the enabled components
+        let xsave_size = cpuid::cpuid(0xD, 0).ebx as usize;
+        if xsave_size <= AREA_SIZE {
+            XSAVE.store(true, Ordering::Relaxed);
+            return xsave_size;
+        }
+        registers::write_xcr0(XCR0_X87 | XCR0_SSE);
+    }
+    512
+}
+
+/// Enable the FPU and SSE, and AVX with XSAVE if available. The boot code
+/// becomes the first FPU context.
+pub fn init() {
+    let features = cpuid::features();
+    if !features.has(Feature::Fxsr) || !features.has(Feature::Sse) {
+        warn!("No FXSAVE or SSE, floating point is software only");
+        return;
+    }
+
+    let size = unsafe { enable(features) };
+    ENABLED.store(true, Ordering::Relaxed);
+    unsafe {
+        CURRENT.get().store(&mut BOOT_CONTEXT as *mut FpuContext as usize, Ordering::Relaxed);
+        // Nobody owns the registers yet, trap on first use.
+        registers::write_cr0(registers::cr0() | CR0_TASK_SWITCHED);
+    }
+
+    info!("FPU state saved with {}, {} bytes",
+          if XSAVE.load(Ordering::Relaxed) { "XSAVE" } else { "FXSAVE" }, size);
+}
+
+/// Enable the FPU on an application processor, as on the bootstrap one.
+/// It has no context until it switches to one.
+pub fn init_ap() {
+    let features = cpuid::features();
+    if features.has(Feature::Fxsr) && features.has(Feature::Sse) {
+        unsafe {
+            enable(features);
+            registers::write_cr0(registers::cr0() | CR0_TASK_SWITCHED);
+        }
+    }
+}
+
+/// Make `context` the current one. Its state is loaded on its first use of
+/// the FPU. The state of the context switched out is saved if it's in the
+/// registers.
+pub unsafe fn switch_to(context: *mut FpuContext) {
+    let current = CURRENT.get().load(Ordering::Relaxed);
+    if current != 0 && current != context as usize &&
+       OWNER.get().load(Ordering::Relaxed) == current {
+        asm!("clts");
+        (*(current as *mut FpuContext)).save();
+        OWNER.get().store(0, Ordering::Relaxed);
+    }
+    CURRENT.get().store(context as usize, Ordering::Relaxed);
+    if OWNER.get().load(Ordering::Relaxed) == context as usize {
+        asm!("clts");
+    } else {
+        registers::write_cr0(registers::cr0() | CR0_TASK_SWITCHED);
+    }
+}
+
+/// The current context, null if none.
+pub fn current() -> *mut FpuContext {
+    CURRENT.get().load(Ordering::Relaxed) as *mut FpuContext
+}
+
+fn construct(object: *mut u8) {
+    unsafe { ptr::write(object as *mut FpuContext, FpuContext::new()); }
+}
+
+/// A context starting with the default state, `None` if out of memory.
+pub fn allocate() -> Option<*mut FpuContext> {
+    slab_allocate!(CONTEXTS).map(|object| object as *mut FpuContext)
+}
+
+/// Forget `context`, from `allocate`, if it owns the registers and give it
+/// back.
+pub fn release(context: *mut FpuContext) {
+    for cpu in 0..MAX_CPUS {
+        if let Some(owner) = OWNER.on(cpu) {
+            let _ = owner.compare_and_swap(context as usize, 0, Ordering::Relaxed);
+        }
+    }
+    unsafe {
+        (*context).saved = false;
+        CONTEXTS.free(context as *mut u8);
+    }
+}
+
+/// Let the kernel use the FPU and SSE registers until `kernel_fpu_end`,
+/// starting from their default state. Interrupts are disabled meanwhile,
+/// so the section can't be switched out. Return `false`, and don't start a
+/// section, without SSE or inside another section, i.e. in a fault.
+pub fn kernel_fpu_begin() -> bool {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return false;
+    }
+    let enabled = registers::rflags().contains(RFLAGS_INTERRUPT);
+    unsafe {
+        interrupts::disable();
+        if IN_KERNEL.get().swap(true, Ordering::Relaxed) {
+            if enabled {
+                interrupts::enable();
+            }
+            return false;
+        }
+        INTERRUPTS_ENABLED.get().store(enabled, Ordering::Relaxed);
+
+        asm!("clts");
+        let owner = OWNER.get().swap(0, Ordering::Relaxed);
+        if owner != 0 {
+            (*(owner as *mut FpuContext)).save();
+        }
+        reset();
+    }
+    true
+}
+
+/// End the section started by `kernel_fpu_begin`. The current context gets
+/// its state back on its next use of the FPU.
+pub fn kernel_fpu_end() {
+    unsafe {
+        registers::write_cr0(registers::cr0() | CR0_TASK_SWITCHED);
+        IN_KERNEL.get().store(false, Ordering::Relaxed);
+        if INTERRUPTS_ENABLED.get().load(Ordering::Relaxed) {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Zero the page at `address`, with non-temporal SSE stores when possible,
+/// which don't fill the caches with zeros.
+pub unsafe fn zero_page(address: usize) {
+    if !kernel_fpu_begin() {
+        ptr::write_bytes(address as *mut u8, 0, PAGE_SIZE);
+        return;
+    }
+    // No xmm0 clobber, the rest of the kernel doesn't use it.
+    let mut cursor = address;
+    asm!("xorps %xmm0, %xmm0
+      1:
+          movntps %xmm0, ($0)
+          movntps %xmm0, 16($0)
+          movntps %xmm0, 32($0)
+          movntps %xmm0, 48($0)
+          add $$64, $0
+          cmp $1, $0
+          jne 1b
+          sfence"
+         : "+r"(cursor) : "r"(address + PAGE_SIZE) : "memory", "cc" : "volatile");
+    kernel_fpu_end();
+}
+
+/// Handle #NM, raised by the first FPU instruction after a context switch:
+/// give the registers to the current context.
+pub fn handle_device_not_available() {
+    let current = CURRENT.get().load(Ordering::Relaxed);
+    if current == 0 {
+        panic!("FPU used without a context");
+    }
+
+    unsafe {
+        asm!("clts");
+        let owner = OWNER.get().load(Ordering::Relaxed);
+        if owner == current {
+            return;
+        }
+        if owner != 0 {
+            (*(owner as *mut FpuContext)).save();
+        }
+        (*(current as *mut FpuContext)).restore();
+    }
+    OWNER.get().store(current, Ordering::Relaxed);
+}
@@ -0,0 +1,107 @@
+//! Per-CPU Global Descriptor Tables and Task State Segments.
+// http://wiki.osdev.org/GDT
+// http://wiki.osdev.org/Task_State_Segment
+// http://www.intel.com/Assets/en_US/PDF/manual/253668.pdf, Sections 3.4.5 and 7.7
+//
+// Segmentation is flat in long mode, the GDT only matters for the code
+// segment privilege level and to point to the TSS. The TSS holds the stack
+// to switch to when an interrupt comes from a lower privilege level, which
+// is different on each CPU, hence a GDT per CPU.
+
+use core::mem::size_of;
+use arch::smp::MAX_CPUS;
+
+/// Selectors, the same as in the boot and trampoline GDTs.
+pub const KERNEL_CODE: u16 = 0x08;
+pub const KERNEL_DATA: u16 = 0x10;
+pub const TSS: u16 = 0x18;
+
+/// Present, code/data, accessed and readable (code) or writable (data).
+const CODE_SEGMENT: u64 = (1 << 40) | (1 << 41) | (1 << 43) | (1 << 44) | (1 << 47) | (1 << 53);
+const DATA_SEGMENT: u64 = (1 << 40) | (1 << 41) | (1 << 44) | (1 << 47);
+
+/// Present, available 64 bit TSS.
+const TSS_SEGMENT: u64 = (0x9 << 40) | (1 << 47);
+
+/// Size of `Tss`.
+const TSS_SIZE: usize = 104;
+
+/// Null, code, data, then the TSS taking two entries.
+const GDT_ENTRIES: usize = 5;
+
+/// Task State Segment, without an I/O permission bitmap.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct Tss {
+    _reserved_0: u32,
+    /// Stacks for privilege levels 0 to 2.
+    pub rsp: [u64; 3],
+    _reserved_1: u64,
+    /// Interrupt stack table.
+    pub ist: [u64; 7],
+    _reserved_2: u64,
+    _reserved_3: u16,
+    iomap_base: u16,
+}
+
+impl Tss {
+    const fn new() -> Tss {
+        Tss {
+            _reserved_0: 0,
+            rsp: [0; 3],
+            _reserved_1: 0,
+            ist: [0; 7],
+            _reserved_2: 0,
+            _reserved_3: 0,
+            // Past the end of the segment, that is no bitmap.
+            iomap_base: TSS_SIZE as u16,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Gdt {
+    entries: [u64; GDT_ENTRIES],
+}
+
+/// The operand of `lgdt`.
+#[repr(C, packed)]
+struct GdtPointer {
+    limit: u16,
+    base: u64,
+}
+
+static mut GDTS: [Gdt; MAX_CPUS] = [Gdt { entries: [0; GDT_ENTRIES] }; MAX_CPUS];
+static mut TSSES: [Tss; MAX_CPUS] = [Tss::new(); MAX_CPUS];
+
+/// Fill the GDT and TSS of `cpu`, whose kernel stack ends at `stack_top`.
+/// Unsafe as `cpu` must not use them yet.
+pub unsafe fn init(cpu: usize, stack_top: usize) {
+    let tss = &mut TSSES[cpu];
+    tss.rsp[0] = stack_top as u64;
+
+    let base = tss as *const Tss as u64;
+    let limit = (TSS_SIZE - 1) as u64;
+    GDTS[cpu].entries = [
+        0,
+        CODE_SEGMENT,
+        DATA_SEGMENT,
+        TSS_SEGMENT | (limit & 0xFFFF) | (base & 0xFF_FFFF) << 16 | (limit >> 16 & 0xF) << 48 |
+            (base >> 24 & 0xFF) << 56,
+        base >> 32,
+    ];
+}
+
+/// Load the GDT and TSS of `cpu`, which must be the running CPU.
+pub unsafe fn load(cpu: usize) {
+    let pointer = GdtPointer {
+        limit: (size_of::<Gdt>() - 1) as u16,
+        base: &GDTS[cpu] as *const Gdt as u64,
+    };
+    asm!("lgdt ($0)" :: "r"(&pointer) : "memory");
+    // cs is already KERNEL_CODE.
+    asm!("mov %ax, %ds
+          mov %ax, %es
+          mov %ax, %ss" :: "{ax}"(KERNEL_DATA) :: "volatile");
+    asm!("ltr %ax" :: "{ax}"(TSS) :: "volatile");
+}
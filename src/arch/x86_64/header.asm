@@ -8,6 +8,17 @@ header_start:
     
     ; insert optional multiboot tags
 
+%ifdef FRAMEBUFFER
+    ; ask for a linear framebuffer, boot anyway if the mode can't be set
+    dw 5                            ; type: framebuffer
+    dw 1                            ; flags: optional
+    dd 20                           ; size
+    dd FRAMEBUFFER_WIDTH            ; width, 0 for no preference
+    dd FRAMEBUFFER_HEIGHT           ; height, 0 for no preference
+    dd FRAMEBUFFER_DEPTH            ; bits per pixel, 0 for no preference
+    align 8, db 0                   ; tags are 8-byte aligned
+%endif
+
     ; required end tag
     dw 0    ; type
     dw 0    ; flags
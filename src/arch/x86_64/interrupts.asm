@@ -1,6 +1,7 @@
 ;;; Interrupts support
 
-VGA_FB_BASE equ 0xB8000
+;;; The VGA text buffer, in the physical memory window.
+VGA_FB_BASE equ 0xFFFF8000000B8000
 
 global dummy_interrupt_handler
 global interrupt_handlers
@@ -36,9 +37,9 @@ interrupt_handler_%1:
 ;;; https://www.cs.cmu.edu/~fp/courses/15213-s07/misc/asm64-handout.pdf
 ;;;
 ;;; Callee-saved register are skipped, as responsability of Rust compiler.
-;;; Don't save any floating point register as it's slow (512 bytes). Support
-;;; for FPU is stripped out of the kernel through recompiling Rust libcore
-;;; without FP support.
+;;; Don't save any floating point register as it's slow (512 bytes). The
+;;; kernel is built with soft-float and never touches them, the FPU state of
+;;; its users is switched lazily (see fpu.rs).
 %macro push_caller_saved_registers 0
     push rax
     push rcx
@@ -67,6 +68,13 @@ interrupt_handler_%1:
 
 ;;; All the interrupt handlers end up here, just a wrapper into a Rust function.
 interrupt_common_handler:
+    ;; Coming from user mode (the RPL of the saved cs, above the interrupt ID
+    ;; and error code, isn't 0), switch to the kernel GS base.
+    test qword [rsp + 24], 3
+    jz .from_kernel
+    swapgs
+.from_kernel:
+
     ;; Push on the stack caller-saved registers
     push_caller_saved_registers
 
@@ -81,14 +89,20 @@ interrupt_common_handler:
     ;; Restore ESP
     add rsp, 16     ; Clean error code and interrupt ID placed here before
 
+    ;; Give the user its GS base back.
+    test qword [rsp + 8], 3
+    jz .to_kernel
+    swapgs
+.to_kernel:
     iretq
 
 ;;; A dummy handler that just prints INT! to the VGA frame buffer
 dummy_interrupt_handler:
     push_caller_saved_registers
 
+    mov rdi, VGA_FB_BASE
     mov rax, 0x4f214f544f4e4f49
-    mov qword [VGA_FB_BASE], rax
+    mov qword [rdi], rax
 
     pop_caller_saved_registers
 
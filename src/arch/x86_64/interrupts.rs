@@ -3,13 +3,20 @@
 use core::ptr;
 use core::fmt;
 use core::mem::size_of;
+use arch::apic;
 use arch::pic::ChainedPics;
+use arch::fpu;
+use arch::ipi;
+use arch::irqstat;
+use arch::pit;
+use arch::registers::{self, RFLAGS_INTERRUPT};
+use arch::softirq;
+use memory::vm;
 use super::irq;
 use spin::Mutex;
 
 const IDT_ENTRY_COUNT: usize = 256;
 
-#[allow(dead_code)]
 extern {
     static gdt64_code_offset: u16;
 
@@ -34,6 +41,22 @@ pub struct InterruptStackContext {
     _interrupt_id_pad: u32,
     error_code: u32,
     _error_code_pad: u32,
+    // Pushed by the CPU
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+impl fmt::Display for InterruptStackContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "rip {:#018x} rsp {:#018x} rflags {:#010x} cs {:#x} ss {:#x}\n",
+                    self.rip, self.rsp, self.rflags, self.cs, self.ss));
+        try!(write!(f, "rax {:#018x} rcx {:#018x} rdx {:#018x}\n", self.rax, self.rcx, self.rdx));
+        try!(write!(f, "rsi {:#018x} rdi {:#018x} r8  {:#018x}\n", self.rsi, self.rdi, self.r8));
+        write!(f, "r9  {:#018x} r10 {:#018x} r11 {:#018x}", self.r9, self.r10, self.r11)
+    }
 }
 
 /// The interface to the Programmable Controller Interface chip.
@@ -46,49 +69,145 @@ static PICS: Mutex<ChainedPics> = Mutex::new(unsafe {
 
 /// Print some useful information about CPU standard exceptions, if they happen.
 fn cpu_interrupt_handler(context: &InterruptStackContext) {
-    println!("{}, error 0x{:x}", 
+    error!("{}, error 0x{:x}",
         irq::CPU_EXCEPTIONS[context.interrupt_id as usize],
         context.error_code);
-
-    // TODO: Print more useful information for specific interrupts, i.e. Page Faults.
+    error!("{}", context);
 
     loop {}
 }
 
+/// Back the faulting page if it belongs to an anonymous region, otherwise
+/// report the fault and hang.
+fn page_fault_handler(context: &InterruptStackContext) {
+    let address = registers::cr2();
+    if vm::handle_page_fault(address, context.error_code) {
+        return;
+    }
+
+    let error_code = context.error_code;
+    let cause = if error_code & vm::FAULT_PRESENT != 0 {
+        "protection violation"
+    } else {
+        "page not present"
+    };
+    let access = if error_code & vm::FAULT_INSTRUCTION != 0 {
+        "executing"
+    } else if error_code & vm::FAULT_WRITE != 0 {
+        "writing"
+    } else {
+        "reading"
+    };
+    let mode = if error_code & vm::FAULT_USER != 0 { "user" } else { "kernel" };
+    let reserved = if error_code & vm::FAULT_RESERVED != 0 { ", reserved bit set" } else { "" };
+    error!("Page fault at {:#018x}: {} {} in {} mode{}", address, cause, access, mode, reserved);
+    match vm::find(address) {
+        Some(region) => error!("In region {}", region),
+        None => error!("Outside of any region"),
+    }
+    cpu_interrupt_handler(context);
+}
+
 /// Eventually called from the assembly code to handle an interrupt.
 #[no_mangle]
 pub unsafe extern "C" fn rust_interrupt_handler(context: &InterruptStackContext) {
     // List of general IBM-PC Compatible Interrupt Information here: 
     // http://wiki.osdev.org/Interrupts
-    match context.interrupt_id {
+    let start = irqstat::start();
+    if is_spurious(context.interrupt_id as u8) {
+        irqstat::record_spurious(context.interrupt_id as u8);
+        return;
+    }
+    match context.interrupt_id as u8 {
+        0x07 => fpu::handle_device_not_available(),
+        0x0E => page_fault_handler(context),
         0x00...0x1F => cpu_interrupt_handler(context),
-        0x20 => { /* Timer */ }
-        0x21 => { /* Keyboard */ }
-        0x22 => { /* Cascade to PIC2, never raised */ }
-        0x23 => { /* COM2 */ }
-        0x24 => { /* COM1 */ }
-        0x25 => { /* LPT2 */ }
-        0x26 => { /* Floppy Disk */ }
-        0x27 => { /* LPT1 (Unreliable spurious interrupts) */ }
-        0x28 => { /* CMOS real-time clock */ }
-        0x29 => { /* Free for peripherals / Legacy SCSI / NIC */ }
-        0x2A => { /* Free for peripherals / SCSI / NIC */ }
-        0x2B => { /* Free for peripherals / SCSI / NIC */ }
-        0x2C => { /* PS2 Mouse */ }
-        0x2D => { /* FPU / Coprocessor / Inter-processor */ }
-        0x2E => { /* Primary ATA Hard Disk */ }
-        0x2F => { /* Secondary ATA Hard Disk */ }
+        0x20 => pit::tick(),
+        // No driver yet for the keyboard (IRQ 1), the serial ports (3 and
+        // 4), the parallel ports (5 and 7), the floppy (6), the RTC (8),
+        // the free lines (9 to 11), the PS/2 mouse (12), the FPU (13) and
+        // the ATA disks (14 and 15). IRQ 2 is the cascade, never raised.
+        // The line is stopped, a level triggered one would fire forever.
+        irq @ 0x21...0x2F => {
+            warn!("Unexpected IRQ {}, masking it", irq - 0x20);
+            mask_irq(irq - 0x20);
+        }
         0x80 => {
             // Generally used for software interrupts on Unix-like OSes
             println!("Not Unix ;)");
         }
+        ipi::CALL_VECTOR => ipi::handle_call(),
+        ipi::TLB_SHOOTDOWN_VECTOR => ipi::handle_tlb_shootdown(),
+        ipi::RESCHEDULE_VECTOR => { /* Only wakes an idle CPU up */ }
+        apic::ERROR_VECTOR => apic::handle_error(),
+        apic::SPURIOUS_VECTOR => {
+            irqstat::record_spurious(apic::SPURIOUS_VECTOR);
+            return;
+        }
         _ => {
-            println!("Unknown Interrupt #{}", context.interrupt_id);
+            error!("Unknown Interrupt #{}", context.interrupt_id);
             loop {}
         }
     }
 
-    PICS.lock().end_of_interrupt(context.interrupt_id as u8);
+    irqstat::record(context.interrupt_id as u8, start);
+    end_of_interrupt(context.interrupt_id as u8);
+    if context.interrupt_id >= 0x20 {
+        softirq::run_pending();
+    }
+}
+
+/// Check if `interrupt_id` is a spurious IRQ of the PICs, when they're
+/// used.
+unsafe fn is_spurious(interrupt_id: u8) -> bool {
+    !apic::is_enabled() && PICS.lock().is_spurious(interrupt_id)
+}
+
+/// Number of spurious IRQs of the PICs.
+pub fn spurious_irqs() -> usize {
+    without_interrupts(|| PICS.lock().spurious_count())
+}
+
+/// The IRQs raised and those in service at the PICs, when they're used.
+pub fn pic_irqs() -> Option<(u16, u16)> {
+    if apic::is_enabled() {
+        return None;
+    }
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        Some(unsafe { (pics.read_irr(), pics.read_isr()) })
+    })
+}
+
+/// Let ISA `irq` through, at the I/O APIC or at the PICs, whichever is
+/// used.
+pub fn unmask_irq(irq: u8) {
+    if apic::is_enabled() {
+        apic::unmask_isa(irq);
+    } else {
+        without_interrupts(|| unsafe { PICS.lock().clear_mask(irq) });
+    }
+}
+
+/// Stop ISA `irq`, at the I/O APIC or at the PICs, whichever is used.
+pub fn mask_irq(irq: u8) {
+    if apic::is_enabled() {
+        apic::mask_isa(irq);
+    } else {
+        without_interrupts(|| unsafe { PICS.lock().set_mask(irq) });
+    }
+}
+
+/// Acknowledge `interrupt_id` to the controller that raised it. CPU
+/// exceptions and software interrupts are not acknowledged.
+unsafe fn end_of_interrupt(interrupt_id: u8) {
+    if apic::is_enabled() {
+        if interrupt_id >= 0x20 && interrupt_id != 0x80 {
+            apic::end_of_interrupt();
+        }
+    } else {
+        PICS.lock().end_of_interrupt(interrupt_id);
+    }
 }
 
 
@@ -162,6 +281,17 @@ pub unsafe fn disable() {
     asm!("cli");
 }
 
+/// Run `f` with interrupts disabled, then enable them again if they were.
+pub fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R {
+    let enabled = registers::rflags().contains(RFLAGS_INTERRUPT);
+    unsafe { disable(); }
+    let result = f();
+    if enabled {
+        unsafe { enable(); }
+    }
+    result
+}
+
 /// Generates a software interrupt.
 #[macro_export]
 macro_rules! int {
@@ -216,19 +346,35 @@ static IDT: Mutex<Idt> = Mutex::new(
     Idt { table: [IdtEntry::missing_handler(); IDT_ENTRY_COUNT] }
 );
 
-#[allow(dead_code)]
 unsafe fn test_interrupt() {
     println!("Triggering interrupt.");
     int!(0x80);
     println!("Interrupt handled.");
 }
 
+/// Load the IDT on an application processor. Interrupts stay disabled.
+pub unsafe fn init_ap() {
+    IDT.lock().load();
+}
+
 /// Initialize interrupts.
 pub unsafe fn init() {
+    // Remap the PICs even if unused, so their spurious interrupts don't
+    // look like CPU exceptions.
     PICS.lock().init();
-    
+
     IDT.lock().init();
 
+    apic::init();
+    PICS.lock().disable();
+    // The system tick
+    unmask_irq(0);
+
+    // Start the system tick
+    pit::init();
+    irqstat::init();
+    softirq::init();
+
     // Test software interrupts
     test_interrupt();
 
@@ -0,0 +1,216 @@
+//! I/O APIC driver, routing external interrupts to the local APICs.
+// http://wiki.osdev.org/IOAPIC
+// http://www.intel.com/design/chipsets/datashts/29056601.pdf
+//
+// Interrupt inputs are numbered with Global System Interrupts (GSI), each
+// I/O APIC handling a range of them. ISA IRQs are identity mapped to GSIs,
+// unless the MADT says otherwise with an interrupt source override.
+
+use core::ptr;
+use spin::Mutex;
+use arch::acpi::{MPS_POLARITY_MASK, MPS_POLARITY_LOW, MPS_TRIGGER_MASK, MPS_TRIGGER_LEVEL};
+use memory::vm;
+
+/// How many I/O APICs are supported.
+const MAX_IO_APICS: usize = 8;
+
+/// Number of ISA IRQs.
+pub const ISA_IRQ_COUNT: usize = 16;
+
+/// Offsets of the index and data registers.
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+/// Indirect registers.
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+/// Redirection entry fields.
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// Electrical characteristics of an interrupt line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Trigger {
+    pub active_low: bool,
+    pub level: bool,
+}
+
+/// Active high and edge triggered, the ISA default.
+pub const ISA_TRIGGER: Trigger = Trigger { active_low: false, level: false };
+
+impl Trigger {
+    /// Decode the MPS INTI flags of the MADT, where 0 means the bus default.
+    pub fn from_mps(flags: u16, default: Trigger) -> Trigger {
+        Trigger {
+            active_low: match flags & MPS_POLARITY_MASK {
+                0 => default.active_low,
+                polarity => polarity == MPS_POLARITY_LOW,
+            },
+            level: match flags & MPS_TRIGGER_MASK {
+                0 => default.level,
+                trigger => trigger == MPS_TRIGGER_LEVEL,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct IoApic {
+    id: u8,
+    /// Virtual address of the registers.
+    base: usize,
+    gsi_base: u32,
+    /// Number of redirection entries.
+    count: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        ptr::write_volatile((self.base + IOREGSEL) as *mut u32, register);
+        ptr::read_volatile((self.base + IOWIN) as *const u32)
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        ptr::write_volatile((self.base + IOREGSEL) as *mut u32, register);
+        ptr::write_volatile((self.base + IOWIN) as *mut u32, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.count
+    }
+
+    fn redirection(&self, gsi: u32) -> u64 {
+        let register = IOREDTBL + 2 * (gsi - self.gsi_base);
+        unsafe { self.read(register) as u64 | (self.read(register + 1) as u64) << 32 }
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOREDTBL + 2 * (gsi - self.gsi_base);
+        unsafe {
+            // Keep the entry masked while it's half written.
+            self.write(register, REDIRECTION_MASKED as u32);
+            self.write(register + 1, (entry >> 32) as u32);
+            self.write(register, entry as u32);
+        }
+    }
+}
+
+/// Where an ISA IRQ is connected.
+#[derive(Clone, Copy)]
+struct IsaRoute {
+    gsi: u32,
+    trigger: Trigger,
+}
+
+struct IoApics {
+    apics: [Option<IoApic>; MAX_IO_APICS],
+    /// The overridden ISA IRQs.
+    isa_overrides: [Option<IsaRoute>; ISA_IRQ_COUNT],
+}
+
+impl IoApics {
+    fn find(&self, gsi: u32) -> Option<IoApic> {
+        self.apics.iter().filter_map(|a| *a).find(|a| a.handles(gsi))
+    }
+
+    fn isa_route(&self, irq: u8) -> IsaRoute {
+        self.isa_overrides[irq as usize]
+            .unwrap_or(IsaRoute { gsi: irq as u32, trigger: ISA_TRIGGER })
+    }
+}
+
+static IO_APICS: Mutex<IoApics> = Mutex::new(IoApics {
+    apics: [None; MAX_IO_APICS],
+    isa_overrides: [None; ISA_IRQ_COUNT],
+});
+
+/// Register the I/O APIC `id` whose registers are at the physical
+/// `address`, and mask all its inputs.
+pub fn add(id: u8, address: usize, gsi_base: u32) {
+    let base = match vm::map_device(address, 0x20, "I/O APIC") {
+        Ok(base) => base,
+        Err(error) => {
+            warn!("Failed to map I/O APIC {} at {:#x}: {:?}", id, address, error);
+            return;
+        }
+    };
+
+    let mut apic = IoApic { id: id, base: base, gsi_base: gsi_base, count: 0 };
+    let version = unsafe { apic.read(IOAPICVER) };
+    apic.count = (version >> 16 & 0xFF) + 1;
+    for gsi in gsi_base..gsi_base + apic.count {
+        apic.set_redirection(gsi, REDIRECTION_MASKED);
+    }
+    info!("I/O APIC {} (id {}) at {:#x}, GSI {}-{}", id, unsafe { apic.read(IOAPICID) } >> 24,
+          address, gsi_base, gsi_base + apic.count - 1);
+
+    let mut io_apics = IO_APICS.lock();
+    match io_apics.apics.iter_mut().find(|a| a.is_none()) {
+        Some(slot) => *slot = Some(apic),
+        None => warn!("Too many I/O APICs, {} ignored", apic.id),
+    }
+}
+
+/// Whether any I/O APIC was registered.
+pub fn is_present() -> bool {
+    IO_APICS.lock().apics.iter().any(|a| a.is_some())
+}
+
+/// Record that ISA `irq` is connected to `gsi`, from a MADT override.
+pub fn set_isa_override(irq: u8, gsi: u32, trigger: Trigger) {
+    if (irq as usize) < ISA_IRQ_COUNT {
+        IO_APICS.lock().isa_overrides[irq as usize] = Some(IsaRoute { gsi: gsi, trigger: trigger });
+    }
+}
+
+/// The GSI ISA `irq` is connected to.
+pub fn isa_gsi(irq: u8) -> u32 {
+    IO_APICS.lock().isa_route(irq).gsi
+}
+
+/// Deliver `gsi` as `vector` to the local APIC `destination`, masked.
+/// Return `false` if no I/O APIC handles `gsi`.
+pub fn route(gsi: u32, vector: u8, trigger: Trigger, destination: u8) -> bool {
+    let apic = match IO_APICS.lock().find(gsi) {
+        Some(apic) => apic,
+        None => return false,
+    };
+
+    let mut entry = vector as u64 | (destination as u64) << 56 | REDIRECTION_MASKED;
+    if trigger.active_low {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if trigger.level {
+        entry |= REDIRECTION_LEVEL;
+    }
+    apic.set_redirection(gsi, entry);
+    true
+}
+
+/// Route ISA `irq`, following the overrides, as `vector`.
+pub fn route_isa(irq: u8, vector: u8, destination: u8) -> bool {
+    let route = IO_APICS.lock().isa_route(irq);
+    self::route(route.gsi, vector, route.trigger, destination)
+}
+
+fn set_masked(gsi: u32, masked: bool) {
+    if let Some(apic) = IO_APICS.lock().find(gsi) {
+        let entry = apic.redirection(gsi);
+        apic.set_redirection(gsi, if masked {
+            entry | REDIRECTION_MASKED
+        } else {
+            entry & !REDIRECTION_MASKED
+        });
+    }
+}
+
+pub fn mask(gsi: u32) {
+    set_masked(gsi, true);
+}
+
+pub fn unmask(gsi: u32) {
+    set_masked(gsi, false);
+}
@@ -0,0 +1,152 @@
+//! Inter-processor interrupts: running functions on other CPUs,
+//! invalidating their TLB entries when memory is unmapped, and waking them
+//! up to run new threads.
+// http://www.intel.com/Assets/en_US/PDF/manual/253668.pdf, Sections 4.10.5 and 10.6
+//
+// A request is posted in a mailbox, then the targets are interrupted and
+// each one acknowledges once done. The sender waits for all of them, so a
+// mailbox holds one request at a time. A CPU waiting for a mailbox must
+// keep answering the requests of the others: interrupts must be enabled.
+// Reschedule interrupts carry nothing and aren't waited for.
+
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use arch::apic::{self, Delivery, Destination};
+use arch::percpu;
+use arch::registers;
+use arch::smp;
+use memory::PAGE_SIZE;
+
+/// Vectors of the requests, above the ISA IRQs and below the APIC ones.
+pub const CALL_VECTOR: u8 = 0xF0;
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xF1;
+pub const RESCHEDULE_VECTOR: u8 = 0xF2;
+
+/// Past this many pages, the whole TLB is flushed instead.
+const FLUSH_ALL_PAGES: usize = 32;
+
+/// A request and its two arguments.
+struct Mailbox {
+    busy: AtomicBool,
+    first: AtomicUsize,
+    second: AtomicUsize,
+    /// Targets yet to acknowledge.
+    pending: AtomicUsize,
+}
+
+impl Mailbox {
+    const fn new() -> Mailbox {
+        Mailbox {
+            busy: ATOMIC_BOOL_INIT,
+            first: ATOMIC_USIZE_INIT,
+            second: ATOMIC_USIZE_INIT,
+            pending: ATOMIC_USIZE_INIT,
+        }
+    }
+
+    /// Post a request to `targets` CPUs at `destination` and wait until
+    /// they are done.
+    fn send(&self, destination: Destination, targets: usize, vector: u8, first: usize,
+            second: usize) {
+        while self.busy.compare_and_swap(false, true, Ordering::Acquire) {
+            unsafe { asm!("pause" :::: "volatile"); }
+        }
+        self.first.store(first, Ordering::Relaxed);
+        self.second.store(second, Ordering::Relaxed);
+        self.pending.store(targets, Ordering::SeqCst);
+
+        apic::send_ipi(destination, Delivery::Fixed(vector));
+        while self.pending.load(Ordering::SeqCst) != 0 {
+            unsafe { asm!("pause" :::: "volatile"); }
+        }
+        self.busy.store(false, Ordering::Release);
+    }
+
+    fn arguments(&self) -> (usize, usize) {
+        (self.first.load(Ordering::Relaxed), self.second.load(Ordering::Relaxed))
+    }
+
+    fn acknowledge(&self) {
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+static CALL: Mailbox = Mailbox::new();
+static TLB_SHOOTDOWN: Mailbox = Mailbox::new();
+
+/// Run `function(argument)` on `cpu`, and wait for it to return. Must be
+/// called with interrupts enabled. On other CPUs, `function` runs in an
+/// interrupt handler.
+pub fn call_on_cpu(cpu: usize, function: fn(usize), argument: usize) {
+    if cpu == percpu::cpu_id() {
+        function(argument);
+    } else if cpu < smp::online() {
+        CALL.send(Destination::Apic(smp::apic_id(cpu)), 1, CALL_VECTOR, function as usize,
+                  argument);
+    } else {
+        warn!("Call on CPU {}, which is offline", cpu);
+    }
+}
+
+/// Run `function(argument)` on every CPU, and wait for them all. Must be
+/// called with interrupts enabled.
+pub fn call_on_all(function: fn(usize), argument: usize) {
+    let others = smp::online().saturating_sub(1);
+    if others > 0 {
+        CALL.send(Destination::AllExcludingSelf, others, CALL_VECTOR, function as usize,
+                  argument);
+    }
+    function(argument);
+}
+
+/// Handle `CALL_VECTOR`.
+pub fn handle_call() {
+    let (function, argument) = CALL.arguments();
+    let function: fn(usize) = unsafe { mem::transmute(function) };
+    function(argument);
+    CALL.acknowledge();
+}
+
+/// Invalidate the TLB entries of `[start, end)` on the running CPU.
+fn flush(start: usize, end: usize) {
+    unsafe {
+        if (end - start) / PAGE_SIZE > FLUSH_ALL_PAGES {
+            registers::write_cr3(registers::cr3());
+            return;
+        }
+        let mut address = start;
+        while address < end {
+            asm!("invlpg ($0)" :: "r"(address) : "memory");
+            address += PAGE_SIZE;
+        }
+    }
+}
+
+/// Invalidate the TLB entries of `[start, end)` on all the CPUs, after
+/// unmapping it or restricting its permissions. Once done, the pages are
+/// really gone and their frames can be reused. Must be called with
+/// interrupts enabled if other CPUs are online.
+pub fn shootdown(start: usize, end: usize) {
+    flush(start, end);
+    let others = smp::online().saturating_sub(1);
+    if others > 0 {
+        TLB_SHOOTDOWN.send(Destination::AllExcludingSelf, others, TLB_SHOOTDOWN_VECTOR, start,
+                           end);
+    }
+}
+
+/// Handle `TLB_SHOOTDOWN_VECTOR`.
+pub fn handle_tlb_shootdown() {
+    let (start, end) = TLB_SHOOTDOWN.arguments();
+    flush(start, end);
+    TLB_SHOOTDOWN.acknowledge();
+}
+
+/// Interrupt the other CPUs, so the idle ones pick up the threads just
+/// made runnable. Can be called from interrupt handlers, must be called
+/// with interrupts disabled.
+pub fn reschedule_others() {
+    if smp::online() > 1 {
+        apic::send_ipi(Destination::AllExcludingSelf, Delivery::Fixed(RESCHEDULE_VECTOR));
+    }
+}
@@ -12,6 +12,12 @@ pub struct InterruptInfo {
     source: &'static str,
 }
 
+impl InterruptInfo {
+    pub fn description(&self) -> &'static str {
+        self.description
+    }
+}
+
 impl Display for InterruptInfo {
     fn fmt(&self, f: &mut Formatter) -> Result {
         write!(f, "{} ({}, vec={}) {}", self.mnemonic, self.irqtype, self.id, self.description)
@@ -0,0 +1,185 @@
+//! Interrupt statistics: how often each vector fires on each CPU, and how
+//! long its handler takes, measured with the time stamp counter (TSC).
+// http://www.intel.com/Assets/en_US/PDF/manual/253668.pdf, Section 17.15
+//
+// The statistics of a CPU are only updated by its own interrupt handlers,
+// which run with interrupts disabled, so they need no lock. Reading those
+// of the other CPUs races with their updates, which is fine for a dump.
+//
+// The TSC frequency is measured against the system tick, from its start to
+// the dump. It assumes the TSC doesn't change frequency with the CPU.
+
+use core::cell::UnsafeCell;
+use core::cmp;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use arch::apic;
+use arch::interrupts;
+use arch::ipi;
+use arch::pit;
+use arch::smp;
+use super::irq;
+
+const VECTOR_COUNT: usize = 256;
+
+/// The statistics of a vector on a CPU. Times are in TSC cycles.
+#[derive(Clone, Copy)]
+struct VectorStats {
+    count: u64,
+    spurious: u64,
+    total: u64,
+    min: u64,
+    max: u64,
+}
+
+impl VectorStats {
+    const fn new() -> VectorStats {
+        VectorStats { count: 0, spurious: 0, total: 0, min: !0, max: 0 }
+    }
+}
+
+/// The statistics of a CPU, by vector.
+struct Stats(UnsafeCell<[VectorStats; VECTOR_COUNT]>);
+
+// Only written by their CPU, the others just take racy copies to print.
+unsafe impl Sync for Stats {}
+
+impl Stats {
+    const fn new() -> Stats {
+        Stats(UnsafeCell::new([VectorStats::new(); VECTOR_COUNT]))
+    }
+
+    /// The statistics of `vector`, only for the CPU they belong to, with
+    /// interrupts disabled.
+    unsafe fn vector(&self, vector: u8) -> &mut VectorStats {
+        &mut (*self.0.get())[vector as usize]
+    }
+
+    /// A copy of the statistics of `vector`.
+    fn read(&self, vector: u8) -> VectorStats {
+        unsafe { ptr::read_volatile(&(*self.0.get())[vector as usize]) }
+    }
+}
+
+per_cpu! {
+    static STATS: Stats = Stats::new();
+}
+
+/// The TSC when the system tick started.
+static START_TSC: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Read the time stamp counter.
+fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe { asm!("rdtsc" : "={eax}"(low), "={edx}"(high) ::: "volatile"); }
+    (high as u64) << 32 | low as u64
+}
+
+/// Start measuring the system tick, when it starts.
+pub fn init() {
+    START_TSC.store(rdtsc() as usize, Ordering::Relaxed);
+}
+
+/// The TSC frequency in kHz, 0 if the system tick hasn't run long enough.
+fn tsc_khz() -> u64 {
+    match pit::uptime_ms() {
+        0 => 0,
+        ms => (rdtsc() - START_TSC.load(Ordering::Relaxed) as u64) / ms,
+    }
+}
+
+/// The time stamp to pass to `record` once the handler is done.
+pub fn start() -> u64 {
+    rdtsc()
+}
+
+/// Account for a handler of `vector` which started at `start`. Must be
+/// called with interrupts disabled.
+pub unsafe fn record(vector: u8, start: u64) {
+    let cycles = rdtsc().wrapping_sub(start);
+    let stats = STATS.get().vector(vector);
+    stats.count += 1;
+    stats.total += cycles;
+    stats.min = cmp::min(stats.min, cycles);
+    stats.max = cmp::max(stats.max, cycles);
+}
+
+/// Account for a spurious interrupt on `vector`, which isn't handled. Must
+/// be called with interrupts disabled.
+pub unsafe fn record_spurious(vector: u8) {
+    STATS.get().vector(vector).spurious += 1;
+}
+
+/// What raises `vector`.
+struct Source(u8);
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            vector @ 0...19 => f.write_str(irq::CPU_EXCEPTIONS[vector as usize].description()),
+            0x20 => f.write_str("Timer"),
+            vector @ 0x21...0x2F => write!(f, "IRQ {}", vector - apic::ISA_VECTOR_BASE),
+            0x80 => f.write_str("Software interrupt"),
+            ipi::CALL_VECTOR => f.write_str("Function call IPI"),
+            ipi::TLB_SHOOTDOWN_VECTOR => f.write_str("TLB shootdown IPI"),
+            ipi::RESCHEDULE_VECTOR => f.write_str("Reschedule IPI"),
+            apic::ERROR_VECTOR => f.write_str("APIC error"),
+            apic::SPURIOUS_VECTOR => f.write_str("APIC spurious"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Print the vectors which fired, with their count on each CPU, their
+/// spurious count and their handler times, like `/proc/interrupts`, then
+/// the state of the PICs if they're used.
+pub fn dump() {
+    // The bootstrap processor is not counted before `smp::init`.
+    let cpus = cmp::max(smp::online(), 1);
+    let khz = tsc_khz();
+    let read = |cpu: usize, vector: u8| {
+        STATS.on(cpu).map(|stats| stats.read(vector)).unwrap_or(VectorStats::new())
+    };
+
+    print!("     ");
+    for cpu in 0..cpus {
+        print!("   CPU{:<4}", cpu);
+    }
+    println!(" {:>9} {:>9} {:>9} {:>9}", "spurious", "min ns", "avg ns", "max ns");
+    for vector in 0..VECTOR_COUNT {
+        let vector = vector as u8;
+        let mut total = VectorStats::new();
+        for cpu in 0..cpus {
+            let stats = read(cpu, vector);
+            total.count += stats.count;
+            total.spurious += stats.spurious;
+            total.total += stats.total;
+            total.min = cmp::min(total.min, stats.min);
+            total.max = cmp::max(total.max, stats.max);
+        }
+        if total.count == 0 && total.spurious == 0 {
+            continue;
+        }
+
+        print!("{:>4}:", vector);
+        for cpu in 0..cpus {
+            print!(" {:>9}", read(cpu, vector).count);
+        }
+        print!(" {:>9}", total.spurious);
+        if total.count > 0 && khz > 0 {
+            let ns = |cycles: u64| cycles * 1_000_000 / khz;
+            print!(" {:>9} {:>9} {:>9}", ns(total.min), ns(total.total / total.count),
+                   ns(total.max));
+        } else {
+            print!(" {:>9} {:>9} {:>9}", "-", "-", "-");
+        }
+        println!("  {}", Source(vector));
+    }
+
+    if let Some((irr, isr)) = interrupts::pic_irqs() {
+        println!("PICs: {} spurious, IRR {:#06x}, ISR {:#06x}", interrupts::spurious_irqs(), irr,
+                 isr);
+    }
+}
@@ -1,24 +1,50 @@
 global long_mode_start
 
-section .text
+extern gdt64_pointer
+extern p4_table
+extern rust_main
+
+KERNEL_OFFSET equ 0xFFFFFFFF80000000
+
+;;; All physical memory is mapped here, i.e. the VGA text buffer.
+PHYSICAL_MEMORY_OFFSET equ 0xFFFF800000000000
+VGA_BUFFER equ PHYSICAL_MEMORY_OFFSET + 0xb8000
+
+section .boot.text
 bits 64
+;;; Still running from the identity map, jump to the higher half.
 long_mode_start:
+    mov rax, higher_half_start
+    jmp rax
+
+section .text
+bits 64
+higher_half_start:
+    ; move the stack to its higher half address
+    mov rax, KERNEL_OFFSET
+    add rsp, rax
+
+    ; reload the GDT from its higher half address
+    lgdt [gdt64_pointer]
+
+    ; drop the identity map and flush the TLB
+    mov rax, p4_table
+    mov qword [rax], 0
+    mov rax, cr3
+    mov cr3, rax
+
     call setup_SSE
 
-    ; call the rust main
-    extern rust_main
+    ; edi holds the Multiboot information pointer, clear the upper half of rdi
+    mov edi, edi
+
+    ; call the rust main, which never returns
     call rust_main
-    
-.os_returned:
-    ; rust main returned, print `OS returned!`
-    mov rax, 0x4f724f204f534f4f
-    mov [0xb8000], rax
-    mov rax, 0x4f724f754f744f65
-    mov [0xb8008], rax
-    mov rax, 0x4f214f644f654f6e
-    mov [0xb8010], rax
 
+.halt:
+    cli
     hlt
+    jmp .halt
 
 ; Check for SSE and enable it. If it's not supported throw error "a".
 setup_SSE:
@@ -46,10 +72,11 @@ setup_SSE:
 ; parameter: error code (in ascii) in al
 ; We need this new function, as the other one is in now invalid 32 bit code
 error:
+    mov rdi, VGA_BUFFER
     mov rbx, 0x4f4f4f524f524f45
-    mov [0xb8000], rbx
+    mov [rdi], rbx
     mov rbx, 0x4f204f204f3a4f52
-    mov [0xb8008], rbx
-    mov byte [0xb800e], al
+    mov [rdi + 0x08], rbx
+    mov byte [rdi + 0x0e], al
     hlt
     jmp error
\ No newline at end of file
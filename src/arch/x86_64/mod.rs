@@ -3,5 +3,25 @@ pub mod cpuio;
 pub mod serial;
 pub mod pic;
 pub mod interrupts;
+pub mod pci;
+pub mod bga;
+pub mod pit;
+pub mod cpuid;
+pub mod registers;
+pub mod msr;
+pub mod fpu;
+pub mod acpi;
+pub mod apic;
+pub mod ioapic;
+pub mod aml;
+pub mod power;
+pub mod gdt;
+pub mod smp;
+pub mod percpu;
+pub mod ipi;
+pub mod irqstat;
+pub mod softirq;
+pub mod thread;
+pub mod workqueue;
 
 mod irq;
@@ -0,0 +1,167 @@
+//! Model specific registers.
+// http://www.intel.com/Assets/en_US/PDF/manual/253669.pdf, Chapter 35
+
+pub const IA32_APIC_BASE: u32 = 0x1B;
+pub const IA32_PAT: u32 = 0x277;
+pub const IA32_TSC_DEADLINE: u32 = 0x6E0;
+pub const IA32_EFER: u32 = 0xC000_0080;
+pub const IA32_STAR: u32 = 0xC000_0081;
+pub const IA32_LSTAR: u32 = 0xC000_0082;
+pub const IA32_CSTAR: u32 = 0xC000_0083;
+pub const IA32_FMASK: u32 = 0xC000_0084;
+pub const IA32_FS_BASE: u32 = 0xC000_0100;
+pub const IA32_GS_BASE: u32 = 0xC000_0101;
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+pub const IA32_TSC_AUX: u32 = 0xC000_0103;
+
+/// Bits 12 and up of IA32_APIC_BASE hold the physical address.
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+bitflags! {
+    /// Fields of IA32_EFER.
+    pub flags Efer: u64 {
+        const EFER_SYSCALL = 1 << 0,
+        const EFER_LONG_MODE_ENABLE = 1 << 8,
+        const EFER_LONG_MODE_ACTIVE = 1 << 10,
+        const EFER_NO_EXECUTE_ENABLE = 1 << 11,
+        const EFER_SECURE_VIRTUAL_MACHINE = 1 << 12,
+        const EFER_FAST_FXSAVE = 1 << 14,
+    }
+}
+
+bitflags! {
+    /// Flags of IA32_APIC_BASE.
+    pub flags ApicBase: u64 {
+        /// Set on the bootstrap processor.
+        const APIC_BASE_BSP = 1 << 8,
+        const APIC_BASE_X2APIC = 1 << 10,
+        const APIC_BASE_ENABLE = 1 << 11,
+    }
+}
+
+/// Memory types of the page attribute table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PatType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+    /// Uncacheable, but overridable by MTRRs.
+    Uncached = 7,
+}
+
+impl PatType {
+    fn from_bits(bits: u8) -> PatType {
+        match bits & 0x7 {
+            1 => PatType::WriteCombining,
+            4 => PatType::WriteThrough,
+            5 => PatType::WriteProtected,
+            6 => PatType::WriteBack,
+            7 => PatType::Uncached,
+            _ => PatType::Uncacheable,
+        }
+    }
+}
+
+/// Read `msr`. Unsafe as reading an unsupported MSR raises #GP.
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (high, low): (u32, u32);
+    asm!("rdmsr" : "={eax}"(low), "={edx}"(high) : "{ecx}"(msr) :: "volatile");
+    (high as u64) << 32 | low as u64
+}
+
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    asm!("wrmsr" :: "{ecx}"(msr), "{eax}"(value as u32), "{edx}"((value >> 32) as u32)
+         :: "volatile");
+}
+
+pub fn efer() -> Efer {
+    Efer::from_bits_truncate(unsafe { rdmsr(IA32_EFER) })
+}
+
+/// Unknown bits are kept as they are.
+pub unsafe fn write_efer(value: Efer) {
+    let reserved = rdmsr(IA32_EFER) & !Efer::all().bits();
+    wrmsr(IA32_EFER, reserved | value.bits());
+}
+
+/// The physical address of the local APIC registers, and the flags.
+pub fn apic_base() -> (usize, ApicBase) {
+    let value = unsafe { rdmsr(IA32_APIC_BASE) };
+    ((value & APIC_BASE_ADDRESS_MASK) as usize, ApicBase::from_bits_truncate(value))
+}
+
+pub unsafe fn write_apic_base(address: usize, flags: ApicBase) {
+    wrmsr(IA32_APIC_BASE, (address as u64 & APIC_BASE_ADDRESS_MASK) | flags.bits());
+}
+
+pub fn fs_base() -> usize {
+    unsafe { rdmsr(IA32_FS_BASE) as usize }
+}
+
+pub unsafe fn write_fs_base(address: usize) {
+    wrmsr(IA32_FS_BASE, address as u64);
+}
+
+pub fn gs_base() -> usize {
+    unsafe { rdmsr(IA32_GS_BASE) as usize }
+}
+
+pub unsafe fn write_gs_base(address: usize) {
+    wrmsr(IA32_GS_BASE, address as u64);
+}
+
+/// The GS base swapped in by `swapgs`.
+pub fn kernel_gs_base() -> usize {
+    unsafe { rdmsr(IA32_KERNEL_GS_BASE) as usize }
+}
+
+pub unsafe fn write_kernel_gs_base(address: usize) {
+    wrmsr(IA32_KERNEL_GS_BASE, address as u64);
+}
+
+/// The segment selector bases loaded by `syscall` and `sysret`.
+pub fn star() -> (u16, u16) {
+    let value = unsafe { rdmsr(IA32_STAR) };
+    ((value >> 32) as u16, (value >> 48) as u16)
+}
+
+pub unsafe fn write_star(syscall_base: u16, sysret_base: u16) {
+    wrmsr(IA32_STAR, (sysret_base as u64) << 48 | (syscall_base as u64) << 32);
+}
+
+/// The `syscall` entry point.
+pub fn lstar() -> usize {
+    unsafe { rdmsr(IA32_LSTAR) as usize }
+}
+
+pub unsafe fn write_lstar(address: usize) {
+    wrmsr(IA32_LSTAR, address as u64);
+}
+
+/// The RFLAGS bits cleared by `syscall`.
+pub fn fmask() -> u64 {
+    unsafe { rdmsr(IA32_FMASK) }
+}
+
+pub unsafe fn write_fmask(mask: u64) {
+    wrmsr(IA32_FMASK, mask);
+}
+
+/// The 8 entries of the page attribute table.
+pub fn pat() -> [PatType; 8] {
+    let value = unsafe { rdmsr(IA32_PAT) };
+    let mut entries = [PatType::Uncacheable; 8];
+    for (i, entry) in entries.iter_mut().enumerate() {
+        *entry = PatType::from_bits((value >> (i * 8)) as u8);
+    }
+    entries
+}
+
+pub unsafe fn write_pat(entries: [PatType; 8]) {
+    let value = entries.iter()
+        .enumerate()
+        .fold(0, |value, (i, &entry)| value | (entry as u64) << (i * 8));
+    wrmsr(IA32_PAT, value);
+}
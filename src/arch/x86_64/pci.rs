@@ -0,0 +1,169 @@
+//! PCI configuration space access through the legacy I/O port mechanism.
+// http://wiki.osdev.org/PCI
+
+use arch::cpuio::UnsafePort;
+use spin::Mutex;
+
+/// Configuration space offset of the vendor and device IDs.
+const VENDOR_DEVICE_OFFSET: u8 = 0x00;
+
+/// Configuration space offset of the class code, subclass and revision.
+const CLASS_OFFSET: u8 = 0x08;
+
+/// Configuration space offset of the header type.
+const HEADER_TYPE_OFFSET: u8 = 0x0C;
+
+/// Configuration space offset of the first Base Address Register.
+const BAR0_OFFSET: u8 = 0x10;
+
+/// Vendor ID returned when no device answers at a given address.
+const NO_VENDOR: u16 = 0xFFFF;
+
+/// The pair of ports used to address and access the configuration space.
+struct ConfigSpace {
+    address: UnsafePort<u32>,
+    data: UnsafePort<u32>,
+}
+
+impl ConfigSpace {
+    /// Build the value for the address port.
+    fn address(bus: u8, slot: u8, function: u8, offset: u8) -> u32 {
+        0x8000_0000 |
+            (bus as u32) << 16 |
+            ((slot as u32) & 0x1F) << 11 |
+            ((function as u32) & 0x07) << 8 |
+            ((offset as u32) & 0xFC)
+    }
+
+    unsafe fn read(&mut self, bus: u8, slot: u8, function: u8, offset: u8) -> u32 {
+        self.address.write(ConfigSpace::address(bus, slot, function, offset));
+        self.data.read()
+    }
+
+    unsafe fn write(&mut self, bus: u8, slot: u8, function: u8, offset: u8, value: u32) {
+        self.address.write(ConfigSpace::address(bus, slot, function, offset));
+        self.data.write(value);
+    }
+}
+
+static CONFIG_SPACE: Mutex<ConfigSpace> = Mutex::new(unsafe {
+    ConfigSpace {
+        address: UnsafePort::new(0xCF8),
+        data: UnsafePort::new(0xCFC),
+    }
+});
+
+/// A Base Address Register decoded.
+#[derive(Clone, Copy, Debug)]
+pub enum Bar {
+    /// A memory mapped region at a physical address.
+    Memory { address: u64, prefetchable: bool },
+    /// A region in I/O port space.
+    Io { port: u16 },
+    /// The BAR is not implemented.
+    None,
+}
+
+/// A single function of a device on the PCI bus.
+#[derive(Clone, Copy, Debug)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+}
+
+impl PciDevice {
+    /// Probe the function at the given address, if any.
+    fn probe(bus: u8, slot: u8, function: u8) -> Option<PciDevice> {
+        let id = read_config(bus, slot, function, VENDOR_DEVICE_OFFSET);
+        if id as u16 == NO_VENDOR {
+            return None;
+        }
+        let class = read_config(bus, slot, function, CLASS_OFFSET);
+        Some(PciDevice {
+            bus: bus,
+            slot: slot,
+            function: function,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+        })
+    }
+
+    /// Read a 32-bit register from the configuration space of this device.
+    pub fn read(&self, offset: u8) -> u32 {
+        read_config(self.bus, self.slot, self.function, offset)
+    }
+
+    /// Write a 32-bit register in the configuration space of this device.
+    ///
+    /// Unsafe because it can move the device's resources around.
+    pub unsafe fn write(&self, offset: u8, value: u32) {
+        CONFIG_SPACE.lock().write(self.bus, self.slot, self.function, offset, value);
+    }
+
+    /// Decode the Base Address Register `index` (0 to 5).
+    pub fn bar(&self, index: u8) -> Bar {
+        assert!(index < 6, "a PCI device only has 6 BARs");
+
+        let offset = BAR0_OFFSET + index * 4;
+        let low = self.read(offset);
+        if low == 0 {
+            return Bar::None;
+        }
+
+        if low & 0x1 == 0x1 {
+            return Bar::Io { port: (low & !0x3) as u16 };
+        }
+
+        let address = match (low >> 1) & 0x3 {
+            // 64-bit BAR, the upper half lives in the next register
+            0x2 if index < 5 => ((self.read(offset + 4) as u64) << 32) | (low & !0xF) as u64,
+            _ => (low & !0xF) as u64,
+        };
+        Bar::Memory { address: address, prefetchable: low & 0x8 != 0 }
+    }
+}
+
+/// Read a 32-bit register from the configuration space of any function.
+pub fn read_config(bus: u8, slot: u8, function: u8, offset: u8) -> u32 {
+    // Reading the configuration space has no side effects.
+    unsafe { CONFIG_SPACE.lock().read(bus, slot, function, offset) }
+}
+
+/// Write a 32-bit register in the configuration space of any function.
+///
+/// Unsafe for the same reasons as `PciDevice::write`.
+pub unsafe fn write_config(bus: u8, slot: u8, function: u8, offset: u8, value: u32) {
+    CONFIG_SPACE.lock().write(bus, slot, function, offset, value);
+}
+
+/// Look for the first function matching `vendor_id` and `device_id` with a
+/// brute-force scan of all buses.
+pub fn find_device(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    for bus in 0..256 {
+        for slot in 0..32 {
+            let function_count = match PciDevice::probe(bus as u8, slot, 0) {
+                None => continue,
+                Some(_) => {
+                    let header = read_config(bus as u8, slot, 0, HEADER_TYPE_OFFSET);
+                    if (header >> 16) & 0x80 != 0 { 8 } else { 1 }
+                }
+            };
+
+            for function in 0..function_count {
+                if let Some(device) = PciDevice::probe(bus as u8, slot, function) {
+                    if device.vendor_id == vendor_id && device.device_id == device_id {
+                        return Some(device);
+                    }
+                }
+            }
+        }
+    }
+    None
+}
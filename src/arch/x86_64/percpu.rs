@@ -0,0 +1,167 @@
+//! Per-CPU variables, declared with `per_cpu!` and reached through the GS
+//! base.
+// http://wiki.osdev.org/SWAPGS
+//
+// The variables are linked in the `.percpu` section, which is only a
+// template: each CPU gets a copy of it, its area, and its GS base points to
+// it. A variable is found at the same offset in every area. The area of the
+// bootstrap processor is reserved in `.bss` so it's usable from the start,
+// the others are allocated when the APs are started.
+//
+// The area header, first in the section, holds the address of the area and
+// the CPU number, read with `gs:` prefixed loads. In user mode the GS base
+// is the user's, interrupt entries from user mode `swapgs` to switch back.
+
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use spin::Mutex;
+use arch::msr;
+use arch::smp::MAX_CPUS;
+use memory::{self, Zone, PAGE_SIZE};
+
+// Defined in linker.ld.
+extern "C" {
+    static __percpu_start: u8;
+    static __percpu_end: u8;
+    static __percpu_boot: u8;
+}
+
+/// A per-CPU variable, use `get` to access the copy of the running CPU.
+/// The value declared is only the initial value of the copies.
+pub struct PerCpu<T> {
+    value: T,
+}
+
+// The copies are distinct, but `on` lets other CPUs reach them.
+unsafe impl<T: Sync> Sync for PerCpu<T> {}
+
+/// Start of every area.
+#[repr(C)]
+struct Header {
+    area: usize,
+    cpu: usize,
+}
+
+#[link_section = ".percpu.header"]
+static HEADER: PerCpu<Header> = PerCpu::new(Header { area: 0, cpu: 0 });
+
+/// The areas, by CPU number, 0 if the CPU has none.
+static AREAS: Mutex<[usize; MAX_CPUS]> = Mutex::new([0; MAX_CPUS]);
+
+fn template_start() -> usize {
+    unsafe { &__percpu_start as *const u8 as usize }
+}
+
+fn template_size() -> usize {
+    unsafe { &__percpu_end as *const u8 as usize - template_start() }
+}
+
+/// The area of the running CPU.
+fn area() -> usize {
+    let area: usize;
+    unsafe { asm!("mov %gs:0, $0" : "=r"(area)); }
+    area
+}
+
+/// The number of the running CPU, 0 for the bootstrap processor.
+pub fn cpu_id() -> usize {
+    let cpu: usize;
+    unsafe { asm!("mov %gs:8, $0" : "=r"(cpu)); }
+    cpu
+}
+
+impl<T> PerCpu<T> {
+    /// Used by `per_cpu!`.
+    pub const fn new(value: T) -> PerCpu<T> {
+        PerCpu { value: value }
+    }
+
+    /// The offset of the variable in the areas.
+    fn offset(&'static self) -> usize {
+        &self.value as *const T as usize - template_start()
+    }
+
+    /// The copy of the running CPU. Only the running CPU uses it, but an
+    /// interrupt handler running on it could too, so `T` should be an
+    /// atomic or a cell, and borrowed with interrupts disabled.
+    pub fn get(&'static self) -> &'static T {
+        unsafe { &*((area() + self.offset()) as *const T) }
+    }
+
+    /// The copy of `cpu`, if it's been started. Others may be using it.
+    pub fn on(&'static self, cpu: usize) -> Option<&'static T> {
+        match AREAS.lock()[cpu] {
+            0 => None,
+            area => Some(unsafe { &*((area + self.offset()) as *const T) }),
+        }
+    }
+}
+
+/// An event counter, as a per-CPU variable. Each CPU increments its own
+/// copy without contention, reading the total adds up the copies.
+pub struct Counter(AtomicUsize);
+
+impl Counter {
+    pub const fn new() -> Counter {
+        Counter(ATOMIC_USIZE_INIT)
+    }
+}
+
+impl PerCpu<Counter> {
+    /// Count an event on the running CPU.
+    pub fn increment(&'static self) {
+        self.get().0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The events counted on `cpu`.
+    pub fn count_on(&'static self, cpu: usize) -> usize {
+        self.on(cpu).map(|counter| counter.0.load(Ordering::Relaxed)).unwrap_or(0)
+    }
+
+    /// The events counted on all CPUs.
+    pub fn sum(&'static self) -> usize {
+        (0..MAX_CPUS).map(|cpu| self.count_on(cpu)).fold(0, |sum, count| sum + count)
+    }
+}
+
+/// Copy the template to `area`, for `cpu`.
+unsafe fn initialize(area: usize, cpu: usize) {
+    ptr::copy_nonoverlapping(template_start() as *const u8, area as *mut u8, template_size());
+    let header = (area + HEADER.offset()) as *mut Header;
+    (*header).area = area;
+    (*header).cpu = cpu;
+    AREAS.lock()[cpu] = area;
+}
+
+/// Set up and load the area of the bootstrap processor. Must come first,
+/// anything may use per-CPU variables.
+pub fn init() {
+    unsafe {
+        let area = &__percpu_boot as *const u8 as usize;
+        initialize(area, 0);
+        load(0);
+    }
+}
+
+/// Allocate the area of the application processor `cpu`. Return `false`
+/// if there's not enough memory.
+pub fn allocate(cpu: usize) -> bool {
+    let mut order = 0;
+    while PAGE_SIZE << order < template_size() {
+        order += 1;
+    }
+    match memory::allocate_frames(order, Zone::Normal) {
+        Some(frame) => {
+            unsafe { initialize(memory::phys_to_virt(frame.start_address()), cpu); }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Make the area of `cpu`, which must be the running CPU, the current one.
+/// The user GS base is 0 until there are users.
+pub unsafe fn load(cpu: usize) {
+    msr::write_gs_base(AREAS.lock()[cpu]);
+    msr::write_kernel_gs_base(0);
+}
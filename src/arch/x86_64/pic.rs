@@ -1,6 +1,6 @@
 //! The implementation for a 8259 Programmable Interrupt Controller (PIC),
-//! which handles I/O interrupts. Eventually, for multicore systems, this
-//! implementation will have to be replaces with a APIC interface.
+//! which handles I/O interrupts. The APICs replace it when present (see
+//! `apic`), the PICs are then remapped and fully masked.
 //!
 //! This implementation follows the IBM PC 8925 PIC architecture where 2 PICs
 //! are used. A slave PIC (PIC2) is chained on the master PIC (PIC1) on IRQ
@@ -16,7 +16,12 @@
 //! The problem here is that, because of a bug in the IBM design, 
 //! CPU exceptions are reserved by Intel from 0x00 to 0x1F, 
 //! so they would conflict with the IRQ managed by the PIC.
-//! Therefore we move PC1 to offset 0x20 to 0x2F and PIC2 to 0x28-0x2F.
+//! Therefore we move PC1 to offset 0x20 to 0x27 and PIC2 to 0x28-0x2F.
+//!
+//! A PIC raises IRQ 7, its lowest priority line, when the line of an
+//! interrupt goes away before the CPU acknowledges it. Such spurious IRQs
+//! are not in service, so they must not be acknowledged with an EOI.
+// http://wiki.osdev.org/8259_PIC
 
 use arch::cpuio::{Port, UnsafePort};
 use spin::Mutex;
@@ -30,6 +35,16 @@ const CMD_END_OF_INTERRUPT: u8 = 0x20;
 /// The mode we want for the PIC configuration
 const MODE_8086: u8 = 0x01;
 
+/// OCW3 commands selecting the register read from the command port.
+const CMD_READ_IRR: u8 = 0x0A;
+const CMD_READ_ISR: u8 = 0x0B;
+
+/// The line of the slave on the master.
+const CASCADE_LINE: u8 = 2;
+
+/// The line of the spurious IRQs of a PIC.
+const SPURIOUS_LINE: u8 = 7;
+
 /// Single PIC chip wrapper. Not used individually, hence not accassible.
 struct Pic {
     offset: u8,
@@ -48,22 +63,35 @@ impl Pic {
         interrupt_id >= self.offset && interrupt_id < self.offset + 8
     }
 
-    /// Sets a mask for the Interrupt Mask register to ignore specific interrupts.
-    unsafe fn set_mask(&mut self, interrupt_id: u8) {
-        let value = self.data.read() | (1 << interrupt_id);
+    /// Sets the bit of `line` in the Interrupt Mask Register to ignore it.
+    unsafe fn set_mask(&mut self, line: u8) {
+        let value = self.data.read() | (1 << line);
         self.data.write(value);
     }
 
-    unsafe fn clear_mask(&mut self, interrupt_id: u8) {
-        let value = self.data.read() & !(1 << interrupt_id);
+    /// Clears the bit of `line` in the Interrupt Mask Register.
+    unsafe fn clear_mask(&mut self, line: u8) {
+        let value = self.data.read() & !(1 << line);
         self.data.write(value);
     }
+
+    /// Read the register selected by the OCW3 `command`.
+    unsafe fn read_register(&mut self, command: u8) -> u8 {
+        self.command.write(command);
+        self.command.read()
+    }
+
+    /// Check if `line` is in service.
+    unsafe fn in_service(&mut self, line: u8) -> bool {
+        self.read_register(CMD_READ_ISR) & (1 << line) != 0
+    }
 }
 
 /// A pair of chained Pics. Standard way on modern x86 architecture.
 pub struct ChainedPics {
     master: Pic,
-    slave: Pic
+    slave: Pic,
+    spurious: usize,
 }
 
 impl ChainedPics {
@@ -79,6 +107,7 @@ impl ChainedPics {
                 command: UnsafePort::new(0xA0),
                 data: UnsafePort::new(0xA1),
             },
+            spurious: 0,
         }
     }
 
@@ -112,9 +141,9 @@ impl ChainedPics {
         wait();
 
         // Setup master-slave relationship
-        self.master.data.write(4);
+        self.master.data.write(1 << CASCADE_LINE);
         wait();
-        self.slave.data.write(2);
+        self.slave.data.write(CASCADE_LINE);
         wait();
 
         // Setup mode
@@ -128,28 +157,87 @@ impl ChainedPics {
         self.slave.data.write(saved_slave_mask);
     }
 
+    /// Mask all the interrupts of both PICs, when handing them off to the
+    /// APICs. They must be initialised so spurious interrupts don't hit CPU
+    /// exception vectors.
+    pub unsafe fn disable(&mut self) {
+        self.master.data.write(0xFF);
+        self.slave.data.write(0xFF);
+    }
+
     /// Check if `interrupt_id` is handled.
     pub fn handles_interrupt(&self, interrupt_id: u8) -> bool {
         self.master.handles_interrupt(interrupt_id) || 
             self.slave.handles_interrupt(interrupt_id)
     }
 
-    /// Select which PIC needs to know about this interrupt handling chaining.
+    /// Acknowledge `interrupt_id`. IRQs of the slave go through the master,
+    /// both need an EOI.
     pub unsafe fn end_of_interrupt(&mut self, interrupt_id: u8) {
-        if self.handles_interrupt(interrupt_id) {
-            if self.master.handles_interrupt(interrupt_id) {
-                self.master.end_of_interrupt();
-            }
+        if self.slave.handles_interrupt(interrupt_id) {
             self.slave.end_of_interrupt();
+            self.master.end_of_interrupt();
+        } else if self.master.handles_interrupt(interrupt_id) {
+            self.master.end_of_interrupt();
         }
     }
 
-    pub unsafe fn set_mask(&mut self, interrupt_id: u8) {
-        if self.handles_interrupt(interrupt_id) {
-            if self.master.handles_interrupt(interrupt_id) {
-                self.master.set_mask(interrupt_id);
+    /// Check if `interrupt_id` is a spurious IRQ, which is then counted and
+    /// must not be handled nor acknowledged. The master doesn't know a
+    /// spurious IRQ of the slave is one, it still gets its EOI here.
+    pub unsafe fn is_spurious(&mut self, interrupt_id: u8) -> bool {
+        if interrupt_id == self.master.offset + SPURIOUS_LINE {
+            if self.master.in_service(SPURIOUS_LINE) {
+                return false;
+            }
+        } else if interrupt_id == self.slave.offset + SPURIOUS_LINE {
+            if self.slave.in_service(SPURIOUS_LINE) {
+                return false;
             }
-            self.slave.set_mask(interrupt_id);
+            self.master.end_of_interrupt();
+        } else {
+            return false;
         }
+        self.spurious += 1;
+        true
+    }
+
+    /// Number of spurious IRQs seen.
+    pub fn spurious_count(&self) -> usize {
+        self.spurious
+    }
+
+    /// Ignore IRQ `irq`, from 0 to 15.
+    pub unsafe fn set_mask(&mut self, irq: u8) {
+        if irq < 8 {
+            self.master.set_mask(irq);
+        } else {
+            self.slave.set_mask(irq - 8);
+        }
+    }
+
+    /// Let IRQ `irq` through, from 0 to 15. The IRQs of the slave also need
+    /// its line on the master.
+    pub unsafe fn clear_mask(&mut self, irq: u8) {
+        if irq < 8 {
+            self.master.clear_mask(irq);
+        } else {
+            self.slave.clear_mask(irq - 8);
+            self.master.clear_mask(CASCADE_LINE);
+        }
+    }
+
+    /// The Interrupt Request Registers, IRQs raised but not yet sent to
+    /// the CPU, the slave in the high byte.
+    pub unsafe fn read_irr(&mut self) -> u16 {
+        self.master.read_register(CMD_READ_IRR) as u16 |
+            (self.slave.read_register(CMD_READ_IRR) as u16) << 8
+    }
+
+    /// The In-Service Registers, IRQs sent to the CPU and not yet
+    /// acknowledged, the slave in the high byte.
+    pub unsafe fn read_isr(&mut self) -> u16 {
+        self.master.read_register(CMD_READ_ISR) as u16 |
+            (self.slave.read_register(CMD_READ_ISR) as u16) << 8
     }
 }
@@ -0,0 +1,53 @@
+//! Programmable Interval Timer (Intel 8253/8254) driver, used as the
+//! system tick until something better is available.
+// http://wiki.osdev.org/Programmable_Interval_Timer
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use spin::Mutex;
+use arch::cpuio::Port;
+
+/// Frequency of the oscillator feeding the PIT channels, in Hz.
+const BASE_FREQUENCY: u32 = 1_193_182;
+
+/// Frequency of the system tick, in Hz.
+pub const TICKS_PER_SECOND: u32 = 1000;
+
+/// Channel 0, access mode lobyte/hibyte, mode 2 (rate generator), binary.
+const CHANNEL0_RATE_GENERATOR: u8 = 0b0011_0100;
+
+/// Ticks elapsed since `init`.
+static TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+struct Pit {
+    channel0: Port<u8>,
+    command: Port<u8>,
+}
+
+static PIT: Mutex<Pit> = Mutex::new(Pit {
+    channel0: Port::new(0x40),
+    command: Port::new(0x43),
+});
+
+/// Make channel 0 raise IRQ0 `TICKS_PER_SECOND` times per second.
+pub fn init() {
+    let divisor = BASE_FREQUENCY / TICKS_PER_SECOND;
+    let mut pit = PIT.lock();
+    pit.command.write(CHANNEL0_RATE_GENERATOR);
+    pit.channel0.write(divisor as u8);
+    pit.channel0.write((divisor >> 8) as u8);
+}
+
+/// Account for a tick, called from the IRQ0 handler.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Ticks elapsed since the timer was initialised.
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds elapsed since the timer was initialised.
+pub fn uptime_ms() -> u64 {
+    ticks() as u64 * 1000 / TICKS_PER_SECOND as u64
+}
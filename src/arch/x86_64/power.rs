@@ -0,0 +1,191 @@
+//! Powering off and resetting the machine, through ACPI when possible and
+//! the legacy ways otherwise.
+// http://www.uefi.org/sites/default/files/resources/ACPI_6_1.pdf, Sections 4.8.3, 4.8.3.6 and 7.3
+// http://wiki.osdev.org/Reboot
+// http://wiki.osdev.org/Shutdown
+
+use core::ptr;
+use arch::acpi::{AddressSpace, Fadt, GenericAddress, FADT_RESET_REGISTER_SUPPORTED};
+use arch::aml;
+use arch::cpuio;
+use arch::interrupts::{self, InterruptDescriptorTablePointer};
+use arch::pci;
+use memory::vm;
+
+/// PM1 control register bits.
+const PM1_SCI_ENABLE: u16 = 1 << 0;
+const PM1_SLEEP_TYPE_SHIFT: u16 = 10;
+const PM1_SLEEP_TYPE_MASK: u16 = 0x7 << PM1_SLEEP_TYPE_SHIFT;
+const PM1_SLEEP_ENABLE: u16 = 1 << 13;
+
+/// The soft off sleep state.
+const SLEEP_STATE_S5: u8 = 5;
+
+/// The 8042 keyboard controller status and command ports.
+const KBC_STATUS: u16 = 0x64;
+const KBC_COMMAND: u16 = 0x64;
+/// Status bit: the controller hasn't read the last command yet.
+const KBC_INPUT_FULL: u8 = 1 << 1;
+/// Pulse the reset line, which is wired to the CPU's.
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+/// The port of QEMU's `isa-debug-exit` device, as set by `make test`.
+const QEMU_EXIT_PORT: u16 = 0xF4;
+
+/// Codes for `exit_qemu`. QEMU exits with `(code << 1) | 1`, so neither
+/// can be confused with QEMU failing by itself.
+pub const QEMU_EXIT_SUCCESS: u32 = 0x10;
+pub const QEMU_EXIT_FAILURE: u32 = 0x11;
+
+/// Roughly wait `microseconds`, a port 0x80 write taking about one.
+fn delay(microseconds: u64) {
+    for _ in 0..microseconds {
+        unsafe { cpuio::outb(0x80, 0); }
+    }
+}
+
+/// Stop for good.
+fn halt() -> ! {
+    loop {
+        unsafe { asm!("cli; hlt" :::: "volatile"); }
+    }
+}
+
+/// Turn ACPI mode on if the firmware still owns the power management
+/// registers, which is the case until the OS asks through `smi_command`.
+fn enable_acpi(fadt: &Fadt) {
+    let control = unsafe { cpuio::inw(fadt.pm1a_control_block as u16) };
+    if control & PM1_SCI_ENABLE != 0 || fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+    unsafe { cpuio::outb(fadt.smi_command as u16, fadt.acpi_enable); }
+    // The transition can take a while, give it up to 3 seconds.
+    for _ in 0..300 {
+        if unsafe { cpuio::inw(fadt.pm1a_control_block as u16) } & PM1_SCI_ENABLE != 0 {
+            return;
+        }
+        delay(10_000);
+    }
+    warn!("ACPI mode could not be enabled");
+}
+
+/// Enter S5 through the PM1 control registers.
+fn acpi_power_off() {
+    let fadt = match Fadt::get() {
+        Some(fadt) if fadt.pm1a_control_block != 0 => fadt,
+        _ => return,
+    };
+    let (a, b) = match aml::sleep_type(SLEEP_STATE_S5) {
+        Ok(types) => types,
+        Err(error) => {
+            warn!("No S5 sleep type: {:?}", error);
+            return;
+        }
+    };
+    aml::prepare_to_sleep(SLEEP_STATE_S5);
+    enable_acpi(&fadt);
+
+    unsafe {
+        interrupts::disable();
+        let write = |port: u32, typ: u8| {
+            let control = cpuio::inw(port as u16) & !PM1_SLEEP_TYPE_MASK;
+            cpuio::outw(port as u16, control | (typ as u16) << PM1_SLEEP_TYPE_SHIFT |
+                                     PM1_SLEEP_ENABLE);
+        };
+        write(fadt.pm1a_control_block, a);
+        if fadt.pm1b_control_block != 0 {
+            write(fadt.pm1b_control_block, b);
+        }
+    }
+    delay(100_000);
+}
+
+/// Write `value` to the register at `address`, which must be byte sized.
+unsafe fn write_register(address: &GenericAddress, value: u8) {
+    match address.space {
+        AddressSpace::Io => cpuio::outb(address.address as u16, value),
+        AddressSpace::Memory => {
+            // Not unmapped, the registers written reset or power off.
+            if let Ok(virt) = vm::map_device(address.address as usize, 1, "ACPI register") {
+                ptr::write_volatile(virt as *mut u8, value);
+            }
+        }
+        AddressSpace::PciConfig => {
+            // Device, function and offset on bus 0, 16 bits each.
+            let (slot, function) = ((address.address >> 32) as u8, (address.address >> 16) as u8);
+            let offset = address.address as u8;
+            let shift = (offset & 3) * 8;
+            let dword = pci::read_config(0, slot, function, offset & !3) & !(0xFF << shift);
+            pci::write_config(0, slot, function, offset & !3, dword | (value as u32) << shift);
+        }
+        AddressSpace::Other(_) => {}
+    }
+}
+
+/// Reset through the FADT reset register.
+fn acpi_reset() {
+    let fadt = match Fadt::get() {
+        Some(fadt) => fadt,
+        None => return,
+    };
+    if let (Some(register), true) = (fadt.reset_register,
+                                     fadt.flags & FADT_RESET_REGISTER_SUPPORTED != 0) {
+        unsafe {
+            interrupts::disable();
+            write_register(&register, fadt.reset_value);
+        }
+        delay(100_000);
+    }
+}
+
+/// Pulse the reset line through the keyboard controller.
+fn keyboard_controller_reset() {
+    unsafe {
+        interrupts::disable();
+        for _ in 0..100_000 {
+            if cpuio::inb(KBC_STATUS) & KBC_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        cpuio::outb(KBC_COMMAND, KBC_PULSE_RESET);
+    }
+    delay(100_000);
+}
+
+/// Load an empty IDT and raise an exception, which can't be delivered and
+/// ends up in a triple fault, resetting the CPU.
+fn triple_fault() {
+    let idt = InterruptDescriptorTablePointer { limit: 0, base: 0 };
+    unsafe {
+        interrupts::disable();
+        interrupts::lidt(&idt);
+        asm!("int3" :::: "volatile");
+    }
+}
+
+/// Power the machine off, or at least reset it.
+pub fn shutdown() -> ! {
+    info!("Powering off");
+    acpi_power_off();
+    error!("Failed to power off, resetting");
+    keyboard_controller_reset();
+    triple_fault();
+    halt()
+}
+
+/// Reset the machine.
+pub fn reboot() -> ! {
+    info!("Rebooting");
+    acpi_reset();
+    keyboard_controller_reset();
+    triple_fault();
+    error!("Failed to reboot, halting");
+    halt()
+}
+
+/// Exit QEMU with `code`, which only works when it was started with
+/// `-device isa-debug-exit,iobase=0xf4,iosize=0x04`. Shut down otherwise.
+pub fn exit_qemu(code: u32) -> ! {
+    unsafe { cpuio::outl(QEMU_EXIT_PORT, code); }
+    shutdown()
+}
@@ -0,0 +1,168 @@
+//! Control registers, RFLAGS and XCR0.
+// http://wiki.osdev.org/CPU_Registers_x86-64
+//
+// Reading is safe in ring 0. Writing can break about every assumption the
+// kernel makes, so it's unsafe.
+
+bitflags! {
+    /// Fields of CR0.
+    pub flags Cr0: usize {
+        const CR0_PROTECTED_MODE = 1 << 0,
+        const CR0_MONITOR_COPROCESSOR = 1 << 1,
+        const CR0_EMULATE_COPROCESSOR = 1 << 2,
+        const CR0_TASK_SWITCHED = 1 << 3,
+        const CR0_EXTENSION_TYPE = 1 << 4,
+        const CR0_NUMERIC_ERROR = 1 << 5,
+        /// Read only pages are read only for the kernel too.
+        const CR0_WRITE_PROTECT = 1 << 16,
+        const CR0_ALIGNMENT_MASK = 1 << 18,
+        const CR0_NOT_WRITE_THROUGH = 1 << 29,
+        const CR0_CACHE_DISABLE = 1 << 30,
+        const CR0_PAGING = 1 << 31,
+    }
+}
+
+bitflags! {
+    /// Fields of CR4.
+    pub flags Cr4: usize {
+        const CR4_VIRTUAL_8086_EXTENSIONS = 1 << 0,
+        const CR4_PROTECTED_VIRTUAL_INTERRUPTS = 1 << 1,
+        const CR4_TIMESTAMP_DISABLE = 1 << 2,
+        const CR4_DEBUGGING_EXTENSIONS = 1 << 3,
+        const CR4_PAGE_SIZE_EXTENSION = 1 << 4,
+        const CR4_PHYSICAL_ADDRESS_EXTENSION = 1 << 5,
+        const CR4_MACHINE_CHECK = 1 << 6,
+        const CR4_PAGE_GLOBAL = 1 << 7,
+        const CR4_PERFORMANCE_COUNTER = 1 << 8,
+        /// FXSAVE/FXRSTOR and SSE instructions.
+        const CR4_OSFXSR = 1 << 9,
+        const CR4_OSXMMEXCPT = 1 << 10,
+        const CR4_UMIP = 1 << 11,
+        const CR4_FSGSBASE = 1 << 16,
+        const CR4_PCID = 1 << 17,
+        /// XSAVE and XCR0.
+        const CR4_OSXSAVE = 1 << 18,
+        const CR4_SMEP = 1 << 20,
+        const CR4_SMAP = 1 << 21,
+        const CR4_PROTECTION_KEYS = 1 << 22,
+    }
+}
+
+bitflags! {
+    /// Fields of RFLAGS.
+    pub flags RFlags: u64 {
+        const RFLAGS_CARRY = 1 << 0,
+        const RFLAGS_PARITY = 1 << 2,
+        const RFLAGS_AUXILIARY_CARRY = 1 << 4,
+        const RFLAGS_ZERO = 1 << 6,
+        const RFLAGS_SIGN = 1 << 7,
+        const RFLAGS_TRAP = 1 << 8,
+        const RFLAGS_INTERRUPT = 1 << 9,
+        const RFLAGS_DIRECTION = 1 << 10,
+        const RFLAGS_OVERFLOW = 1 << 11,
+        const RFLAGS_IOPL = 3 << 12,
+        const RFLAGS_NESTED_TASK = 1 << 14,
+        const RFLAGS_RESUME = 1 << 16,
+        const RFLAGS_VIRTUAL_8086 = 1 << 17,
+        const RFLAGS_ALIGNMENT_CHECK = 1 << 18,
+        const RFLAGS_VIRTUAL_INTERRUPT = 1 << 19,
+        const RFLAGS_VIRTUAL_INTERRUPT_PENDING = 1 << 20,
+        const RFLAGS_ID = 1 << 21,
+    }
+}
+
+bitflags! {
+    /// State components enabled for XSAVE in XCR0.
+    pub flags Xcr0: u64 {
+        const XCR0_X87 = 1 << 0,
+        const XCR0_SSE = 1 << 1,
+        const XCR0_AVX = 1 << 2,
+        const XCR0_BNDREG = 1 << 3,
+        const XCR0_BNDCSR = 1 << 4,
+        const XCR0_OPMASK = 1 << 5,
+        const XCR0_ZMM_HI256 = 1 << 6,
+        const XCR0_HI16_ZMM = 1 << 7,
+        const XCR0_PKRU = 1 << 9,
+    }
+}
+
+pub fn cr0() -> Cr0 {
+    let value: usize;
+    unsafe { asm!("mov %cr0, $0" : "=r"(value)); }
+    Cr0::from_bits_truncate(value)
+}
+
+/// Unknown bits are kept as they are.
+pub unsafe fn write_cr0(value: Cr0) {
+    let current: usize;
+    asm!("mov %cr0, $0" : "=r"(current));
+    let value = current & !Cr0::all().bits() | value.bits();
+    asm!("mov $0, %cr0" :: "r"(value) : "memory");
+}
+
+/// The address of the last page fault.
+pub fn cr2() -> usize {
+    let value: usize;
+    unsafe { asm!("mov %cr2, $0" : "=r"(value)); }
+    value
+}
+
+/// The physical address of the active P4 table, with the PCID or the
+/// caching bits in the low 12 bits.
+pub fn cr3() -> usize {
+    let value: usize;
+    unsafe { asm!("mov %cr3, $0" : "=r"(value)); }
+    value
+}
+
+/// Switch page tables, flushing the non-global TLB entries.
+pub unsafe fn write_cr3(value: usize) {
+    asm!("mov $0, %cr3" :: "r"(value) : "memory");
+}
+
+pub fn cr4() -> Cr4 {
+    let value: usize;
+    unsafe { asm!("mov %cr4, $0" : "=r"(value)); }
+    Cr4::from_bits_truncate(value)
+}
+
+/// Unknown bits are kept as they are.
+pub unsafe fn write_cr4(value: Cr4) {
+    let current: usize;
+    asm!("mov %cr4, $0" : "=r"(current));
+    let value = current & !Cr4::all().bits() | value.bits();
+    asm!("mov $0, %cr4" :: "r"(value) : "memory");
+}
+
+/// The task priority, interrupts of a lower priority class are masked.
+pub fn cr8() -> u8 {
+    let value: usize;
+    unsafe { asm!("mov %cr8, $0" : "=r"(value)); }
+    value as u8
+}
+
+pub unsafe fn write_cr8(priority: u8) {
+    asm!("mov $0, %cr8" :: "r"(priority as usize) : "memory");
+}
+
+pub fn rflags() -> RFlags {
+    let value: u64;
+    unsafe { asm!("pushfq; popq $0" : "=r"(value) :: "memory"); }
+    RFlags::from_bits_truncate(value)
+}
+
+pub unsafe fn write_rflags(value: RFlags) {
+    asm!("pushq $0; popfq" :: "r"(value.bits()) : "memory", "cc");
+}
+
+/// XCR0, only readable once CR4.OSXSAVE is set.
+pub unsafe fn xcr0() -> Xcr0 {
+    let (high, low): (u32, u32);
+    asm!("xgetbv" : "={eax}"(low), "={edx}"(high) : "{ecx}"(0));
+    Xcr0::from_bits_truncate((high as u64) << 32 | low as u64)
+}
+
+pub unsafe fn write_xcr0(value: Xcr0) {
+    let bits = value.bits();
+    asm!("xsetbv" :: "{ecx}"(0), "{eax}"(bits as u32), "{edx}"((bits >> 32) as u32) :: "volatile");
+}
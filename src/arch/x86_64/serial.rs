@@ -6,6 +6,12 @@ use arch::cpuio::UnsafePort;
 use self::SerialRegister::*;
 use spin::Mutex;
 
+/// The UART clock divided by 16, the baud rate with a divisor of 1.
+const MAX_BAUD_RATE: u32 = 115200;
+
+/// Baud rate used until configured otherwise.
+const DEFAULT_BAUD_RATE: u32 = 57600;
+
 /// Each COM serial port has 8 data registers, offset from the port address.
 /// The first two have dual use depending on DLAB bit in the `LineControl` register.
 #[repr(C, u8)]
@@ -23,12 +29,22 @@ enum SerialRegister {
 
 /// A COM serial port wrapper.
 pub struct SerialPort {
-    base_address: u16
+    base_address: u16,
+    baud_divisor: u16,
 }
 
 impl SerialPort {
     const unsafe fn new(base_address: u16) -> Self {
-        SerialPort { base_address: base_address }
+        SerialPort {
+            base_address: base_address,
+            baud_divisor: (MAX_BAUD_RATE / DEFAULT_BAUD_RATE) as u16,
+        }
+    }
+
+    /// Set the speed of the line, rounded to the nearest rate supported.
+    pub fn set_baud_rate(&mut self, baud_rate: u32) {
+        let divisor = (MAX_BAUD_RATE + baud_rate / 2) / baud_rate;
+        self.baud_divisor = if divisor == 0 { 1 } else { divisor as u16 };
     }
 
     unsafe fn lazy_init(&self) {
@@ -39,8 +55,8 @@ impl SerialPort {
         let saved_line_control_mode = self.port(LineControl).read();
         self.port(LineControl).write(0x80 | saved_line_control_mode);
 
-        // Set divisor to 3
-        let baud_divisor: u16 = 2;
+        // Set the baud rate divisor
+        let baud_divisor = self.baud_divisor;
         self.port(DataOrBaudDivisorLowByte).write(baud_divisor as u8);
         self.port(InterruptEnableOrBaudDivisorHighByte).write((baud_divisor >> 8) as u8);
 
@@ -85,7 +101,12 @@ impl Write for SerialPort {
     }
 }
 
-/// The COM1 port
+/// The COM1 port, the `ttyS0` console.
 pub static COM1: Mutex<SerialPort> = Mutex::new(unsafe {
     SerialPort::new(0x03F8)
 });
+
+/// The COM2 port, the `ttyS1` console.
+pub static COM2: Mutex<SerialPort> = Mutex::new(unsafe {
+    SerialPort::new(0x02F8)
+});
@@ -0,0 +1,278 @@
+//! Symmetric multiprocessing: starting the application processors (APs)
+//! listed in the MADT.
+// http://wiki.osdev.org/Symmetric_Multiprocessing
+// http://www.intel.com/Assets/en_US/PDF/manual/253668.pdf, Section 8.4
+//
+// The APs are started one at a time: the bootstrap processor fills the
+// trampoline data with the stack of the next AP, sends it INIT then
+// startup IPIs and waits for it to reach `ap_main`.
+//
+// An AP which doesn't start in time is given up: it's sent INIT again,
+// which stops it until the next startup IPI, and its CPU number is given
+// to the next one. In case it reached `ap_main` meanwhile, each attempt
+// has a token the AP must claim before using anything of its CPU number,
+// or it halts.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use spin::Mutex;
+use arch::acpi::{Madt, MadtEntry, LOCAL_APIC_ENABLED};
+use arch::apic::{self, Delivery, Destination};
+use arch::fpu;
+use arch::gdt;
+use arch::interrupts;
+use arch::msr::{self, EFER_LONG_MODE_ENABLE, EFER_NO_EXECUTE_ENABLE};
+use arch::percpu;
+use arch::pit;
+use arch::registers;
+use arch::thread;
+use memory::{self, Frame, Zone, PAGE_SIZE};
+use memory::paging::{PRESENT, WRITABLE, HUGE_PAGE, ENTRY_COUNT};
+
+/// Most CPUs supported.
+pub const MAX_CPUS: usize = 64;
+
+/// Where the trampoline is copied, in conventional memory. Must match
+/// trampoline.asm.
+const TRAMPOLINE: usize = 0x8000;
+
+/// The page tables of the trampoline, after it. They identity map the
+/// first 2 MiB, executable, and share the kernel half of the kernel tables.
+const TRAMPOLINE_P4: usize = 0x9000;
+const TRAMPOLINE_P3: usize = 0xA000;
+const TRAMPOLINE_P2: usize = 0xB000;
+
+/// The AP stacks are 2^STACK_ORDER frames.
+const STACK_ORDER: usize = 2;
+
+/// How long to wait after INIT, after a startup IPI, and for an AP to run.
+const INIT_DELAY_MS: u64 = 10;
+const STARTUP_DELAY_MS: u64 = 1;
+const START_TIMEOUT_MS: u64 = 1000;
+
+extern "C" {
+    static trampoline_start: u8;
+    static trampoline_end: u8;
+    static trampoline_data: u8;
+    static kernel_stack_top: u8;
+}
+
+/// The parameters of the trampoline, at `trampoline_data`.
+#[repr(C)]
+struct TrampolineData {
+    cr3: u64,
+    efer: u64,
+    stack: u64,
+    entry: u64,
+    cpu: u64,
+    attempt: u64,
+}
+
+/// CPUs running, the bootstrap processor included.
+static ONLINE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Set by the AP being started once it runs Rust code.
+static STARTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The token of the start being waited for, 0 once claimed by the AP or
+/// given up.
+static ATTEMPT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The token of the last start.
+static LAST_ATTEMPT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The page tables the APs switch to, those of the bootstrap processor.
+static KERNEL_P4: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The local APIC IDs of the online CPUs, by CPU number.
+static APIC_IDS: Mutex<[u32; MAX_CPUS]> = Mutex::new([0; MAX_CPUS]);
+
+/// Number of CPUs running.
+pub fn online() -> usize {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+/// The local APIC ID of `cpu`.
+pub fn apic_id(cpu: usize) -> u32 {
+    APIC_IDS.lock()[cpu]
+}
+
+/// Busy wait at least `ms` milliseconds, interrupts must be enabled.
+fn wait_ms(ms: u64) {
+    let end = pit::uptime_ms() + ms + 1;
+    while pit::uptime_ms() < end {
+        unsafe { asm!("pause" :::: "volatile"); }
+    }
+}
+
+/// Wait up to `ms` milliseconds for the AP being started to run.
+fn wait_started(ms: u64) -> bool {
+    let end = pit::uptime_ms() + ms + 1;
+    while pit::uptime_ms() < end {
+        if STARTED.load(Ordering::SeqCst) {
+            return true;
+        }
+        unsafe { asm!("pause" :::: "volatile"); }
+    }
+    STARTED.load(Ordering::SeqCst)
+}
+
+/// Copy the trampoline to low memory, and write its page tables.
+unsafe fn install_trampoline() {
+    let start = &trampoline_start as *const u8;
+    let size = &trampoline_end as *const u8 as usize - start as usize;
+    assert!(size <= TRAMPOLINE_P4 - TRAMPOLINE, "Trampoline too large");
+    ptr::copy_nonoverlapping(start, memory::phys_to_virt(TRAMPOLINE) as *mut u8, size);
+
+    let table = |address: usize| memory::phys_to_virt(address) as *mut u64;
+    for &address in [TRAMPOLINE_P4, TRAMPOLINE_P3, TRAMPOLINE_P2].iter() {
+        ptr::write_bytes(table(address), 0, ENTRY_COUNT);
+    }
+    *table(TRAMPOLINE_P4) = TRAMPOLINE_P3 as u64 | PRESENT | WRITABLE;
+    *table(TRAMPOLINE_P3) = TRAMPOLINE_P2 as u64 | PRESENT | WRITABLE;
+    *table(TRAMPOLINE_P2) = PRESENT | WRITABLE | HUGE_PAGE;
+
+    let kernel_p4 = memory::phys_to_virt(registers::cr3()) as *const u64;
+    for index in ENTRY_COUNT / 2..ENTRY_COUNT {
+        *table(TRAMPOLINE_P4).offset(index as isize) = *kernel_p4.offset(index as isize);
+    }
+}
+
+/// The trampoline data, once copied.
+fn trampoline_data_address() -> *mut TrampolineData {
+    let offset = unsafe {
+        &trampoline_data as *const u8 as usize - &trampoline_start as *const u8 as usize
+    };
+    memory::phys_to_virt(TRAMPOLINE + offset) as *mut TrampolineData
+}
+
+/// Start the AP of local APIC `apic_id` as CPU number `cpu`.
+fn start(cpu: usize, apic_id: u32) -> bool {
+    let attempt = LAST_ATTEMPT.fetch_add(1, Ordering::SeqCst) + 1;
+    let stack = match memory::allocate_frames(STACK_ORDER, Zone::Normal) {
+        Some(frame) => memory::phys_to_virt(frame.start_address()),
+        None => {
+            warn!("No memory for the stack of CPU {}", cpu);
+            return false;
+        }
+    };
+    let stack_top = stack + (PAGE_SIZE << STACK_ORDER);
+    if !percpu::allocate(cpu) {
+        warn!("No memory for the per-CPU area of CPU {}", cpu);
+        memory::deallocate_frames(Frame::containing_address(memory::virt_to_phys(stack)),
+                                  STACK_ORDER);
+        return false;
+    }
+
+    unsafe {
+        gdt::init(cpu, stack_top);
+        let efer = msr::efer() & (EFER_LONG_MODE_ENABLE | EFER_NO_EXECUTE_ENABLE);
+        ptr::write_volatile(trampoline_data_address(), TrampolineData {
+            cr3: TRAMPOLINE_P4 as u64,
+            efer: efer.bits(),
+            stack: stack_top as u64,
+            entry: ap_main as usize as u64,
+            cpu: cpu as u64,
+            attempt: attempt as u64,
+        });
+    }
+    STARTED.store(false, Ordering::SeqCst);
+    ATTEMPT.store(attempt, Ordering::SeqCst);
+
+    apic::send_ipi(Destination::Apic(apic_id), Delivery::Init);
+    wait_ms(INIT_DELAY_MS);
+    // The second startup IPI is only for processors missing the first.
+    let page = (TRAMPOLINE / PAGE_SIZE) as u8;
+    for _ in 0..2 {
+        apic::send_ipi(Destination::Apic(apic_id), Delivery::Startup(page));
+        if wait_started(STARTUP_DELAY_MS) {
+            break;
+        }
+    }
+    if !wait_started(START_TIMEOUT_MS) {
+        if ATTEMPT.compare_and_swap(attempt, 0, Ordering::SeqCst) == attempt {
+            // Stop it before reusing its stack. The per-CPU area is
+            // leaked, `percpu` can't free it.
+            apic::send_ipi(Destination::Apic(apic_id), Delivery::Init);
+            wait_ms(INIT_DELAY_MS);
+            memory::deallocate_frames(Frame::containing_address(memory::virt_to_phys(stack)),
+                                      STACK_ORDER);
+            warn!("CPU {} (APIC {}) did not start", cpu, apic_id);
+            return false;
+        }
+        // Claimed just now, it's running.
+        while !STARTED.load(Ordering::SeqCst) {
+            unsafe { asm!("pause" :::: "volatile"); }
+        }
+    }
+    APIC_IDS.lock()[cpu] = apic_id;
+    true
+}
+
+/// The entry point of the APs, called by the trampoline.
+extern "C" fn ap_main(cpu: usize, attempt: usize) -> ! {
+    if ATTEMPT.compare_and_swap(attempt, 0, Ordering::SeqCst) != attempt {
+        // Given up, INIT is on its way.
+        loop {
+            unsafe { asm!("cli; hlt" :::: "volatile"); }
+        }
+    }
+    unsafe {
+        registers::write_cr3(KERNEL_P4.load(Ordering::SeqCst));
+        percpu::load(cpu);
+        gdt::load(cpu);
+        interrupts::init_ap();
+    }
+    fpu::init_ap();
+    apic::init_ap();
+    thread::init_cpu();
+
+    ONLINE.fetch_add(1, Ordering::SeqCst);
+    STARTED.store(true, Ordering::SeqCst);
+    info!("CPU {} online, APIC {}", cpu, apic::id());
+    thread::idle();
+}
+
+/// Give the bootstrap processor its GDT and TSS, then start the enabled
+/// processors of the MADT. Needs the APICs and the system tick.
+pub fn init() {
+    unsafe {
+        gdt::init(0, &kernel_stack_top as *const u8 as usize);
+        gdt::load(0);
+    }
+    ONLINE.store(1, Ordering::SeqCst);
+
+    let madt = match Madt::get() {
+        Some(madt) => madt,
+        None => return,
+    };
+    if !apic::is_enabled() {
+        info!("APIC disabled, running on the bootstrap processor only");
+        return;
+    }
+    let bsp = apic::id();
+    APIC_IDS.lock()[0] = bsp;
+
+    unsafe { install_trampoline(); }
+    KERNEL_P4.store(registers::cr3(), Ordering::SeqCst);
+
+    let mut cpu = 1;
+    for entry in madt.entries() {
+        let (apic_id, flags) = match entry {
+            MadtEntry::LocalApic { apic_id, flags, .. } => (apic_id as u32, flags),
+            MadtEntry::LocalX2Apic { apic_id, flags, .. } => (apic_id, flags),
+            _ => continue,
+        };
+        if flags & LOCAL_APIC_ENABLED == 0 || apic_id == bsp {
+            continue;
+        }
+        if cpu == MAX_CPUS {
+            warn!("More than {} CPUs, the others are not used", MAX_CPUS);
+            break;
+        }
+        if start(cpu, apic_id) {
+            cpu += 1;
+        }
+    }
+    info!("{} CPUs online", online());
+}
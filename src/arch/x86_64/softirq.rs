@@ -0,0 +1,158 @@
+//! Softirqs and tasklets, the bottom halves of the interrupt handlers.
+//
+// A handler does the urgent part of its work, then raises a softirq or
+// schedules a tasklet for the rest. The pending softirqs of a CPU run on
+// the way out of its interrupts, once acknowledged and with interrupts
+// enabled, so other IRQs aren't held up. Interrupts nested in a softirq
+// leave theirs to the outer one. Softirqs must not block: longer jobs go
+// to the work queue (see `workqueue`).
+//
+// Tasklets are a softirq running queued jobs, on the CPU which queued them.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use spin::Mutex;
+use arch::interrupts;
+
+/// Number of softirqs.
+pub const MAX_SOFTIRQS: usize = 8;
+
+/// The softirq running the tasklets.
+pub const TASKLET_SOFTIRQ: usize = 0;
+
+/// Times the pending softirqs are run again when raised while running,
+/// before leaving them to the next interrupt.
+const MAX_RESTARTS: usize = 10;
+
+/// Jobs queued at most.
+const MAX_JOBS: usize = 64;
+
+/// A function to call later with its argument.
+#[derive(Clone, Copy)]
+pub struct Job {
+    pub function: fn(usize),
+    pub argument: usize,
+}
+
+impl Job {
+    pub fn run(self) {
+        (self.function)(self.argument)
+    }
+}
+
+/// A fixed size queue of jobs, run in order.
+pub struct Jobs {
+    jobs: [Option<Job>; MAX_JOBS],
+    head: usize,
+    len: usize,
+}
+
+impl Jobs {
+    pub const fn new() -> Jobs {
+        Jobs { jobs: [None; MAX_JOBS], head: 0, len: 0 }
+    }
+
+    /// Queue `job`. Return `false` if the queue is full.
+    pub fn push(&mut self, job: Job) -> bool {
+        if self.len == MAX_JOBS {
+            return false;
+        }
+        self.jobs[(self.head + self.len) % MAX_JOBS] = Some(job);
+        self.len += 1;
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<Job> {
+        if self.len == 0 {
+            return None;
+        }
+        let job = self.jobs[self.head].take();
+        self.head = (self.head + 1) % MAX_JOBS;
+        self.len -= 1;
+        job
+    }
+}
+
+/// The tasklets of a CPU, only used by it with interrupts disabled.
+struct Tasklets(UnsafeCell<Jobs>);
+
+unsafe impl Sync for Tasklets {}
+
+per_cpu! {
+    /// The softirqs raised, one bit each.
+    static PENDING: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// Whether the softirqs are running.
+    static RUNNING: AtomicBool = ATOMIC_BOOL_INIT;
+
+    static TASKLETS: Tasklets = Tasklets(UnsafeCell::new(Jobs::new()));
+}
+
+/// The handlers, locked with interrupts disabled.
+static HANDLERS: Mutex<[Option<fn()>; MAX_SOFTIRQS]> = Mutex::new([None; MAX_SOFTIRQS]);
+
+/// Make `handler` run when `softirq` is raised.
+pub fn register(softirq: usize, handler: fn()) {
+    assert!(softirq < MAX_SOFTIRQS, "Softirq {} out of range", softirq);
+    interrupts::without_interrupts(|| HANDLERS.lock()[softirq] = Some(handler));
+}
+
+/// Run the handler of `softirq` on the running CPU, at the end of the
+/// next interrupt.
+pub fn raise(softirq: usize) {
+    PENDING.get().fetch_or(1 << softirq, Ordering::Relaxed);
+}
+
+/// Run `function(argument)` from a softirq on the running CPU. Return
+/// `false` if too many tasklets are queued.
+pub fn schedule_tasklet(function: fn(usize), argument: usize) -> bool {
+    let job = Job { function: function, argument: argument };
+    let queued = interrupts::without_interrupts(|| unsafe { (*TASKLETS.get().0.get()).push(job) });
+    if queued {
+        raise(TASKLET_SOFTIRQ);
+    }
+    queued
+}
+
+fn run_tasklets() {
+    while let Some(job) = interrupts::without_interrupts(|| unsafe {
+        (*TASKLETS.get().0.get()).pop()
+    }) {
+        job.run();
+    }
+}
+
+/// Run the pending softirqs of the running CPU. Called at the end of an
+/// interrupt, once acknowledged, with interrupts disabled. They are
+/// enabled while the handlers run.
+pub unsafe fn run_pending() {
+    if RUNNING.get().load(Ordering::Relaxed) {
+        return;
+    }
+    RUNNING.get().store(true, Ordering::Relaxed);
+
+    for _ in 0..MAX_RESTARTS {
+        let pending = PENDING.get().swap(0, Ordering::Relaxed);
+        if pending == 0 {
+            break;
+        }
+        interrupts::enable();
+        for softirq in 0..MAX_SOFTIRQS {
+            if pending & 1 << softirq == 0 {
+                continue;
+            }
+            match interrupts::without_interrupts(|| HANDLERS.lock()[softirq]) {
+                Some(handler) => handler(),
+                None => warn!("Softirq {} raised without a handler", softirq),
+            }
+        }
+        interrupts::disable();
+    }
+
+    RUNNING.get().store(false, Ordering::Relaxed);
+}
+
+/// Register the tasklet softirq.
+pub fn init() {
+    register(TASKLET_SOFTIRQ, run_tasklets);
+}
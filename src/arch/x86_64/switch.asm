@@ -0,0 +1,42 @@
+;;; Switching between kernel threads, see thread.rs.
+;;;
+;;; Only the callee-saved registers need saving, the caller of switch_stacks
+;;; saves the others. They're pushed on the stack of the thread switched out,
+;;; and popped from the stack of the next one, so a new thread's stack starts
+;;; with zeroed registers and thread_start as return address.
+
+global switch_stacks
+global thread_start
+
+extern thread_entry
+
+section .text
+bits 64
+
+;;; fn switch_stacks(old_rsp: *mut usize, new_rsp: usize)
+switch_stacks:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+;;; Where new threads return to from switch_stacks, with interrupts disabled.
+thread_start:
+    call thread_entry
+
+    ; thread_entry never returns
+.halt:
+    cli
+    hlt
+    jmp .halt
@@ -0,0 +1,326 @@
+//! Kernel threads, scheduled cooperatively: a thread runs until it yields,
+//! parks or exits.
+//
+// The code each CPU boots in becomes its idle thread, which runs when no
+// other thread can and halts the CPU. The other threads are shared by the
+// CPUs, whichever schedules takes the next runnable one of the thread
+// table, round robin. A CPU halted in its idle thread only notices a
+// thread made runnable at its next interrupt, the system tick for the
+// bootstrap processor.
+//
+// A switch pushes the callee-saved registers of the running thread on its
+// stack, saves its stack pointer and loads the one of the next thread (see
+// switch.asm). The thread switched out can't run elsewhere before its
+// stack pointer is saved, so it's marked as switching until the next
+// thread, running, clears it.
+//
+// The thread table is locked with interrupts disabled, interrupt handlers
+// can unpark threads.
+
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use spin::Mutex;
+use arch::fpu::{self, FpuContext};
+use arch::interrupts;
+use memory::{self, Frame, Zone, PAGE_SIZE};
+
+/// Most threads, the idle ones included.
+const MAX_THREADS: usize = 64;
+
+/// The stacks are 2^STACK_ORDER frames.
+const STACK_ORDER: usize = 2;
+
+/// Registers popped by `switch_stacks`, before its return address.
+const SAVED_REGISTERS: usize = 6;
+
+pub type ThreadId = usize;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// The slot is unused.
+    Free,
+    Runnable,
+    Running,
+    /// Waiting to be unparked.
+    Parked,
+    /// Done, freed once switched out.
+    Exited,
+}
+
+#[derive(Clone, Copy)]
+struct Thread {
+    state: State,
+    /// Idle threads only run on their CPU, when nothing else can.
+    idle: bool,
+    /// Switched out, but its stack pointer isn't saved yet.
+    switching: bool,
+    /// Set by `unpark` when not parked, consumed by the next `park`.
+    permit: bool,
+    rsp: usize,
+    /// The bottom of the stack, 0 for the idle threads.
+    stack: usize,
+    fpu: *mut FpuContext,
+    entry: Option<fn(usize)>,
+    argument: usize,
+}
+
+impl Thread {
+    const fn new() -> Thread {
+        Thread {
+            state: State::Free,
+            idle: false,
+            switching: false,
+            permit: false,
+            rsp: 0,
+            stack: 0,
+            fpu: 0 as *mut FpuContext,
+            entry: None,
+            argument: 0,
+        }
+    }
+
+    /// Whether another CPU can run it.
+    fn can_run(&self) -> bool {
+        self.state == State::Runnable && !self.idle && !self.switching
+    }
+}
+
+struct Threads {
+    threads: [Thread; MAX_THREADS],
+}
+
+unsafe impl Send for Threads {}
+
+static THREADS: Mutex<Threads> = Mutex::new(Threads { threads: [Thread::new(); MAX_THREADS] });
+
+/// Where the round robin resumes.
+static NEXT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+per_cpu! {
+    /// The thread running on the CPU.
+    static CURRENT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// The idle thread of the CPU.
+    static IDLE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// The thread the CPU switched out last, for `finish_switch`.
+    static PREVIOUS: AtomicUsize = ATOMIC_USIZE_INIT;
+}
+
+// Defined in switch.asm.
+extern "C" {
+    fn switch_stacks(old_rsp: *mut usize, new_rsp: usize);
+    fn thread_start();
+}
+
+impl Threads {
+    /// A free slot.
+    fn free(&self) -> Option<ThreadId> {
+        self.threads.iter().position(|thread| thread.state == State::Free)
+    }
+
+    /// The next thread to run instead of `current`, if any.
+    fn pick(&self, current: ThreadId) -> Option<ThreadId> {
+        let start = NEXT.load(Ordering::Relaxed);
+        for i in 1..MAX_THREADS + 1 {
+            let id = (start + i) % MAX_THREADS;
+            if id != current && self.threads[id].can_run() {
+                NEXT.store(id, Ordering::Relaxed);
+                return Some(id);
+            }
+        }
+        None
+    }
+}
+
+/// The running thread.
+pub fn current() -> ThreadId {
+    CURRENT.get().load(Ordering::Relaxed)
+}
+
+/// Make the code running on this CPU its idle thread. Needs its FPU.
+pub fn init_cpu() {
+    interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let id = threads.free().expect("No thread left for the idle thread");
+        threads.threads[id] = Thread {
+            state: State::Running,
+            idle: true,
+            fpu: fpu::current(),
+            ..Thread::new()
+        };
+        CURRENT.get().store(id, Ordering::Relaxed);
+        IDLE.get().store(id, Ordering::Relaxed);
+    });
+}
+
+/// Start a thread running `entry(argument)`. Return `None` if there are
+/// too many threads or not enough memory.
+pub fn spawn(entry: fn(usize), argument: usize) -> Option<ThreadId> {
+    let fpu = match fpu::allocate() {
+        Some(fpu) => fpu,
+        None => return None,
+    };
+    let stack = match memory::allocate_frames(STACK_ORDER, Zone::Normal) {
+        Some(frame) => memory::phys_to_virt(frame.start_address()),
+        None => {
+            fpu::release(fpu);
+            return None;
+        }
+    };
+    let top = stack + (PAGE_SIZE << STACK_ORDER);
+    // What `switch_stacks` pops: zeroed registers, then `thread_start` as
+    // the return address. `thread_entry` is called with an aligned stack.
+    let rsp = top - (SAVED_REGISTERS + 1) * 8;
+    unsafe {
+        ptr::write_bytes(rsp as *mut u64, 0, SAVED_REGISTERS);
+        *((top - 8) as *mut u64) = thread_start as usize as u64;
+    }
+
+    let id = interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let id = threads.free();
+        if let Some(id) = id {
+            threads.threads[id] = Thread {
+                state: State::Runnable,
+                rsp: rsp,
+                stack: stack,
+                fpu: fpu,
+                entry: Some(entry),
+                argument: argument,
+                ..Thread::new()
+            };
+        }
+        id
+    });
+    if id.is_none() {
+        fpu::release(fpu);
+        memory::deallocate_frames(Frame::containing_address(memory::virt_to_phys(stack)),
+                                  STACK_ORDER);
+    }
+    id
+}
+
+/// Switch to the next thread, leaving the running one in `state`, and
+/// return once switched back. Return `false` without switching if the
+/// running thread should go on. Interrupts must be disabled.
+unsafe fn schedule(state: State) -> bool {
+    let current = current();
+    let (next, old_rsp, new_rsp, fpu) = {
+        let mut threads = THREADS.lock();
+        if state == State::Parked && threads.threads[current].permit {
+            threads.threads[current].permit = false;
+            return false;
+        }
+        let next = match threads.pick(current) {
+            Some(next) => next,
+            // Nothing else to run, a runnable thread goes on.
+            None if state == State::Runnable => return false,
+            None => IDLE.get().load(Ordering::Relaxed),
+        };
+        assert!(next != current, "The idle thread can't park or exit");
+
+        threads.threads[current].state = state;
+        threads.threads[current].switching = true;
+        threads.threads[next].state = State::Running;
+        let old_rsp = &mut threads.threads[current].rsp as *mut usize;
+        let thread = threads.threads[next];
+        (next, old_rsp, thread.rsp, thread.fpu)
+    };
+
+    PREVIOUS.get().store(current, Ordering::Relaxed);
+    CURRENT.get().store(next, Ordering::Relaxed);
+    fpu::switch_to(fpu);
+    switch_stacks(old_rsp, new_rsp);
+
+    // Switched back, maybe on another CPU.
+    finish_switch();
+    true
+}
+
+/// Let the thread this CPU switched out run elsewhere, its stack pointer
+/// being saved, or free it if it exited.
+fn finish_switch() {
+    let previous = PREVIOUS.get().load(Ordering::Relaxed);
+    let (stack, fpu) = {
+        let mut threads = THREADS.lock();
+        let thread = &mut threads.threads[previous];
+        thread.switching = false;
+        if thread.state != State::Exited {
+            return;
+        }
+        thread.state = State::Free;
+        (thread.stack, thread.fpu)
+    };
+    fpu::release(fpu);
+    memory::deallocate_frames(Frame::containing_address(memory::virt_to_phys(stack)),
+                              STACK_ORDER);
+}
+
+/// Where new threads start, called by `thread_start` with interrupts
+/// disabled.
+#[no_mangle]
+pub extern "C" fn thread_entry() -> ! {
+    finish_switch();
+    let (entry, argument) = {
+        let threads = THREADS.lock();
+        let thread = &threads.threads[current()];
+        (thread.entry, thread.argument)
+    };
+    unsafe { interrupts::enable(); }
+    if let Some(entry) = entry {
+        entry(argument);
+    }
+    exit();
+}
+
+/// Let the other threads run. The running one goes on if there are none.
+pub fn yield_now() {
+    interrupts::without_interrupts(|| unsafe { schedule(State::Runnable); });
+}
+
+/// Wait until `unpark` is called for the running thread, unless it was
+/// since the last `park`. It may also return early.
+pub fn park() {
+    interrupts::without_interrupts(|| unsafe { schedule(State::Parked); });
+}
+
+/// Make `thread` runnable if parked, otherwise its next `park` returns at
+/// once. Can be called from interrupt handlers.
+pub fn unpark(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let thread = &mut threads.threads[id];
+        if thread.state == State::Parked {
+            thread.state = State::Runnable;
+        } else {
+            thread.permit = true;
+        }
+    });
+}
+
+/// End the running thread.
+pub fn exit() -> ! {
+    unsafe {
+        interrupts::disable();
+        schedule(State::Exited);
+    }
+    unreachable!("Exited thread switched back to");
+}
+
+/// Run the other threads, halting the CPU while there are none. Where the
+/// idle threads end up.
+pub fn idle() -> ! {
+    loop {
+        unsafe {
+            interrupts::disable();
+            if schedule(State::Runnable) {
+                interrupts::enable();
+            } else {
+                // Interrupts are enabled after the next instruction, no
+                // interrupt is lost before halting.
+                asm!("sti; hlt" :::: "volatile");
+            }
+        }
+    }
+}
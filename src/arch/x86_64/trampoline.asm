@@ -0,0 +1,105 @@
+;;; Startup code of the application processors (APs), see smp.rs.
+;;;
+;;; The startup IPI starts an AP in real mode at vector * 4 KiB, so this code
+;;; is copied to TRAMPOLINE_BASE and runs there, not where it's linked. It
+;;; switches to protected mode then to long mode with the page tables given
+;;; in trampoline_data, which identity map the trampoline, and calls the Rust
+;;; entry point with the CPU number on the stack prepared for the AP.
+
+global trampoline_start
+global trampoline_end
+global trampoline_data
+
+;;; Must match smp::TRAMPOLINE.
+TRAMPOLINE_BASE equ 0x8000
+
+;;; Linear address of a trampoline label once copied.
+%define ADDRESS(label) (TRAMPOLINE_BASE + (label) - trampoline_start)
+
+CODE64 equ gdt.code64 - gdt
+DATA equ gdt.data - gdt
+CODE32 equ gdt.code32 - gdt
+
+section .rodata
+bits 16
+trampoline_start:
+    cli
+    cld
+    ; cs is TRAMPOLINE_BASE / 16, address the trampoline data through ds
+    mov ax, cs
+    mov ds, ax
+
+    o32 lgdt [gdt.pointer - trampoline_start]
+
+    mov eax, cr0
+    or eax, 1                       ; protection enable
+    mov cr0, eax
+    jmp dword CODE32:ADDRESS(protected_mode)
+
+bits 32
+protected_mode:
+    mov ax, DATA
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    ; PAE, needed for long mode
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+
+    mov eax, [ADDRESS(trampoline_data.cr3)]
+    mov cr3, eax
+
+    ; long mode, and no-execute if the kernel uses it
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, [ADDRESS(trampoline_data.efer)]
+    wrmsr
+
+    ; paging, and read only pages read only for the kernel too
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16)
+    mov cr0, eax
+
+    jmp CODE64:ADDRESS(long_mode)
+
+bits 64
+long_mode:
+    mov rsp, [ADDRESS(trampoline_data.stack)]
+    mov rdi, [ADDRESS(trampoline_data.cpu)]
+    mov rsi, [ADDRESS(trampoline_data.attempt)]
+    mov rax, [ADDRESS(trampoline_data.entry)]
+    call rax
+
+    ; the entry point never returns
+.halt:
+    cli
+    hlt
+    jmp .halt
+
+;;; Flat segments. The code and data selectors are the ones of the boot GDT,
+;;; so the kernel GDT can be loaded without reloading cs.
+align 8
+gdt:
+    dq 0
+.code64:
+    dq 0x00AF9A000000FFFF
+.data:
+    dq 0x00CF92000000FFFF
+.code32:
+    dq 0x00CF9A000000FFFF
+.pointer:
+    dw $ - gdt - 1
+    dd ADDRESS(gdt)
+
+;;; Filled by smp.rs before starting each AP, see `TrampolineData`.
+align 8
+trampoline_data:
+.cr3: dq 0      ; physical address of the P4, below 4 GiB
+.efer: dq 0     ; bits to set in IA32_EFER
+.stack: dq 0    ; top of the stack of the AP
+.entry: dq 0    ; fn(cpu: usize, attempt: usize) -> !
+.cpu: dq 0      ; arguments of the entry point
+.attempt: dq 0
+trampoline_end:
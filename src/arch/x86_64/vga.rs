@@ -5,6 +5,7 @@ use core::fmt::{Write, Result};
 use core::ptr::Unique;
 use spin::Mutex;
 use arch::cpuio::Port;
+use memory;
 
 const HEIGHT: usize = 25;
 const WIDTH: usize = 80;
@@ -81,6 +82,12 @@ impl Screen {
         self
     }
 
+    /// Set the current foreground color, keeping the background.
+    pub fn set_foreground(&mut self, color: Color) -> &mut Self {
+        self.colors = ColorCode((self.colors.0 & 0xF0) | color as u8);
+        self
+    }
+
     /// Write the string `s` to screen.
     pub fn write(&mut self, s: &str) {
         self.write_bytes(s.as_bytes())
@@ -177,7 +184,7 @@ impl Cursor {
 pub static SCREEN: Mutex<Screen> = Mutex::new(Screen {
     col: 0,
     colors: ColorCode::new(Color::White, Color::Black),
-    buffer: unsafe { Unique::new(0xb8000 as *mut _) },
+    buffer: unsafe { Unique::new(memory::phys_to_virt(0xb8000) as *mut _) },
 });
 
 pub static CURSOR: Mutex<Cursor> = Mutex::new(Cursor {
@@ -0,0 +1,61 @@
+//! The work queue: jobs too long for a softirq, run by kernel worker
+//! threads, which may block.
+//
+// Queueing wakes all the workers, those finding the queue empty park again.
+// There are few of them, one per CPU. The other CPUs only notice runnable
+// threads at their next interrupt, they are sent one.
+
+use core::cmp;
+use spin::Mutex;
+use arch::interrupts;
+use arch::ipi;
+use arch::smp;
+use arch::softirq::{Job, Jobs};
+use arch::thread::{self, ThreadId};
+
+/// Most worker threads.
+const MAX_WORKERS: usize = 8;
+
+/// The work queued, locked with interrupts disabled.
+static QUEUE: Mutex<Jobs> = Mutex::new(Jobs::new());
+
+static WORKERS: Mutex<[Option<ThreadId>; MAX_WORKERS]> = Mutex::new([None; MAX_WORKERS]);
+
+/// Run `function(argument)` in a worker thread. Can be called from
+/// interrupt handlers. Return `false` if too much work is queued.
+pub fn queue_work(function: fn(usize), argument: usize) -> bool {
+    let job = Job { function: function, argument: argument };
+    interrupts::without_interrupts(|| {
+        if !QUEUE.lock().push(job) {
+            return false;
+        }
+        for worker in WORKERS.lock().iter().filter_map(|worker| *worker) {
+            thread::unpark(worker);
+        }
+        ipi::reschedule_others();
+        true
+    })
+}
+
+fn worker(_: usize) {
+    loop {
+        match interrupts::without_interrupts(|| QUEUE.lock().pop()) {
+            Some(job) => job.run(),
+            None => thread::park(),
+        }
+    }
+}
+
+/// Start the worker threads, one per CPU online. Needs the APs started.
+pub fn init() {
+    let count = cmp::min(cmp::max(smp::online(), 1), MAX_WORKERS);
+    for i in 0..count {
+        match thread::spawn(worker, 0) {
+            Some(id) => interrupts::without_interrupts(|| WORKERS.lock()[i] = Some(id)),
+            None => {
+                warn!("No memory for the worker threads, {} started", i);
+                break;
+            }
+        }
+    }
+}
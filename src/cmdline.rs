@@ -0,0 +1,302 @@
+//! Kernel command line parsing.
+//!
+//! The command line is a list of space separated arguments, either flags
+//! (`noapic`) or `key=value` pairs (`loglevel=debug`). Double quotes allow
+//! spaces in values (`key="a b"`). Subsystems declare the options they
+//! accept as `Param`s with `param!`, which collects them in the `.params`
+//! section, and read their value with the typed accessors. Options nobody
+//! declared are reported by `check`.
+
+use core::fmt;
+use core::mem::size_of;
+use core::slice;
+use spin::Mutex;
+use multiboot2::BootInformation;
+
+/// Maximum number of arguments kept, the others are dropped.
+const MAX_ARGUMENTS: usize = 32;
+
+/// The type of value an option takes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// No value, the option is either present or not.
+    Flag,
+    /// `1`, `y`, `yes`, `on`, `true` or `0`, `n`, `no`, `off`, `false`.
+    Bool,
+    /// An unsigned decimal or `0x` prefixed hexadecimal number, optionally
+    /// followed by a `K`, `M` or `G` multiplier.
+    Integer,
+    /// Any string.
+    String,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match *self {
+            Kind::Flag => "flag",
+            Kind::Bool => "bool",
+            Kind::Integer => "integer",
+            Kind::String => "string",
+        })
+    }
+}
+
+/// A command line option accepted by the kernel.
+pub struct Param {
+    pub name: &'static str,
+    pub kind: Kind,
+    /// The value used when the option is absent or invalid.
+    pub default: &'static str,
+    pub help: &'static str,
+}
+
+param! {
+    /// Print the list of known options at boot.
+    pub static HELP: Param = Param {
+        name: "help",
+        kind: Kind::Flag,
+        default: "",
+        help: "List the command line options known to the kernel",
+    };
+}
+
+extern "C" {
+    static __params_start: u8;
+    static __params_end: u8;
+}
+
+/// All the options declared with `param!`, wherever they are.
+fn known_params() -> &'static [Param] {
+    unsafe {
+        let start = &__params_start as *const u8 as usize;
+        let end = &__params_end as *const u8 as usize;
+        slice::from_raw_parts(start as *const Param, (end - start) / size_of::<Param>())
+    }
+}
+
+/// A single argument, as found on the command line.
+#[derive(Clone, Copy)]
+struct Argument {
+    key: &'static str,
+    value: Option<&'static str>,
+}
+
+/// The parsed command line.
+#[derive(Clone, Copy)]
+struct CommandLine {
+    arguments: [Option<Argument>; MAX_ARGUMENTS],
+    /// Arguments dropped because there were too many.
+    dropped: usize,
+}
+
+impl CommandLine {
+    /// Split `command_line` into arguments.
+    fn parse(command_line: &'static str) -> CommandLine {
+        let mut parsed = CommandLine { arguments: [None; MAX_ARGUMENTS], dropped: 0 };
+        let bytes = command_line.as_bytes();
+        let mut count = 0;
+        let mut i = 0;
+
+        while i < bytes.len() {
+            if bytes[i] == b' ' {
+                i += 1;
+                continue;
+            }
+
+            let start = i;
+            let mut in_quotes = false;
+            while i < bytes.len() && (in_quotes || bytes[i] != b' ') {
+                if bytes[i] == b'"' {
+                    in_quotes = !in_quotes;
+                }
+                i += 1;
+            }
+
+            if count == MAX_ARGUMENTS {
+                parsed.dropped += 1;
+                continue;
+            }
+            parsed.arguments[count] = Some(Argument::parse(&command_line[start..i]));
+            count += 1;
+        }
+        parsed
+    }
+
+    /// The value of the last occurrence of `name`, `Some(None)` for a flag.
+    fn find(&self, name: &str) -> Option<Option<&'static str>> {
+        self.arguments.iter()
+            .filter_map(|a| *a)
+            .filter(|a| a.key == name)
+            .last()
+            .map(|a| a.value)
+    }
+}
+
+impl Argument {
+    /// Split an argument into key and value at the first `=` outside
+    /// quotes, and remove the quotes.
+    fn parse(argument: &'static str) -> Argument {
+        let mut in_quotes = false;
+        for (i, b) in argument.bytes().enumerate() {
+            match b {
+                b'"' => in_quotes = !in_quotes,
+                b'=' if !in_quotes => return Argument {
+                    key: unquote(&argument[..i]),
+                    value: Some(unquote(&argument[i + 1..])),
+                },
+                _ => {}
+            }
+        }
+        Argument { key: unquote(argument), value: None }
+    }
+}
+
+/// Remove the double quotes around `s`, if any.
+fn unquote(s: &'static str) -> &'static str {
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        &s[1..s.len() - 1]
+    } else {
+        s
+    }
+}
+
+static COMMAND_LINE: Mutex<CommandLine> = Mutex::new(CommandLine {
+    arguments: [None; MAX_ARGUMENTS],
+    dropped: 0,
+});
+
+/// Parse the command line passed by the bootloader. Nothing is printed
+/// here, so this can run before the console is set up; see `check`.
+pub fn init(boot_info: &BootInformation) {
+    if let Some(command_line) = boot_info.command_line() {
+        *COMMAND_LINE.lock() = CommandLine::parse(command_line);
+    }
+}
+
+/// Warn about unknown options and invalid values, and print the list of
+/// known options if asked to.
+pub fn check() {
+    let command_line = *COMMAND_LINE.lock();
+
+    for argument in command_line.arguments.iter().filter_map(|a| *a) {
+        match known_params().iter().find(|p| p.name == argument.key) {
+            None => warn!("Unknown command line option `{}`", argument.key),
+            Some(param) => if !is_valid(param.kind, argument.value) {
+                warn!("Invalid value for command line option `{}` ({}), using `{}`",
+                      param.name, param.kind, param.default);
+            },
+        }
+    }
+
+    if command_line.dropped > 0 {
+        warn!("Too many command line options, {} ignored", command_line.dropped);
+    }
+
+    if flag(&HELP) {
+        print_help();
+    }
+}
+
+/// Print the known options, with their type, default and description.
+pub fn print_help() {
+    println!("Kernel command line options:");
+    for param in known_params() {
+        println!("  {:12} {:8} (default `{}`) {}", param.name, param.kind, param.default, param.help);
+    }
+}
+
+/// Whether `value` is acceptable for an option of type `kind`.
+fn is_valid(kind: Kind, value: Option<&str>) -> bool {
+    match (kind, value) {
+        (Kind::Flag, None) => true,
+        (Kind::Bool, Some(value)) => parse_bool(value).is_some(),
+        (Kind::Integer, Some(value)) => parse_integer(value).is_some(),
+        (Kind::String, Some(_)) => true,
+        _ => false,
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "1" | "y" | "yes" | "on" | "true" => Some(true),
+        "0" | "n" | "no" | "off" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_integer(value: &str) -> Option<u64> {
+    let (value, multiplier) = match value.as_bytes().last() {
+        Some(&b'K') | Some(&b'k') => (&value[..value.len() - 1], 1 << 10),
+        Some(&b'M') | Some(&b'm') => (&value[..value.len() - 1], 1 << 20),
+        Some(&b'G') | Some(&b'g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    let number = if value.starts_with("0x") {
+        u64::from_str_radix(&value[2..], 16)
+    } else {
+        u64::from_str_radix(value, 10)
+    };
+    number.ok().and_then(|n| n.checked_mul(multiplier))
+}
+
+/// The value given for `param`, if valid.
+fn value(param: &Param) -> Option<&'static str> {
+    match COMMAND_LINE.lock().find(param.name) {
+        Some(value) if is_valid(param.kind, value) => value,
+        _ => None,
+    }
+}
+
+/// Whether the flag `param` is on the command line.
+pub fn flag(param: &Param) -> bool {
+    COMMAND_LINE.lock().find(param.name) == Some(None)
+}
+
+/// The value of the boolean option `param`.
+pub fn boolean(param: &Param) -> bool {
+    value(param).and_then(parse_bool)
+        .or_else(|| parse_bool(param.default))
+        .unwrap_or(false)
+}
+
+/// The value of the integer option `param`.
+pub fn integer(param: &Param) -> u64 {
+    value(param).and_then(parse_integer)
+        .or_else(|| parse_integer(param.default))
+        .unwrap_or(0)
+}
+
+/// The value of the string option `param`.
+pub fn string(param: &Param) -> &'static str {
+    value(param).unwrap_or(param.default)
+}
+
+/// All the values given for `param`, for options that can be repeated
+/// (i.e. `console=`).
+pub fn values(param: &'static Param) -> Values {
+    Values { command_line: *COMMAND_LINE.lock(), param: param, index: 0 }
+}
+
+/// An iterator over the values of a repeated option.
+pub struct Values {
+    command_line: CommandLine,
+    param: &'static Param,
+    index: usize,
+}
+
+impl Iterator for Values {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        while self.index < MAX_ARGUMENTS {
+            let argument = self.command_line.arguments[self.index];
+            self.index += 1;
+            if let Some(Argument { key, value: Some(value) }) = argument {
+                if key == self.param.name {
+                    return Some(value);
+                }
+            }
+        }
+        None
+    }
+}
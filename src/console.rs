@@ -1,16 +1,430 @@
-//! A wrapper around a VGA console and a COM1 serial port.serial
+//! The kernel console, a registry of output sinks.
+//!
+//! Everything printed goes to each registered `ConsoleSink` whose minimum
+//! level allows it. The sinks registered at boot are chosen with `console=`
+//! on the command line, `tty0` being the display (a text console drawn on a
+//! linear framebuffer when one is available, the VGA text screen otherwise)
+//! and `ttyS0`/`ttyS1` the COM1/COM2 serial ports. Without it, both the
+//! display and COM1 are used.
+//!
+//! The registry and the sinks are used with interrupts disabled, so an
+//! interrupt handler printing can't wait for a lock held by the code it
+//! interrupted. Exceptions and NMIs can't be held off: what they print
+//! while their CPU is printing already is dropped.
 
-use core::fmt::{Write, Result};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 use spin::Mutex;
-use arch::{vga, serial};
+use arch::{vga, serial, bga, interrupts};
+use arch::vga::Color;
+use arch::serial::SerialPort;
+use cmdline::{self, Param, Kind};
+use fbcon::{self, FramebufferConsole};
+use font;
+use framebuffer::{Framebuffer, PixelLayout, Channel};
+use memory::{self, vm};
+use multiboot2::{BootInformation, FramebufferType};
 
-pub struct Console;
+/// Framebuffers must be in the physical memory window of the boot page
+/// tables until `remap_framebuffer`.
+const MAPPED_MEMORY_END: u64 = memory::MAX_PHYSICAL_ADDRESS as u64;
 
-impl Write for Console {
-    fn write_str(&mut self, s: &str) -> Result {
-        try!(vga::SCREEN.lock().write_str(s));
-        serial::COM1.lock().write_str(s)
+/// How many sinks can be registered at the same time.
+const MAX_SINKS: usize = 8;
+
+/// Text color used when no level color is in effect.
+const DEFAULT_COLOR: Color = Color::White;
+
+param! {
+    pub static CONSOLE: Param = Param {
+        name: "console",
+        kind: Kind::String,
+        default: "",
+        help: "Output device, `tty0` or `ttyS<n>[,<baud>]`, can be repeated",
+    };
+}
+
+/// Severity of console output, from the most verbose to the most severe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Trace = 0,
+    Debug = 1,
+    Info = 2,
+    Warn = 3,
+    Error = 4,
+}
+
+impl Level {
+    /// Parse a level from its lowercase name, i.e. `warn`.
+    pub fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "trace" => Some(Level::Trace),
+            "debug" => Some(Level::Debug),
+            "info" => Some(Level::Info),
+            "warn" => Some(Level::Warn),
+            "error" => Some(Level::Error),
+            _ => None,
+        }
+    }
+
+    /// The uppercase name of the level, as shown in log records.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Level::Trace => "TRACE",
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        }
+    }
+
+    /// The text color for messages of this level on color capable sinks.
+    fn color(&self) -> Color {
+        match *self {
+            Level::Trace => Color::DarkGrey,
+            Level::Debug => Color::LightGrey,
+            Level::Info => DEFAULT_COLOR,
+            Level::Warn => Color::Yellow,
+            Level::Error => Color::LightRed,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// A destination for console output.
+///
+/// Sinks are shared by everything printing, so they take care of their
+/// own locking.
+pub trait ConsoleSink: Sync {
+    /// Write `s` to the sink.
+    fn write_str(&self, s: &str);
+
+    /// Change the color of the text written from now on, if supported.
+    fn set_color(&self, _color: Color) {}
+
+    /// Go back to the default color.
+    fn reset_color(&self) {
+        self.set_color(DEFAULT_COLOR);
+    }
+}
+
+/// Reasons for failing to register a sink.
+#[derive(Debug)]
+pub enum Error {
+    /// A sink with the same name is already registered.
+    AlreadyRegistered,
+    /// No room left in the registry.
+    Full,
+}
+
+/// A sink in the registry, with its settings.
+#[derive(Clone, Copy)]
+struct Registration {
+    name: &'static str,
+    sink: &'static ConsoleSink,
+    min_level: Level,
+    color: bool,
+}
+
+static SINKS: Mutex<[Option<Registration>; MAX_SINKS]> = Mutex::new([None; MAX_SINKS]);
+
+per_cpu! {
+    /// Whether the CPU is writing to the sinks.
+    static PRINTING: AtomicBool = ATOMIC_BOOL_INIT;
+}
+
+/// Add `sink` to the console under `name`. Only output of at least
+/// `min_level` reaches it, colored by level if `color` is set.
+pub fn register(name: &'static str, sink: &'static ConsoleSink, min_level: Level, color: bool)
+                -> Result<(), Error> {
+    interrupts::without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+        if sinks.iter().any(|r| r.map_or(false, |r| r.name == name)) {
+            return Err(Error::AlreadyRegistered);
+        }
+
+        match sinks.iter_mut().find(|r| r.is_none()) {
+            Some(slot) => {
+                *slot = Some(Registration {
+                    name: name,
+                    sink: sink,
+                    min_level: min_level,
+                    color: color,
+                });
+                Ok(())
+            }
+            None => Err(Error::Full),
+        }
+    })
+}
+
+/// Remove the sink registered as `name`. Return whether it was found.
+pub fn unregister(name: &str) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+        match sinks.iter_mut().find(|r| r.map_or(false, |r| r.name == name)) {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Change the minimum level of the sink registered as `name`.
+/// Return whether it was found.
+pub fn set_level(name: &str, min_level: Level) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+        for registration in sinks.iter_mut() {
+            if let Some(ref mut registration) = *registration {
+                if registration.name == name {
+                    registration.min_level = min_level;
+                    return true;
+                }
+            }
+        }
+        false
+    })
+}
+
+/// Run `f` on the registered sinks, unless the running CPU is using them
+/// already.
+fn with_sinks<F: FnOnce(&[Option<Registration>; MAX_SINKS])>(f: F) {
+    interrupts::without_interrupts(|| {
+        let printing = PRINTING.get();
+        if printing.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        f(&SINKS.lock());
+        printing.store(false, Ordering::Relaxed);
+    });
+}
+
+/// Write formatted output of severity `level` to every sink accepting it.
+pub fn print(level: Level, args: fmt::Arguments) {
+    with_sinks(|sinks| {
+        for registration in sinks.iter().filter_map(|r| *r) {
+            if level < registration.min_level {
+                continue;
+            }
+
+            if registration.color {
+                registration.sink.set_color(level.color());
+            }
+            let _ = SinkWriter(registration.sink).write_fmt(args);
+            if registration.color {
+                registration.sink.reset_color();
+            }
+        }
+    });
+}
+
+/// Write formatted output to the sink registered as `name` only, whatever
+/// its level.
+pub fn print_to(name: &str, args: fmt::Arguments) {
+    with_sinks(|sinks| {
+        if let Some(registration) = sinks.iter().filter_map(|r| *r).find(|r| r.name == name) {
+            let _ = SinkWriter(registration.sink).write_fmt(args);
+        }
+    });
+}
+
+/// Adapter to use the formatting machinery on a sink.
+struct SinkWriter(&'static ConsoleSink);
+
+impl Write for SinkWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s);
+        Ok(())
+    }
+}
+
+impl ConsoleSink for Mutex<vga::Screen> {
+    fn write_str(&self, s: &str) {
+        self.lock().write(s);
+    }
+
+    fn set_color(&self, color: Color) {
+        self.lock().set_foreground(color);
+    }
+}
+
+impl ConsoleSink for Mutex<Option<FramebufferConsole>> {
+    fn write_str(&self, s: &str) {
+        if let Some(ref mut screen) = *self.lock() {
+            screen.write(s);
+        }
+    }
+
+    fn set_color(&self, color: Color) {
+        if let Some(ref mut screen) = *self.lock() {
+            screen.set_foreground(fbcon::vga_to_rgb(color));
+        }
+    }
+}
+
+/// Serial ports get ANSI escape sequences for colors.
+impl ConsoleSink for Mutex<SerialPort> {
+    fn write_str(&self, s: &str) {
+        let _ = self.lock().write_str(s);
+    }
+
+    fn set_color(&self, color: Color) {
+        let code = match color {
+            Color::Black => 30,
+            Color::Red => 31,
+            Color::Green => 32,
+            Color::Brown => 33,
+            Color::Blue => 34,
+            Color::Magenta => 35,
+            Color::Cyan => 36,
+            Color::LightGrey => 37,
+            Color::DarkGrey => 90,
+            Color::LightRed => 91,
+            Color::LightGreen => 92,
+            Color::Yellow => 93,
+            Color::LightBlue => 94,
+            Color::LightMagenta => 95,
+            Color::LightCyan => 96,
+            Color::White => 97,
+        };
+        let _ = write!(self.lock(), "\x1b[{}m", code);
+    }
+
+    /// The terminal's own default, which may not be white.
+    fn reset_color(&self) {
+        let _ = self.lock().write_str("\x1b[0m");
+    }
+}
+
+/// Register the sinks asked for with `console=`, or the display and COM1.
+pub fn init(boot_info: &BootInformation) {
+    let mut display = false;
+    let mut configured = false;
+
+    for value in cmdline::values(&CONSOLE) {
+        configured = true;
+        let (device, options) = split_console(value);
+        match device {
+            "tty0" => display = true,
+            "ttyS0" => add_serial("ttyS0", &serial::COM1, options),
+            "ttyS1" => add_serial("ttyS1", &serial::COM2, options),
+            _ => {}
+        }
+    }
+
+    if !configured {
+        add_serial("ttyS0", &serial::COM1, None);
+        display = true;
+    }
+
+    if display {
+        init_display(boot_info);
+    }
+
+    // Now that there's somewhere to print to.
+    for value in cmdline::values(&CONSOLE) {
+        match split_console(value).0 {
+            "tty0" | "ttyS0" | "ttyS1" => {}
+            device => warn!("Unknown console device `{}`", device),
+        }
     }
 }
 
-pub static CONSOLE: Mutex<Console> = Mutex::new(Console);
+/// Split a `console=` value into device name and options.
+fn split_console(value: &'static str) -> (&'static str, Option<&'static str>) {
+    match value.find(',') {
+        Some(comma) => (&value[..comma], Some(&value[comma + 1..])),
+        None => (value, None),
+    }
+}
+
+/// Register a serial port as a sink, `options` starting with the baud rate.
+fn add_serial(name: &'static str, port: &'static Mutex<SerialPort>, options: Option<&str>) {
+    if let Some(options) = options {
+        let digits = options.bytes().position(|b| b < b'0' || b > b'9').unwrap_or(options.len());
+        if let Ok(baud_rate) = options[..digits].parse::<u32>() {
+            port.lock().set_baud_rate(baud_rate);
+        }
+    }
+    let _ = register(name, port, Level::Debug, true);
+}
+
+/// Register the best display available, that is the framebuffer granted by
+/// the bootloader, then a BGA graphics mode, then the VGA text screen.
+fn init_display(boot_info: &BootInformation) {
+    if let Some(framebuffer) = bootloader_framebuffer(boot_info) {
+        use_framebuffer(framebuffer);
+        return;
+    }
+
+    if let Ok(framebuffer) = bga::BGA.lock().set_mode(1024, 768, 32) {
+        use_framebuffer(framebuffer);
+        return;
+    }
+
+    register("vga", &vga::SCREEN, Level::Info, true).expect("vga already registered");
+}
+
+/// The linear framebuffer described by the Multiboot2 framebuffer info tag.
+///
+/// EGA text mode is already handled by `vga::Screen`, indexed modes and
+/// unusual depths are not supported, all return `None`.
+fn bootloader_framebuffer(boot_info: &BootInformation) -> Option<Framebuffer> {
+    let tag = match boot_info.framebuffer_tag() {
+        Some(tag) => tag,
+        None => return None,
+    };
+
+    let layout = match tag.framebuffer_type {
+        FramebufferType::Rgb { red, green, blue } => PixelLayout {
+            red: Channel { shift: red.position, size: red.size },
+            green: Channel { shift: green.position, size: green.size },
+            blue: Channel { shift: blue.position, size: blue.size },
+        },
+        FramebufferType::Indexed { .. } | FramebufferType::EgaText => return None,
+    };
+
+    let size = tag.pitch as u64 * tag.height as u64;
+    if tag.address + size > MAPPED_MEMORY_END {
+        return None;
+    }
+
+    unsafe {
+        Framebuffer::new(memory::phys_to_virt(tag.address as usize), tag.width as usize, tag.height as usize,
+                         tag.pitch as usize, tag.bpp as usize, layout)
+    }
+}
+
+/// Move the framebuffer console, drawn through the physical memory window
+/// of the boot page tables, to an uncached device mapping. Needs the
+/// kernel page tables.
+pub fn remap_framebuffer() {
+    let mut fbcon = fbcon::FBCON.lock();
+    let framebuffer = match *fbcon {
+        Some(ref mut screen) => screen.framebuffer(),
+        None => return,
+    };
+    let physical = memory::virt_to_phys(framebuffer.base());
+    match vm::map_device(physical, framebuffer.size(), "framebuffer") {
+        Ok(base) => unsafe { framebuffer.move_to(base) },
+        Err(error) => warn!("Failed to map the framebuffer: {:?}", error),
+    }
+}
+
+/// Replace the VGA text screen with a text console drawn on `framebuffer`.
+pub fn use_framebuffer(framebuffer: Framebuffer) {
+    let mut screen = FramebufferConsole::new(framebuffer, font::default());
+    screen.clear();
+    *fbcon::FBCON.lock() = Some(screen);
+
+    unregister("vga");
+    let _ = register("fbcon", &fbcon::FBCON, Level::Info, true);
+}
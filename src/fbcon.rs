@@ -0,0 +1,144 @@
+//! A text console drawn with a bitmap font on a linear framebuffer.
+
+use core::fmt::{Write, Result};
+use spin::Mutex;
+use arch::vga::Color;
+use font::Font;
+use framebuffer::{Framebuffer, Rgb};
+
+/// Map the standard VGA text colors to RGB, so the framebuffer console
+/// can be used wherever a `vga::Screen` is.
+pub fn vga_to_rgb(color: Color) -> Rgb {
+    match color {
+        Color::Black => Rgb(0x00, 0x00, 0x00),
+        Color::Blue => Rgb(0x00, 0x00, 0xAA),
+        Color::Green => Rgb(0x00, 0xAA, 0x00),
+        Color::Cyan => Rgb(0x00, 0xAA, 0xAA),
+        Color::Red => Rgb(0xAA, 0x00, 0x00),
+        Color::Magenta => Rgb(0xAA, 0x00, 0xAA),
+        Color::Brown => Rgb(0xAA, 0x55, 0x00),
+        Color::LightGrey => Rgb(0xAA, 0xAA, 0xAA),
+        Color::DarkGrey => Rgb(0x55, 0x55, 0x55),
+        Color::LightBlue => Rgb(0x55, 0x55, 0xFF),
+        Color::LightGreen => Rgb(0x55, 0xFF, 0x55),
+        Color::LightCyan => Rgb(0x55, 0xFF, 0xFF),
+        Color::LightRed => Rgb(0xFF, 0x55, 0x55),
+        Color::LightMagenta => Rgb(0xFF, 0x55, 0xFF),
+        Color::Yellow => Rgb(0xFF, 0xFF, 0x55),
+        Color::White => Rgb(0xFF, 0xFF, 0xFF),
+    }
+}
+
+/// A framebuffer in character mode.
+pub struct FramebufferConsole {
+    framebuffer: Framebuffer,
+    font: Font,
+    cols: usize,
+    rows: usize,
+    col: usize,
+    row: usize,
+    foreground: Rgb,
+    background: Rgb,
+}
+
+impl FramebufferConsole {
+    /// Create a console covering the whole `framebuffer`.
+    pub fn new(framebuffer: Framebuffer, font: Font) -> Self {
+        let cols = framebuffer.width() / font.width;
+        let rows = framebuffer.height() / font.height;
+        FramebufferConsole {
+            framebuffer: framebuffer,
+            font: font,
+            cols: cols,
+            rows: rows,
+            col: 0,
+            row: 0,
+            foreground: vga_to_rgb(Color::White),
+            background: vga_to_rgb(Color::Black),
+        }
+    }
+
+    /// The framebuffer drawn on.
+    pub fn framebuffer(&mut self) -> &mut Framebuffer {
+        &mut self.framebuffer
+    }
+
+    /// Number of text columns.
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    /// Number of text rows.
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Clear the screen and move to the top left corner.
+    pub fn clear(&mut self) -> &mut Self {
+        let background = self.background;
+        self.framebuffer.clear(background);
+        self.col = 0;
+        self.row = 0;
+        self
+    }
+
+    /// Set current text colors.
+    pub fn set_colors(&mut self, foreground: Rgb, background: Rgb) -> &mut Self {
+        self.foreground = foreground;
+        self.background = background;
+        self
+    }
+
+    /// Set the current foreground color, keeping the background.
+    pub fn set_foreground(&mut self, foreground: Rgb) -> &mut Self {
+        self.foreground = foreground;
+        self
+    }
+
+    /// Write the string `s` to screen.
+    pub fn write(&mut self, s: &str) {
+        for &b in s.as_bytes() {
+            self.write_byte(b);
+        }
+    }
+
+    /// Write a single byte to the screen.
+    pub fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+            b'\r' => self.col = 0,
+            byte => {
+                if self.col >= self.cols {
+                    self.new_line();
+                }
+
+                let (x, y) = (self.col * self.font.width, self.row * self.font.height);
+                let (width, height) = (self.font.width, self.font.height);
+                self.framebuffer.draw_bitmap(x, y, width, height,
+                                             self.font.glyph(byte),
+                                             self.foreground, self.background);
+                self.col += 1;
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            let (lines, background) = (self.font.height, self.background);
+            self.framebuffer.scroll_up(lines, background);
+        }
+    }
+}
+
+impl Write for FramebufferConsole {
+    fn write_str(&mut self, s: &str) -> Result {
+        self.write(s);
+        Ok(())
+    }
+}
+
+/// The framebuffer console, once a graphics mode has been set.
+pub static FBCON: Mutex<Option<FramebufferConsole>> = Mutex::new(None);
@@ -0,0 +1,216 @@
+//! Bitmap fonts for the framebuffer console, either built-in or loaded
+//! from PC Screen Font (PSF1/PSF2) files.
+// Glyphs based on the public domain font8x8 by Daniel Hepper.
+// PSF format: https://www.win.tue.nl/~aeb/linux/kbd/font-formats-1.html
+
+/// Magic bytes and mode flag of a PSF1 header.
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_HEADER_SIZE: usize = 4;
+
+/// Magic bytes of a PSF2 header.
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+const PSF2_HEADER_SIZE: usize = 32;
+
+/// The default console font, 8x16 pixels in PSF1 format.
+static DEFAULT_PSF: &'static [u8] = include_bytes!("fonts/default8x16.psf");
+
+/// A monochrome bitmap font.
+///
+/// Each glyph is `height` rows of `(width + 7) / 8` bytes. In every row
+/// the most significant bit is the leftmost pixel.
+#[derive(Clone, Copy)]
+pub struct Font {
+    /// Glyph width in pixels.
+    pub width: usize,
+    /// Glyph height in pixels.
+    pub height: usize,
+    /// The character code of the first glyph in `glyphs`.
+    first: u8,
+    /// Raw glyph bitmaps, stored back to back.
+    glyphs: &'static [u8],
+}
+
+impl Font {
+    /// Parse a PC Screen Font, version 1 or 2. Unicode translation tables
+    /// are ignored, glyphs are indexed by character code.
+    pub fn from_psf(data: &'static [u8]) -> Option<Font> {
+        if data.len() >= PSF1_HEADER_SIZE && &data[0..2] == &PSF1_MAGIC[..] {
+            let count = if data[2] & PSF1_MODE_512 != 0 { 512 } else { 256 };
+            let height = data[3] as usize;
+            let end = PSF1_HEADER_SIZE + count * height;
+            if height == 0 || data.len() < end {
+                return None;
+            }
+            return Some(Font {
+                width: 8,
+                height: height,
+                first: 0,
+                glyphs: &data[PSF1_HEADER_SIZE..end],
+            });
+        }
+
+        if data.len() >= PSF2_HEADER_SIZE && &data[0..4] == &PSF2_MAGIC[..] {
+            let header_size = read_u32(data, 8) as usize;
+            let count = read_u32(data, 16) as usize;
+            let glyph_size = read_u32(data, 20) as usize;
+            let height = read_u32(data, 24) as usize;
+            let width = read_u32(data, 28) as usize;
+            let end = header_size + count * glyph_size;
+            if width == 0 || height == 0 || glyph_size != ((width + 7) / 8) * height ||
+               data.len() < end {
+                return None;
+            }
+            return Some(Font {
+                width: width,
+                height: height,
+                first: 0,
+                glyphs: &data[header_size..end],
+            });
+        }
+
+        None
+    }
+
+    /// Size in bytes of a single glyph.
+    pub fn bytes_per_glyph(&self) -> usize {
+        ((self.width + 7) / 8) * self.height
+    }
+
+    /// Number of bytes in a single glyph row.
+    pub fn bytes_per_row(&self) -> usize {
+        (self.width + 7) / 8
+    }
+
+    /// Get the bitmap for character `c`, or the one for `?` if the font
+    /// has no glyph for it.
+    pub fn glyph(&self, c: u8) -> &'static [u8] {
+        let size = self.bytes_per_glyph();
+        let count = self.glyphs.len() / size;
+        let index = if c >= self.first && ((c - self.first) as usize) < count {
+            (c - self.first) as usize
+        } else {
+            (b'?' - self.first) as usize
+        };
+        &self.glyphs[index * size..(index + 1) * size]
+    }
+}
+
+/// The default console font, falling back to the built-in 8x8 one.
+pub fn default() -> Font {
+    Font::from_psf(DEFAULT_PSF).unwrap_or(FONT_8X8)
+}
+
+/// Read a little endian `u32` at `offset`.
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    (data[offset] as u32) |
+        (data[offset + 1] as u32) << 8 |
+        (data[offset + 2] as u32) << 16 |
+        (data[offset + 3] as u32) << 24
+}
+
+/// The built-in 8x8 font, covering printable ASCII.
+pub static FONT_8X8: Font = Font {
+    width: 8,
+    height: 8,
+    first: 0x20,
+    glyphs: &FONT_8X8_GLYPHS,
+};
+
+static FONT_8X8_GLYPHS: [u8; 8 * 95] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,   // U+0020 (space)
+    0x18, 0x3C, 0x3C, 0x18, 0x18, 0x00, 0x18, 0x00,   // U+0021 (!)
+    0x6C, 0x6C, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,   // U+0022 (")
+    0x6C, 0x6C, 0xFE, 0x6C, 0xFE, 0x6C, 0x6C, 0x00,   // U+0023 (#)
+    0x30, 0x7C, 0xC0, 0x78, 0x0C, 0xF8, 0x30, 0x00,   // U+0024 ($)
+    0x00, 0xC6, 0xCC, 0x18, 0x30, 0x66, 0xC6, 0x00,   // U+0025 (%)
+    0x38, 0x6C, 0x38, 0x76, 0xDC, 0xCC, 0x76, 0x00,   // U+0026 (&)
+    0x60, 0x60, 0xC0, 0x00, 0x00, 0x00, 0x00, 0x00,   // U+0027 (')
+    0x18, 0x30, 0x60, 0x60, 0x60, 0x30, 0x18, 0x00,   // U+0028 (()
+    0x60, 0x30, 0x18, 0x18, 0x18, 0x30, 0x60, 0x00,   // U+0029 ())
+    0x00, 0x66, 0x3C, 0xFF, 0x3C, 0x66, 0x00, 0x00,   // U+002A (*)
+    0x00, 0x30, 0x30, 0xFC, 0x30, 0x30, 0x00, 0x00,   // U+002B (+)
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x30, 0x30, 0x60,   // U+002C (,)
+    0x00, 0x00, 0x00, 0xFC, 0x00, 0x00, 0x00, 0x00,   // U+002D (-)
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x30, 0x30, 0x00,   // U+002E (.)
+    0x06, 0x0C, 0x18, 0x30, 0x60, 0xC0, 0x80, 0x00,   // U+002F (/)
+    0x7C, 0xC6, 0xCE, 0xDE, 0xF6, 0xE6, 0x7C, 0x00,   // U+0030 (0)
+    0x30, 0x70, 0x30, 0x30, 0x30, 0x30, 0xFC, 0x00,   // U+0031 (1)
+    0x78, 0xCC, 0x0C, 0x38, 0x60, 0xCC, 0xFC, 0x00,   // U+0032 (2)
+    0x78, 0xCC, 0x0C, 0x38, 0x0C, 0xCC, 0x78, 0x00,   // U+0033 (3)
+    0x1C, 0x3C, 0x6C, 0xCC, 0xFE, 0x0C, 0x1E, 0x00,   // U+0034 (4)
+    0xFC, 0xC0, 0xF8, 0x0C, 0x0C, 0xCC, 0x78, 0x00,   // U+0035 (5)
+    0x38, 0x60, 0xC0, 0xF8, 0xCC, 0xCC, 0x78, 0x00,   // U+0036 (6)
+    0xFC, 0xCC, 0x0C, 0x18, 0x30, 0x30, 0x30, 0x00,   // U+0037 (7)
+    0x78, 0xCC, 0xCC, 0x78, 0xCC, 0xCC, 0x78, 0x00,   // U+0038 (8)
+    0x78, 0xCC, 0xCC, 0x7C, 0x0C, 0x18, 0x70, 0x00,   // U+0039 (9)
+    0x00, 0x30, 0x30, 0x00, 0x00, 0x30, 0x30, 0x00,   // U+003A (:)
+    0x00, 0x30, 0x30, 0x00, 0x00, 0x30, 0x30, 0x60,   // U+003B (;)
+    0x18, 0x30, 0x60, 0xC0, 0x60, 0x30, 0x18, 0x00,   // U+003C (<)
+    0x00, 0x00, 0xFC, 0x00, 0x00, 0xFC, 0x00, 0x00,   // U+003D (=)
+    0x60, 0x30, 0x18, 0x0C, 0x18, 0x30, 0x60, 0x00,   // U+003E (>)
+    0x78, 0xCC, 0x0C, 0x18, 0x30, 0x00, 0x30, 0x00,   // U+003F (?)
+    0x7C, 0xC6, 0xDE, 0xDE, 0xDE, 0xC0, 0x78, 0x00,   // U+0040 (@)
+    0x30, 0x78, 0xCC, 0xCC, 0xFC, 0xCC, 0xCC, 0x00,   // U+0041 (A)
+    0xFC, 0x66, 0x66, 0x7C, 0x66, 0x66, 0xFC, 0x00,   // U+0042 (B)
+    0x3C, 0x66, 0xC0, 0xC0, 0xC0, 0x66, 0x3C, 0x00,   // U+0043 (C)
+    0xF8, 0x6C, 0x66, 0x66, 0x66, 0x6C, 0xF8, 0x00,   // U+0044 (D)
+    0xFE, 0x62, 0x68, 0x78, 0x68, 0x62, 0xFE, 0x00,   // U+0045 (E)
+    0xFE, 0x62, 0x68, 0x78, 0x68, 0x60, 0xF0, 0x00,   // U+0046 (F)
+    0x3C, 0x66, 0xC0, 0xC0, 0xCE, 0x66, 0x3E, 0x00,   // U+0047 (G)
+    0xCC, 0xCC, 0xCC, 0xFC, 0xCC, 0xCC, 0xCC, 0x00,   // U+0048 (H)
+    0x78, 0x30, 0x30, 0x30, 0x30, 0x30, 0x78, 0x00,   // U+0049 (I)
+    0x1E, 0x0C, 0x0C, 0x0C, 0xCC, 0xCC, 0x78, 0x00,   // U+004A (J)
+    0xE6, 0x66, 0x6C, 0x78, 0x6C, 0x66, 0xE6, 0x00,   // U+004B (K)
+    0xF0, 0x60, 0x60, 0x60, 0x62, 0x66, 0xFE, 0x00,   // U+004C (L)
+    0xC6, 0xEE, 0xFE, 0xFE, 0xD6, 0xC6, 0xC6, 0x00,   // U+004D (M)
+    0xC6, 0xE6, 0xF6, 0xDE, 0xCE, 0xC6, 0xC6, 0x00,   // U+004E (N)
+    0x38, 0x6C, 0xC6, 0xC6, 0xC6, 0x6C, 0x38, 0x00,   // U+004F (O)
+    0xFC, 0x66, 0x66, 0x7C, 0x60, 0x60, 0xF0, 0x00,   // U+0050 (P)
+    0x78, 0xCC, 0xCC, 0xCC, 0xDC, 0x78, 0x1C, 0x00,   // U+0051 (Q)
+    0xFC, 0x66, 0x66, 0x7C, 0x6C, 0x66, 0xE6, 0x00,   // U+0052 (R)
+    0x78, 0xCC, 0xE0, 0x70, 0x1C, 0xCC, 0x78, 0x00,   // U+0053 (S)
+    0xFC, 0xB4, 0x30, 0x30, 0x30, 0x30, 0x78, 0x00,   // U+0054 (T)
+    0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xFC, 0x00,   // U+0055 (U)
+    0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0x78, 0x30, 0x00,   // U+0056 (V)
+    0xC6, 0xC6, 0xC6, 0xD6, 0xFE, 0xEE, 0xC6, 0x00,   // U+0057 (W)
+    0xC6, 0xC6, 0x6C, 0x38, 0x38, 0x6C, 0xC6, 0x00,   // U+0058 (X)
+    0xCC, 0xCC, 0xCC, 0x78, 0x30, 0x30, 0x78, 0x00,   // U+0059 (Y)
+    0xFE, 0xC6, 0x8C, 0x18, 0x32, 0x66, 0xFE, 0x00,   // U+005A (Z)
+    0x78, 0x60, 0x60, 0x60, 0x60, 0x60, 0x78, 0x00,   // U+005B ([)
+    0xC0, 0x60, 0x30, 0x18, 0x0C, 0x06, 0x02, 0x00,   // U+005C (\)
+    0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0x78, 0x00,   // U+005D (])
+    0x10, 0x38, 0x6C, 0xC6, 0x00, 0x00, 0x00, 0x00,   // U+005E (^)
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF,   // U+005F (_)
+    0x30, 0x30, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00,   // U+0060 (`)
+    0x00, 0x00, 0x78, 0x0C, 0x7C, 0xCC, 0x76, 0x00,   // U+0061 (a)
+    0xE0, 0x60, 0x60, 0x7C, 0x66, 0x66, 0xDC, 0x00,   // U+0062 (b)
+    0x00, 0x00, 0x78, 0xCC, 0xC0, 0xCC, 0x78, 0x00,   // U+0063 (c)
+    0x1C, 0x0C, 0x0C, 0x7C, 0xCC, 0xCC, 0x76, 0x00,   // U+0064 (d)
+    0x00, 0x00, 0x78, 0xCC, 0xFC, 0xC0, 0x78, 0x00,   // U+0065 (e)
+    0x38, 0x6C, 0x60, 0xF0, 0x60, 0x60, 0xF0, 0x00,   // U+0066 (f)
+    0x00, 0x00, 0x76, 0xCC, 0xCC, 0x7C, 0x0C, 0xF8,   // U+0067 (g)
+    0xE0, 0x60, 0x6C, 0x76, 0x66, 0x66, 0xE6, 0x00,   // U+0068 (h)
+    0x30, 0x00, 0x70, 0x30, 0x30, 0x30, 0x78, 0x00,   // U+0069 (i)
+    0x0C, 0x00, 0x0C, 0x0C, 0x0C, 0xCC, 0xCC, 0x78,   // U+006A (j)
+    0xE0, 0x60, 0x66, 0x6C, 0x78, 0x6C, 0xE6, 0x00,   // U+006B (k)
+    0x70, 0x30, 0x30, 0x30, 0x30, 0x30, 0x78, 0x00,   // U+006C (l)
+    0x00, 0x00, 0xCC, 0xFE, 0xFE, 0xD6, 0xC6, 0x00,   // U+006D (m)
+    0x00, 0x00, 0xF8, 0xCC, 0xCC, 0xCC, 0xCC, 0x00,   // U+006E (n)
+    0x00, 0x00, 0x78, 0xCC, 0xCC, 0xCC, 0x78, 0x00,   // U+006F (o)
+    0x00, 0x00, 0xDC, 0x66, 0x66, 0x7C, 0x60, 0xF0,   // U+0070 (p)
+    0x00, 0x00, 0x76, 0xCC, 0xCC, 0x7C, 0x0C, 0x1E,   // U+0071 (q)
+    0x00, 0x00, 0xDC, 0x76, 0x66, 0x60, 0xF0, 0x00,   // U+0072 (r)
+    0x00, 0x00, 0x7C, 0xC0, 0x78, 0x0C, 0xF8, 0x00,   // U+0073 (s)
+    0x10, 0x30, 0x7C, 0x30, 0x30, 0x34, 0x18, 0x00,   // U+0074 (t)
+    0x00, 0x00, 0xCC, 0xCC, 0xCC, 0xCC, 0x76, 0x00,   // U+0075 (u)
+    0x00, 0x00, 0xCC, 0xCC, 0xCC, 0x78, 0x30, 0x00,   // U+0076 (v)
+    0x00, 0x00, 0xC6, 0xD6, 0xFE, 0xFE, 0x6C, 0x00,   // U+0077 (w)
+    0x00, 0x00, 0xC6, 0x6C, 0x38, 0x6C, 0xC6, 0x00,   // U+0078 (x)
+    0x00, 0x00, 0xCC, 0xCC, 0xCC, 0x7C, 0x0C, 0xF8,   // U+0079 (y)
+    0x00, 0x00, 0xFC, 0x98, 0x30, 0x64, 0xFC, 0x00,   // U+007A (z)
+    0x1C, 0x30, 0x30, 0xE0, 0x30, 0x30, 0x1C, 0x00,   // U+007B ({)
+    0x18, 0x18, 0x18, 0x00, 0x18, 0x18, 0x18, 0x00,   // U+007C (|)
+    0xE0, 0x30, 0x30, 0x1C, 0x30, 0x30, 0xE0, 0x00,   // U+007D (})
+    0x76, 0xDC, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,   // U+007E (~)
+];
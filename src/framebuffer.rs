@@ -0,0 +1,218 @@
+//! A linear framebuffer with basic drawing primitives.
+
+use core::ptr;
+use core::cmp::min;
+
+/// A color with 8 bits per channel, independent of the framebuffer format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+/// Position and size in bits of one color channel inside a pixel.
+#[derive(Clone, Copy, Debug)]
+pub struct Channel {
+    pub shift: u8,
+    pub size: u8,
+}
+
+/// How red, green and blue are packed inside a pixel.
+#[derive(Clone, Copy, Debug)]
+pub struct PixelLayout {
+    pub red: Channel,
+    pub green: Channel,
+    pub blue: Channel,
+}
+
+impl PixelLayout {
+    /// The usual layout for 24 and 32 bits per pixel modes (xRGB 8:8:8:8).
+    pub const fn rgb888() -> Self {
+        PixelLayout {
+            red: Channel { shift: 16, size: 8 },
+            green: Channel { shift: 8, size: 8 },
+            blue: Channel { shift: 0, size: 8 },
+        }
+    }
+
+    /// The usual layout for 15 bits per pixel modes, stored in 16 bits
+    /// (xRGB 1:5:5:5).
+    pub const fn rgb555() -> Self {
+        PixelLayout {
+            red: Channel { shift: 10, size: 5 },
+            green: Channel { shift: 5, size: 5 },
+            blue: Channel { shift: 0, size: 5 },
+        }
+    }
+
+    /// The usual layout for 16 bits per pixel modes (RGB 5:6:5).
+    pub const fn rgb565() -> Self {
+        PixelLayout {
+            red: Channel { shift: 11, size: 5 },
+            green: Channel { shift: 5, size: 6 },
+            blue: Channel { shift: 0, size: 5 },
+        }
+    }
+}
+
+/// A memory mapped linear framebuffer.
+pub struct Framebuffer {
+    base: *mut u8,
+    width: usize,
+    height: usize,
+    pitch: usize,
+    bpp: usize,
+    layout: PixelLayout,
+}
+
+// The framebuffer memory is only ever accessed through the owning wrapper.
+unsafe impl Send for Framebuffer {}
+
+impl Framebuffer {
+    /// Wrap the framebuffer memory starting at `base`.
+    ///
+    /// `pitch` is the number of bytes in a scan line and `bpp` the number of
+    /// bits per pixel (8, 16, 24 or 32), `None` for other depths. Unsafe
+    /// because the whole `pitch * height` region must be mapped and not used
+    /// by anything else.
+    pub unsafe fn new(base: usize, width: usize, height: usize, pitch: usize,
+                      bpp: usize, layout: PixelLayout) -> Option<Self> {
+        if bpp != 8 && bpp != 16 && bpp != 24 && bpp != 32 {
+            return None;
+        }
+        Some(Framebuffer {
+            base: base as *mut u8,
+            width: width,
+            height: height,
+            pitch: pitch,
+            bpp: bpp,
+            layout: layout,
+        })
+    }
+
+    /// Width in pixels.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Height in pixels.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Bits per pixel.
+    pub fn bpp(&self) -> usize {
+        self.bpp
+    }
+
+    /// The address of the framebuffer memory.
+    pub fn base(&self) -> usize {
+        self.base as usize
+    }
+
+    /// Size of the framebuffer memory in bytes.
+    pub fn size(&self) -> usize {
+        self.pitch * self.height
+    }
+
+    /// Access the framebuffer memory at `base` from now on. Unsafe because
+    /// it must be mapped to the same memory.
+    pub unsafe fn move_to(&mut self, base: usize) {
+        self.base = base as *mut u8;
+    }
+
+    /// Pack `color` in the native pixel format.
+    pub fn pixel(&self, color: Rgb) -> u32 {
+        fn channel(value: u8, channel: Channel) -> u32 {
+            ((value as u32) >> (8 - channel.size)) << channel.shift
+        }
+        channel(color.0, self.layout.red) |
+            channel(color.1, self.layout.green) |
+            channel(color.2, self.layout.blue)
+    }
+
+    /// Draw a single pixel. Coordinates outside the screen are ignored.
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        if x < self.width && y < self.height {
+            let value = self.pixel(color);
+            unsafe { self.write_pixel(x, y, value); }
+        }
+    }
+
+    /// Fill a rectangle, clipped to the screen.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        let value = self.pixel(color);
+        let x_end = min(x.saturating_add(width), self.width);
+        let y_end = min(y.saturating_add(height), self.height);
+        for row in y..y_end {
+            for col in x..x_end {
+                unsafe { self.write_pixel(col, row, value); }
+            }
+        }
+    }
+
+    /// Clear the whole screen with `color`.
+    pub fn clear(&mut self, color: Rgb) {
+        let (width, height) = (self.width, self.height);
+        self.fill_rect(0, 0, width, height, color);
+    }
+
+    /// Copy a `width` x `height` image from `pixels` (row-major) to the
+    /// screen at `x`, `y`, clipped to the screen.
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, height: usize, pixels: &[Rgb]) {
+        assert!(pixels.len() >= width * height, "image smaller than blit area");
+        let x_end = min(x.saturating_add(width), self.width);
+        let y_end = min(y.saturating_add(height), self.height);
+        for row in y..y_end {
+            for col in x..x_end {
+                let value = self.pixel(pixels[(row - y) * width + (col - x)]);
+                unsafe { self.write_pixel(col, row, value); }
+            }
+        }
+    }
+
+    /// Draw a monochrome bitmap where each row is `(width + 7) / 8` bytes,
+    /// the most significant bit being the leftmost pixel.
+    pub fn draw_bitmap(&mut self, x: usize, y: usize, width: usize, height: usize,
+                       bitmap: &[u8], foreground: Rgb, background: Rgb) {
+        let (fg, bg) = (self.pixel(foreground), self.pixel(background));
+        let stride = (width + 7) / 8;
+        let x_end = min(x.saturating_add(width), self.width);
+        let y_end = min(y.saturating_add(height), self.height);
+        for row in y..y_end {
+            let line = &bitmap[(row - y) * stride..(row - y + 1) * stride];
+            for col in x..x_end {
+                let bit = col - x;
+                let value = if line[bit / 8] & (0x80 >> (bit % 8)) != 0 { fg } else { bg };
+                unsafe { self.write_pixel(col, row, value); }
+            }
+        }
+    }
+
+    /// Move the whole screen content up by `lines` scan lines and fill
+    /// the uncovered area at the bottom with `color`.
+    pub fn scroll_up(&mut self, lines: usize, color: Rgb) {
+        let lines = min(lines, self.height);
+        let kept = self.height - lines;
+        unsafe {
+            ptr::copy(self.base.offset((lines * self.pitch) as isize),
+                      self.base,
+                      kept * self.pitch);
+        }
+        let width = self.width;
+        self.fill_rect(0, kept, width, lines, color);
+    }
+
+    /// Write an already packed pixel. Coordinates must be on screen.
+    unsafe fn write_pixel(&mut self, x: usize, y: usize, value: u32) {
+        let offset = y * self.pitch + x * (self.bpp / 8);
+        let pixel = self.base.offset(offset as isize);
+        match self.bpp {
+            8 => *pixel = value as u8,
+            16 => *(pixel as *mut u16) = value as u16,
+            24 => {
+                *pixel = value as u8;
+                *pixel.offset(1) = (value >> 8) as u8;
+                *pixel.offset(2) = (value >> 16) as u8;
+            }
+            _ => *(pixel as *mut u32) = value,
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Parsing of cpio archives in the "new" portable format (newc).
+// https://www.freebsd.org/cgi/man.cgi?query=cpio&sektion=5
+
+use super::{Entry, Kind};
+
+/// Size of the header, all fields are 8 hexadecimal digits.
+const HEADER_SIZE: usize = 110;
+
+/// Magic numbers, without and with checksum.
+const MAGIC: &'static [u8] = b"070701";
+const MAGIC_CRC: &'static [u8] = b"070702";
+
+/// Name of the last, empty, entry.
+const TRAILER: &'static [u8] = b"TRAILER!!!";
+
+/// Offsets of the fields used.
+const MODE_OFFSET: usize = 14;
+const FILE_SIZE_OFFSET: usize = 54;
+const NAME_SIZE_OFFSET: usize = 94;
+
+/// File type bits of the mode.
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_FILE: u32 = 0o100000;
+const MODE_SYMLINK: u32 = 0o120000;
+
+pub fn is_cpio(data: &[u8]) -> bool {
+    data.len() >= HEADER_SIZE && (&data[..6] == MAGIC || &data[..6] == MAGIC_CRC)
+}
+
+/// Parse a header field.
+fn field(header: &[u8], offset: usize) -> Option<u32> {
+    let mut value = 0u32;
+    for &digit in &header[offset..offset + 8] {
+        let nibble = match digit {
+            b'0'...b'9' => digit - b'0',
+            b'a'...b'f' => digit - b'a' + 10,
+            b'A'...b'F' => digit - b'A' + 10,
+            _ => return None,
+        };
+        value = value << 4 | nibble as u32;
+    }
+    Some(value)
+}
+
+/// Round up to a multiple of 4, as headers and data are 4-byte aligned.
+fn align4(value: usize) -> usize {
+    (value + 3) & !3
+}
+
+/// Parse the entry at `offset`, returning it with the offset of the next.
+pub fn next_entry(data: &'static [u8], offset: usize) -> Option<(Entry, usize)> {
+    if offset + HEADER_SIZE > data.len() || !is_cpio(&data[offset..]) {
+        return None;
+    }
+    let header = &data[offset..offset + HEADER_SIZE];
+    let (mode, file_size, name_size) = match (field(header, MODE_OFFSET),
+                                              field(header, FILE_SIZE_OFFSET),
+                                              field(header, NAME_SIZE_OFFSET)) {
+        (Some(mode), Some(file_size), Some(name_size)) =>
+            (mode, file_size as usize, name_size as usize),
+        _ => return None,
+    };
+
+    let name_start = offset + HEADER_SIZE;
+    let data_start = align4(name_start + name_size);
+    let data_end = data_start + file_size;
+    if name_size == 0 || data_end > data.len() {
+        return None;
+    }
+
+    // The name size includes the terminating NUL.
+    let name = &data[name_start..name_start + name_size - 1];
+    if name == TRAILER {
+        return None;
+    }
+
+    let kind = match mode & MODE_TYPE_MASK {
+        MODE_FILE => Kind::File,
+        MODE_DIRECTORY => Kind::Directory,
+        MODE_SYMLINK => Kind::Symlink,
+        _ => Kind::Other,
+    };
+    Some((Entry::new(b"", name, kind, &data[data_start..data_end]), align4(data_end)))
+}
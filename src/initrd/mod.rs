@@ -0,0 +1,247 @@
+//! A read-only in-memory filesystem on top of an archive loaded as a boot
+//! module, either an uncompressed cpio (newc) or a ustar archive.
+
+use core::str;
+use spin::Mutex;
+use multiboot2::BootInformation;
+
+mod cpio;
+mod tar;
+
+/// Longest path supported, in bytes. Entries with longer ones are skipped.
+pub const MAX_PATH: usize = 256;
+
+/// The module name (as given on the `module2` line) of the initrd.
+const MODULE_NAME: &'static str = "initrd";
+
+/// The archive formats understood.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Cpio,
+    Tar,
+}
+
+/// What an entry is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+/// A file, directory or other entry of the archive.
+pub struct Entry {
+    path: [u8; MAX_PATH],
+    path_length: usize,
+    /// Whether the path didn't fit in `path`.
+    truncated: bool,
+    pub kind: Kind,
+    /// The file content, or the link target for symlinks.
+    pub data: &'static [u8],
+}
+
+impl Entry {
+    /// Build an entry from the path found in the archive, possibly split in
+    /// `prefix` and `name` (ustar). The path is made relative to the root
+    /// of the archive: no `./` or `/` at the start and no `/` at the end.
+    fn new(prefix: &[u8], name: &[u8], kind: Kind, data: &'static [u8]) -> Entry {
+        let mut entry = Entry { path: [0; MAX_PATH], path_length: 0, truncated: false, kind: kind,
+                                data: data };
+        if !prefix.is_empty() {
+            entry.push(normalize(prefix));
+        }
+        entry.push(normalize(name));
+        entry
+    }
+
+    /// Append a component to the path.
+    fn push(&mut self, component: &[u8]) {
+        if component.is_empty() {
+            return;
+        }
+        if self.path_length > 0 && self.path_length < MAX_PATH {
+            self.path[self.path_length] = b'/';
+            self.path_length += 1;
+        }
+        for &b in component {
+            if self.path_length == MAX_PATH {
+                self.truncated = true;
+                break;
+            }
+            self.path[self.path_length] = b;
+            self.path_length += 1;
+        }
+    }
+
+    /// The path from the root of the archive, i.e. `etc/motd`.
+    pub fn path(&self) -> &str {
+        str::from_utf8(&self.path[..self.path_length]).unwrap_or("")
+    }
+
+    /// The last component of the path.
+    pub fn name(&self) -> &str {
+        let path = self.path();
+        match path.rfind('/') {
+            Some(slash) => &path[slash + 1..],
+            None => path,
+        }
+    }
+
+    /// The path of the directory containing the entry, empty for the root.
+    pub fn parent(&self) -> &str {
+        let path = self.path();
+        match path.rfind('/') {
+            Some(slash) => &path[..slash],
+            None => "",
+        }
+    }
+
+    /// The file content as text, if valid UTF-8.
+    pub fn as_str(&self) -> Option<&'static str> {
+        str::from_utf8(self.data).ok()
+    }
+}
+
+/// Strip the leading `./` and `/` and the trailing `/` of a path.
+fn normalize(mut path: &[u8]) -> &[u8] {
+    loop {
+        if path.starts_with(b"./") {
+            path = &path[2..];
+        } else if path.starts_with(b"/") {
+            path = &path[1..];
+        } else {
+            break;
+        }
+    }
+    while path.ends_with(b"/") {
+        path = &path[..path.len() - 1];
+    }
+    if path == &b"."[..] { &path[..0] } else { path }
+}
+
+/// Strip the leading and trailing `/` of a path given by a user.
+fn normalize_str(path: &str) -> &str {
+    str::from_utf8(normalize(path.as_bytes())).unwrap_or("")
+}
+
+/// A mounted archive.
+#[derive(Clone, Copy)]
+pub struct Initrd {
+    data: &'static [u8],
+    format: Format,
+}
+
+impl Initrd {
+    /// Recognise the archive in `data`.
+    pub fn new(data: &'static [u8]) -> Option<Initrd> {
+        let format = if cpio::is_cpio(data) {
+            Format::Cpio
+        } else if tar::is_tar(data) {
+            Format::Tar
+        } else {
+            return None;
+        };
+        Some(Initrd { data: data, format: format })
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Iterate over all the entries of the archive.
+    pub fn entries(&self) -> Entries {
+        Entries { data: self.data, offset: 0, format: self.format }
+    }
+
+    /// Look up the entry at `path`, i.e. `/etc/motd`.
+    pub fn open(&self, path: &str) -> Option<Entry> {
+        let path = normalize_str(path);
+        self.entries().find(|entry| entry.path() == path)
+    }
+
+    /// Iterate over the entries directly inside the directory at `path`,
+    /// `/` being the root.
+    pub fn read_dir<'a>(&self, path: &'a str) -> ReadDir<'a> {
+        ReadDir { entries: self.entries(), path: normalize_str(path) }
+    }
+}
+
+/// An iterator over the entries of an archive.
+pub struct Entries {
+    data: &'static [u8],
+    offset: usize,
+    format: Format,
+}
+
+impl Iterator for Entries {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        loop {
+            let next = match self.format {
+                Format::Cpio => cpio::next_entry(self.data, self.offset),
+                Format::Tar => tar::next_entry(self.data, self.offset),
+            };
+            match next {
+                Some((entry, next_offset)) => {
+                    self.offset = next_offset;
+                    if entry.truncated {
+                        warn!("Skipping initrd entry {}..., longer than {} bytes", entry.path(),
+                              MAX_PATH);
+                    } else if entry.path_length > 0 {
+                        // Not the archive root itself
+                        return Some(entry);
+                    }
+                }
+                None => return None,
+            }
+        }
+    }
+}
+
+/// An iterator over the entries of a directory.
+pub struct ReadDir<'a> {
+    entries: Entries,
+    path: &'a str,
+}
+
+impl<'a> Iterator for ReadDir<'a> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        let path = self.path;
+        self.entries.find(|entry| entry.parent() == path)
+    }
+}
+
+/// The initrd, once mounted.
+pub static INITRD: Mutex<Option<Initrd>> = Mutex::new(None);
+
+/// Mount the boot module named `initrd`, or the first module if none is.
+pub fn init(boot_info: &BootInformation) {
+    let module = match boot_info.modules().find(|m| m.name() == MODULE_NAME) {
+        Some(module) => module,
+        None => match boot_info.modules().next() {
+            Some(module) => module,
+            None => {
+                info!("No initrd module");
+                return;
+            }
+        },
+    };
+
+    // Modules are in the physical memory window and reserved by `memory::init`.
+    let data = unsafe { module.data() };
+    match Initrd::new(data) {
+        Some(initrd) => {
+            info!("Mounted {:?} initrd at {:#x}, {} bytes",
+                  initrd.format(), module.start_address(), data.len());
+            for entry in initrd.entries() {
+                debug!("  {:?} /{} ({} bytes)", entry.kind, entry.path(), entry.data.len());
+            }
+            *INITRD.lock() = Some(initrd);
+        }
+        None => warn!("Module `{}` is not a cpio or tar archive", module.name()),
+    }
+}
@@ -0,0 +1,74 @@
+//! Parsing of POSIX ustar archives.
+// https://www.gnu.org/software/tar/manual/html_node/Standard.html
+
+use super::{Entry, Kind};
+
+/// Archives are made of 512 bytes blocks.
+const BLOCK_SIZE: usize = 512;
+
+/// Offsets and sizes of the header fields used.
+const NAME: (usize, usize) = (0, 100);
+const SIZE: (usize, usize) = (124, 12);
+const TYPE_FLAG_OFFSET: usize = 156;
+const LINK_NAME: (usize, usize) = (157, 100);
+const MAGIC: (usize, usize) = (257, 5);
+const PREFIX: (usize, usize) = (345, 155);
+
+pub fn is_tar(data: &[u8]) -> bool {
+    data.len() >= BLOCK_SIZE && &data[MAGIC.0..MAGIC.0 + MAGIC.1] == &b"ustar"[..]
+}
+
+/// A NUL padded string field.
+fn string(header: &[u8], (offset, size): (usize, usize)) -> &[u8] {
+    let field = &header[offset..offset + size];
+    let length = field.iter().position(|&b| b == 0).unwrap_or(size);
+    &field[..length]
+}
+
+/// An octal number field, NUL or space terminated.
+fn octal(header: &[u8], (offset, size): (usize, usize)) -> Option<usize> {
+    let mut value = 0;
+    for &digit in &header[offset..offset + size] {
+        match digit {
+            b'0'...b'7' => value = value * 8 + (digit - b'0') as usize,
+            b' ' if value == 0 => {}
+            b' ' | 0 => break,
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+/// Parse the entry at `offset`, returning it with the offset of the next.
+pub fn next_entry(data: &'static [u8], offset: usize) -> Option<(Entry, usize)> {
+    // The archive ends with zero blocks, which fail the magic check.
+    if offset + BLOCK_SIZE > data.len() || !is_tar(&data[offset..]) {
+        return None;
+    }
+    let header = &data[offset..offset + BLOCK_SIZE];
+    let size = match octal(header, SIZE) {
+        Some(size) => size,
+        None => return None,
+    };
+
+    let data_start = offset + BLOCK_SIZE;
+    let data_end = data_start + size;
+    if data_end > data.len() {
+        return None;
+    }
+
+    let kind = match header[TYPE_FLAG_OFFSET] {
+        b'0' | 0 => Kind::File,
+        b'5' => Kind::Directory,
+        b'2' => Kind::Symlink,
+        _ => Kind::Other,
+    };
+    let content = match kind {
+        // The target of a symlink is in the header.
+        Kind::Symlink => string(header, LINK_NAME),
+        _ => &data[data_start..data_end],
+    };
+
+    let next = data_start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+    Some((Entry::new(string(header, PREFIX), string(header, NAME), kind, content), next))
+}
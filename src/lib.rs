@@ -5,32 +5,89 @@
 
 extern crate rlibc;
 extern crate spin;
+#[macro_use]
+extern crate bitflags;
 
 pub use arch::interrupts::rust_interrupt_handler;
+pub use arch::thread::thread_entry;
+
+use cmdline::{Param, Kind};
 
 #[macro_use]
 mod macros;
 mod arch;
+mod cmdline;
 mod console;
+mod fbcon;
+mod font;
+mod framebuffer;
+mod initrd;
+mod log;
+mod memory;
+mod multiboot2;
+
+param! {
+    static BOOTSTATS: Param = Param {
+        name: "bootstats",
+        kind: Kind::Flag,
+        default: "",
+        help: "Print the interrupt and slab statistics once booted",
+    };
+}
 
 #[no_mangle] // ensure that this symbol is called `main` in the output
-pub extern "C" fn rust_main() {
+pub extern "C" fn rust_main(multiboot_information_address: usize) -> ! {
     use arch::vga::{SCREEN, CURSOR, ColorCode};
     use arch::vga::Color::*;
 
+    arch::percpu::init();
+    let boot_info = unsafe { multiboot2::load(multiboot_information_address) };
+
     CURSOR.lock().enable();
     SCREEN.lock()
         .set_colors(ColorCode::new(White, Black))
         .clear();
+    cmdline::init(boot_info);
+    console::init(boot_info);
+    log::init();
+    cmdline::check();
     println!("Hello World!");
 
+    arch::cpuid::init();
+    arch::fpu::init();
+    memory::init(boot_info);
+    console::remap_framebuffer();
+    initrd::init(boot_info);
+    arch::acpi::init(boot_info);
+
+    if let Some(motd) = initrd::INITRD.lock().and_then(|initrd| initrd.open("/etc/motd")) {
+        print!("{}", motd.as_str().unwrap_or(""));
+    }
+
     unsafe {
         arch::interrupts::init();
     }
+    arch::aml::init();
+    arch::smp::init();
+    arch::thread::init_cpu();
+    arch::workqueue::init();
 
     println!("Running...");
+    if cmdline::flag(&BOOTSTATS) {
+        // Run by a worker once this thread idles, when the boot is over.
+        arch::workqueue::queue_work(print_stats, 0);
+    }
 
-    loop {}
+    // Test builds are done once the kernel is up.
+    if cfg!(feature = "isa_debug_exit") {
+        arch::power::exit_qemu(arch::power::QEMU_EXIT_SUCCESS);
+    }
+    arch::thread::idle();
+}
+
+fn print_stats(_: usize) {
+    arch::irqstat::dump();
+    memory::slab::dump();
 }
 
 // These functions and traits are used by the compiler, but not
@@ -40,5 +97,8 @@ pub extern "C" fn rust_main() {
 extern "C" fn eh_personality() {}
 #[lang = "panic_fmt"]
 extern "C" fn panic_fmt() -> ! {
+    if cfg!(feature = "isa_debug_exit") {
+        arch::power::exit_qemu(arch::power::QEMU_EXIT_FAILURE);
+    }
     loop {}
 }
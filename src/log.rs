@@ -0,0 +1,204 @@
+//! Leveled kernel logging.
+//!
+//! Records are written with the `error!`, `warn!`, `info!`, `debug!` and
+//! `trace!` macros. Each one is stamped with the uptime and kept in the
+//! `DMESG` ring buffer, then filtered by level, globally or per module path
+//! prefix, and sent to the console.
+//!
+//! The filter is locked with interrupts disabled, interrupt handlers log.
+
+use core::cmp::min;
+use core::fmt::{self, Write};
+use core::str;
+use spin::Mutex;
+use arch::interrupts;
+use arch::pit;
+use cmdline::{self, Param, Kind};
+use console::{self, ConsoleSink};
+
+pub use console::Level;
+
+/// Size of the in-memory log, in bytes.
+const LOG_BUFFER_SIZE: usize = 16 * 1024;
+
+/// How many per module filters can be set.
+const MAX_MODULE_FILTERS: usize = 8;
+
+param! {
+    pub static LOGLEVEL: Param = Param {
+        name: "loglevel",
+        kind: Kind::String,
+        default: "info",
+        help: "Minimum level of log records: trace, debug, info, warn or error",
+    };
+}
+
+/// Which records are let through.
+struct Filter {
+    /// Minimum level for modules without a specific filter.
+    level: Level,
+    /// Minimum level for modules whose path starts with a prefix.
+    modules: [Option<(&'static str, Level)>; MAX_MODULE_FILTERS],
+}
+
+impl Filter {
+    /// Whether a record of `level` from `module` is enabled. The longest
+    /// matching prefix wins.
+    fn enabled(&self, level: Level, module: &str) -> bool {
+        let mut min_level = self.level;
+        let mut longest = 0;
+        for &(prefix, prefix_level) in self.modules.iter().filter_map(|m| m.as_ref()) {
+            if module.starts_with(prefix) && prefix.len() >= longest {
+                longest = prefix.len();
+                min_level = prefix_level;
+            }
+        }
+        level >= min_level
+    }
+}
+
+static FILTER: Mutex<Filter> = Mutex::new(Filter {
+    level: Level::Info,
+    modules: [None; MAX_MODULE_FILTERS],
+});
+
+/// Set the minimum level of records to log.
+pub fn set_level(level: Level) {
+    interrupts::without_interrupts(|| FILTER.lock().level = level);
+}
+
+/// The minimum level of records to log.
+pub fn level() -> Level {
+    interrupts::without_interrupts(|| FILTER.lock().level)
+}
+
+/// Set the minimum level for modules whose path starts with `prefix`,
+/// i.e. `rustos::arch`. Return `false` if there is no room left.
+pub fn set_module_level(prefix: &'static str, level: Level) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut filter = FILTER.lock();
+        let slot = match filter.modules.iter().position(|m| m.map_or(false, |m| m.0 == prefix)) {
+            Some(index) => index,
+            None => match filter.modules.iter().position(|m| m.is_none()) {
+                Some(index) => index,
+                None => return false,
+            },
+        };
+        filter.modules[slot] = Some((prefix, level));
+        true
+    })
+}
+
+/// Remove the filter for `prefix`, if any.
+pub fn clear_module_level(prefix: &str) {
+    interrupts::without_interrupts(|| {
+        let mut filter = FILTER.lock();
+        for module in filter.modules.iter_mut() {
+            if module.map_or(false, |m| m.0 == prefix) {
+                *module = None;
+            }
+        }
+    });
+}
+
+/// Log a record, use the logging macros instead.
+pub fn log(level: Level, module: &'static str, args: fmt::Arguments) {
+    let enabled = interrupts::without_interrupts(|| FILTER.lock().enabled(level, module));
+    // The console keeps a copy in the log, records filtered out go there only.
+    let print = |record: fmt::Arguments| {
+        if enabled {
+            console::print(level, record);
+        } else {
+            console::print_to("dmesg", record);
+        }
+    };
+
+    let uptime = pit::uptime_ms();
+    print(format_args!("[{:5}.{:03}] {:5} {}: {}\n",
+                       uptime / 1000, uptime % 1000, level, module, args));
+}
+
+/// A fixed-size ring buffer keeping the most recent console output.
+pub struct LogBuffer {
+    data: [u8; LOG_BUFFER_SIZE],
+    /// Total number of bytes ever written, the write position is this
+    /// modulo the buffer size.
+    written: usize,
+}
+
+impl LogBuffer {
+    /// Append `bytes`, overwriting the oldest ones when full.
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.data[self.written % LOG_BUFFER_SIZE] = b;
+            self.written += 1;
+        }
+    }
+
+    /// Offset of the oldest byte still in the buffer.
+    pub fn start(&self) -> usize {
+        self.written.saturating_sub(LOG_BUFFER_SIZE)
+    }
+
+    /// Offset one past the newest byte in the buffer.
+    pub fn end(&self) -> usize {
+        self.written
+    }
+
+    /// Copy bytes starting at `offset` into `buf` and return how many were
+    /// copied. Offsets older than `start` have been overwritten already,
+    /// reading starts from `start` instead.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let offset = if offset < self.start() { self.start() } else { offset };
+        let count = min(buf.len(), self.end().saturating_sub(offset));
+        for (i, b) in buf[..count].iter_mut().enumerate() {
+            *b = self.data[(offset + i) % LOG_BUFFER_SIZE];
+        }
+        count
+    }
+}
+
+impl ConsoleSink for Mutex<LogBuffer> {
+    fn write_str(&self, s: &str) {
+        self.lock().write(s.as_bytes());
+    }
+}
+
+/// The kernel log, holding everything printed on the console.
+pub static DMESG: Mutex<LogBuffer> = Mutex::new(LogBuffer {
+    data: [0; LOG_BUFFER_SIZE],
+    written: 0,
+});
+
+/// Write the content of the kernel log to `out`.
+pub fn dmesg<W: Write>(out: &mut W) -> fmt::Result {
+    // Stop at the current end, as `out` could be feeding the log itself.
+    let (mut offset, end) = {
+        let log = DMESG.lock();
+        (log.start(), log.end())
+    };
+
+    let mut chunk = [0u8; 128];
+    while offset < end {
+        let length = min(chunk.len(), end - offset);
+        let count = DMESG.lock().read(offset, &mut chunk[..length]);
+        if count == 0 {
+            break;
+        }
+        try!(out.write_str(str::from_utf8(&chunk[..count]).unwrap_or("?")));
+        offset += count;
+    }
+    Ok(())
+}
+
+/// Start recording console output and apply `loglevel=` from the
+/// kernel command line.
+pub fn init() {
+    let _ = console::register("dmesg", &DMESG, Level::Trace, false);
+
+    let value = cmdline::string(&LOGLEVEL);
+    match Level::from_name(value) {
+        Some(level) => set_level(level),
+        None => warn!("Unknown log level `{}`", value),
+    }
+}
@@ -5,7 +5,86 @@ macro_rules! println {
 
 macro_rules! print {
     ($($arg:tt)*) => ({
-        use core::fmt::Write;
-        $crate::console::CONSOLE.lock().write_fmt(format_args!($($arg)*)).unwrap();
+        $crate::console::print($crate::console::Level::Info, format_args!($($arg)*));
     });
 }
+
+/// Log a record with an explicit `log::Level`.
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ({
+        $crate::log::log($level, module_path!(), format_args!($($arg)*));
+    });
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => (log!($crate::log::Level::Error, $($arg)*));
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => (log!($crate::log::Level::Warn, $($arg)*));
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => (log!($crate::log::Level::Info, $($arg)*));
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => (log!($crate::log::Level::Debug, $($arg)*));
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => (log!($crate::log::Level::Trace, $($arg)*));
+}
+
+/// Allocate from a slab cache, recording the call site for `heap_debug`.
+macro_rules! slab_allocate {
+    ($cache:expr) => ($cache.allocate_at(file!(), line!()));
+}
+
+/// Declare command line options, see `cmdline`:
+///
+/// ```ignore
+/// param! {
+///     pub static NOAPIC: Param = Param { name: "noapic", ... };
+/// }
+/// ```
+macro_rules! param {
+    () => ();
+    ($(#[$attr:meta])* static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => (
+        $(#[$attr])*
+        #[link_section = ".params"]
+        static $name: $t = $init;
+        param!($($rest)*);
+    );
+    ($(#[$attr:meta])* pub static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => (
+        $(#[$attr])*
+        #[link_section = ".params"]
+        pub static $name: $t = $init;
+        param!($($rest)*);
+    );
+}
+
+/// Declare per-CPU variables, see `arch::percpu`:
+///
+/// ```ignore
+/// per_cpu! {
+///     static EVENTS: Counter = Counter::new();
+/// }
+/// EVENTS.increment();
+/// ```
+macro_rules! per_cpu {
+    () => ();
+    ($(#[$attr:meta])* static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => (
+        $(#[$attr])*
+        #[link_section = ".percpu"]
+        static $name: $crate::arch::percpu::PerCpu<$t> = $crate::arch::percpu::PerCpu::new($init);
+        per_cpu!($($rest)*);
+    );
+    ($(#[$attr:meta])* pub static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => (
+        $(#[$attr])*
+        #[link_section = ".percpu"]
+        pub static $name: $crate::arch::percpu::PerCpu<$t> =
+            $crate::arch::percpu::PerCpu::new($init);
+        per_cpu!($($rest)*);
+    );
+}
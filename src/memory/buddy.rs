@@ -0,0 +1,287 @@
+//! A binary buddy allocator for physically contiguous blocks of frames.
+// http://www.memorymanagement.org/mmref/alloc.html#buddy-system
+//
+// A block of order `n` is `2^n` frames, aligned on its size. Free blocks are
+// kept in a doubly linked list per zone and order, linked through the
+// blocks themselves, and marked in a bitmap per order so the buddy of a
+// freed block can be found and merged in constant time.
+
+use core::fmt;
+use core::ptr;
+use memory::{Frame, FrameAllocator, PAGE_SIZE, MAX_PHYSICAL_ADDRESS};
+use memory::phys_to_virt;
+
+/// Largest order, 4 MiB blocks.
+pub const MAX_ORDER: usize = 10;
+const ORDER_COUNT: usize = MAX_ORDER + 1;
+
+/// Frames below `MAX_PHYSICAL_ADDRESS`, the only ones managed.
+const FRAME_COUNT: usize = MAX_PHYSICAL_ADDRESS / PAGE_SIZE;
+
+/// One bit per block of each order: `FRAME_COUNT` bits for order 0, half
+/// as many for order 1 and so on.
+const BITMAP_WORDS: usize = 2 * FRAME_COUNT / 64;
+
+/// End of a free list. Low memory is always reserved, so no free block
+/// starts at physical address 0.
+const NONE: usize = 0;
+
+const ZONE_COUNT: usize = 3;
+const ZONE_DMA_END: usize = 0x100_0000;
+const ZONE_DMA32_END: usize = 0x1_0000_0000;
+
+/// Physical memory ranges, for devices that can't address all of it.
+/// Zone boundaries are aligned on the largest block size, so a block
+/// never spans two zones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Zone {
+    /// Below 16 MiB, for ISA DMA.
+    Dma = 0,
+    /// Below 4 GiB, for 32 bit DMA.
+    Dma32 = 1,
+    /// Anywhere.
+    Normal = 2,
+}
+
+impl Zone {
+    /// The zone containing the physical `address`.
+    pub fn of(address: usize) -> Zone {
+        if address < ZONE_DMA_END {
+            Zone::Dma
+        } else if address < ZONE_DMA32_END {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+
+    fn from_index(index: usize) -> Zone {
+        match index {
+            0 => Zone::Dma,
+            1 => Zone::Dma32,
+            _ => Zone::Normal,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Zone::Dma => "DMA",
+            Zone::Dma32 => "DMA32",
+            Zone::Normal => "Normal",
+        }
+    }
+}
+
+/// The links at the start of a free block.
+struct FreeBlock {
+    next: usize,
+    prev: usize,
+}
+
+/// Number of free blocks of each order, and of frames managed, per zone.
+#[derive(Clone, Copy)]
+pub struct Stats {
+    pub free_blocks: [[usize; ORDER_COUNT]; ZONE_COUNT],
+    pub total_frames: [usize; ZONE_COUNT],
+}
+
+impl Stats {
+    /// Free frames in `zone`.
+    pub fn free_frames(&self, zone: Zone) -> usize {
+        self.free_blocks[zone as usize].iter()
+            .enumerate()
+            .map(|(order, &count)| count << order)
+            .sum()
+    }
+
+    /// How much of the free memory of `zone` can't be used for a block of
+    /// `order`, in per mille: 0 when every free frame is in a block large
+    /// enough, close to 1000 when memory is too fragmented.
+    pub fn unusable_index(&self, zone: Zone, order: usize) -> usize {
+        let free = self.free_frames(zone);
+        if free == 0 {
+            return 0;
+        }
+        let usable: usize = self.free_blocks[zone as usize][order..].iter()
+            .enumerate()
+            .map(|(i, &count)| count << (order + i))
+            .sum();
+        (free - usable) * 1000 / free
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for index in 0..ZONE_COUNT {
+            let zone = Zone::from_index(index);
+            if self.total_frames[index] == 0 {
+                continue;
+            }
+            try!(write!(f, "{:6} {:7}/{:7} frames free, by order:",
+                        zone.name(), self.free_frames(zone), self.total_frames[index]));
+            for count in self.free_blocks[index].iter() {
+                try!(write!(f, " {}", count));
+            }
+            try!(write!(f, ", unusable for 4 MiB: {}.{}%\n",
+                        self.unusable_index(zone, MAX_ORDER) / 10,
+                        self.unusable_index(zone, MAX_ORDER) % 10));
+        }
+        Ok(())
+    }
+}
+
+pub struct BuddyAllocator {
+    /// First free block of each order, per zone.
+    free_lists: [[usize; ORDER_COUNT]; ZONE_COUNT],
+    /// Bit set for each block heading a free list entry of its order.
+    bitmap: [u64; BITMAP_WORDS],
+    stats: Stats,
+}
+
+impl BuddyAllocator {
+    /// An allocator without any memory, see `add_range`.
+    pub const fn new() -> BuddyAllocator {
+        BuddyAllocator {
+            free_lists: [[NONE; ORDER_COUNT]; ZONE_COUNT],
+            bitmap: [0; BITMAP_WORDS],
+            stats: Stats {
+                free_blocks: [[0; ORDER_COUNT]; ZONE_COUNT],
+                total_frames: [0; ZONE_COUNT],
+            },
+        }
+    }
+
+    /// Hand the physical memory `[start, end)` to the allocator. Partial
+    /// frames and memory above `MAX_PHYSICAL_ADDRESS` are ignored.
+    pub fn add_range(&mut self, start: usize, end: usize) {
+        let mut frame = (start + PAGE_SIZE - 1) / PAGE_SIZE;
+        let end_frame = if end > MAX_PHYSICAL_ADDRESS { FRAME_COUNT } else { end / PAGE_SIZE };
+
+        while frame < end_frame {
+            // The largest aligned block starting at `frame` and fitting
+            let mut order = MAX_ORDER;
+            while frame % (1 << order) != 0 || frame + (1 << order) > end_frame {
+                order -= 1;
+            }
+            self.stats.total_frames[Zone::of(frame * PAGE_SIZE) as usize] += 1 << order;
+            self.free(Frame { number: frame }, order);
+            frame += 1 << order;
+        }
+    }
+
+    /// Allocate `2^order` contiguous frames, aligned on their size, in
+    /// `zone` or a lower one.
+    pub fn allocate(&mut self, order: usize, zone: Zone) -> Option<Frame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        for zone in (0..zone as usize + 1).rev() {
+            for current in order..ORDER_COUNT {
+                let block = self.free_lists[zone][current];
+                if block == NONE {
+                    continue;
+                }
+
+                self.remove(block / PAGE_SIZE, current);
+                // Give back the upper halves until the block has the right size
+                let number = block / PAGE_SIZE;
+                for split in (order..current).rev() {
+                    self.insert(number + (1 << split), split);
+                }
+                return Some(Frame { number: number });
+            }
+        }
+        None
+    }
+
+    /// Give back the `2^order` frames starting at `frame`, merging them
+    /// with their free buddies.
+    pub fn free(&mut self, frame: Frame, order: usize) {
+        let mut number = frame.number;
+        let mut order = order;
+        assert!(number % (1 << order) == 0, "Misaligned block freed");
+
+        while order < MAX_ORDER {
+            let buddy = number ^ (1 << order);
+            if buddy >= FRAME_COUNT || !self.is_free(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            number &= !(1 << order);
+            order += 1;
+        }
+        self.insert(number, order);
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    fn bit(number: usize, order: usize) -> usize {
+        2 * FRAME_COUNT - (2 * FRAME_COUNT >> order) + (number >> order)
+    }
+
+    fn is_free(&self, number: usize, order: usize) -> bool {
+        let bit = BuddyAllocator::bit(number, order);
+        self.bitmap[bit / 64] & (1 << (bit % 64)) != 0
+    }
+
+    fn set_free(&mut self, number: usize, order: usize, free: bool) {
+        let bit = BuddyAllocator::bit(number, order);
+        if free {
+            self.bitmap[bit / 64] |= 1 << (bit % 64);
+        } else {
+            self.bitmap[bit / 64] &= !(1 << (bit % 64));
+        }
+    }
+
+    fn block(address: usize) -> *mut FreeBlock {
+        phys_to_virt(address) as *mut FreeBlock
+    }
+
+    /// Push the block at frame `number` on its free list.
+    fn insert(&mut self, number: usize, order: usize) {
+        let address = number * PAGE_SIZE;
+        let zone = Zone::of(address) as usize;
+        let head = self.free_lists[zone][order];
+        unsafe {
+            ptr::write(BuddyAllocator::block(address), FreeBlock { next: head, prev: NONE });
+            if head != NONE {
+                (*BuddyAllocator::block(head)).prev = address;
+            }
+        }
+        self.free_lists[zone][order] = address;
+        self.set_free(number, order, true);
+        self.stats.free_blocks[zone][order] += 1;
+    }
+
+    /// Unlink the free block at frame `number` from its free list.
+    fn remove(&mut self, number: usize, order: usize) {
+        let address = number * PAGE_SIZE;
+        let zone = Zone::of(address) as usize;
+        unsafe {
+            let FreeBlock { next, prev } = ptr::read(BuddyAllocator::block(address));
+            if prev == NONE {
+                self.free_lists[zone][order] = next;
+            } else {
+                (*BuddyAllocator::block(prev)).next = next;
+            }
+            if next != NONE {
+                (*BuddyAllocator::block(next)).prev = prev;
+            }
+        }
+        self.set_free(number, order, false);
+        self.stats.free_blocks[zone][order] -= 1;
+    }
+}
+
+impl FrameAllocator for BuddyAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocate(0, Zone::Normal)
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        self.free(frame, 0)
+    }
+}
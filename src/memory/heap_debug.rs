@@ -0,0 +1,141 @@
+//! Heap debugging for the slab caches, enabled by the `heap_debug` feature.
+//!
+//! Each object is surrounded by redzones filled with a known pattern, and
+//! followed by a `Track` recording its state and allocation call site.
+//! Objects of caches without constructor are poisoned when freed. Redzones
+//! are checked on free, the poison on the next allocation, so overflows,
+//! use after free and double frees are caught close to the culprit.
+
+use core::cmp;
+use core::fmt::Write;
+use core::mem::size_of;
+use core::ptr;
+use core::slice;
+use arch::serial::COM1;
+
+/// Bytes of redzone on each side of an object.
+const REDZONE_SIZE: usize = 16;
+
+const REDZONE_BYTE: u8 = 0xBB;
+const POISON_BYTE: u8 = 0x6B;
+
+/// `Track::state` values, anything else means the track was overwritten.
+const ALLOCATED: u32 = 0xA110_CA7E;
+const FREE: u32 = 0xF4EE_F4EE;
+
+/// Bookkeeping kept after the right redzone of each object.
+pub struct Track {
+    /// Next free object of the slab, the object itself being poisoned.
+    pub next: usize,
+    /// Where the object was last allocated.
+    file: &'static str,
+    line: u32,
+    state: u32,
+}
+
+/// `size` rounded up to a word, the start of the right redzone padding.
+fn padded(size: usize) -> usize {
+    (size + 7) & !7
+}
+
+/// Space before an object, keeping it aligned on `align`.
+pub fn left_redzone(align: usize) -> usize {
+    cmp::max(REDZONE_SIZE, align)
+}
+
+/// Size of the slot holding an object of `size` bytes.
+pub fn slot_size(size: usize, align: usize) -> usize {
+    left_redzone(align) + padded(size) + REDZONE_SIZE + size_of::<Track>()
+}
+
+/// The track of the object at `object`.
+pub unsafe fn track(object: usize, size: usize) -> &'static mut Track {
+    &mut *((object + padded(size) + REDZONE_SIZE) as *mut Track)
+}
+
+impl Track {
+    pub fn is_allocated(&self) -> bool {
+        self.state == ALLOCATED
+    }
+}
+
+/// The left redzone, and the right one from the end of the object.
+unsafe fn redzones(object: usize, size: usize) -> (&'static mut [u8], &'static mut [u8]) {
+    (slice::from_raw_parts_mut((object - REDZONE_SIZE) as *mut u8, REDZONE_SIZE),
+     slice::from_raw_parts_mut((object + size) as *mut u8, padded(size) - size + REDZONE_SIZE))
+}
+
+/// Offset of the first byte of `bytes` different from `expected`.
+fn find_corruption(bytes: &[u8], expected: u8) -> Option<usize> {
+    bytes.iter().position(|&b| b != expected)
+}
+
+/// Report a corrupted heap and stop.
+fn corrupted(cache: &str, object: usize, what: &str, track: &Track) -> ! {
+    error!("Heap corruption in {} object {:#x}: {}", cache, object, what);
+    error!("Object last allocated at {}:{}", track.file, track.line);
+    panic!("heap corruption");
+}
+
+/// Set up a new object: redzones, poison and a free track.
+pub unsafe fn init(object: usize, size: usize, poison: bool) {
+    let (left, right) = redzones(object, size);
+    ptr::write_bytes(left.as_mut_ptr(), REDZONE_BYTE, left.len());
+    ptr::write_bytes(right.as_mut_ptr(), REDZONE_BYTE, right.len());
+    if poison {
+        ptr::write_bytes(object as *mut u8, POISON_BYTE, size);
+    }
+    ptr::write(track(object, size) as *mut Track,
+               Track { next: 0, file: "", line: 0, state: FREE });
+}
+
+/// Check a free object about to be handed out, and record the call site.
+pub unsafe fn on_allocate(cache: &str, object: usize, size: usize, poison: bool,
+                          file: &'static str, line: u32) {
+    let track = track(object, size);
+    if track.state != FREE {
+        corrupted(cache, object, "free list corrupted", track);
+    }
+    if poison {
+        let bytes = slice::from_raw_parts(object as *const u8, size);
+        if let Some(offset) = find_corruption(bytes, POISON_BYTE) {
+            error!("Byte {} written after free", offset);
+            corrupted(cache, object, "use after free", track);
+        }
+    }
+
+    track.file = file;
+    track.line = line;
+    track.state = ALLOCATED;
+}
+
+/// Check an object being freed: its redzones and that it was allocated.
+pub unsafe fn on_free(cache: &str, object: usize, size: usize, poison: bool) {
+    let track = track(object, size);
+    match track.state {
+        ALLOCATED => {}
+        FREE => corrupted(cache, object, "double free", track),
+        _ => corrupted(cache, object, "invalid free, or track overwritten", track),
+    }
+
+    let (left, right) = redzones(object, size);
+    if let Some(offset) = find_corruption(left, REDZONE_BYTE) {
+        error!("Left redzone written at byte {} of {}", offset, REDZONE_SIZE);
+        corrupted(cache, object, "buffer underflow", track);
+    }
+    if let Some(offset) = find_corruption(right, REDZONE_BYTE) {
+        error!("Right redzone written {} bytes past the end", offset);
+        corrupted(cache, object, "buffer overflow", track);
+    }
+
+    if poison {
+        ptr::write_bytes(object as *mut u8, POISON_BYTE, size);
+    }
+    track.state = FREE;
+}
+
+/// Print a live object on the serial port.
+pub fn report_live(cache: &str, object: usize, track: &Track) {
+    let _ = write!(COM1.lock(), "{:16} {:#018x} allocated at {}:{}\n",
+                   cache, object, track.file, track.line);
+}
@@ -0,0 +1,215 @@
+//! Physical and virtual memory management.
+
+pub use self::buddy::{BuddyAllocator, Zone, MAX_ORDER};
+
+use core::cmp;
+use spin::Mutex;
+use multiboot2::{BootInformation, MemoryMapTag};
+
+pub mod paging;
+pub mod slab;
+pub mod vm;
+
+mod buddy;
+#[cfg(feature = "heap_debug")]
+mod heap_debug;
+
+/// Size of a page, and of a physical frame.
+pub const PAGE_SIZE: usize = 4096;
+
+/// Physical memory above this address is not mapped, so it's not used.
+pub const MAX_PHYSICAL_ADDRESS: usize = 0x1_0000_0000;
+
+/// Where the kernel is linked, mapping the first GiB of physical memory.
+pub const KERNEL_OFFSET: usize = 0xFFFF_FFFF_8000_0000;
+
+/// Where the physical memory below `MAX_PHYSICAL_ADDRESS` is mapped.
+pub const PHYSICAL_MEMORY_OFFSET: usize = 0xFFFF_8000_0000_0000;
+
+/// How many physical memory regions can be reserved.
+const MAX_RESERVED_REGIONS: usize = 16;
+
+/// The first MiB holds the BIOS data area, the VGA memory and ROMs.
+const LOW_MEMORY_END: usize = 0x10_0000;
+
+/// The virtual address of the physical `address`, in the physical memory
+/// window set up by boot.asm.
+pub const fn phys_to_virt(address: usize) -> usize {
+    address + PHYSICAL_MEMORY_OFFSET
+}
+
+/// The physical address of a kernel image or physical memory window
+/// `address`. Other addresses are returned unchanged, i.e. the boot code
+/// linked at its physical address.
+pub fn virt_to_phys(address: usize) -> usize {
+    if address >= KERNEL_OFFSET {
+        address - KERNEL_OFFSET
+    } else if address >= PHYSICAL_MEMORY_OFFSET {
+        address - PHYSICAL_MEMORY_OFFSET
+    } else {
+        address
+    }
+}
+
+/// The memory map given by the bootloader.
+static MEMORY_MAP: Mutex<Option<&'static MemoryMapTag>> = Mutex::new(None);
+
+/// The bytes of RAM, usable or not, in the physical `[start, end)`. The
+/// rest may be devices.
+pub fn ram_bytes(start: usize, end: usize) -> usize {
+    let memory_map = match *MEMORY_MAP.lock() {
+        Some(memory_map) => memory_map,
+        None => return 0,
+    };
+    memory_map.ram_areas()
+        .map(|area| {
+            let area_start = cmp::max(area.base_address as usize, start);
+            let area_end = cmp::min(area.end_address() as usize, end);
+            area_end.saturating_sub(area_start)
+        })
+        .fold(0, |sum, bytes| sum + bytes)
+}
+
+/// A physical memory page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Frame {
+    number: usize,
+}
+
+impl Frame {
+    /// The frame containing the physical `address`.
+    pub fn containing_address(address: usize) -> Frame {
+        Frame { number: address / PAGE_SIZE }
+    }
+
+    /// Physical address of the first byte of the frame.
+    pub fn start_address(&self) -> usize {
+        self.number * PAGE_SIZE
+    }
+}
+
+/// Something handing out physical frames.
+pub trait FrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame>;
+    fn deallocate_frame(&mut self, frame: Frame);
+}
+
+/// A range of physical memory `[start, end)` that must not be allocated.
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub name: &'static str,
+}
+
+impl Region {
+    /// Whether `frame` overlaps the region.
+    pub fn contains(&self, frame: Frame) -> bool {
+        frame.start_address() < self.end && frame.start_address() + PAGE_SIZE > self.start
+    }
+}
+
+static RESERVED: Mutex<[Option<Region>; MAX_RESERVED_REGIONS]> =
+    Mutex::new([None; MAX_RESERVED_REGIONS]);
+
+/// Keep the frames overlapping `[start, end)` out of the frame allocator.
+/// Only effective before `init`. Return `false` if there is no room left.
+pub fn reserve(start: usize, end: usize, name: &'static str) -> bool {
+    let mut reserved = RESERVED.lock();
+    match reserved.iter_mut().find(|r| r.is_none()) {
+        Some(slot) => {
+            *slot = Some(Region { start: start, end: end, name: name });
+            true
+        }
+        None => false,
+    }
+}
+
+/// The reserved regions.
+pub fn reserved_regions() -> [Option<Region>; MAX_RESERVED_REGIONS] {
+    *RESERVED.lock()
+}
+
+/// The frame allocator, empty until `init`.
+pub static FRAME_ALLOCATOR: Mutex<BuddyAllocator> = Mutex::new(BuddyAllocator::new());
+
+/// Allocate a physical frame. The empty slabs are released when out of
+/// memory.
+pub fn allocate_frame() -> Option<Frame> {
+    let frame = FRAME_ALLOCATOR.lock().allocate_frame();
+    match frame {
+        None if slab::reclaim() > 0 => FRAME_ALLOCATOR.lock().allocate_frame(),
+        frame => frame,
+    }
+}
+
+/// Give back a frame obtained with `allocate_frame`.
+pub fn deallocate_frame(frame: Frame) {
+    FRAME_ALLOCATOR.lock().deallocate_frame(frame);
+}
+
+/// Allocate `2^order` physically contiguous frames, aligned on their size,
+/// in `zone` or below. The empty slabs are released when out of memory.
+pub fn allocate_frames(order: usize, zone: Zone) -> Option<Frame> {
+    let frame = FRAME_ALLOCATOR.lock().allocate(order, zone);
+    match frame {
+        None if slab::reclaim() > 0 => FRAME_ALLOCATOR.lock().allocate(order, zone),
+        frame => frame,
+    }
+}
+
+/// Give back frames obtained with `allocate_frames`.
+pub fn deallocate_frames(frame: Frame, order: usize) {
+    FRAME_ALLOCATOR.lock().free(frame, order);
+}
+
+/// Hand `[start, end)` to the frame allocator, minus the reserved regions.
+fn add_free_range(start: usize, end: usize, reserved: &[Option<Region>]) {
+    let overlapping = reserved.iter()
+        .filter_map(|r| *r)
+        .find(|r| r.start < end && start < r.end);
+    match overlapping {
+        Some(region) => {
+            if start < region.start {
+                add_free_range(start, region.start, reserved);
+            }
+            if region.end < end {
+                add_free_range(region.end, end, reserved);
+            }
+        }
+        None => FRAME_ALLOCATOR.lock().add_range(start, end),
+    }
+}
+
+/// Reserve the memory used by the kernel, the boot information and the
+/// boot modules, then set up the frame allocator with what's left and
+/// remap the kernel.
+pub fn init(boot_info: &BootInformation) {
+    let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
+    let elf_sections_tag = boot_info.elf_sections_tag().expect("Elf sections tag required");
+
+    let (kernel_start, kernel_end) = elf_sections_tag.kernel_range();
+    reserve(0, LOW_MEMORY_END, "low memory");
+    reserve(kernel_start, kernel_end, "kernel");
+    reserve(virt_to_phys(boot_info.start_address()), virt_to_phys(boot_info.end_address()),
+            "boot information");
+    for module in boot_info.modules() {
+        if !reserve(module.start_address(), module.end_address(), "module") {
+            warn!("Too many reserved regions, module at {:#x} may be overwritten",
+                  module.start_address());
+        }
+    }
+
+    for region in reserved_regions().iter().filter_map(|r| *r) {
+        debug!("Reserved {:#010x}-{:#010x} {}", region.start, region.end, region.name);
+    }
+
+    let reserved = reserved_regions();
+    for area in memory_map_tag.memory_areas() {
+        add_free_range(area.base_address as usize, area.end_address() as usize, &reserved);
+    }
+    debug!("Physical memory:\n{}", FRAME_ALLOCATOR.lock().stats());
+
+    *MEMORY_MAP.lock() = Some(memory_map_tag);
+    paging::init();
+}
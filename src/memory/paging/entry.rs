@@ -0,0 +1,51 @@
+//! Page table entries.
+
+use memory::Frame;
+
+/// Bits of a page table entry.
+pub const PRESENT: u64 = 1 << 0;
+pub const WRITABLE: u64 = 1 << 1;
+pub const USER_ACCESSIBLE: u64 = 1 << 2;
+pub const WRITE_THROUGH: u64 = 1 << 3;
+pub const NO_CACHE: u64 = 1 << 4;
+pub const ACCESSED: u64 = 1 << 5;
+pub const DIRTY: u64 = 1 << 6;
+/// In a P2 (P3) entry, map a 2 MiB (1 GiB) page instead of a table.
+pub const HUGE_PAGE: u64 = 1 << 7;
+pub const GLOBAL: u64 = 1 << 8;
+/// Only honored once EFER.NXE is set, a reserved bit before.
+pub const NO_EXECUTE: u64 = 1 << 63;
+
+/// Bits 12-51 hold the physical address of the frame or next table.
+const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// An entry of any level of page table.
+pub struct Entry(u64);
+
+impl Entry {
+    pub fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn set_unused(&mut self) {
+        self.0 = 0;
+    }
+
+    pub fn flags(&self) -> u64 {
+        self.0 & !ADDRESS_MASK
+    }
+
+    /// The frame mapped by the entry, or holding the next table.
+    pub fn pointed_frame(&self) -> Option<Frame> {
+        if self.flags() & PRESENT != 0 {
+            Some(Frame::containing_address((self.0 & ADDRESS_MASK) as usize))
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&mut self, frame: Frame, flags: u64) {
+        assert!(frame.start_address() as u64 & !ADDRESS_MASK == 0);
+        self.0 = frame.start_address() as u64 | flags;
+    }
+}
@@ -0,0 +1,329 @@
+//! Virtual memory: 4-level page tables and the kernel address space.
+// Based on http://os.phil-opp.com/modifying-page-tables.html
+
+pub use self::entry::*;
+
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use spin::Mutex;
+use arch::cpuid::{self, Feature};
+use arch::interrupts;
+use arch::ipi;
+use arch::msr::{self, EFER_NO_EXECUTE_ENABLE};
+use arch::registers::{self, CR0_WRITE_PROTECT};
+use memory::{self, Frame, PAGE_SIZE, KERNEL_OFFSET, PHYSICAL_MEMORY_OFFSET, MAX_PHYSICAL_ADDRESS};
+use memory::vm::{self, Backing};
+use self::table::Table;
+
+mod entry;
+mod table;
+
+/// Entries in a table of any level.
+pub const ENTRY_COUNT: usize = 512;
+
+/// Size of the pages mapped by a P2 entry.
+pub const HUGE_PAGE_SIZE: usize = PAGE_SIZE * ENTRY_COUNT;
+
+/// The flags of the physical memory window outside RAM.
+const UNCACHED_WINDOW: u64 = WRITABLE | NO_EXECUTE | NO_CACHE | WRITE_THROUGH;
+
+/// Whether EFER.NXE is set, so `NO_EXECUTE` can be used.
+static NO_EXECUTE_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// How many pages `unmap_range` unmaps per TLB shootdown.
+const UNMAP_BATCH: usize = 64;
+
+/// Held while editing the active page tables, which all the CPUs share.
+/// Taken with interrupts disabled, the page fault handler edits them.
+static ACTIVE_TABLES: Mutex<()> = Mutex::new(());
+
+/// Reasons for failing to map a page.
+#[derive(Debug)]
+pub enum Error {
+    /// The page is already mapped.
+    AlreadyMapped,
+    /// The page is covered by a huge page.
+    HugePage,
+    /// No frame left for a page table.
+    OutOfFrames,
+}
+
+/// A virtual memory page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Page {
+    number: usize,
+}
+
+impl Page {
+    /// The page containing the virtual `address`.
+    pub fn containing_address(address: usize) -> Page {
+        Page { number: address / PAGE_SIZE }
+    }
+
+    /// Virtual address of the first byte of the page.
+    pub fn start_address(&self) -> usize {
+        self.number * PAGE_SIZE
+    }
+
+    fn p4_index(&self) -> usize {
+        (self.number >> 27) & 0o777
+    }
+
+    fn p3_index(&self) -> usize {
+        (self.number >> 18) & 0o777
+    }
+
+    fn p2_index(&self) -> usize {
+        (self.number >> 9) & 0o777
+    }
+
+    fn p1_index(&self) -> usize {
+        self.number & 0o777
+    }
+}
+
+/// A 4-level page table hierarchy, edited through the physical memory
+/// window so it doesn't need to be active.
+pub struct Mapper {
+    p4: Frame,
+}
+
+impl Mapper {
+    /// The page tables whose P4 is in `p4`. Unsafe as the tables must be
+    /// valid and not edited by someone else meanwhile.
+    pub unsafe fn new(p4: Frame) -> Mapper {
+        Mapper { p4: p4 }
+    }
+
+    /// The page tables in use, see `with_active`.
+    unsafe fn active() -> Mapper {
+        Mapper::new(Frame::containing_address(registers::cr3()))
+    }
+
+    fn p4(&self) -> &'static mut Table {
+        unsafe { Table::at(self.p4) }
+    }
+
+    /// The physical address mapped at the virtual `address`, if any.
+    pub fn translate(&self, address: usize) -> Option<usize> {
+        let page = Page::containing_address(address);
+        let p3 = match self.p4().next_table(page.p4_index()) {
+            Some(p3) => p3,
+            None => return None,
+        };
+
+        let p3_entry = &p3[page.p3_index()];
+        if p3_entry.flags() & HUGE_PAGE != 0 {
+            return p3_entry.pointed_frame()
+                .map(|frame| frame.start_address() + address % (HUGE_PAGE_SIZE * ENTRY_COUNT));
+        }
+
+        let p2 = match p3.next_table(page.p3_index()) {
+            Some(p2) => p2,
+            None => return None,
+        };
+
+        let p2_entry = &p2[page.p2_index()];
+        if p2_entry.flags() & HUGE_PAGE != 0 {
+            return p2_entry.pointed_frame()
+                .map(|frame| frame.start_address() + address % HUGE_PAGE_SIZE);
+        }
+
+        p2.next_table(page.p2_index())
+            .and_then(|p1| p1[page.p1_index()].pointed_frame())
+            .map(|frame| frame.start_address() + address % PAGE_SIZE)
+    }
+
+    /// Map `page` to `frame`.
+    pub fn map_to(&mut self, page: Page, frame: Frame, flags: u64) -> Result<(), Error> {
+        let p3 = try!(self.p4().next_table_create(page.p4_index()));
+        let p2 = try!(p3.next_table_create(page.p3_index()));
+        let p1 = try!(p2.next_table_create(page.p2_index()));
+
+        if !p1[page.p1_index()].is_unused() {
+            return Err(Error::AlreadyMapped);
+        }
+        p1[page.p1_index()].set(frame, supported(flags) | PRESENT);
+        Ok(())
+    }
+
+    /// Map the 2 MiB page starting at `page` to the 2 MiB aligned frames
+    /// starting at `frame`.
+    pub fn map_huge_to(&mut self, page: Page, frame: Frame, flags: u64) -> Result<(), Error> {
+        assert!(page.start_address() % HUGE_PAGE_SIZE == 0);
+        assert!(frame.start_address() % HUGE_PAGE_SIZE == 0);
+        let p3 = try!(self.p4().next_table_create(page.p4_index()));
+        let p2 = try!(p3.next_table_create(page.p3_index()));
+
+        if !p2[page.p2_index()].is_unused() {
+            return Err(Error::AlreadyMapped);
+        }
+        p2[page.p2_index()].set(frame, supported(flags) | PRESENT | HUGE_PAGE);
+        Ok(())
+    }
+
+    /// Map `page` to a newly allocated frame.
+    pub fn map(&mut self, page: Page, flags: u64) -> Result<(), Error> {
+        let frame = try!(memory::allocate_frame().ok_or(Error::OutOfFrames));
+        self.map_to(page, frame, flags)
+    }
+
+    /// Unmap `page` and return the frame it was mapped to. The page is not
+    /// flushed from the TLBs, see `unmap_range`.
+    fn unmap(&mut self, page: Page) -> Option<Frame> {
+        let p1 = match self.p4().next_table(page.p4_index())
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .and_then(|p2| p2.next_table(page.p2_index())) {
+            Some(p1) => p1,
+            None => return None,
+        };
+
+        let frame = p1[page.p1_index()].pointed_frame();
+        p1[page.p1_index()].set_unused();
+        frame
+    }
+}
+
+/// Run `f` on the page tables in use, locked with interrupts disabled.
+pub fn with_active<F: FnOnce(&mut Mapper) -> R, R>(f: F) -> R {
+    interrupts::without_interrupts(|| {
+        let _tables = ACTIVE_TABLES.lock();
+        f(&mut unsafe { Mapper::active() })
+    })
+}
+
+/// Unmap the pages of `[start, end)` from the page tables in use, flush them
+/// from the TLBs of all the CPUs, then hand the frames they were mapped to
+/// over to `release`. Must be called with interrupts enabled if other CPUs
+/// are online.
+pub fn unmap_range<F: FnMut(Frame)>(start: usize, end: usize, mut release: F) {
+    // The shootdown waits for the other CPUs, which may be spinning on the
+    // table lock with interrupts disabled: it's sent once the lock is
+    // released, for up to a batch of pages at a time.
+    let mut address = start;
+    while address < end {
+        let batch_start = address;
+        let mut frames = [None; UNMAP_BATCH];
+        with_active(|mapper| {
+            for frame in frames.iter_mut() {
+                if address >= end {
+                    break;
+                }
+                *frame = mapper.unmap(Page::containing_address(address));
+                address += PAGE_SIZE;
+            }
+        });
+        if frames.iter().any(|f| f.is_some()) {
+            ipi::shootdown(batch_start, address);
+        }
+        for frame in frames.iter().filter_map(|f| *f) {
+            release(frame);
+        }
+    }
+}
+
+/// Drop the flags the CPU would not accept.
+fn supported(flags: u64) -> u64 {
+    if NO_EXECUTE_ENABLED.load(Ordering::Relaxed) {
+        flags
+    } else {
+        flags & !NO_EXECUTE
+    }
+}
+
+// Section boundaries, defined in linker.ld.
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+    static __bss_start: u8;
+    static __bss_end: u8;
+    // In boot.asm, the page below the boot stack.
+    static kernel_stack_guard: u8;
+}
+
+/// Replace the boot page tables, where everything is writable and
+/// executable, with tables mapping:
+///   - `.text` read only and executable,
+///   - `.rodata` read only, `.data` and `.bss` writable, both no-execute,
+///     except the guard page below the boot stack, left unmapped,
+///   - the physical memory window, writable and no-execute, cached where
+///     the memory map has RAM and uncached elsewhere.
+/// Nothing is mapped in the lower half, so a null pointer dereference
+/// faults. Needs the frame allocator.
+pub fn init() {
+    unsafe {
+        if cpuid::has(Feature::Nx) {
+            msr::write_efer(msr::efer() | EFER_NO_EXECUTE_ENABLE);
+            NO_EXECUTE_ENABLED.store(true, Ordering::Relaxed);
+        } else {
+            warn!("No NX support, data is executable");
+        }
+    }
+
+    let p4_frame = memory::allocate_frame().expect("No frame for the kernel page tables");
+    unsafe { Table::at(p4_frame).zero(); }
+    let mut mapper = unsafe { Mapper::new(p4_frame) };
+
+    let sections = unsafe {
+        [(".text", &__text_start as *const u8 as usize, &__text_end as *const u8 as usize, 0),
+         (".rodata", &__rodata_start as *const u8 as usize, &__rodata_end as *const u8 as usize,
+          NO_EXECUTE),
+         (".data", &__data_start as *const u8 as usize, &__data_end as *const u8 as usize,
+          WRITABLE | NO_EXECUTE),
+         (".bss", &__bss_start as *const u8 as usize, &__bss_end as *const u8 as usize,
+          WRITABLE | NO_EXECUTE)]
+    };
+
+    for &(name, start, end, flags) in sections.iter() {
+        debug!("Mapping {:8} {:#x}-{:#x}", name, start, end);
+        let mut address = start;
+        while address < end {
+            let frame = Frame::containing_address(address - KERNEL_OFFSET);
+            mapper.map_to(Page::containing_address(address), frame, flags)
+                .expect("Failed to map the kernel");
+            address += PAGE_SIZE;
+        }
+        vm::register(start, end, name, flags, Backing::Mapped)
+            .expect("Failed to register the kernel");
+    }
+    // Overflowing the boot stack faults.
+    let guard = unsafe { &kernel_stack_guard as *const u8 as usize };
+    mapper.unmap(Page::containing_address(guard));
+
+    // RAM is cached, the holes between it are uncached, devices may be
+    // there. A huge page holding both is split.
+    let window = |address: usize| Page::containing_address(memory::phys_to_virt(address));
+    let mut address = 0;
+    while address < MAX_PHYSICAL_ADDRESS {
+        let ram = memory::ram_bytes(address, address + HUGE_PAGE_SIZE);
+        if ram == 0 || ram == HUGE_PAGE_SIZE {
+            let flags = if ram == 0 { UNCACHED_WINDOW } else { WRITABLE | NO_EXECUTE };
+            mapper.map_huge_to(window(address), Frame::containing_address(address), flags)
+                .expect("Failed to map the physical memory");
+            address += HUGE_PAGE_SIZE;
+            continue;
+        }
+        for _ in 0..ENTRY_COUNT {
+            let flags = match memory::ram_bytes(address, address + PAGE_SIZE) {
+                PAGE_SIZE => WRITABLE | NO_EXECUTE,
+                _ => UNCACHED_WINDOW,
+            };
+            mapper.map_to(window(address), Frame::containing_address(address), flags)
+                .expect("Failed to map the physical memory");
+            address += PAGE_SIZE;
+        }
+    }
+    vm::register(PHYSICAL_MEMORY_OFFSET, memory::phys_to_virt(MAX_PHYSICAL_ADDRESS),
+                 "physical memory", WRITABLE | NO_EXECUTE, Backing::Mapped)
+        .expect("Failed to register the physical memory");
+
+    unsafe {
+        // Make read only pages read only for the kernel too.
+        registers::write_cr0(registers::cr0() | CR0_WRITE_PROTECT);
+        registers::write_cr3(p4_frame.start_address());
+    }
+    info!("Kernel remapped, physical memory at {:#x}", PHYSICAL_MEMORY_OFFSET);
+}
@@ -0,0 +1,56 @@
+//! Page tables, accessed through the physical memory window.
+
+use core::ops::{Index, IndexMut};
+use memory::{self, Frame};
+use super::entry::*;
+use super::{Error, ENTRY_COUNT};
+
+pub struct Table {
+    entries: [Entry; ENTRY_COUNT],
+}
+
+impl Table {
+    /// The table stored in `frame`.
+    pub unsafe fn at(frame: Frame) -> &'static mut Table {
+        &mut *(memory::phys_to_virt(frame.start_address()) as *mut Table)
+    }
+
+    pub fn zero(&mut self) {
+        for entry in self.entries.iter_mut() {
+            entry.set_unused();
+        }
+    }
+
+    /// The table referenced by entry `index`, if present and not a huge page.
+    pub fn next_table(&self, index: usize) -> Option<&'static mut Table> {
+        if self[index].flags() & HUGE_PAGE != 0 {
+            return None;
+        }
+        self[index].pointed_frame().map(|frame| unsafe { Table::at(frame) })
+    }
+
+    /// The table referenced by entry `index`, allocated if missing.
+    pub fn next_table_create(&mut self, index: usize) -> Result<&'static mut Table, Error> {
+        if self[index].is_unused() {
+            let frame = try!(memory::allocate_frame().ok_or(Error::OutOfFrames));
+            unsafe { Table::at(frame).zero(); }
+            // Permissions are enforced by the last level, keep the upper ones open.
+            self[index].set(frame, PRESENT | WRITABLE);
+        }
+        self.next_table(index).ok_or(Error::HugePage)
+    }
+}
+
+impl Index<usize> for Table {
+    type Output = Entry;
+
+    fn index(&self, index: usize) -> &Entry {
+        &self.entries[index]
+    }
+}
+
+impl IndexMut<usize> for Table {
+    fn index_mut(&mut self, index: usize) -> &mut Entry {
+        &mut self.entries[index]
+    }
+}
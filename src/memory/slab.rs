@@ -0,0 +1,401 @@
+//! Slab allocator for fixed-size kernel objects.
+// https://www.usenix.org/legacy/publications/library/proceedings/bos94/full_papers/bonwick.a
+//
+// A cache hands out objects of a single size, carved from slabs: blocks of
+// frames from the buddy allocator, aligned on their size, starting with a
+// `Slab` header followed by the objects. The header of any object is found
+// by rounding its address down to the slab size. Free objects are linked
+// through their first word, or through a word after them in caches with a
+// constructor, whose free objects must stay intact. Slabs are kept in three
+// lists, by whether they are full, partially used or empty.
+
+use core::fmt;
+use core::mem::size_of;
+use core::ptr;
+use spin::Mutex;
+use memory::{self, Frame, Zone, PAGE_SIZE, MAX_ORDER};
+#[cfg(feature = "heap_debug")]
+use memory::heap_debug;
+
+/// How many caches can exist, for introspection.
+const MAX_CACHES: usize = 32;
+
+/// Slabs are made large enough to hold at least this many objects.
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+/// End of a list.
+const NONE: usize = 0;
+
+/// The header at the start of each slab.
+struct Slab {
+    next: usize,
+    prev: usize,
+    /// First free object.
+    free: usize,
+    /// Allocated objects.
+    in_use: usize,
+}
+
+/// Usage counters of a cache.
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub empty_slabs: usize,
+    pub active_objects: usize,
+    pub allocations: usize,
+    pub frees: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:16} {:6} bytes {:5}/{:5} objects {:4} slabs ({} empty) {} allocs {} frees",
+               self.name, self.object_size, self.active_objects,
+               self.slabs * self.objects_per_slab, self.slabs, self.empty_slabs,
+               self.allocations, self.frees)
+    }
+}
+
+/// Slab lists and counters, behind the cache lock.
+struct Slabs {
+    full: usize,
+    partial: usize,
+    empty: usize,
+    /// Buddy order of a slab, 0 until the first slab is created.
+    order: usize,
+    /// Distance between objects, and offset of the first one. Computed on
+    /// the first allocation.
+    stride: usize,
+    first_object: usize,
+    /// Offset of the free link in an object slot.
+    link: usize,
+    stats: Stats,
+}
+
+/// A cache of objects of the same size and alignment.
+pub struct Cache {
+    name: &'static str,
+    size: usize,
+    align: usize,
+    /// Called once on every object of a new slab. Freed objects must be
+    /// given back in their constructed state.
+    constructor: Option<fn(*mut u8)>,
+    slabs: Mutex<Slabs>,
+}
+
+impl Cache {
+    /// A cache named `name` for objects of `size` bytes, aligned on
+    /// `align`, a power of two.
+    pub const fn new(name: &'static str, size: usize, align: usize,
+                     constructor: Option<fn(*mut u8)>) -> Cache {
+        Cache {
+            name: name,
+            size: size,
+            align: align,
+            constructor: constructor,
+            slabs: Mutex::new(Slabs {
+                full: NONE,
+                partial: NONE,
+                empty: NONE,
+                order: 0,
+                stride: 0,
+                first_object: 0,
+                link: 0,
+                stats: Stats {
+                    name: name,
+                    object_size: size,
+                    objects_per_slab: 0,
+                    slabs: 0,
+                    empty_slabs: 0,
+                    active_objects: 0,
+                    allocations: 0,
+                    frees: 0,
+                },
+            }),
+        }
+    }
+
+    /// Allocate an object for the code at `file:line`, `None` if out of
+    /// memory. See `slab_allocate!`.
+    #[cfg_attr(not(feature = "heap_debug"), allow(unused_variables))]
+    pub fn allocate_at(&'static self, file: &'static str, line: u32) -> Option<*mut u8> {
+        let mut slabs = self.slabs.lock();
+        if slabs.stride == 0 {
+            self.compute_layout(&mut slabs);
+            register(self);
+        }
+
+        if slabs.partial == NONE {
+            if slabs.empty == NONE {
+                if !self.grow(&mut slabs) {
+                    return None;
+                }
+            }
+            let slab = slabs.empty;
+            unlink(&mut slabs.empty, slab);
+            push(&mut slabs.partial, slab);
+            slabs.stats.empty_slabs -= 1;
+        }
+
+        let slab = slabs.partial;
+        let object = unsafe {
+            let header = &mut *(slab as *mut Slab);
+            let object = header.free;
+            header.free = next_free(object, self.size, slabs.link);
+            header.in_use += 1;
+            #[cfg(feature = "heap_debug")]
+            heap_debug::on_allocate(self.name, object, self.size, self.constructor.is_none(),
+                                    file, line);
+            object
+        };
+        if unsafe { (*(slab as *mut Slab)).in_use } == slabs.stats.objects_per_slab {
+            unlink(&mut slabs.partial, slab);
+            push(&mut slabs.full, slab);
+        }
+
+        slabs.stats.active_objects += 1;
+        slabs.stats.allocations += 1;
+        Some(object as *mut u8)
+    }
+
+    /// Give back `object`, obtained from `allocate` on this cache. Empty
+    /// slabs beyond the first one are released right away.
+    pub unsafe fn free(&self, object: *mut u8) {
+        let mut slabs = self.slabs.lock();
+        let slab = object as usize & !(self.slab_size(&slabs) - 1);
+        let header = &mut *(slab as *mut Slab);
+
+        if header.in_use == slabs.stats.objects_per_slab {
+            unlink(&mut slabs.full, slab);
+            push(&mut slabs.partial, slab);
+        }
+        #[cfg(feature = "heap_debug")]
+        heap_debug::on_free(self.name, object as usize, self.size, self.constructor.is_none());
+        set_next_free(object as usize, self.size, slabs.link, header.free);
+        header.free = object as usize;
+        header.in_use -= 1;
+
+        if header.in_use == 0 {
+            unlink(&mut slabs.partial, slab);
+            if slabs.empty != NONE {
+                self.release(&mut slabs, slab);
+            } else {
+                push(&mut slabs.empty, slab);
+                slabs.stats.empty_slabs += 1;
+            }
+        }
+
+        slabs.stats.active_objects -= 1;
+        slabs.stats.frees += 1;
+    }
+
+    /// Release all the empty slabs. Return the number of frames freed.
+    pub fn shrink(&self) -> usize {
+        let mut slabs = self.slabs.lock();
+        let mut frames = 0;
+        while slabs.empty != NONE {
+            let slab = slabs.empty;
+            unlink(&mut slabs.empty, slab);
+            slabs.stats.empty_slabs -= 1;
+            self.release(&mut slabs, slab);
+            frames += 1 << slabs.order;
+        }
+        frames
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.slabs.lock().stats
+    }
+
+    fn slab_size(&self, slabs: &Slabs) -> usize {
+        PAGE_SIZE << slabs.order
+    }
+
+    /// Pick the slab size and object placement.
+    fn compute_layout(&self, slabs: &mut Slabs) {
+        let align = if self.align > size_of::<usize>() { self.align } else { size_of::<usize>() };
+        let round_up = |n: usize| (n + align - 1) & !(align - 1);
+        let word = size_of::<usize>();
+        // The free link of a constructed object goes after it.
+        slabs.link = match self.constructor {
+            Some(_) => (self.size + word - 1) & !(word - 1),
+            None => 0,
+        };
+        let size = if self.size > slabs.link + word { self.size } else { slabs.link + word };
+        #[cfg(feature = "heap_debug")]
+        let size = heap_debug::slot_size(size, align);
+
+        slabs.stride = round_up(size);
+        slabs.first_object = round_up(size_of::<Slab>());
+        slabs.order = 0;
+        while slabs.order < MAX_ORDER &&
+              (self.slab_size(slabs) - slabs.first_object) / slabs.stride < MIN_OBJECTS_PER_SLAB {
+            slabs.order += 1;
+        }
+        slabs.stats.objects_per_slab = (self.slab_size(slabs) - slabs.first_object) / slabs.stride;
+        assert!(slabs.stats.objects_per_slab > 0, "Slab objects too large");
+    }
+
+    /// Add an empty slab, its objects constructed and linked.
+    fn grow(&self, slabs: &mut Slabs) -> bool {
+        // Not `memory::allocate_frames`, whose reclaim would shrink this
+        // cache, locked.
+        let frame = match memory::FRAME_ALLOCATOR.lock().allocate(slabs.order, Zone::Normal) {
+            Some(frame) => frame,
+            None => return false,
+        };
+        let slab = memory::phys_to_virt(frame.start_address());
+
+        let mut free = NONE;
+        for i in (0..slabs.stats.objects_per_slab).rev() {
+            let object = self.object_in(slab + slabs.first_object + i * slabs.stride);
+            if let Some(constructor) = self.constructor {
+                constructor(object as *mut u8);
+            }
+            unsafe {
+                #[cfg(feature = "heap_debug")]
+                heap_debug::init(object, self.size, self.constructor.is_none());
+                set_next_free(object, self.size, slabs.link, free);
+            }
+            free = object;
+        }
+
+        unsafe {
+            ptr::write(slab as *mut Slab, Slab { next: NONE, prev: NONE, free: free, in_use: 0 });
+        }
+        push(&mut slabs.empty, slab);
+        slabs.stats.empty_slabs += 1;
+        slabs.stats.slabs += 1;
+        true
+    }
+
+    /// The object stored in the slot at `slot`.
+    #[cfg(not(feature = "heap_debug"))]
+    fn object_in(&self, slot: usize) -> usize {
+        slot
+    }
+
+    /// The object stored in the slot at `slot`, after the left redzone.
+    #[cfg(feature = "heap_debug")]
+    fn object_in(&self, slot: usize) -> usize {
+        let align = if self.align > size_of::<usize>() { self.align } else { size_of::<usize>() };
+        slot + heap_debug::left_redzone(align)
+    }
+
+    /// Print the allocated objects on the serial port.
+    #[cfg(feature = "heap_debug")]
+    fn dump_live(&self) {
+        let slabs = self.slabs.lock();
+        for &head in [slabs.full, slabs.partial].iter() {
+            let mut slab = head;
+            while slab != NONE {
+                for i in 0..slabs.stats.objects_per_slab {
+                    let object = self.object_in(slab + slabs.first_object + i * slabs.stride);
+                    let track = unsafe { heap_debug::track(object, self.size) };
+                    if track.is_allocated() {
+                        heap_debug::report_live(self.name, object, track);
+                    }
+                }
+                slab = unsafe { (*(slab as *const Slab)).next };
+            }
+        }
+    }
+
+    /// Give the frames of an unlinked empty slab back.
+    fn release(&self, slabs: &mut Slabs, slab: usize) {
+        let frame = Frame::containing_address(memory::virt_to_phys(slab));
+        memory::deallocate_frames(frame, slabs.order);
+        slabs.stats.slabs -= 1;
+    }
+}
+
+/// The free object following `object`, linked through the word at `link`.
+#[cfg(not(feature = "heap_debug"))]
+unsafe fn next_free(object: usize, _size: usize, link: usize) -> usize {
+    ptr::read((object + link) as *const usize)
+}
+
+#[cfg(not(feature = "heap_debug"))]
+unsafe fn set_next_free(object: usize, _size: usize, link: usize, next: usize) {
+    ptr::write((object + link) as *mut usize, next);
+}
+
+/// The free object following `object`, linked through its track as the
+/// object itself is poisoned.
+#[cfg(feature = "heap_debug")]
+unsafe fn next_free(object: usize, size: usize, _link: usize) -> usize {
+    heap_debug::track(object, size).next
+}
+
+#[cfg(feature = "heap_debug")]
+unsafe fn set_next_free(object: usize, size: usize, _link: usize, next: usize) {
+    heap_debug::track(object, size).next = next;
+}
+
+/// Push `slab` at the front of the list starting at `head`.
+fn push(head: &mut usize, slab: usize) {
+    unsafe {
+        let header = &mut *(slab as *mut Slab);
+        header.prev = NONE;
+        header.next = *head;
+        if *head != NONE {
+            (*(*head as *mut Slab)).prev = slab;
+        }
+    }
+    *head = slab;
+}
+
+/// Remove `slab` from the list starting at `head`.
+fn unlink(head: &mut usize, slab: usize) {
+    unsafe {
+        let header = &mut *(slab as *mut Slab);
+        if header.prev == NONE {
+            *head = header.next;
+        } else {
+            (*(header.prev as *mut Slab)).next = header.next;
+        }
+        if header.next != NONE {
+            (*(header.next as *mut Slab)).prev = header.prev;
+        }
+    }
+}
+
+static CACHES: Mutex<[Option<&'static Cache>; MAX_CACHES]> = Mutex::new([None; MAX_CACHES]);
+
+/// Add `cache` to the list used by `caches` and `reclaim`.
+fn register(cache: &'static Cache) {
+    let mut caches = CACHES.lock();
+    match caches.iter_mut().find(|c| c.is_none()) {
+        Some(slot) => *slot = Some(cache),
+        None => warn!("Too many slab caches, {} is not listed", cache.name),
+    }
+}
+
+/// The caches used so far.
+pub fn caches() -> [Option<&'static Cache>; MAX_CACHES] {
+    *CACHES.lock()
+}
+
+/// Release the empty slabs of all caches. Return the number of frames
+/// freed. Called by the frame allocator when out of memory.
+pub fn reclaim() -> usize {
+    caches().iter().filter_map(|c| *c).map(|cache| cache.shrink()).sum()
+}
+
+/// Print the statistics of all caches.
+pub fn dump() {
+    for cache in caches().iter().filter_map(|c| *c) {
+        info!("{}", cache.stats());
+    }
+}
+
+/// Print the objects allocated from all caches, with their call site, on
+/// the serial port to look for leaks.
+#[cfg(feature = "heap_debug")]
+pub fn dump_live() {
+    for cache in caches().iter().filter_map(|c| *c) {
+        cache.dump_live();
+    }
+}
@@ -0,0 +1,254 @@
+//! Regions of the kernel virtual address space.
+//!
+//! Every range of virtual memory the kernel uses is registered here, either
+//! mapped up front (the kernel image, the physical memory window) or
+//! anonymous: backed by zeroed frames allocated on first touch by the page
+//! fault handler. Large ranges, such as heaps and stacks, can so be reserved
+//! without paying for them until they are used.
+
+use core::fmt;
+use spin::Mutex;
+use arch::fpu;
+use memory::{self, Frame, PAGE_SIZE};
+use memory::paging::{self, Page, WRITABLE, NO_EXECUTE, NO_CACHE, WRITE_THROUGH};
+
+/// How many regions can be registered at the same time.
+const MAX_REGIONS: usize = 32;
+
+/// Where `allocate` picks address ranges, between the physical memory
+/// window and the kernel image.
+pub const DYNAMIC_START: usize = 0xFFFF_C000_0000_0000;
+pub const DYNAMIC_END: usize = 0xFFFF_E000_0000_0000;
+
+/// Page fault error code bits.
+pub const FAULT_PRESENT: u32 = 1 << 0;
+pub const FAULT_WRITE: u32 = 1 << 1;
+pub const FAULT_USER: u32 = 1 << 2;
+pub const FAULT_RESERVED: u32 = 1 << 3;
+pub const FAULT_INSTRUCTION: u32 = 1 << 4;
+
+/// What's behind the pages of a region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backing {
+    /// Mapped when the region was registered, faults are errors.
+    Mapped,
+    /// Zeroed frames allocated on first access.
+    Anonymous,
+}
+
+/// Reasons for failing to register a region.
+#[derive(Debug)]
+pub enum Error {
+    /// Start or end is not page aligned, or the region is empty.
+    Unaligned,
+    /// The region overlaps a registered one.
+    Overlap,
+    /// No room left in the registry, or in the dynamic area.
+    Full,
+    /// The pages of the region could not be mapped.
+    Map(paging::Error),
+}
+
+/// A range of virtual memory `[start, end)`.
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub name: &'static str,
+    /// Page table entry flags of the pages, see `memory::paging`.
+    pub flags: u64,
+    pub backing: Backing,
+}
+
+impl Region {
+    pub fn contains(&self, address: usize) -> bool {
+        self.start <= address && address < self.end
+    }
+
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end
+    }
+
+    /// Whether an access described by the page fault `error_code` is
+    /// allowed by the region flags.
+    fn allows(&self, error_code: u32) -> bool {
+        (error_code & FAULT_WRITE == 0 || self.flags & WRITABLE != 0) &&
+            (error_code & FAULT_INSTRUCTION == 0 || self.flags & NO_EXECUTE == 0)
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}-{:#018x} r{}{} {:?} {}",
+               self.start, self.end,
+               if self.flags & WRITABLE != 0 { "w" } else { "-" },
+               if self.flags & NO_EXECUTE != 0 { "-" } else { "x" },
+               self.backing, self.name)
+    }
+}
+
+static REGIONS: Mutex<[Option<Region>; MAX_REGIONS]> = Mutex::new([None; MAX_REGIONS]);
+
+/// Register the region `[start, end)`. Pages of `Backing::Mapped` regions
+/// must be mapped by the caller.
+pub fn register(start: usize, end: usize, name: &'static str, flags: u64, backing: Backing)
+                -> Result<(), Error> {
+    if start % PAGE_SIZE != 0 || end % PAGE_SIZE != 0 || start >= end {
+        return Err(Error::Unaligned);
+    }
+
+    insert(&mut REGIONS.lock(),
+           Region { start: start, end: end, name: name, flags: flags, backing: backing })
+}
+
+/// Register `size` bytes, a multiple of pages, of free address space in the
+/// dynamic area and return the start address.
+fn register_free(size: usize, name: &'static str, flags: u64, backing: Backing)
+                 -> Result<usize, Error> {
+    // Under the same lock, or another CPU could pick the same range.
+    let mut regions = REGIONS.lock();
+    let start = try!(find_free(&regions, size));
+    try!(insert(&mut regions, Region { start: start, end: start + size, name: name,
+                                       flags: flags, backing: backing }));
+    Ok(start)
+}
+
+fn insert(regions: &mut [Option<Region>; MAX_REGIONS], region: Region) -> Result<(), Error> {
+    if regions.iter().filter_map(|r| *r).any(|r| r.overlaps(region.start, region.end)) {
+        return Err(Error::Overlap);
+    }
+    match regions.iter_mut().find(|r| r.is_none()) {
+        Some(slot) => {
+            *slot = Some(region);
+            Ok(())
+        }
+        None => Err(Error::Full),
+    }
+}
+
+/// Find `size` bytes, a multiple of pages, of free address space in the
+/// dynamic area.
+fn find_free(regions: &[Option<Region>; MAX_REGIONS], size: usize) -> Result<usize, Error> {
+    // Try right after each region of the dynamic area, lowest first.
+    let mut start = DYNAMIC_START;
+    loop {
+        let end = match start.checked_add(size) {
+            Some(end) if end <= DYNAMIC_END => end,
+            _ => return Err(Error::Full),
+        };
+        let next = regions.iter()
+            .filter_map(|r| *r)
+            .filter(|r| r.overlaps(start, end))
+            .map(|r| r.end)
+            .max();
+        match next {
+            Some(next) => start = next,
+            None => return Ok(start),
+        }
+    }
+}
+
+/// Reserve `size` bytes, rounded up to pages, of anonymous memory in the
+/// dynamic area and return the start address. Nothing is allocated until
+/// the pages are touched.
+pub fn allocate(size: usize, name: &'static str, flags: u64) -> Result<usize, Error> {
+    let size = (size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    register_free(size, name, flags, Backing::Anonymous)
+}
+
+/// Map the device memory `[physical, physical + size)` uncached in the
+/// dynamic area, and return the virtual address of `physical`.
+pub fn map_device(physical: usize, size: usize, name: &'static str) -> Result<usize, Error> {
+    let first = physical / PAGE_SIZE * PAGE_SIZE;
+    let size = (physical + size - first + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    let flags = WRITABLE | NO_EXECUTE | NO_CACHE | WRITE_THROUGH;
+    let start = try!(register_free(size, name, flags, Backing::Mapped));
+
+    let mapped = paging::with_active(|mapper| {
+        let mut offset = 0;
+        while offset < size {
+            try!(mapper.map_to(Page::containing_address(start + offset),
+                               Frame::containing_address(first + offset), flags));
+            offset += PAGE_SIZE;
+        }
+        Ok(())
+    });
+    if let Err(error) = mapped {
+        // Unmaps the pages mapped so far.
+        unregister(start);
+        return Err(Error::Map(error));
+    }
+    Ok(start + physical - first)
+}
+
+/// Remove the region starting at `start`. The pages of a region of the
+/// dynamic area are unmapped, and the frames backing an anonymous one given
+/// back. Return `false` if there is no such region.
+pub fn unregister(start: usize) -> bool {
+    let region = {
+        let mut regions = REGIONS.lock();
+        match regions.iter_mut().find(|r| r.map_or(false, |r| r.start == start)) {
+            Some(slot) => slot.take().unwrap(),
+            None => return false,
+        }
+    };
+
+    if region.start < DYNAMIC_START || region.end > DYNAMIC_END {
+        return true;
+    }
+
+    match region.backing {
+        Backing::Anonymous => paging::unmap_range(region.start, region.end,
+                                                  memory::deallocate_frame),
+        // Device frames are left alone.
+        Backing::Mapped => paging::unmap_range(region.start, region.end, |_| {}),
+    }
+    true
+}
+
+/// The region containing `address`, if any.
+pub fn find(address: usize) -> Option<Region> {
+    REGIONS.lock().iter().filter_map(|r| *r).find(|r| r.contains(address))
+}
+
+/// The registered regions.
+pub fn regions() -> [Option<Region>; MAX_REGIONS] {
+    *REGIONS.lock()
+}
+
+/// Try to resolve a page fault at `address`: back the page with a zeroed
+/// frame if it belongs to an anonymous region allowing the access. Return
+/// `false` if the fault is an error.
+pub fn handle_page_fault(address: usize, error_code: u32) -> bool {
+    let region = match find(address) {
+        Some(region) => region,
+        None => return false,
+    };
+    if region.backing != Backing::Anonymous || error_code & FAULT_PRESENT != 0 ||
+       !region.allows(error_code) {
+        return false;
+    }
+
+    let frame = match memory::allocate_frame() {
+        Some(frame) => frame,
+        None => {
+            error!("Out of memory backing {} at {:#x}", region.name, address);
+            return false;
+        }
+    };
+    unsafe { fpu::zero_page(memory::phys_to_virt(frame.start_address())); }
+
+    let page = Page::containing_address(address);
+    match paging::with_active(|mapper| mapper.map_to(page, frame, region.flags)) {
+        Ok(()) => true,
+        Err(paging::Error::AlreadyMapped) => {
+            // Another path mapped it meanwhile, retrying is enough.
+            memory::deallocate_frame(frame);
+            true
+        }
+        Err(_) => {
+            memory::deallocate_frame(frame);
+            false
+        }
+    }
+}
@@ -0,0 +1,448 @@
+//! Access to the boot information structure passed by a Multiboot2
+//! compliant bootloader (i.e. GRUB 2).
+// http://nongnu.askapache.com/grub/phcoder/multiboot.pdf
+
+use core::mem::size_of;
+use core::slice;
+use core::str;
+use memory::{phys_to_virt, virt_to_phys};
+
+/// Tag types we know about.
+const TAG_END: u32 = 0;
+const TAG_COMMAND_LINE: u32 = 1;
+const TAG_MODULE: u32 = 3;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_ELF_SECTIONS: u32 = 9;
+const TAG_ACPI_OLD_RSDP: u32 = 14;
+const TAG_ACPI_NEW_RSDP: u32 = 15;
+
+/// Memory area type for usable RAM.
+const MEMORY_AVAILABLE: u32 = 1;
+/// Memory area types for RAM holding the ACPI tables, and kept by the
+/// firmware.
+const MEMORY_ACPI_RECLAIMABLE: u32 = 3;
+const MEMORY_ACPI_NVS: u32 = 4;
+
+static USABLE_TYPES: [u32; 1] = [MEMORY_AVAILABLE];
+static RAM_TYPES: [u32; 3] = [MEMORY_AVAILABLE, MEMORY_ACPI_RECLAIMABLE, MEMORY_ACPI_NVS];
+
+/// ELF section flag for sections occupying memory at run time.
+const ELF_SECTION_ALLOCATED: u64 = 0x2;
+
+/// The fixed part at the start of the boot information structure,
+/// followed by 8-byte aligned tags.
+#[repr(C)]
+pub struct BootInformation {
+    pub total_size: u32,
+    _reserved: u32,
+}
+
+/// Load the boot information structure at the physical `address`, as found
+/// in `ebx` when the bootloader jumped to the kernel.
+pub unsafe fn load(address: usize) -> &'static BootInformation {
+    &*(phys_to_virt(address) as *const BootInformation)
+}
+
+impl BootInformation {
+    /// The first byte of the structure.
+    pub fn start_address(&self) -> usize {
+        self as *const _ as usize
+    }
+
+    /// One past the last byte of the structure.
+    pub fn end_address(&self) -> usize {
+        self.start_address() + self.total_size as usize
+    }
+
+    /// Iterate over all tags.
+    pub fn tags(&self) -> TagIter {
+        TagIter { current: (self.start_address() + size_of::<BootInformation>()) as *const Tag }
+    }
+
+    /// The first tag of type `typ`, if any.
+    pub fn find_tag(&self, typ: u32) -> Option<&'static Tag> {
+        self.tags().find(|tag| tag.typ == typ)
+    }
+
+    /// The kernel command line, if any.
+    pub fn command_line(&self) -> Option<&'static str> {
+        self.find_tag(TAG_COMMAND_LINE).and_then(|tag| {
+            let data = tag.data();
+            // The string is NUL terminated.
+            let length = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+            str::from_utf8(&data[..length]).ok()
+        })
+    }
+
+    /// Information about the framebuffer set up by the bootloader, if any.
+    pub fn framebuffer_tag(&self) -> Option<FramebufferTag> {
+        self.find_tag(TAG_FRAMEBUFFER).map(|tag| unsafe { FramebufferTag::parse(tag) })
+    }
+
+    /// The physical memory map.
+    pub fn memory_map_tag(&self) -> Option<&'static MemoryMapTag> {
+        self.find_tag(TAG_MEMORY_MAP)
+            .map(|tag| unsafe { &*(tag as *const Tag as *const MemoryMapTag) })
+    }
+
+    /// The kernel ELF section headers.
+    pub fn elf_sections_tag(&self) -> Option<&'static ElfSectionsTag> {
+        self.find_tag(TAG_ELF_SECTIONS)
+            .map(|tag| unsafe { &*(tag as *const Tag as *const ElfSectionsTag) })
+    }
+
+    /// A copy of the ACPI RSDP, the ACPI 2.0 one if available.
+    pub fn acpi_rsdp(&self) -> Option<&'static [u8]> {
+        self.find_tag(TAG_ACPI_NEW_RSDP)
+            .or_else(|| self.find_tag(TAG_ACPI_OLD_RSDP))
+            .map(|tag| tag.data())
+    }
+
+    /// The modules loaded along with the kernel.
+    pub fn modules(&self) -> ModuleIter {
+        ModuleIter { tags: self.tags() }
+    }
+}
+
+/// The common header of all tags.
+#[repr(C)]
+pub struct Tag {
+    pub typ: u32,
+    pub size: u32,
+}
+
+impl Tag {
+    /// The tag payload, after the common header.
+    pub fn data(&self) -> &'static [u8] {
+        unsafe {
+            slice::from_raw_parts((self as *const Tag).offset(1) as *const u8,
+                                  self.size as usize - size_of::<Tag>())
+        }
+    }
+}
+
+/// An iterator over the tags of a `BootInformation`.
+pub struct TagIter {
+    current: *const Tag,
+}
+
+impl Iterator for TagIter {
+    type Item = &'static Tag;
+
+    fn next(&mut self) -> Option<&'static Tag> {
+        let tag = unsafe { &*self.current };
+        if tag.typ == TAG_END {
+            return None;
+        }
+
+        // Tags are padded to start on an 8-byte boundary.
+        let next = self.current as usize + ((tag.size as usize + 7) & !7);
+        self.current = next as *const Tag;
+        Some(tag)
+    }
+}
+
+/// Position and size in bits of a color channel in a direct RGB framebuffer.
+#[derive(Clone, Copy, Debug)]
+pub struct ColorField {
+    pub position: u8,
+    pub size: u8,
+}
+
+/// An entry of the palette of an indexed framebuffer.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct PaletteColor {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+/// The kind of framebuffer the bootloader set up.
+#[derive(Debug)]
+pub enum FramebufferType {
+    /// Pixels are indexes into a palette.
+    Indexed { palette: &'static [PaletteColor] },
+    /// Pixels are direct RGB colors.
+    Rgb { red: ColorField, green: ColorField, blue: ColorField },
+    /// The usual VGA text mode, i.e. `address` is 0xB8000.
+    EgaText,
+}
+
+/// The fixed part of the framebuffer info tag.
+#[repr(C, packed)]
+struct FramebufferTagHeader {
+    typ: u32,
+    size: u32,
+    address: u64,
+    pitch: u32,
+    width: u32,
+    height: u32,
+    bpp: u8,
+    framebuffer_type: u8,
+    _reserved: u16,
+}
+
+/// The framebuffer info tag.
+#[derive(Debug)]
+pub struct FramebufferTag {
+    /// Physical address of the framebuffer.
+    pub address: u64,
+    /// Bytes per scan line.
+    pub pitch: u32,
+    /// Width in pixels, or in characters for EGA text.
+    pub width: u32,
+    /// Height in pixels, or in characters for EGA text.
+    pub height: u32,
+    /// Bits per pixel.
+    pub bpp: u8,
+    pub framebuffer_type: FramebufferType,
+}
+
+impl FramebufferTag {
+    unsafe fn parse(tag: &'static Tag) -> FramebufferTag {
+        let header = &*(tag as *const Tag as *const FramebufferTagHeader);
+        let color_info = &tag.data()[size_of::<FramebufferTagHeader>() - size_of::<Tag>()..];
+
+        let framebuffer_type = match header.framebuffer_type {
+            0 => {
+                let count = (color_info[0] as usize) | (color_info[1] as usize) << 8;
+                let palette = slice::from_raw_parts(
+                    color_info[2..].as_ptr() as *const PaletteColor, count);
+                FramebufferType::Indexed { palette: palette }
+            }
+            1 => FramebufferType::Rgb {
+                red: ColorField { position: color_info[0], size: color_info[1] },
+                green: ColorField { position: color_info[2], size: color_info[3] },
+                blue: ColorField { position: color_info[4], size: color_info[5] },
+            },
+            _ => FramebufferType::EgaText,
+        };
+
+        FramebufferTag {
+            address: header.address,
+            pitch: header.pitch,
+            width: header.width,
+            height: header.height,
+            bpp: header.bpp,
+            framebuffer_type: framebuffer_type,
+        }
+    }
+}
+
+/// The memory map tag, a list of `MemoryArea`s.
+#[repr(C)]
+pub struct MemoryMapTag {
+    typ: u32,
+    size: u32,
+    entry_size: u32,
+    entry_version: u32,
+}
+
+impl MemoryMapTag {
+    /// Iterate over the areas of usable RAM.
+    pub fn memory_areas(&'static self) -> MemoryAreaIter {
+        self.areas(&USABLE_TYPES)
+    }
+
+    /// Iterate over the areas of RAM, usable or not. The rest of the
+    /// physical address space may be devices.
+    pub fn ram_areas(&'static self) -> MemoryAreaIter {
+        self.areas(&RAM_TYPES)
+    }
+
+    fn areas(&'static self, types: &'static [u32]) -> MemoryAreaIter {
+        let start = self as *const MemoryMapTag as usize + size_of::<MemoryMapTag>();
+        MemoryAreaIter {
+            current: start,
+            end: self as *const MemoryMapTag as usize + self.size as usize,
+            entry_size: self.entry_size as usize,
+            types: types,
+        }
+    }
+}
+
+/// An area of physical memory.
+#[derive(Debug)]
+#[repr(C)]
+pub struct MemoryArea {
+    pub base_address: u64,
+    pub length: u64,
+    typ: u32,
+    _reserved: u32,
+}
+
+impl MemoryArea {
+    /// One past the last byte of the area.
+    pub fn end_address(&self) -> u64 {
+        self.base_address + self.length
+    }
+}
+
+/// An iterator over the areas of some types of a `MemoryMapTag`.
+#[derive(Clone)]
+pub struct MemoryAreaIter {
+    current: usize,
+    end: usize,
+    entry_size: usize,
+    types: &'static [u32],
+}
+
+impl Iterator for MemoryAreaIter {
+    type Item = &'static MemoryArea;
+
+    fn next(&mut self) -> Option<&'static MemoryArea> {
+        while self.current < self.end {
+            let area = unsafe { &*(self.current as *const MemoryArea) };
+            self.current += self.entry_size;
+            if self.types.contains(&area.typ) {
+                return Some(area);
+            }
+        }
+        None
+    }
+}
+
+/// The ELF sections tag, followed by the kernel section headers.
+#[repr(C)]
+pub struct ElfSectionsTag {
+    typ: u32,
+    size: u32,
+    number_of_sections: u32,
+    entry_size: u32,
+    string_table_index: u32,
+}
+
+impl ElfSectionsTag {
+    /// Iterate over the section headers.
+    pub fn sections(&'static self) -> ElfSectionIter {
+        ElfSectionIter {
+            current: self as *const ElfSectionsTag as usize + size_of::<ElfSectionsTag>(),
+            remaining: self.number_of_sections,
+            entry_size: self.entry_size as usize,
+        }
+    }
+
+    /// The physical address range `[start, end)` covered by the kernel in
+    /// memory. Sections are linked either in the higher half or, for the
+    /// boot code, at their physical address.
+    pub fn kernel_range(&'static self) -> (usize, usize) {
+        let mut start = !0;
+        let mut end = 0;
+        for section in self.sections().filter(|s| s.is_allocated()) {
+            let section_start = virt_to_phys(section.address as usize);
+            let section_end = section_start + section.size as usize;
+            if section_start < start {
+                start = section_start;
+            }
+            if section_end > end {
+                end = section_end;
+            }
+        }
+        (start, end)
+    }
+}
+
+/// An ELF64 section header.
+#[derive(Debug)]
+#[repr(C)]
+pub struct ElfSection {
+    name_index: u32,
+    typ: u32,
+    pub flags: u64,
+    pub address: u64,
+    offset: u64,
+    pub size: u64,
+    link: u32,
+    info: u32,
+    pub alignment: u64,
+    entry_size: u64,
+}
+
+impl ElfSection {
+    /// Whether the section occupies memory at run time.
+    pub fn is_allocated(&self) -> bool {
+        self.flags & ELF_SECTION_ALLOCATED != 0
+    }
+
+    /// One past the last byte of the section.
+    pub fn end_address(&self) -> usize {
+        (self.address + self.size) as usize
+    }
+}
+
+/// An iterator over the section headers of an `ElfSectionsTag`.
+pub struct ElfSectionIter {
+    current: usize,
+    remaining: u32,
+    entry_size: usize,
+}
+
+impl Iterator for ElfSectionIter {
+    type Item = &'static ElfSection;
+
+    fn next(&mut self) -> Option<&'static ElfSection> {
+        while self.remaining > 0 {
+            let section = unsafe { &*(self.current as *const ElfSection) };
+            self.current += self.entry_size;
+            self.remaining -= 1;
+            // Skip the null section at index 0
+            if section.typ != 0 {
+                return Some(section);
+            }
+        }
+        None
+    }
+}
+
+/// A module tag, describing a file loaded by the bootloader.
+#[repr(C)]
+pub struct ModuleTag {
+    typ: u32,
+    size: u32,
+    start: u32,
+    end: u32,
+}
+
+impl ModuleTag {
+    /// Physical address of the first byte of the module.
+    pub fn start_address(&self) -> usize {
+        self.start as usize
+    }
+
+    /// Physical address one past the last byte of the module.
+    pub fn end_address(&self) -> usize {
+        self.end as usize
+    }
+
+    /// The string given after the file name on the `module2` line.
+    pub fn name(&self) -> &'static str {
+        let data = unsafe {
+            slice::from_raw_parts((self as *const ModuleTag).offset(1) as *const u8,
+                                  self.size as usize - size_of::<ModuleTag>())
+        };
+        let length = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+        str::from_utf8(&data[..length]).unwrap_or("")
+    }
+
+    /// The module content.
+    pub unsafe fn data(&self) -> &'static [u8] {
+        slice::from_raw_parts(phys_to_virt(self.start as usize) as *const u8,
+                              (self.end - self.start) as usize)
+    }
+}
+
+/// An iterator over the module tags.
+pub struct ModuleIter {
+    tags: TagIter,
+}
+
+impl Iterator for ModuleIter {
+    type Item = &'static ModuleTag;
+
+    fn next(&mut self) -> Option<&'static ModuleTag> {
+        self.tags.find(|tag| tag.typ == TAG_MODULE)
+            .map(|tag| unsafe { &*(tag as *const Tag as *const ModuleTag) })
+    }
+}